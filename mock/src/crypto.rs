@@ -0,0 +1,554 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem;
+use std::ptr;
+
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, BlockSizeUser, KeyInit};
+use aes::{Aes128, Aes192, Aes256};
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+use rsa::{BigUint, Pkcs1v15Encrypt, Pkcs1v15Sign, Pss, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use pkcs11::der;
+use pkcs11::types::*;
+use pkcs11::x509;
+
+use object::{ulong_value, Attribute, Object};
+
+const AES_BLOCK: usize = 16;
+
+/// Mechanism type and parameter bytes as passed to one of the `C_*Init` calls.
+#[derive(Debug, Clone)]
+pub struct Mechanism {
+  pub mechanism: CK_MECHANISM_TYPE,
+  pub parameter: Vec<u8>,
+}
+
+/// The mechanisms the mock token supports, with key sizes and flags for `C_GetMechanismInfo`.
+pub const MECHANISMS: &[(CK_MECHANISM_TYPE, CK_ULONG, CK_ULONG, CK_FLAGS)] = &[
+  (CKM_SHA_1, 0, 0, CKF_DIGEST),
+  (CKM_SHA256, 0, 0, CKF_DIGEST),
+  (CKM_SHA384, 0, 0, CKF_DIGEST),
+  (CKM_SHA512, 0, 0, CKF_DIGEST),
+  (CKM_AES_KEY_GEN, 16, 32, CKF_GENERATE),
+  (CKM_GENERIC_SECRET_KEY_GEN, 8, 4096, CKF_GENERATE),
+  (CKM_DES3_KEY_GEN, 24, 24, CKF_GENERATE),
+  (CKM_AES_ECB, 16, 32, CKF_ENCRYPT | CKF_DECRYPT),
+  (CKM_AES_CBC, 16, 32, CKF_ENCRYPT | CKF_DECRYPT),
+  (CKM_AES_CBC_PAD, 16, 32, CKF_ENCRYPT | CKF_DECRYPT),
+  (CKM_RSA_PKCS_KEY_PAIR_GEN, 512, 4096, CKF_GENERATE_KEY_PAIR),
+  (CKM_RSA_PKCS, 512, 4096, CKF_ENCRYPT | CKF_DECRYPT | CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA1_RSA_PKCS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA256_RSA_PKCS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA384_RSA_PKCS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA512_RSA_PKCS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_RSA_PKCS_PSS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA256_RSA_PKCS_PSS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA384_RSA_PKCS_PSS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_SHA512_RSA_PKCS_PSS, 512, 4096, CKF_SIGN | CKF_VERIFY),
+  (CKM_EC_KEY_PAIR_GEN, 256, 384, CKF_GENERATE_KEY_PAIR | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+  (CKM_ECDSA, 256, 384, CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+  (CKM_ECDSA_SHA1, 256, 384, CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+  (CKM_ECDSA_SHA256, 256, 384, CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+  (CKM_ECDSA_SHA384, 256, 384, CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+  (CKM_ECDSA_SHA512, 256, 384, CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS),
+];
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+  let mut out = vec![0u8; len];
+  OsRng.fill_bytes(&mut out);
+  out
+}
+
+/// A running digest.
+#[derive(Clone)]
+pub enum Hasher {
+  Sha1(Sha1),
+  Sha256(Sha256),
+  Sha384(Sha384),
+  Sha512(Sha512),
+}
+
+impl Hasher {
+  pub fn new(mechanism: CK_MECHANISM_TYPE) -> Option<Hasher> {
+    match mechanism {
+      CKM_SHA_1 => Some(Hasher::Sha1(Sha1::new())),
+      CKM_SHA256 => Some(Hasher::Sha256(Sha256::new())),
+      CKM_SHA384 => Some(Hasher::Sha384(Sha384::new())),
+      CKM_SHA512 => Some(Hasher::Sha512(Sha512::new())),
+      _ => None,
+    }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    match *self {
+      Hasher::Sha1(ref mut h) => h.update(data),
+      Hasher::Sha256(ref mut h) => h.update(data),
+      Hasher::Sha384(ref mut h) => h.update(data),
+      Hasher::Sha512(ref mut h) => h.update(data),
+    }
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    match self {
+      Hasher::Sha1(h) => h.finalize().to_vec(),
+      Hasher::Sha256(h) => h.finalize().to_vec(),
+      Hasher::Sha384(h) => h.finalize().to_vec(),
+      Hasher::Sha512(h) => h.finalize().to_vec(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub enum EcPrivateKey {
+  P256(p256::ecdsa::SigningKey),
+  P384(p384::ecdsa::SigningKey),
+}
+
+#[derive(Clone)]
+pub enum EcPublicKey {
+  P256(p256::ecdsa::VerifyingKey),
+  P384(p384::ecdsa::VerifyingKey),
+}
+
+/// Key material reconstructed from the attributes of a key object.
+#[derive(Clone)]
+pub enum Key {
+  Secret(Vec<u8>),
+  RsaPrivate(Box<RsaPrivateKey>),
+  RsaPublic(RsaPublicKey),
+  EcPrivate(EcPrivateKey),
+  EcPublic(EcPublicKey),
+}
+
+fn biguint(object: &Object, attr_type: CK_ATTRIBUTE_TYPE) -> Result<BigUint, CK_RV> {
+  object.get(attr_type).map(BigUint::from_bytes_be).ok_or(CKR_KEY_TYPE_INCONSISTENT)
+}
+
+/// The field size of a supported named curve in `CKA_EC_PARAMS`.
+fn curve_width(params: &[u8]) -> Result<usize, CK_RV> {
+  if params == &der::oid(x509::OID_SECP256R1)[..] {
+    Ok(32)
+  } else if params == &der::oid(x509::OID_SECP384R1)[..] {
+    Ok(48)
+  } else {
+    Err(CKR_CURVE_NOT_SUPPORTED)
+  }
+}
+
+impl Key {
+  pub fn from_object(object: &Object) -> Result<Key, CK_RV> {
+    match (object.class(), object.key_type()) {
+      (Some(CKO_SECRET_KEY), Some(CKK_AES)) | (Some(CKO_SECRET_KEY), Some(CKK_GENERIC_SECRET)) => object.get(CKA_VALUE).map(|v| Key::Secret(v.to_vec())).ok_or(CKR_KEY_TYPE_INCONSISTENT),
+      (Some(CKO_PRIVATE_KEY), Some(CKK_RSA)) => {
+        let primes = match (object.get(CKA_PRIME_1), object.get(CKA_PRIME_2)) {
+          (Some(p), Some(q)) => vec![BigUint::from_bytes_be(p), BigUint::from_bytes_be(q)],
+          _ => Vec::new(),
+        };
+        RsaPrivateKey::from_components(biguint(object, CKA_MODULUS)?, biguint(object, CKA_PUBLIC_EXPONENT)?, biguint(object, CKA_PRIVATE_EXPONENT)?, primes)
+          .map(|key| Key::RsaPrivate(Box::new(key)))
+          .map_err(|_| CKR_KEY_TYPE_INCONSISTENT)
+      }
+      (Some(CKO_PUBLIC_KEY), Some(CKK_RSA)) => RsaPublicKey::new(biguint(object, CKA_MODULUS)?, biguint(object, CKA_PUBLIC_EXPONENT)?)
+        .map(Key::RsaPublic)
+        .map_err(|_| CKR_KEY_TYPE_INCONSISTENT),
+      (Some(CKO_PRIVATE_KEY), Some(CKK_EC)) => {
+        let value = object.get(CKA_VALUE).ok_or(CKR_KEY_TYPE_INCONSISTENT)?;
+        let key = match curve_width(object.get(CKA_EC_PARAMS).unwrap_or(&[]))? {
+          32 => p256::ecdsa::SigningKey::from_slice(value).map(EcPrivateKey::P256),
+          _ => p384::ecdsa::SigningKey::from_slice(value).map(EcPrivateKey::P384),
+        };
+        key.map(Key::EcPrivate).map_err(|_| CKR_KEY_TYPE_INCONSISTENT)
+      }
+      (Some(CKO_PUBLIC_KEY), Some(CKK_EC)) => {
+        let point = x509::unwrap_ec_point(object.get(CKA_EC_POINT).ok_or(CKR_KEY_TYPE_INCONSISTENT)?);
+        let key = match curve_width(object.get(CKA_EC_PARAMS).unwrap_or(&[]))? {
+          32 => p256::ecdsa::VerifyingKey::from_sec1_bytes(&point).map(EcPublicKey::P256),
+          _ => p384::ecdsa::VerifyingKey::from_sec1_bytes(&point).map(EcPublicKey::P384),
+        };
+        key.map(Key::EcPublic).map_err(|_| CKR_KEY_TYPE_INCONSISTENT)
+      }
+      _ => Err(CKR_KEY_TYPE_INCONSISTENT),
+    }
+  }
+}
+
+fn key_attributes(class: CK_OBJECT_CLASS, key_type: CK_KEY_TYPE) -> Vec<Attribute> {
+  vec![(CKA_CLASS, ulong_value(class)), (CKA_KEY_TYPE, ulong_value(key_type))]
+}
+
+/// Generates the value of an AES key.
+pub fn generate_aes(len: usize) -> Result<Vec<Attribute>, CK_RV> {
+  if len != 16 && len != 24 && len != 32 {
+    return Err(CKR_KEY_SIZE_RANGE);
+  }
+  let mut attrs = key_attributes(CKO_SECRET_KEY, CKK_AES);
+  attrs.push((CKA_VALUE, random_bytes(len)));
+  attrs.push((CKA_VALUE_LEN, ulong_value(len as CK_ULONG)));
+  Ok(attrs)
+}
+
+/// Generates the value of a generic secret, e.g. for HMAC, or a DES3 key.
+pub fn generate_secret(key_type: CK_KEY_TYPE, len: Option<usize>) -> Result<Vec<Attribute>, CK_RV> {
+  let len = match (key_type, len) {
+    (CKK_DES3, None) | (CKK_DES3, Some(24)) => 24,
+    (CKK_GENERIC_SECRET, Some(len)) if (1..=512).contains(&len) => len,
+    (CKK_GENERIC_SECRET, None) => return Err(CKR_TEMPLATE_INCOMPLETE),
+    _ => return Err(CKR_KEY_SIZE_RANGE),
+  };
+  let mut attrs = key_attributes(CKO_SECRET_KEY, key_type);
+  attrs.push((CKA_VALUE, random_bytes(len)));
+  attrs.push((CKA_VALUE_LEN, ulong_value(len as CK_ULONG)));
+  Ok(attrs)
+}
+
+/// Generates an RSA key pair and returns the public and private key attributes.
+pub fn generate_rsa(bits: usize, public_exponent: Option<&[u8]>) -> Result<(Vec<Attribute>, Vec<Attribute>), CK_RV> {
+  if !(512..=4096).contains(&bits) {
+    return Err(CKR_KEY_SIZE_RANGE);
+  }
+  let exponent = public_exponent.map(BigUint::from_bytes_be).unwrap_or_else(|| BigUint::from(65537u32));
+  let key = RsaPrivateKey::new_with_exp(&mut OsRng, bits, &exponent).map_err(|_| CKR_TEMPLATE_INCONSISTENT)?;
+  let mut public = key_attributes(CKO_PUBLIC_KEY, CKK_RSA);
+  public.push((CKA_MODULUS, key.n().to_bytes_be()));
+  public.push((CKA_PUBLIC_EXPONENT, key.e().to_bytes_be()));
+  public.push((CKA_MODULUS_BITS, ulong_value(bits as CK_ULONG)));
+  let mut private = key_attributes(CKO_PRIVATE_KEY, CKK_RSA);
+  private.push((CKA_MODULUS, key.n().to_bytes_be()));
+  private.push((CKA_PUBLIC_EXPONENT, key.e().to_bytes_be()));
+  private.push((CKA_PRIVATE_EXPONENT, key.d().to_bytes_be()));
+  private.push((CKA_PRIME_1, key.primes()[0].to_bytes_be()));
+  private.push((CKA_PRIME_2, key.primes()[1].to_bytes_be()));
+  Ok((public, private))
+}
+
+/// Generates an EC key pair on a supported named curve.
+pub fn generate_ec(params: &[u8]) -> Result<(Vec<Attribute>, Vec<Attribute>), CK_RV> {
+  let (point, value) = match curve_width(params)? {
+    32 => {
+      let key = p256::ecdsa::SigningKey::random(&mut OsRng);
+      (key.verifying_key().to_encoded_point(false).as_bytes().to_vec(), key.to_bytes().to_vec())
+    }
+    _ => {
+      let key = p384::ecdsa::SigningKey::random(&mut OsRng);
+      (key.verifying_key().to_encoded_point(false).as_bytes().to_vec(), key.to_bytes().to_vec())
+    }
+  };
+  let mut public = key_attributes(CKO_PUBLIC_KEY, CKK_EC);
+  public.push((CKA_EC_PARAMS, params.to_vec()));
+  public.push((CKA_EC_POINT, der::octet_string(&point)));
+  let mut private = key_attributes(CKO_PRIVATE_KEY, CKK_EC);
+  private.push((CKA_EC_PARAMS, params.to_vec()));
+  private.push((CKA_VALUE, value));
+  Ok((public, private))
+}
+
+fn pkcs1v15_scheme(hash: CK_MECHANISM_TYPE) -> Pkcs1v15Sign {
+  match hash {
+    CKM_SHA_1 => Pkcs1v15Sign::new::<Sha1>(),
+    CKM_SHA256 => Pkcs1v15Sign::new::<Sha256>(),
+    CKM_SHA384 => Pkcs1v15Sign::new::<Sha384>(),
+    CKM_SHA512 => Pkcs1v15Sign::new::<Sha512>(),
+    _ => Pkcs1v15Sign::new_unprefixed(),
+  }
+}
+
+fn pss_scheme(hash: CK_MECHANISM_TYPE, salt_len: usize) -> Result<Pss, CK_RV> {
+  match hash {
+    CKM_SHA_1 => Ok(Pss::new_with_salt::<Sha1>(salt_len)),
+    CKM_SHA256 => Ok(Pss::new_with_salt::<Sha256>(salt_len)),
+    CKM_SHA384 => Ok(Pss::new_with_salt::<Sha384>(salt_len)),
+    CKM_SHA512 => Ok(Pss::new_with_salt::<Sha512>(salt_len)),
+    _ => Err(CKR_MECHANISM_PARAM_INVALID),
+  }
+}
+
+/// The digest combined into a signature mechanism, if any.
+fn signature_digest(mechanism: CK_MECHANISM_TYPE) -> Option<CK_MECHANISM_TYPE> {
+  match mechanism {
+    CKM_SHA1_RSA_PKCS | CKM_ECDSA_SHA1 => Some(CKM_SHA_1),
+    CKM_SHA256_RSA_PKCS | CKM_SHA256_RSA_PKCS_PSS | CKM_ECDSA_SHA256 => Some(CKM_SHA256),
+    CKM_SHA384_RSA_PKCS | CKM_SHA384_RSA_PKCS_PSS | CKM_ECDSA_SHA384 => Some(CKM_SHA384),
+    CKM_SHA512_RSA_PKCS | CKM_SHA512_RSA_PKCS_PSS | CKM_ECDSA_SHA512 => Some(CKM_SHA512),
+    _ => None,
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SignatureFamily {
+  Pkcs1,
+  Pss,
+  Ecdsa,
+}
+
+/// A sign or verify operation. Data is hashed as it arrives for the combined
+/// mechanisms and collected for the raw ones.
+#[derive(Clone)]
+pub struct SignOperation {
+  family: SignatureFamily,
+  key: Key,
+  digest: Option<CK_MECHANISM_TYPE>,
+  hasher: Option<Hasher>,
+  data: Vec<u8>,
+  salt_len: usize,
+  /// Set for keys with `CKA_ALWAYS_AUTHENTICATE` until the context specific login.
+  pub needs_login: bool,
+}
+
+impl SignOperation {
+  pub fn new(mechanism: &Mechanism, key: Key) -> Result<SignOperation, CK_RV> {
+    let family = match mechanism.mechanism {
+      CKM_RSA_PKCS | CKM_SHA1_RSA_PKCS | CKM_SHA256_RSA_PKCS | CKM_SHA384_RSA_PKCS | CKM_SHA512_RSA_PKCS => SignatureFamily::Pkcs1,
+      CKM_RSA_PKCS_PSS | CKM_SHA256_RSA_PKCS_PSS | CKM_SHA384_RSA_PKCS_PSS | CKM_SHA512_RSA_PKCS_PSS => SignatureFamily::Pss,
+      CKM_ECDSA | CKM_ECDSA_SHA1 | CKM_ECDSA_SHA256 | CKM_ECDSA_SHA384 | CKM_ECDSA_SHA512 => SignatureFamily::Ecdsa,
+      _ => return Err(CKR_MECHANISM_INVALID),
+    };
+    let key_fits = match key {
+      Key::RsaPrivate(_) | Key::RsaPublic(_) => family != SignatureFamily::Ecdsa,
+      Key::EcPrivate(_) | Key::EcPublic(_) => family == SignatureFamily::Ecdsa,
+      Key::Secret(_) => false,
+    };
+    if !key_fits {
+      return Err(CKR_KEY_TYPE_INCONSISTENT);
+    }
+    let combined = signature_digest(mechanism.mechanism);
+    let mut digest = combined;
+    let mut salt_len = 0;
+    if family == SignatureFamily::Pss {
+      if mechanism.parameter.len() != mem::size_of::<CK_RSA_PKCS_PSS_PARAMS>() {
+        return Err(CKR_MECHANISM_PARAM_INVALID);
+      }
+      let params: CK_RSA_PKCS_PSS_PARAMS = unsafe { ptr::read_unaligned(mechanism.parameter.as_ptr() as *const CK_RSA_PKCS_PSS_PARAMS) };
+      let hash_alg = params.hashAlg;
+      if combined.is_some_and(|c| c != hash_alg) {
+        return Err(CKR_MECHANISM_PARAM_INVALID);
+      }
+      pss_scheme(hash_alg, 0)?;
+      digest = Some(hash_alg);
+      salt_len = params.sLen as usize;
+    }
+    Ok(SignOperation {
+      family,
+      key,
+      digest,
+      hasher: combined.and_then(Hasher::new),
+      data: Vec::new(),
+      salt_len,
+      needs_login: false,
+    })
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    match self.hasher {
+      Some(ref mut hasher) => hasher.update(data),
+      None => self.data.extend_from_slice(data),
+    }
+  }
+
+  fn message(&self) -> Vec<u8> {
+    match self.hasher {
+      Some(ref hasher) => hasher.clone().finish(),
+      None => self.data.clone(),
+    }
+  }
+
+  pub fn sign(&self) -> Result<Vec<u8>, CK_RV> {
+    let message = self.message();
+    match self.key {
+      Key::RsaPrivate(ref key) => match self.family {
+        SignatureFamily::Pss => key.sign_with_rng(&mut OsRng, pss_scheme(self.digest.unwrap_or(0), self.salt_len)?, &message),
+        _ => key.sign(pkcs1v15_scheme(self.digest.unwrap_or(0)), &message),
+      }
+      .map_err(|_| CKR_DATA_LEN_RANGE),
+      Key::EcPrivate(EcPrivateKey::P256(ref key)) => {
+        let signature: p256::ecdsa::Signature = key.sign_prehash(&message).map_err(|_| CKR_DATA_LEN_RANGE)?;
+        Ok(signature.to_bytes().to_vec())
+      }
+      Key::EcPrivate(EcPrivateKey::P384(ref key)) => {
+        let signature: p384::ecdsa::Signature = key.sign_prehash(&message).map_err(|_| CKR_DATA_LEN_RANGE)?;
+        Ok(signature.to_bytes().to_vec())
+      }
+      _ => Err(CKR_KEY_FUNCTION_NOT_PERMITTED),
+    }
+  }
+
+  pub fn verify(&self, signature: &[u8]) -> Result<(), CK_RV> {
+    let message = self.message();
+    let public = match self.key {
+      Key::RsaPrivate(ref key) => Key::RsaPublic(key.to_public_key()),
+      ref key => key.clone(),
+    };
+    match public {
+      Key::RsaPublic(key) => {
+        if signature.len() != key.size() {
+          return Err(CKR_SIGNATURE_LEN_RANGE);
+        }
+        match self.family {
+          SignatureFamily::Pss => key.verify(pss_scheme(self.digest.unwrap_or(0), self.salt_len)?, &message, signature),
+          _ => key.verify(pkcs1v15_scheme(self.digest.unwrap_or(0)), &message, signature),
+        }
+        .map_err(|_| CKR_SIGNATURE_INVALID)
+      }
+      Key::EcPublic(EcPublicKey::P256(key)) => {
+        let signature = p256::ecdsa::Signature::from_slice(signature).map_err(|_| CKR_SIGNATURE_LEN_RANGE)?;
+        key.verify_prehash(&message, &signature).map_err(|_| CKR_SIGNATURE_INVALID)
+      }
+      Key::EcPublic(EcPublicKey::P384(key)) => {
+        let signature = p384::ecdsa::Signature::from_slice(signature).map_err(|_| CKR_SIGNATURE_LEN_RANGE)?;
+        key.verify_prehash(&message, &signature).map_err(|_| CKR_SIGNATURE_INVALID)
+      }
+      _ => Err(CKR_KEY_FUNCTION_NOT_PERMITTED),
+    }
+  }
+}
+
+fn aes_blocks<C: BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>>(cipher: &C, iv: Option<&mut Vec<u8>>, encrypt: bool, data: &mut [u8]) {
+  let mut iv = iv;
+  for block in data.chunks_mut(AES_BLOCK) {
+    if encrypt {
+      if let Some(ref iv) = iv {
+        block.iter_mut().zip(iv.iter()).for_each(|(b, v)| *b ^= v);
+      }
+      cipher.encrypt_block(GenericArray::from_mut_slice(block));
+      if let Some(ref mut iv) = iv {
+        iv.copy_from_slice(block);
+      }
+    } else {
+      let input = block.to_vec();
+      cipher.decrypt_block(GenericArray::from_mut_slice(block));
+      if let Some(ref mut iv) = iv {
+        block.iter_mut().zip(iv.iter()).for_each(|(b, v)| *b ^= v);
+        iv.copy_from_slice(&input);
+      }
+    }
+  }
+}
+
+fn aes_process(key: &[u8], iv: Option<&mut Vec<u8>>, encrypt: bool, data: &mut [u8]) -> Result<(), CK_RV> {
+  match key.len() {
+    16 => aes_blocks(&Aes128::new_from_slice(key).unwrap(), iv, encrypt, data),
+    24 => aes_blocks(&Aes192::new_from_slice(key).unwrap(), iv, encrypt, data),
+    32 => aes_blocks(&Aes256::new_from_slice(key).unwrap(), iv, encrypt, data),
+    _ => return Err(CKR_KEY_SIZE_RANGE),
+  }
+  Ok(())
+}
+
+/// An encrypt or decrypt operation. AES output is produced block by block;
+/// RSA collects the input and works on `finish`.
+#[derive(Clone)]
+pub struct CipherOperation {
+  mechanism: CK_MECHANISM_TYPE,
+  key: Key,
+  iv: Option<Vec<u8>>,
+  buffer: Vec<u8>,
+  encrypt: bool,
+  /// Set for keys with `CKA_ALWAYS_AUTHENTICATE` until the context specific login.
+  pub needs_login: bool,
+}
+
+impl CipherOperation {
+  pub fn new(mechanism: &Mechanism, key: Key, encrypt: bool) -> Result<CipherOperation, CK_RV> {
+    let iv = match mechanism.mechanism {
+      CKM_AES_ECB => None,
+      CKM_AES_CBC | CKM_AES_CBC_PAD if mechanism.parameter.len() == AES_BLOCK => Some(mechanism.parameter.clone()),
+      CKM_AES_CBC | CKM_AES_CBC_PAD => return Err(CKR_MECHANISM_PARAM_INVALID),
+      CKM_RSA_PKCS => None,
+      _ => return Err(CKR_MECHANISM_INVALID),
+    };
+    let key_fits = match key {
+      Key::Secret(ref value) => mechanism.mechanism != CKM_RSA_PKCS && [16, 24, 32].contains(&value.len()),
+      Key::RsaPrivate(_) => mechanism.mechanism == CKM_RSA_PKCS && !encrypt,
+      Key::RsaPublic(_) => mechanism.mechanism == CKM_RSA_PKCS && encrypt,
+      _ => false,
+    };
+    if !key_fits {
+      return Err(CKR_KEY_TYPE_INCONSISTENT);
+    }
+    Ok(CipherOperation {
+      mechanism: mechanism.mechanism,
+      key,
+      iv,
+      buffer: Vec::new(),
+      encrypt,
+      needs_login: false,
+    })
+  }
+
+  pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, CK_RV> {
+    self.buffer.extend_from_slice(data);
+    let secret = match self.key {
+      Key::Secret(ref value) => value.clone(),
+      _ => return Ok(Vec::new()),
+    };
+    let mut ready = self.buffer.len() / AES_BLOCK * AES_BLOCK;
+    // padded decryption keeps the last block back until it is known to be the final one
+    if self.mechanism == CKM_AES_CBC_PAD && !self.encrypt && ready == self.buffer.len() && ready > 0 {
+      ready -= AES_BLOCK;
+    }
+    let mut out: Vec<u8> = self.buffer.drain(..ready).collect();
+    aes_process(&secret, self.iv.as_mut(), self.encrypt, &mut out)?;
+    Ok(out)
+  }
+
+  pub fn finish(&mut self) -> Result<Vec<u8>, CK_RV> {
+    let data = mem::take(&mut self.buffer);
+    match self.key {
+      Key::Secret(ref secret) => {
+        if self.mechanism != CKM_AES_CBC_PAD {
+          return match (data.is_empty(), self.encrypt) {
+            (true, _) => Ok(Vec::new()),
+            (false, true) => Err(CKR_DATA_LEN_RANGE),
+            (false, false) => Err(CKR_ENCRYPTED_DATA_LEN_RANGE),
+          };
+        }
+        let mut out = data;
+        if self.encrypt {
+          let pad = AES_BLOCK - out.len() % AES_BLOCK;
+          out.extend(std::iter::repeat_n(pad as u8, pad));
+          aes_process(secret, self.iv.as_mut(), true, &mut out)?;
+          return Ok(out);
+        }
+        if out.len() != AES_BLOCK {
+          return Err(CKR_ENCRYPTED_DATA_LEN_RANGE);
+        }
+        aes_process(secret, self.iv.as_mut(), false, &mut out)?;
+        let pad = out[AES_BLOCK - 1] as usize;
+        if pad == 0 || pad > AES_BLOCK || out[AES_BLOCK - pad..].iter().any(|b| *b as usize != pad) {
+          return Err(CKR_ENCRYPTED_DATA_INVALID);
+        }
+        out.truncate(AES_BLOCK - pad);
+        Ok(out)
+      }
+      Key::RsaPublic(ref key) => key.encrypt(&mut OsRng, Pkcs1v15Encrypt, &data).map_err(|_| CKR_DATA_LEN_RANGE),
+      Key::RsaPrivate(ref key) => key.decrypt(Pkcs1v15Encrypt, &data).map_err(|_| CKR_ENCRYPTED_DATA_INVALID),
+      _ => Err(CKR_KEY_TYPE_INCONSISTENT),
+    }
+  }
+
+  /// A complete single-part operation.
+  pub fn run(&mut self, data: &[u8]) -> Result<Vec<u8>, CK_RV> {
+    let mut out = self.update(data)?;
+    out.extend(self.finish()?);
+    Ok(out)
+  }
+}
+
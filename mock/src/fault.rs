@@ -0,0 +1,264 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The function list handed out by `C_GetFunctionList`. Every entry checks the
+//! fault rules and then forwards to the target module: the in-memory token or
+//! the module set with `pkcs11_mock_wrap` or `PKCS11_MOCK_WRAP`.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_char;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use libloading;
+use pkcs11::fault::Rules;
+use pkcs11::types::*;
+
+struct Faults {
+  rules: Rules,
+  calls: HashMap<String, u64>,
+  target: &'static CK_FUNCTION_LIST,
+}
+
+static FAULTS: Mutex<Option<Faults>> = Mutex::new(None);
+
+/// The fault state, configured from the environment on first use.
+fn faults() -> Result<MutexGuard<'static, Option<Faults>>, CK_RV> {
+  let mut faults = FAULTS.lock().unwrap_or_else(|e| e.into_inner());
+  if faults.is_none() {
+    let target = match env::var("PKCS11_MOCK_WRAP") {
+      Ok(path) => load(&path)?,
+      Err(_) => &::MOCK_FUNCTION_LIST,
+    };
+    let rules = match env::var("PKCS11_MOCK_FAULTS") {
+      Ok(script) => script.parse().map_err(|_| CKR_ARGUMENTS_BAD)?,
+      Err(_) => Rules::new(),
+    };
+    *faults = Some(Faults {
+      rules,
+      calls: HashMap::new(),
+      target,
+    });
+  }
+  Ok(faults)
+}
+
+/// Loads the module to wrap. The library stays loaded for the rest of the process.
+fn load(path: &str) -> Result<&'static CK_FUNCTION_LIST, CK_RV> {
+  unsafe {
+    let lib = libloading::Library::new(path).map_err(|_| CKR_FUNCTION_FAILED)?;
+    let list = {
+      let func: libloading::Symbol<unsafe extern "C" fn(CK_FUNCTION_LIST_PTR_PTR) -> CK_RV> = lib.get(b"C_GetFunctionList").map_err(|_| CKR_FUNCTION_FAILED)?;
+      let mut list: CK_FUNCTION_LIST_PTR = ::std::ptr::null_mut();
+      match func(&mut list) {
+        CKR_OK if !list.is_null() => &*list,
+        CKR_OK => return Err(CKR_FUNCTION_FAILED),
+        err => return Err(err),
+      }
+    };
+    mem::forget(lib);
+    Ok(list)
+  }
+}
+
+/// Counts the call, applies the matching rules and forwards the call unless a rule fails it.
+fn intercept<F>(function: &'static str, forward: F) -> CK_RV
+where
+  F: FnOnce(&'static CK_FUNCTION_LIST) -> CK_RV,
+{
+  let (target, (delay, fail)) = {
+    let mut faults = match faults() {
+      Ok(faults) => faults,
+      Err(rv) => return rv,
+    };
+    let faults = faults.as_mut().unwrap();
+    let call = faults.calls.entry(function.to_string()).or_insert(0);
+    *call += 1;
+    (faults.target, faults.rules.check(function, *call))
+  };
+  if delay > Default::default() {
+    thread::sleep(delay);
+  }
+  match fail {
+    Some(rv) => rv,
+    None => forward(target),
+  }
+}
+
+pub fn load_env() -> CK_RV {
+  match faults() {
+    Ok(_) => CKR_OK,
+    Err(rv) => rv,
+  }
+}
+
+unsafe fn arg<'a>(s: *const c_char) -> Result<&'a str, CK_RV> {
+  if s.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  CStr::from_ptr(s).to_str().map_err(|_| CKR_ARGUMENTS_BAD)
+}
+
+/// Forwards all calls to the module at `path` from now on.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_mock_wrap(path: *const c_char) -> CK_RV {
+  let target = match arg(path).and_then(load) {
+    Ok(target) => target,
+    Err(rv) => return rv,
+  };
+  match faults() {
+    Ok(mut faults) => {
+      faults.as_mut().unwrap().target = target;
+      CKR_OK
+    }
+    Err(rv) => rv,
+  }
+}
+
+/// Replaces the rules with the ones in `script` and resets the call counters.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_mock_set_faults(script: *const c_char) -> CK_RV {
+  let rules: Rules = match arg(script).map(str::parse) {
+    Ok(Ok(rules)) => rules,
+    _ => return CKR_ARGUMENTS_BAD,
+  };
+  match faults() {
+    Ok(mut faults) => {
+      let faults = faults.as_mut().unwrap();
+      faults.rules = rules;
+      faults.calls.clear();
+      CKR_OK
+    }
+    Err(rv) => rv,
+  }
+}
+
+/// The number of calls to `function` since the rules were last set.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_mock_call_count(function: *const c_char) -> CK_ULONG {
+  match (arg(function), faults()) {
+    (Ok(function), Ok(faults)) => faults.as_ref().unwrap().calls.get(function).cloned().unwrap_or(0) as CK_ULONG,
+    _ => 0,
+  }
+}
+
+macro_rules! forwarding_functions {
+  ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+    $(
+      extern "C" fn $name($($arg: $ty),*) -> CK_RV {
+        intercept(stringify!($name), |target| match target.$name {
+          Some(f) => f($($arg),*),
+          None => CKR_FUNCTION_NOT_SUPPORTED,
+        })
+      }
+    )*
+
+    pub static FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
+      version: CK_VERSION { major: 2, minor: 40 },
+      C_GetFunctionList: Some(::C_GetFunctionList),
+      $($name: Some($name),)*
+    };
+  };
+}
+
+forwarding_functions! {
+  C_Initialize(pInitArgs: CK_C_INITIALIZE_ARGS_PTR);
+  C_Finalize(pReserved: CK_VOID_PTR);
+  C_GetInfo(pInfo: CK_INFO_PTR);
+  C_GetSlotList(tokenPresent: CK_BBOOL, pSlotList: CK_SLOT_ID_PTR, pulCount: CK_ULONG_PTR);
+  C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR);
+  C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR);
+  C_GetMechanismList(slotID: CK_SLOT_ID, pMechanismList: CK_MECHANISM_TYPE_PTR, pulCount: CK_ULONG_PTR);
+  C_GetMechanismInfo(slotID: CK_SLOT_ID, mechType: CK_MECHANISM_TYPE, pInfo: CK_MECHANISM_INFO_PTR);
+  C_InitToken(slotID: CK_SLOT_ID, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG, pLabel: CK_UTF8CHAR_PTR);
+  C_InitPIN(hSession: CK_SESSION_HANDLE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG);
+  C_SetPIN(hSession: CK_SESSION_HANDLE, pOldPin: CK_UTF8CHAR_PTR, ulOldLen: CK_ULONG, pNewPin: CK_UTF8CHAR_PTR, ulNewLen: CK_ULONG);
+  C_OpenSession(slotID: CK_SLOT_ID, flags: CK_FLAGS, pApplication: CK_VOID_PTR, Notify: CK_NOTIFY, phSession: CK_SESSION_HANDLE_PTR);
+  C_CloseSession(hSession: CK_SESSION_HANDLE);
+  C_CloseAllSessions(slotID: CK_SLOT_ID);
+  C_GetSessionInfo(hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR);
+  C_GetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, pulOperationStateLen: CK_ULONG_PTR);
+  C_SetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, ulOperationStateLen: CK_ULONG, hEncryptionKey: CK_OBJECT_HANDLE, hAuthenticationKey: CK_OBJECT_HANDLE);
+  C_Login(hSession: CK_SESSION_HANDLE, userType: CK_USER_TYPE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG);
+  C_Logout(hSession: CK_SESSION_HANDLE);
+  C_CreateObject(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phObject: CK_OBJECT_HANDLE_PTR);
+  C_CopyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phNewObject: CK_OBJECT_HANDLE_PTR);
+  C_DestroyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE);
+  C_GetObjectSize(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pulSize: CK_ULONG_PTR);
+  C_GetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+  C_SetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+  C_FindObjectsInit(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+  C_FindObjects(hSession: CK_SESSION_HANDLE, phObject: CK_OBJECT_HANDLE_PTR, ulMaxObjectCount: CK_ULONG, pulObjectCount: CK_ULONG_PTR);
+  C_FindObjectsFinal(hSession: CK_SESSION_HANDLE);
+  C_EncryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_Encrypt(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pEncryptedData: CK_BYTE_PTR, pulEncryptedDataLen: CK_ULONG_PTR);
+  C_EncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+  C_EncryptFinal(hSession: CK_SESSION_HANDLE, pLastEncryptedPart: CK_BYTE_PTR, pulLastEncryptedPartLen: CK_ULONG_PTR);
+  C_DecryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_Decrypt(hSession: CK_SESSION_HANDLE, pEncryptedData: CK_BYTE_PTR, ulEncryptedDataLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR);
+  C_DecryptUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+  C_DecryptFinal(hSession: CK_SESSION_HANDLE, pLastPart: CK_BYTE_PTR, pulLastPartLen: CK_ULONG_PTR);
+  C_DigestInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR);
+  C_Digest(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR);
+  C_DigestUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+  C_DigestKey(hSession: CK_SESSION_HANDLE, hKey: CK_OBJECT_HANDLE);
+  C_DigestFinal(hSession: CK_SESSION_HANDLE, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR);
+  C_SignInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_Sign(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+  C_SignUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+  C_SignFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+  C_SignRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_SignRecover(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+  C_VerifyInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_Verify(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG);
+  C_VerifyUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+  C_VerifyFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG);
+  C_VerifyRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+  C_VerifyRecover(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR);
+  C_DigestEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+  C_DecryptDigestUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+  C_SignEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+  C_DecryptVerifyUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+  C_GenerateKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR);
+  C_GenerateKeyPair(
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    pPublicKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPublicKeyAttributeCount: CK_ULONG,
+    pPrivateKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPrivateKeyAttributeCount: CK_ULONG,
+    phPublicKey: CK_OBJECT_HANDLE_PTR,
+    phPrivateKey: CK_OBJECT_HANDLE_PTR
+  );
+  C_WrapKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hWrappingKey: CK_OBJECT_HANDLE, hKey: CK_OBJECT_HANDLE, pWrappedKey: CK_BYTE_PTR, pulWrappedKeyLen: CK_ULONG_PTR);
+  C_UnwrapKey(
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hUnwrappingKey: CK_OBJECT_HANDLE,
+    pWrappedKey: CK_BYTE_PTR,
+    ulWrappedKeyLen: CK_ULONG,
+    pTemplate: CK_ATTRIBUTE_PTR,
+    ulAttributeCount: CK_ULONG,
+    phKey: CK_OBJECT_HANDLE_PTR
+  );
+  C_DeriveKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hBaseKey: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulAttributeCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR);
+  C_SeedRandom(hSession: CK_SESSION_HANDLE, pSeed: CK_BYTE_PTR, ulSeedLen: CK_ULONG);
+  C_GenerateRandom(hSession: CK_SESSION_HANDLE, RandomData: CK_BYTE_PTR, ulRandomLen: CK_ULONG);
+  C_GetFunctionStatus(hSession: CK_SESSION_HANDLE);
+  C_CancelFunction(hSession: CK_SESSION_HANDLE);
+  C_WaitForSlotEvent(flags: CK_FLAGS, pSlot: CK_SLOT_ID_PTR, pRserved: CK_VOID_PTR);
+}
@@ -0,0 +1,1283 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-memory PKCS#11 module with a single slot, used as a test fixture for
+//! the `pkcs11` crate where no hardware token or SoftHSM is available.
+//!
+//! The token supports SHA digests, AES (ECB/CBC/CBC_PAD), RSA PKCS#1 v1.5 and
+//! PSS signatures, RSA PKCS#1 v1.5 encryption and ECDSA on P-256 and P-384.
+//! Token objects live as long as the process: every copy of the shared library
+//! that gets loaded is a separate token.
+//!
+//! All calls pass the fault injection rules of `pkcs11::fault` first, and the
+//! module can forward to another module instead of the in-memory token.
+#![allow(non_snake_case)]
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+extern crate aes;
+extern crate libloading;
+extern crate p256;
+extern crate p384;
+extern crate pkcs11;
+extern crate rand;
+extern crate rsa;
+extern crate sha1;
+extern crate sha2;
+
+mod crypto;
+mod fault;
+mod object;
+
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::Mutex;
+
+use pkcs11::types::*;
+
+use crypto::{CipherOperation, Hasher, Key, Mechanism, SignOperation};
+use object::{find, find_ulong, ulong_value, Attribute, Object};
+
+const SLOT_ID: CK_SLOT_ID = 0;
+const MIN_PIN_LEN: usize = 4;
+const MAX_PIN_LEN: usize = 255;
+/// Wrong PIN attempts until the PIN is locked.
+const MAX_PIN_FAILURES: u32 = 3;
+
+static STATE: Mutex<Option<Module>> = Mutex::new(None);
+
+struct Pin {
+  value: Option<Vec<u8>>,
+  failures: u32,
+  /// Set by the SO, so the user is asked to change it.
+  to_be_changed: bool,
+}
+
+impl Pin {
+  fn new() -> Pin {
+    Pin {
+      value: None,
+      failures: 0,
+      to_be_changed: false,
+    }
+  }
+
+  fn set(&mut self, pin: &[u8]) -> Result<(), CK_RV> {
+    check_pin_len(pin)?;
+    self.value = Some(pin.to_vec());
+    self.failures = 0;
+    self.to_be_changed = false;
+    Ok(())
+  }
+
+  fn check(&mut self, pin: &[u8]) -> Result<(), CK_RV> {
+    if self.failures >= MAX_PIN_FAILURES {
+      return Err(CKR_PIN_LOCKED);
+    }
+    if self.value.as_ref().map(|v| &v[..]) != Some(pin) {
+      self.failures += 1;
+      return Err(CKR_PIN_INCORRECT);
+    }
+    self.failures = 0;
+    Ok(())
+  }
+
+  fn flags(&self, count_low: CK_FLAGS, final_try: CK_FLAGS, locked: CK_FLAGS) -> CK_FLAGS {
+    match self.failures {
+      0 => 0,
+      n if n >= MAX_PIN_FAILURES => locked,
+      n if n + 1 == MAX_PIN_FAILURES => count_low | final_try,
+      _ => count_low,
+    }
+  }
+}
+
+fn check_pin_len(pin: &[u8]) -> Result<(), CK_RV> {
+  if pin.len() < MIN_PIN_LEN || pin.len() > MAX_PIN_LEN {
+    return Err(CKR_PIN_LEN_RANGE);
+  }
+  Ok(())
+}
+
+#[derive(Default)]
+struct Session {
+  rw: bool,
+  find: Option<Vec<CK_OBJECT_HANDLE>>,
+  digest: Option<Hasher>,
+  sign: Option<SignOperation>,
+  verify: Option<SignOperation>,
+  encrypt: Option<CipherOperation>,
+  decrypt: Option<CipherOperation>,
+}
+
+struct Module {
+  initialized: bool,
+  token_initialized: bool,
+  label: [CK_UTF8CHAR; 32],
+  so_pin: Pin,
+  user_pin: Pin,
+  login: Option<CK_USER_TYPE>,
+  objects: BTreeMap<CK_OBJECT_HANDLE, Object>,
+  sessions: BTreeMap<CK_SESSION_HANDLE, Session>,
+  next_handle: CK_ULONG,
+}
+
+impl Module {
+  fn new() -> Module {
+    Module {
+      initialized: false,
+      token_initialized: false,
+      label: [b' '; 32],
+      so_pin: Pin::new(),
+      user_pin: Pin::new(),
+      login: None,
+      objects: BTreeMap::new(),
+      sessions: BTreeMap::new(),
+      next_handle: 1,
+    }
+  }
+
+  fn handle(&mut self) -> CK_ULONG {
+    let handle = self.next_handle;
+    self.next_handle += 1;
+    handle
+  }
+
+  fn session(&mut self, handle: CK_SESSION_HANDLE) -> Result<&mut Session, CK_RV> {
+    self.sessions.get_mut(&handle).ok_or(CKR_SESSION_HANDLE_INVALID)
+  }
+
+  fn session_state(&self, session: &Session) -> CK_ULONG {
+    match (self.login, session.rw) {
+      (Some(CKU_SO), _) => CKS_RW_SO_FUNCTIONS,
+      (Some(_), true) => CKS_RW_USER_FUNCTIONS,
+      (Some(_), false) => CKS_RO_USER_FUNCTIONS,
+      (None, true) => CKS_RW_PUBLIC_SESSION,
+      (None, false) => CKS_RO_PUBLIC_SESSION,
+    }
+  }
+
+  fn visible(&self, object: &Object) -> bool {
+    !object.is_private() || self.login == Some(CKU_USER)
+  }
+
+  fn object(&self, handle: CK_OBJECT_HANDLE) -> Result<&Object, CK_RV> {
+    self.objects.get(&handle).filter(|o| self.visible(o)).ok_or(CKR_OBJECT_HANDLE_INVALID)
+  }
+
+  /// Checks that the session may modify an existing or create a new object.
+  fn check_writable(&mut self, session: CK_SESSION_HANDLE, object: &Object) -> Result<(), CK_RV> {
+    let rw = self.session(session)?.rw;
+    if object.flag(CKA_TOKEN) && !rw {
+      return Err(CKR_SESSION_READ_ONLY);
+    }
+    if object.is_private() && self.login != Some(CKU_USER) {
+      return Err(CKR_USER_NOT_LOGGED_IN);
+    }
+    Ok(())
+  }
+
+  fn insert(&mut self, session: CK_SESSION_HANDLE, mut object: Object) -> CK_OBJECT_HANDLE {
+    if !object.flag(CKA_TOKEN) {
+      object.session = Some(session);
+    }
+    let handle = self.handle();
+    self.objects.insert(handle, object);
+    handle
+  }
+
+  /// Looks up a key for a cryptographic operation and whether it needs a context specific login.
+  fn key(&self, handle: CK_OBJECT_HANDLE, usage: CK_ATTRIBUTE_TYPE) -> Result<(Key, bool), CK_RV> {
+    let object = self.objects.get(&handle).filter(|o| self.visible(o)).ok_or(CKR_KEY_HANDLE_INVALID)?;
+    if !matches!(object.class(), Some(CKO_SECRET_KEY) | Some(CKO_PRIVATE_KEY) | Some(CKO_PUBLIC_KEY)) {
+      return Err(CKR_KEY_HANDLE_INVALID);
+    }
+    if !object.flag(usage) {
+      return Err(CKR_KEY_FUNCTION_NOT_PERMITTED);
+    }
+    Ok((Key::from_object(object)?, object.flag(CKA_ALWAYS_AUTHENTICATE)))
+  }
+
+  fn close_session(&mut self, handle: CK_SESSION_HANDLE) -> Result<(), CK_RV> {
+    self.sessions.remove(&handle).ok_or(CKR_SESSION_HANDLE_INVALID)?;
+    self.objects.retain(|_, o| o.session != Some(handle));
+    if self.sessions.is_empty() {
+      self.login = None;
+    }
+    Ok(())
+  }
+
+  fn logout(&mut self) {
+    self.login = None;
+    self.objects.retain(|_, o| !(o.session.is_some() && o.is_private()));
+  }
+}
+
+/// Runs the body of an entry point on the initialized module, turning panics into `CKR_GENERAL_ERROR`.
+fn entry<F>(f: F) -> CK_RV
+where
+  F: FnOnce(&mut Module) -> Result<(), CK_RV>,
+{
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    match state.as_mut() {
+      Some(module) if module.initialized => f(module),
+      _ => Err(CKR_CRYPTOKI_NOT_INITIALIZED),
+    }
+  }));
+  match result {
+    Ok(Ok(())) => CKR_OK,
+    Ok(Err(rv)) => rv,
+    Err(_) => CKR_GENERAL_ERROR,
+  }
+}
+
+unsafe fn bytes<'a>(data: *const CK_BYTE, len: CK_ULONG) -> Result<&'a [u8], CK_RV> {
+  if data.is_null() {
+    return if len == 0 { Ok(&[]) } else { Err(CKR_ARGUMENTS_BAD) };
+  }
+  Ok(slice::from_raw_parts(data, len as usize))
+}
+
+unsafe fn template(attrs: CK_ATTRIBUTE_PTR, count: CK_ULONG) -> Result<Vec<Attribute>, CK_RV> {
+  if count == 0 {
+    return Ok(Vec::new());
+  }
+  if attrs.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  slice::from_raw_parts(attrs, count as usize)
+    .iter()
+    .map(|a| Ok((a.attrType, bytes(a.pValue as *const CK_BYTE, a.ulValueLen)?.to_vec())))
+    .collect()
+}
+
+unsafe fn mechanism(mechanism: CK_MECHANISM_PTR) -> Result<Mechanism, CK_RV> {
+  if mechanism.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  let m = &*mechanism;
+  Ok(Mechanism {
+    mechanism: m.mechanism,
+    parameter: bytes(m.pParameter as *const CK_BYTE, m.ulParameterLen)?.to_vec(),
+  })
+}
+
+unsafe fn write<T>(ptr: *mut T, value: T) -> Result<(), CK_RV> {
+  if ptr.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  *ptr = value;
+  Ok(())
+}
+
+/// Returns the output with the usual two-call convention. `Ok(false)` means only the length was reported.
+unsafe fn write_output(data: &[u8], out: CK_BYTE_PTR, out_len: CK_ULONG_PTR) -> Result<bool, CK_RV> {
+  if out_len.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  let available = *out_len as usize;
+  *out_len = data.len() as CK_ULONG;
+  if out.is_null() {
+    return Ok(false);
+  }
+  if available < data.len() {
+    return Err(CKR_BUFFER_TOO_SMALL);
+  }
+  slice::from_raw_parts_mut(out, data.len()).copy_from_slice(data);
+  Ok(true)
+}
+
+/// Runs one step of a cryptographic operation on a copy of its state. The
+/// operation only advances once the caller received the output, so size
+/// queries and `CKR_BUFFER_TOO_SMALL` leave it untouched; other errors end it.
+unsafe fn step<S, F>(slot: &mut Option<S>, finishes: bool, out: CK_BYTE_PTR, out_len: CK_ULONG_PTR, f: F) -> Result<(), CK_RV>
+where
+  S: Clone,
+  F: FnOnce(&mut S) -> Result<Vec<u8>, CK_RV>,
+{
+  let mut state = slot.clone().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+  let output = f(&mut state).inspect_err(|_| *slot = None)?;
+  match write_output(&output, out, out_len) {
+    Ok(true) => {
+      *slot = if finishes { None } else { Some(state) };
+      Ok(())
+    }
+    Ok(false) => Ok(()),
+    Err(CKR_BUFFER_TOO_SMALL) => Err(CKR_BUFFER_TOO_SMALL),
+    Err(rv) => {
+      *slot = None;
+      Err(rv)
+    }
+  }
+}
+
+fn start<T>(slot: &mut Option<T>, operation: T) -> Result<(), CK_RV> {
+  if slot.is_some() {
+    return Err(CKR_OPERATION_ACTIVE);
+  }
+  *slot = Some(operation);
+  Ok(())
+}
+
+fn pad(dst: &mut [u8], text: &[u8]) {
+  for (i, b) in dst.iter_mut().enumerate() {
+    *b = text.get(i).cloned().unwrap_or(b' ');
+  }
+}
+
+/// Builds a new key object from a generation template, before the key material is known.
+fn new_key(template: &[Attribute], class: CK_OBJECT_CLASS, key_type: CK_KEY_TYPE) -> Result<Object, CK_RV> {
+  if find_ulong(template, CKA_CLASS).is_some_and(|c| c != class) || find_ulong(template, CKA_KEY_TYPE).is_some_and(|t| t != key_type) {
+    return Err(CKR_TEMPLATE_INCONSISTENT);
+  }
+  let mut object = Object::new(template);
+  object.set(CKA_CLASS, ulong_value(class));
+  object.set(CKA_KEY_TYPE, ulong_value(key_type));
+  object.apply_defaults(true);
+  Ok(object)
+}
+
+extern "C" fn C_Initialize(_pInitArgs: CK_C_INITIALIZE_ARGS_PTR) -> CK_RV {
+  let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+  let module = state.get_or_insert_with(Module::new);
+  if module.initialized {
+    return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+  }
+  module.initialized = true;
+  CKR_OK
+}
+
+extern "C" fn C_Finalize(pReserved: CK_VOID_PTR) -> CK_RV {
+  if !pReserved.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  entry(|m| {
+    m.sessions.clear();
+    m.objects.retain(|_, o| o.session.is_none());
+    m.login = None;
+    m.initialized = false;
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetInfo(pInfo: CK_INFO_PTR) -> CK_RV {
+  entry(|_| unsafe {
+    let mut info = CK_INFO {
+      cryptokiVersion: CK_VERSION { major: 2, minor: 40 },
+      manufacturerID: [0; 32],
+      flags: 0,
+      libraryDescription: [0; 32],
+      libraryVersion: CK_VERSION { major: 0, minor: 5 },
+    };
+    pad(&mut info.manufacturerID, b"rust-pkcs11");
+    pad(&mut info.libraryDescription, b"In-memory mock token");
+    write(pInfo, info)
+  })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn C_GetFunctionList(ppFunctionList: CK_FUNCTION_LIST_PTR_PTR) -> CK_RV {
+  if ppFunctionList.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  match fault::load_env() {
+    CKR_OK => {}
+    err => return err,
+  }
+  unsafe {
+    *ppFunctionList = &fault::FUNCTION_LIST as *const CK_FUNCTION_LIST as CK_FUNCTION_LIST_PTR;
+  }
+  CKR_OK
+}
+
+extern "C" fn C_GetSlotList(_tokenPresent: CK_BBOOL, pSlotList: CK_SLOT_ID_PTR, pulCount: CK_ULONG_PTR) -> CK_RV {
+  entry(|_| unsafe {
+    if pulCount.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    let available = *pulCount;
+    *pulCount = 1;
+    if pSlotList.is_null() {
+      return Ok(());
+    }
+    if available < 1 {
+      return Err(CKR_BUFFER_TOO_SMALL);
+    }
+    *pSlotList = SLOT_ID;
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR) -> CK_RV {
+  entry(|_| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    let mut info = CK_SLOT_INFO {
+      slotDescription: [0; 64],
+      manufacturerID: [0; 32],
+      flags: CKF_TOKEN_PRESENT,
+      hardwareVersion: CK_VERSION { major: 0, minor: 5 },
+      firmwareVersion: CK_VERSION { major: 0, minor: 5 },
+    };
+    pad(&mut info.slotDescription, b"Mock slot");
+    pad(&mut info.manufacturerID, b"rust-pkcs11");
+    write(pInfo, info)
+  })
+}
+
+extern "C" fn C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    let mut flags = CKF_RNG | CKF_LOGIN_REQUIRED;
+    if m.token_initialized {
+      flags |= CKF_TOKEN_INITIALIZED;
+    }
+    if m.user_pin.value.is_some() {
+      flags |= CKF_USER_PIN_INITIALIZED;
+    }
+    if m.user_pin.to_be_changed {
+      flags |= CKF_USER_PIN_TO_BE_CHANGED;
+    }
+    flags |= m.user_pin.flags(CKF_USER_PIN_COUNT_LOW, CKF_USER_PIN_FINAL_TRY, CKF_USER_PIN_LOCKED);
+    flags |= m.so_pin.flags(CKF_SO_PIN_COUNT_LOW, CKF_SO_PIN_FINAL_TRY, CKF_SO_PIN_LOCKED);
+    let mut info = CK_TOKEN_INFO {
+      label: m.label,
+      manufacturerID: [0; 32],
+      model: [0; 16],
+      serialNumber: [0; 16],
+      flags,
+      ulMaxSessionCount: CK_EFFECTIVELY_INFINITE,
+      ulSessionCount: m.sessions.len() as CK_ULONG,
+      ulMaxRwSessionCount: CK_EFFECTIVELY_INFINITE,
+      ulRwSessionCount: m.sessions.values().filter(|s| s.rw).count() as CK_ULONG,
+      ulMaxPinLen: MAX_PIN_LEN as CK_ULONG,
+      ulMinPinLen: MIN_PIN_LEN as CK_ULONG,
+      ulTotalPublicMemory: CK_UNAVAILABLE_INFORMATION,
+      ulFreePublicMemory: CK_UNAVAILABLE_INFORMATION,
+      ulTotalPrivateMemory: CK_UNAVAILABLE_INFORMATION,
+      ulFreePrivateMemory: CK_UNAVAILABLE_INFORMATION,
+      hardwareVersion: CK_VERSION { major: 0, minor: 5 },
+      firmwareVersion: CK_VERSION { major: 0, minor: 5 },
+      utcTime: [b' '; 16],
+    };
+    pad(&mut info.manufacturerID, b"rust-pkcs11");
+    pad(&mut info.model, b"Mock");
+    pad(&mut info.serialNumber, b"0000000000000001");
+    write(pInfo, info)
+  })
+}
+
+extern "C" fn C_GetMechanismList(slotID: CK_SLOT_ID, pMechanismList: CK_MECHANISM_TYPE_PTR, pulCount: CK_ULONG_PTR) -> CK_RV {
+  entry(|_| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    if pulCount.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    let available = *pulCount as usize;
+    *pulCount = crypto::MECHANISMS.len() as CK_ULONG;
+    if pMechanismList.is_null() {
+      return Ok(());
+    }
+    if available < crypto::MECHANISMS.len() {
+      return Err(CKR_BUFFER_TOO_SMALL);
+    }
+    let list = slice::from_raw_parts_mut(pMechanismList, crypto::MECHANISMS.len());
+    for (dst, m) in list.iter_mut().zip(crypto::MECHANISMS) {
+      *dst = m.0;
+    }
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetMechanismInfo(slotID: CK_SLOT_ID, mechType: CK_MECHANISM_TYPE, pInfo: CK_MECHANISM_INFO_PTR) -> CK_RV {
+  entry(|_| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    let &(_, min, max, flags) = crypto::MECHANISMS.iter().find(|m| m.0 == mechType).ok_or(CKR_MECHANISM_INVALID)?;
+    write(
+      pInfo,
+      CK_MECHANISM_INFO {
+        ulMinKeySize: min,
+        ulMaxKeySize: max,
+        flags,
+      },
+    )
+  })
+}
+
+extern "C" fn C_InitToken(slotID: CK_SLOT_ID, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG, pLabel: CK_UTF8CHAR_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    let pin = bytes(pPin, ulPinLen)?;
+    let label = bytes(pLabel, if pLabel.is_null() { 0 } else { 32 })?;
+    if !m.sessions.is_empty() {
+      return Err(CKR_SESSION_EXISTS);
+    }
+    if m.token_initialized {
+      m.so_pin.check(pin)?;
+    }
+    m.so_pin.set(pin)?;
+    m.user_pin = Pin::new();
+    m.objects.clear();
+    pad(&mut m.label, label);
+    m.token_initialized = true;
+    Ok(())
+  })
+}
+
+extern "C" fn C_InitPIN(hSession: CK_SESSION_HANDLE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let pin = bytes(pPin, ulPinLen)?;
+    let rw = m.session(hSession)?.rw;
+    if m.login != Some(CKU_SO) {
+      return Err(CKR_USER_NOT_LOGGED_IN);
+    }
+    if !rw {
+      return Err(CKR_SESSION_READ_ONLY);
+    }
+    m.user_pin.set(pin)?;
+    m.user_pin.to_be_changed = true;
+    Ok(())
+  })
+}
+
+extern "C" fn C_SetPIN(hSession: CK_SESSION_HANDLE, pOldPin: CK_UTF8CHAR_PTR, ulOldLen: CK_ULONG, pNewPin: CK_UTF8CHAR_PTR, ulNewLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let old_pin = bytes(pOldPin, ulOldLen)?;
+    let new_pin = bytes(pNewPin, ulNewLen)?;
+    if !m.session(hSession)?.rw {
+      return Err(CKR_SESSION_READ_ONLY);
+    }
+    check_pin_len(new_pin)?;
+    let pin = if m.login == Some(CKU_SO) { &mut m.so_pin } else { &mut m.user_pin };
+    pin.check(old_pin)?;
+    pin.set(new_pin)
+  })
+}
+
+extern "C" fn C_OpenSession(slotID: CK_SLOT_ID, flags: CK_FLAGS, _pApplication: CK_VOID_PTR, _Notify: CK_NOTIFY, phSession: CK_SESSION_HANDLE_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    if phSession.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    if flags & CKF_SERIAL_SESSION == 0 {
+      return Err(CKR_SESSION_PARALLEL_NOT_SUPPORTED);
+    }
+    if !m.token_initialized {
+      return Err(CKR_TOKEN_NOT_RECOGNIZED);
+    }
+    let rw = flags & CKF_RW_SESSION != 0;
+    if !rw && m.login == Some(CKU_SO) {
+      return Err(CKR_SESSION_READ_WRITE_SO_EXISTS);
+    }
+    let handle = m.handle();
+    m.sessions.insert(handle, Session { rw, ..Session::default() });
+    write(phSession, handle)
+  })
+}
+
+extern "C" fn C_CloseSession(hSession: CK_SESSION_HANDLE) -> CK_RV {
+  entry(|m| m.close_session(hSession))
+}
+
+extern "C" fn C_CloseAllSessions(slotID: CK_SLOT_ID) -> CK_RV {
+  entry(|m| {
+    if slotID != SLOT_ID {
+      return Err(CKR_SLOT_ID_INVALID);
+    }
+    let handles: Vec<CK_SESSION_HANDLE> = m.sessions.keys().cloned().collect();
+    for handle in handles {
+      m.close_session(handle)?;
+    }
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetSessionInfo(hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let session = m.sessions.get(&hSession).ok_or(CKR_SESSION_HANDLE_INVALID)?;
+    let mut flags = CKF_SERIAL_SESSION;
+    if session.rw {
+      flags |= CKF_RW_SESSION;
+    }
+    write(
+      pInfo,
+      CK_SESSION_INFO {
+        slotID: SLOT_ID,
+        state: m.session_state(session),
+        flags,
+        ulDeviceError: 0,
+      },
+    )
+  })
+}
+
+extern "C" fn C_GetOperationState(_hSession: CK_SESSION_HANDLE, _pOperationState: CK_BYTE_PTR, _pulOperationStateLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_SetOperationState(
+  _hSession: CK_SESSION_HANDLE,
+  _pOperationState: CK_BYTE_PTR,
+  _ulOperationStateLen: CK_ULONG,
+  _hEncryptionKey: CK_OBJECT_HANDLE,
+  _hAuthenticationKey: CK_OBJECT_HANDLE,
+) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_Login(hSession: CK_SESSION_HANDLE, userType: CK_USER_TYPE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let pin = bytes(pPin, ulPinLen)?;
+    m.session(hSession)?;
+    match (userType, m.login) {
+      (CKU_CONTEXT_SPECIFIC, Some(CKU_USER)) => {
+        m.user_pin.check(pin)?;
+        let session = m.session(hSession)?;
+        if session.sign.is_none() && session.decrypt.is_none() {
+          return Err(CKR_OPERATION_NOT_INITIALIZED);
+        }
+        if let Some(op) = session.sign.as_mut() {
+          op.needs_login = false;
+        }
+        if let Some(op) = session.decrypt.as_mut() {
+          op.needs_login = false;
+        }
+        Ok(())
+      }
+      (CKU_CONTEXT_SPECIFIC, _) => Err(CKR_USER_NOT_LOGGED_IN),
+      (CKU_SO, None) | (CKU_USER, None) => {
+        if userType == CKU_SO {
+          if m.sessions.values().any(|s| !s.rw) {
+            return Err(CKR_SESSION_READ_ONLY_EXISTS);
+          }
+          m.so_pin.check(pin)?;
+        } else {
+          if m.user_pin.value.is_none() {
+            return Err(CKR_USER_PIN_NOT_INITIALIZED);
+          }
+          m.user_pin.check(pin)?;
+        }
+        m.login = Some(userType);
+        Ok(())
+      }
+      (CKU_SO, Some(login)) | (CKU_USER, Some(login)) if login == userType => Err(CKR_USER_ALREADY_LOGGED_IN),
+      (CKU_SO, Some(_)) | (CKU_USER, Some(_)) => Err(CKR_USER_ANOTHER_ALREADY_LOGGED_IN),
+      _ => Err(CKR_USER_TYPE_INVALID),
+    }
+  })
+}
+
+extern "C" fn C_Logout(hSession: CK_SESSION_HANDLE) -> CK_RV {
+  entry(|m| {
+    m.session(hSession)?;
+    if m.login.is_none() {
+      return Err(CKR_USER_NOT_LOGGED_IN);
+    }
+    m.logout();
+    Ok(())
+  })
+}
+
+extern "C" fn C_CreateObject(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phObject: CK_OBJECT_HANDLE_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let template = template(pTemplate, ulCount)?;
+    let mut object = Object::new(&template);
+    match object.class() {
+      None => return Err(CKR_TEMPLATE_INCOMPLETE),
+      Some(CKO_SECRET_KEY) | Some(CKO_PRIVATE_KEY) | Some(CKO_PUBLIC_KEY) if object.key_type().is_none() => return Err(CKR_TEMPLATE_INCOMPLETE),
+      _ => {}
+    }
+    object.apply_defaults(false);
+    m.check_writable(hSession, &object)?;
+    let handle = m.insert(hSession, object);
+    write(phObject, handle)
+  })
+}
+
+extern "C" fn C_CopyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phNewObject: CK_OBJECT_HANDLE_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let template = template(pTemplate, ulCount)?;
+    m.session(hSession)?;
+    let mut object = m.object(hObject)?.clone();
+    if !object.flag(CKA_COPYABLE) {
+      return Err(CKR_ACTION_PROHIBITED);
+    }
+    object.update(&template)?;
+    object.session = None;
+    m.check_writable(hSession, &object)?;
+    let handle = m.insert(hSession, object);
+    write(phNewObject, handle)
+  })
+}
+
+extern "C" fn C_DestroyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| {
+    m.session(hSession)?;
+    let object = m.object(hObject)?.clone();
+    if !object.flag(CKA_DESTROYABLE) {
+      return Err(CKR_ACTION_PROHIBITED);
+    }
+    m.check_writable(hSession, &object)?;
+    m.objects.remove(&hObject);
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetObjectSize(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pulSize: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    m.session(hSession)?;
+    let size = m.object(hObject)?.size();
+    write(pulSize, size as CK_ULONG)
+  })
+}
+
+extern "C" fn C_GetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    m.session(hSession)?;
+    let object = m.object(hObject)?;
+    if ulCount == 0 {
+      return Ok(());
+    }
+    if pTemplate.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    // Every attribute is processed; the last error wins as in most modules.
+    let mut rv = Ok(());
+    for attr in slice::from_raw_parts_mut(pTemplate, ulCount as usize) {
+      let value = match object.get(attr.attrType) {
+        Some(_) if object.is_sensitive(attr.attrType) => {
+          attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+          rv = Err(CKR_ATTRIBUTE_SENSITIVE);
+          continue;
+        }
+        Some(value) => value,
+        None => {
+          attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+          rv = Err(CKR_ATTRIBUTE_TYPE_INVALID);
+          continue;
+        }
+      };
+      if attr.pValue.is_null() {
+        attr.ulValueLen = value.len() as CK_ULONG;
+      } else if (attr.ulValueLen as usize) < value.len() {
+        attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+        rv = Err(CKR_BUFFER_TOO_SMALL);
+      } else {
+        slice::from_raw_parts_mut(attr.pValue as CK_BYTE_PTR, value.len()).copy_from_slice(value);
+        attr.ulValueLen = value.len() as CK_ULONG;
+      }
+    }
+    rv
+  })
+}
+
+extern "C" fn C_SetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let template = template(pTemplate, ulCount)?;
+    m.session(hSession)?;
+    let mut object = m.object(hObject)?.clone();
+    if !object.flag(CKA_MODIFIABLE) {
+      return Err(CKR_ACTION_PROHIBITED);
+    }
+    m.check_writable(hSession, &object)?;
+    object.update(&template)?;
+    m.objects.insert(hObject, object);
+    Ok(())
+  })
+}
+
+extern "C" fn C_FindObjectsInit(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let template = template(pTemplate, ulCount)?;
+    let mut found: Vec<CK_OBJECT_HANDLE> = m.objects.iter().filter(|&(_, o)| m.visible(o) && o.matches(&template)).map(|(h, _)| *h).collect();
+    // handed out from the back
+    found.reverse();
+    start(&mut m.session(hSession)?.find, found)
+  })
+}
+
+extern "C" fn C_FindObjects(hSession: CK_SESSION_HANDLE, phObject: CK_OBJECT_HANDLE_PTR, ulMaxObjectCount: CK_ULONG, pulObjectCount: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    if pulObjectCount.is_null() || (phObject.is_null() && ulMaxObjectCount > 0) {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    let found = m.session(hSession)?.find.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    let mut count = 0;
+    while count < ulMaxObjectCount as usize {
+      match found.pop() {
+        Some(handle) => *phObject.add(count) = handle,
+        None => break,
+      }
+      count += 1;
+    }
+    *pulObjectCount = count as CK_ULONG;
+    Ok(())
+  })
+}
+
+extern "C" fn C_FindObjectsFinal(hSession: CK_SESSION_HANDLE) -> CK_RV {
+  entry(|m| m.session(hSession)?.find.take().map(|_| ()).ok_or(CKR_OPERATION_NOT_INITIALIZED))
+}
+
+extern "C" fn C_EncryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    m.session(hSession)?;
+    let (key, _) = m.key(hKey, CKA_ENCRYPT)?;
+    let operation = CipherOperation::new(&mechanism, key, true)?;
+    start(&mut m.session(hSession)?.encrypt, operation)
+  })
+}
+
+extern "C" fn C_Encrypt(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pEncryptedData: CK_BYTE_PTR, pulEncryptedDataLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let data = bytes(pData, ulDataLen)?;
+    step(&mut m.session(hSession)?.encrypt, true, pEncryptedData, pulEncryptedDataLen, |op| op.run(data))
+  })
+}
+
+extern "C" fn C_EncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let part = bytes(pPart, ulPartLen)?;
+    step(&mut m.session(hSession)?.encrypt, false, pEncryptedPart, pulEncryptedPartLen, |op| op.update(part))
+  })
+}
+
+extern "C" fn C_EncryptFinal(hSession: CK_SESSION_HANDLE, pLastEncryptedPart: CK_BYTE_PTR, pulLastEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe { step(&mut m.session(hSession)?.encrypt, true, pLastEncryptedPart, pulLastEncryptedPartLen, |op| op.finish()) })
+}
+
+extern "C" fn C_DecryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    m.session(hSession)?;
+    let (key, always_authenticate) = m.key(hKey, CKA_DECRYPT)?;
+    let mut operation = CipherOperation::new(&mechanism, key, false)?;
+    operation.needs_login = always_authenticate;
+    start(&mut m.session(hSession)?.decrypt, operation)
+  })
+}
+
+fn check_login(needs_login: bool) -> Result<(), CK_RV> {
+  if needs_login {
+    return Err(CKR_USER_NOT_LOGGED_IN);
+  }
+  Ok(())
+}
+
+extern "C" fn C_Decrypt(hSession: CK_SESSION_HANDLE, pEncryptedData: CK_BYTE_PTR, ulEncryptedDataLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let data = bytes(pEncryptedData, ulEncryptedDataLen)?;
+    step(&mut m.session(hSession)?.decrypt, true, pData, pulDataLen, |op| {
+      check_login(op.needs_login)?;
+      op.run(data)
+    })
+  })
+}
+
+extern "C" fn C_DecryptUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let part = bytes(pEncryptedPart, ulEncryptedPartLen)?;
+    step(&mut m.session(hSession)?.decrypt, false, pPart, pulPartLen, |op| {
+      check_login(op.needs_login)?;
+      op.update(part)
+    })
+  })
+}
+
+extern "C" fn C_DecryptFinal(hSession: CK_SESSION_HANDLE, pLastPart: CK_BYTE_PTR, pulLastPartLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    step(&mut m.session(hSession)?.decrypt, true, pLastPart, pulLastPartLen, |op| {
+      check_login(op.needs_login)?;
+      op.finish()
+    })
+  })
+}
+
+extern "C" fn C_DigestInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    let hasher = Hasher::new(mechanism.mechanism).ok_or(CKR_MECHANISM_INVALID)?;
+    start(&mut m.session(hSession)?.digest, hasher)
+  })
+}
+
+extern "C" fn C_Digest(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let data = bytes(pData, ulDataLen)?;
+    step(&mut m.session(hSession)?.digest, true, pDigest, pulDigestLen, |hasher| {
+      hasher.update(data);
+      Ok(hasher.clone().finish())
+    })
+  })
+}
+
+extern "C" fn C_DigestUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let part = bytes(pPart, ulPartLen)?;
+    let hasher = m.session(hSession)?.digest.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    hasher.update(part);
+    Ok(())
+  })
+}
+
+extern "C" fn C_DigestKey(hSession: CK_SESSION_HANDLE, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| {
+    m.session(hSession)?;
+    let object = m.objects.get(&hKey).filter(|o| m.visible(o)).ok_or(CKR_KEY_HANDLE_INVALID)?;
+    let value = match object.class() {
+      Some(CKO_SECRET_KEY) => object.get(CKA_VALUE).ok_or(CKR_KEY_INDIGESTIBLE)?.to_vec(),
+      _ => return Err(CKR_KEY_INDIGESTIBLE),
+    };
+    let hasher = m.session(hSession)?.digest.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    hasher.update(&value);
+    Ok(())
+  })
+}
+
+extern "C" fn C_DigestFinal(hSession: CK_SESSION_HANDLE, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe { step(&mut m.session(hSession)?.digest, true, pDigest, pulDigestLen, |hasher| Ok(hasher.clone().finish())) })
+}
+
+extern "C" fn C_SignInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    m.session(hSession)?;
+    let (key, always_authenticate) = m.key(hKey, CKA_SIGN)?;
+    let mut operation = SignOperation::new(&mechanism, key)?;
+    operation.needs_login = always_authenticate;
+    start(&mut m.session(hSession)?.sign, operation)
+  })
+}
+
+extern "C" fn C_Sign(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let data = bytes(pData, ulDataLen)?;
+    step(&mut m.session(hSession)?.sign, true, pSignature, pulSignatureLen, |op| {
+      check_login(op.needs_login)?;
+      op.update(data);
+      op.sign()
+    })
+  })
+}
+
+extern "C" fn C_SignUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let part = bytes(pPart, ulPartLen)?;
+    let op = m.session(hSession)?.sign.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    op.update(part);
+    Ok(())
+  })
+}
+
+extern "C" fn C_SignFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    step(&mut m.session(hSession)?.sign, true, pSignature, pulSignatureLen, |op| {
+      check_login(op.needs_login)?;
+      op.sign()
+    })
+  })
+}
+
+extern "C" fn C_SignRecoverInit(_hSession: CK_SESSION_HANDLE, _pMechanism: CK_MECHANISM_PTR, _hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_SignRecover(_hSession: CK_SESSION_HANDLE, _pData: CK_BYTE_PTR, _ulDataLen: CK_ULONG, _pSignature: CK_BYTE_PTR, _pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_VerifyInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    m.session(hSession)?;
+    let (key, _) = m.key(hKey, CKA_VERIFY)?;
+    let operation = SignOperation::new(&mechanism, key)?;
+    start(&mut m.session(hSession)?.verify, operation)
+  })
+}
+
+extern "C" fn C_Verify(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let data = bytes(pData, ulDataLen)?;
+    let signature = bytes(pSignature, ulSignatureLen)?;
+    let mut op = m.session(hSession)?.verify.take().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    op.update(data);
+    op.verify(signature)
+  })
+}
+
+extern "C" fn C_VerifyUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let part = bytes(pPart, ulPartLen)?;
+    let op = m.session(hSession)?.verify.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    op.update(part);
+    Ok(())
+  })
+}
+
+extern "C" fn C_VerifyFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    let signature = bytes(pSignature, ulSignatureLen)?;
+    let op = m.session(hSession)?.verify.take().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    op.verify(signature)
+  })
+}
+
+extern "C" fn C_VerifyRecoverInit(_hSession: CK_SESSION_HANDLE, _pMechanism: CK_MECHANISM_PTR, _hKey: CK_OBJECT_HANDLE) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_VerifyRecover(_hSession: CK_SESSION_HANDLE, _pSignature: CK_BYTE_PTR, _ulSignatureLen: CK_ULONG, _pData: CK_BYTE_PTR, _pulDataLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_DigestEncryptUpdate(_hSession: CK_SESSION_HANDLE, _pPart: CK_BYTE_PTR, _ulPartLen: CK_ULONG, _pEncryptedPart: CK_BYTE_PTR, _pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_DecryptDigestUpdate(_hSession: CK_SESSION_HANDLE, _pEncryptedPart: CK_BYTE_PTR, _ulEncryptedPartLen: CK_ULONG, _pPart: CK_BYTE_PTR, _pulPartLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_SignEncryptUpdate(_hSession: CK_SESSION_HANDLE, _pPart: CK_BYTE_PTR, _ulPartLen: CK_ULONG, _pEncryptedPart: CK_BYTE_PTR, _pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_DecryptVerifyUpdate(_hSession: CK_SESSION_HANDLE, _pEncryptedPart: CK_BYTE_PTR, _ulEncryptedPartLen: CK_ULONG, _pPart: CK_BYTE_PTR, _pulPartLen: CK_ULONG_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_GenerateKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    let template = template(pTemplate, ulCount)?;
+    let key_type = match mechanism.mechanism {
+      CKM_AES_KEY_GEN => CKK_AES,
+      CKM_GENERIC_SECRET_KEY_GEN => CKK_GENERIC_SECRET,
+      CKM_DES3_KEY_GEN => CKK_DES3,
+      _ => return Err(CKR_MECHANISM_INVALID),
+    };
+    let mut object = new_key(&template, CKO_SECRET_KEY, key_type)?;
+    m.check_writable(hSession, &object)?;
+    let len = find_ulong(&template, CKA_VALUE_LEN).map(|len| len as usize);
+    let attrs = if key_type == CKK_AES {
+      crypto::generate_aes(len.ok_or(CKR_TEMPLATE_INCOMPLETE)?)?
+    } else {
+      crypto::generate_secret(key_type, len)?
+    };
+    for (t, v) in attrs {
+      object.set(t, v);
+    }
+    let handle = m.insert(hSession, object);
+    write(phKey, handle)
+  })
+}
+
+extern "C" fn C_GenerateKeyPair(
+  hSession: CK_SESSION_HANDLE,
+  pMechanism: CK_MECHANISM_PTR,
+  pPublicKeyTemplate: CK_ATTRIBUTE_PTR,
+  ulPublicKeyAttributeCount: CK_ULONG,
+  pPrivateKeyTemplate: CK_ATTRIBUTE_PTR,
+  ulPrivateKeyAttributeCount: CK_ULONG,
+  phPublicKey: CK_OBJECT_HANDLE_PTR,
+  phPrivateKey: CK_OBJECT_HANDLE_PTR,
+) -> CK_RV {
+  entry(|m| unsafe {
+    let mechanism = mechanism(pMechanism)?;
+    let public_template = template(pPublicKeyTemplate, ulPublicKeyAttributeCount)?;
+    let private_template = template(pPrivateKeyTemplate, ulPrivateKeyAttributeCount)?;
+    if phPublicKey.is_null() || phPrivateKey.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    let key_type = match mechanism.mechanism {
+      CKM_RSA_PKCS_KEY_PAIR_GEN => CKK_RSA,
+      CKM_EC_KEY_PAIR_GEN => CKK_EC,
+      _ => return Err(CKR_MECHANISM_INVALID),
+    };
+    let mut public = new_key(&public_template, CKO_PUBLIC_KEY, key_type)?;
+    let mut private = new_key(&private_template, CKO_PRIVATE_KEY, key_type)?;
+    m.check_writable(hSession, &public)?;
+    m.check_writable(hSession, &private)?;
+    let (public_attrs, private_attrs) = if key_type == CKK_RSA {
+      let bits = find_ulong(&public_template, CKA_MODULUS_BITS).ok_or(CKR_TEMPLATE_INCOMPLETE)?;
+      crypto::generate_rsa(bits as usize, find(&public_template, CKA_PUBLIC_EXPONENT))?
+    } else {
+      crypto::generate_ec(find(&public_template, CKA_EC_PARAMS).ok_or(CKR_TEMPLATE_INCOMPLETE)?)?
+    };
+    for (t, v) in public_attrs {
+      public.set(t, v);
+    }
+    for (t, v) in private_attrs {
+      private.set(t, v);
+    }
+    *phPublicKey = m.insert(hSession, public);
+    *phPrivateKey = m.insert(hSession, private);
+    Ok(())
+  })
+}
+
+extern "C" fn C_WrapKey(
+  _hSession: CK_SESSION_HANDLE,
+  _pMechanism: CK_MECHANISM_PTR,
+  _hWrappingKey: CK_OBJECT_HANDLE,
+  _hKey: CK_OBJECT_HANDLE,
+  _pWrappedKey: CK_BYTE_PTR,
+  _pulWrappedKeyLen: CK_ULONG_PTR,
+) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_UnwrapKey(
+  _hSession: CK_SESSION_HANDLE,
+  _pMechanism: CK_MECHANISM_PTR,
+  _hUnwrappingKey: CK_OBJECT_HANDLE,
+  _pWrappedKey: CK_BYTE_PTR,
+  _ulWrappedKeyLen: CK_ULONG,
+  _pTemplate: CK_ATTRIBUTE_PTR,
+  _ulAttributeCount: CK_ULONG,
+  _phKey: CK_OBJECT_HANDLE_PTR,
+) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_DeriveKey(
+  _hSession: CK_SESSION_HANDLE,
+  _pMechanism: CK_MECHANISM_PTR,
+  _hBaseKey: CK_OBJECT_HANDLE,
+  _pTemplate: CK_ATTRIBUTE_PTR,
+  _ulAttributeCount: CK_ULONG,
+  _phKey: CK_OBJECT_HANDLE_PTR,
+) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+extern "C" fn C_SeedRandom(hSession: CK_SESSION_HANDLE, pSeed: CK_BYTE_PTR, ulSeedLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    bytes(pSeed, ulSeedLen)?;
+    m.session(hSession).map(|_| ())
+  })
+}
+
+extern "C" fn C_GenerateRandom(hSession: CK_SESSION_HANDLE, RandomData: CK_BYTE_PTR, ulRandomLen: CK_ULONG) -> CK_RV {
+  entry(|m| unsafe {
+    m.session(hSession)?;
+    if RandomData.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    slice::from_raw_parts_mut(RandomData, ulRandomLen as usize).copy_from_slice(&crypto::random_bytes(ulRandomLen as usize));
+    Ok(())
+  })
+}
+
+extern "C" fn C_GetFunctionStatus(_hSession: CK_SESSION_HANDLE) -> CK_RV {
+  CKR_FUNCTION_NOT_PARALLEL
+}
+
+extern "C" fn C_CancelFunction(_hSession: CK_SESSION_HANDLE) -> CK_RV {
+  CKR_FUNCTION_NOT_PARALLEL
+}
+
+extern "C" fn C_WaitForSlotEvent(_flags: CK_FLAGS, _pSlot: CK_SLOT_ID_PTR, _pRserved: CK_VOID_PTR) -> CK_RV {
+  CKR_FUNCTION_NOT_SUPPORTED
+}
+
+/// The in-memory token. `C_GetFunctionList` hands out the fault injecting list in front of it.
+static MOCK_FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
+  version: CK_VERSION { major: 2, minor: 40 },
+  C_Initialize: Some(C_Initialize),
+  C_Finalize: Some(C_Finalize),
+  C_GetInfo: Some(C_GetInfo),
+  C_GetFunctionList: Some(C_GetFunctionList),
+  C_GetSlotList: Some(C_GetSlotList),
+  C_GetSlotInfo: Some(C_GetSlotInfo),
+  C_GetTokenInfo: Some(C_GetTokenInfo),
+  C_GetMechanismList: Some(C_GetMechanismList),
+  C_GetMechanismInfo: Some(C_GetMechanismInfo),
+  C_InitToken: Some(C_InitToken),
+  C_InitPIN: Some(C_InitPIN),
+  C_SetPIN: Some(C_SetPIN),
+  C_OpenSession: Some(C_OpenSession),
+  C_CloseSession: Some(C_CloseSession),
+  C_CloseAllSessions: Some(C_CloseAllSessions),
+  C_GetSessionInfo: Some(C_GetSessionInfo),
+  C_GetOperationState: Some(C_GetOperationState),
+  C_SetOperationState: Some(C_SetOperationState),
+  C_Login: Some(C_Login),
+  C_Logout: Some(C_Logout),
+  C_CreateObject: Some(C_CreateObject),
+  C_CopyObject: Some(C_CopyObject),
+  C_DestroyObject: Some(C_DestroyObject),
+  C_GetObjectSize: Some(C_GetObjectSize),
+  C_GetAttributeValue: Some(C_GetAttributeValue),
+  C_SetAttributeValue: Some(C_SetAttributeValue),
+  C_FindObjectsInit: Some(C_FindObjectsInit),
+  C_FindObjects: Some(C_FindObjects),
+  C_FindObjectsFinal: Some(C_FindObjectsFinal),
+  C_EncryptInit: Some(C_EncryptInit),
+  C_Encrypt: Some(C_Encrypt),
+  C_EncryptUpdate: Some(C_EncryptUpdate),
+  C_EncryptFinal: Some(C_EncryptFinal),
+  C_DecryptInit: Some(C_DecryptInit),
+  C_Decrypt: Some(C_Decrypt),
+  C_DecryptUpdate: Some(C_DecryptUpdate),
+  C_DecryptFinal: Some(C_DecryptFinal),
+  C_DigestInit: Some(C_DigestInit),
+  C_Digest: Some(C_Digest),
+  C_DigestUpdate: Some(C_DigestUpdate),
+  C_DigestKey: Some(C_DigestKey),
+  C_DigestFinal: Some(C_DigestFinal),
+  C_SignInit: Some(C_SignInit),
+  C_Sign: Some(C_Sign),
+  C_SignUpdate: Some(C_SignUpdate),
+  C_SignFinal: Some(C_SignFinal),
+  C_SignRecoverInit: Some(C_SignRecoverInit),
+  C_SignRecover: Some(C_SignRecover),
+  C_VerifyInit: Some(C_VerifyInit),
+  C_Verify: Some(C_Verify),
+  C_VerifyUpdate: Some(C_VerifyUpdate),
+  C_VerifyFinal: Some(C_VerifyFinal),
+  C_VerifyRecoverInit: Some(C_VerifyRecoverInit),
+  C_VerifyRecover: Some(C_VerifyRecover),
+  C_DigestEncryptUpdate: Some(C_DigestEncryptUpdate),
+  C_DecryptDigestUpdate: Some(C_DecryptDigestUpdate),
+  C_SignEncryptUpdate: Some(C_SignEncryptUpdate),
+  C_DecryptVerifyUpdate: Some(C_DecryptVerifyUpdate),
+  C_GenerateKey: Some(C_GenerateKey),
+  C_GenerateKeyPair: Some(C_GenerateKeyPair),
+  C_WrapKey: Some(C_WrapKey),
+  C_UnwrapKey: Some(C_UnwrapKey),
+  C_DeriveKey: Some(C_DeriveKey),
+  C_SeedRandom: Some(C_SeedRandom),
+  C_GenerateRandom: Some(C_GenerateRandom),
+  C_GetFunctionStatus: Some(C_GetFunctionStatus),
+  C_CancelFunction: Some(C_CancelFunction),
+  C_WaitForSlotEvent: Some(C_WaitForSlotEvent),
+};
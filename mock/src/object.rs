@@ -0,0 +1,186 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use pkcs11::types::*;
+
+/// An attribute as passed in a template: type and raw value.
+pub type Attribute = (CK_ATTRIBUTE_TYPE, Vec<u8>);
+
+pub fn ulong_value(value: CK_ULONG) -> Vec<u8> {
+  value.to_ne_bytes().to_vec()
+}
+
+pub fn bool_value(value: bool) -> Vec<u8> {
+  vec![if value { CK_TRUE } else { CK_FALSE }]
+}
+
+/// Looks up an attribute in a template.
+pub fn find(template: &[Attribute], attr_type: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+  template.iter().find(|&&(t, _)| t == attr_type).map(|(_, v)| &v[..])
+}
+
+pub fn find_ulong(template: &[Attribute], attr_type: CK_ATTRIBUTE_TYPE) -> Option<CK_ULONG> {
+  find(template, attr_type).and_then(to_ulong)
+}
+
+fn to_ulong(value: &[u8]) -> Option<CK_ULONG> {
+  let mut bytes = [0u8; mem::size_of::<CK_ULONG>()];
+  if value.len() != bytes.len() {
+    return None;
+  }
+  bytes.copy_from_slice(value);
+  Some(CK_ULONG::from_ne_bytes(bytes))
+}
+
+/// An object on the token or in a session, stored as raw attribute values.
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+  attrs: BTreeMap<CK_ATTRIBUTE_TYPE, Vec<u8>>,
+  /// The owning session of session objects; `None` for token objects.
+  pub session: Option<CK_SESSION_HANDLE>,
+}
+
+impl Object {
+  pub fn new(template: &[Attribute]) -> Object {
+    Object {
+      attrs: template.iter().cloned().collect(),
+      session: None,
+    }
+  }
+
+  pub fn set(&mut self, attr_type: CK_ATTRIBUTE_TYPE, value: Vec<u8>) {
+    self.attrs.insert(attr_type, value);
+  }
+
+  /// Sets the attribute unless the template already provided it.
+  pub fn set_default(&mut self, attr_type: CK_ATTRIBUTE_TYPE, value: Vec<u8>) {
+    self.attrs.entry(attr_type).or_insert(value);
+  }
+
+  pub fn get(&self, attr_type: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+    self.attrs.get(&attr_type).map(|v| &v[..])
+  }
+
+  pub fn ulong(&self, attr_type: CK_ATTRIBUTE_TYPE) -> Option<CK_ULONG> {
+    self.get(attr_type).and_then(to_ulong)
+  }
+
+  /// Whether a boolean attribute is present and true.
+  pub fn flag(&self, attr_type: CK_ATTRIBUTE_TYPE) -> bool {
+    self.get(attr_type).is_some_and(|v| v.iter().any(|b| *b != 0))
+  }
+
+  pub fn class(&self) -> Option<CK_OBJECT_CLASS> {
+    self.ulong(CKA_CLASS)
+  }
+
+  pub fn key_type(&self) -> Option<CK_KEY_TYPE> {
+    self.ulong(CKA_KEY_TYPE)
+  }
+
+  pub fn is_private(&self) -> bool {
+    self.flag(CKA_PRIVATE)
+  }
+
+  /// Whether the value of `attr_type` must not leave the token.
+  pub fn is_sensitive(&self, attr_type: CK_ATTRIBUTE_TYPE) -> bool {
+    let secret = match self.class() {
+      Some(CKO_SECRET_KEY) => attr_type == CKA_VALUE,
+      Some(CKO_PRIVATE_KEY) => [CKA_VALUE, CKA_PRIVATE_EXPONENT, CKA_PRIME_1, CKA_PRIME_2, CKA_EXPONENT_1, CKA_EXPONENT_2, CKA_COEFFICIENT].contains(&attr_type),
+      _ => false,
+    };
+    secret && (self.flag(CKA_SENSITIVE) || !self.flag(CKA_EXTRACTABLE))
+  }
+
+  /// Whether every attribute of the template has exactly the given value.
+  pub fn matches(&self, template: &[Attribute]) -> bool {
+    template.iter().all(|(t, v)| self.get(*t) == Some(&v[..]))
+  }
+
+  /// A rough size estimate for `C_GetObjectSize`.
+  pub fn size(&self) -> usize {
+    self.attrs.values().map(|v| v.len() + 2 * mem::size_of::<CK_ULONG>()).sum()
+  }
+
+  /// Fills in the defaults the mock token uses for key and data objects. `local` marks generated keys.
+  pub fn apply_defaults(&mut self, local: bool) {
+    let class = self.class();
+    let is_key = matches!(class, Some(CKO_SECRET_KEY) | Some(CKO_PRIVATE_KEY) | Some(CKO_PUBLIC_KEY));
+    let is_secret = matches!(class, Some(CKO_SECRET_KEY) | Some(CKO_PRIVATE_KEY));
+    self.set_default(CKA_TOKEN, bool_value(false));
+    self.set_default(CKA_PRIVATE, bool_value(is_secret));
+    self.set_default(CKA_MODIFIABLE, bool_value(true));
+    self.set_default(CKA_COPYABLE, bool_value(true));
+    self.set_default(CKA_DESTROYABLE, bool_value(true));
+    self.set_default(CKA_LABEL, Vec::new());
+    if !is_key {
+      return;
+    }
+    self.set_default(CKA_ID, Vec::new());
+    self.set_default(CKA_DERIVE, bool_value(false));
+    self.set(CKA_LOCAL, bool_value(local));
+    match class {
+      Some(CKO_PUBLIC_KEY) => {
+        self.set_default(CKA_ENCRYPT, bool_value(true));
+        self.set_default(CKA_VERIFY, bool_value(true));
+        self.set_default(CKA_WRAP, bool_value(false));
+      }
+      Some(CKO_PRIVATE_KEY) => {
+        self.set_default(CKA_DECRYPT, bool_value(true));
+        self.set_default(CKA_SIGN, bool_value(true));
+        self.set_default(CKA_UNWRAP, bool_value(false));
+        self.set_default(CKA_ALWAYS_AUTHENTICATE, bool_value(false));
+      }
+      _ => {
+        self.set_default(CKA_ENCRYPT, bool_value(true));
+        self.set_default(CKA_DECRYPT, bool_value(true));
+        self.set_default(CKA_SIGN, bool_value(true));
+        self.set_default(CKA_VERIFY, bool_value(true));
+        self.set_default(CKA_WRAP, bool_value(false));
+        self.set_default(CKA_UNWRAP, bool_value(false));
+      }
+    }
+    if is_secret {
+      self.set_default(CKA_SENSITIVE, bool_value(true));
+      self.set_default(CKA_EXTRACTABLE, bool_value(false));
+      let always_sensitive = local && self.flag(CKA_SENSITIVE);
+      let never_extractable = local && !self.flag(CKA_EXTRACTABLE);
+      self.set(CKA_ALWAYS_SENSITIVE, bool_value(always_sensitive));
+      self.set(CKA_NEVER_EXTRACTABLE, bool_value(never_extractable));
+    }
+  }
+
+  /// Applies a `C_SetAttributeValue` or `C_CopyObject` template, enforcing the
+  /// attributes that can only change in one direction.
+  pub fn update(&mut self, template: &[Attribute]) -> Result<(), CK_RV> {
+    for (t, v) in template {
+      let value = v.iter().any(|b| *b != 0);
+      match *t {
+        CKA_CLASS | CKA_KEY_TYPE | CKA_LOCAL | CKA_ALWAYS_SENSITIVE | CKA_NEVER_EXTRACTABLE | CKA_MODULUS | CKA_EC_PARAMS | CKA_EC_POINT if self.get(*t) != Some(&v[..]) => {
+          return Err(CKR_ATTRIBUTE_READ_ONLY)
+        }
+        CKA_SENSITIVE if self.flag(CKA_SENSITIVE) && !value => return Err(CKR_ATTRIBUTE_READ_ONLY),
+        CKA_EXTRACTABLE if !self.flag(CKA_EXTRACTABLE) && value && self.class() != Some(CKO_PUBLIC_KEY) => return Err(CKR_ATTRIBUTE_READ_ONLY),
+        _ => {}
+      }
+    }
+    for (t, v) in template {
+      self.set(*t, v.clone());
+    }
+    Ok(())
+  }
+}
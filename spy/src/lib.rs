@@ -0,0 +1,765 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A PKCS#11 module that logs every call and forwards it to another module,
+//! like OpenSC's `pkcs11-spy`. Load it in place of the real module and point
+//! `PKCS11_SPY_MODULE` to the real one:
+//!
+//! ```text
+//! PKCS11_SPY_MODULE=/usr/lib/softhsm/libsofthsm2.so \
+//! PKCS11_SPY_OUTPUT=/tmp/pkcs11.log \
+//! some-application --module target/release/libpkcs11_spy.so
+//! ```
+//!
+//! The log goes to `PKCS11_SPY_OUTPUT`, or to stderr if that is not set. Each
+//! call is logged with its arguments, decoded templates and mechanisms, the
+//! outputs and the name of the return value. PINs, secret attribute values,
+//! random data and operation states are redacted.
+//!
+//! With `PKCS11_SPY_RECORD` the calls are also written to a trace file, which
+//! `PKCS11_SPY_REPLAY` replays without any module, see `pkcs11::replay`.
+#![allow(non_snake_case)]
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+extern crate libloading;
+extern crate pkcs11;
+
+mod log;
+
+use std::env;
+use std::ffi::{CStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use pkcs11::names;
+use pkcs11::replay::{self, Call, Divergence, Trace};
+use pkcs11::types::*;
+
+use log::Log;
+
+enum Target {
+  /// Forwards to a module, recording to the trace file if there is one.
+  Module(&'static CK_FUNCTION_LIST, Option<Mutex<File>>),
+  Replay(Mutex<Replay>),
+}
+
+struct Replay {
+  calls: Vec<Call>,
+  next: usize,
+  divergence: Option<Box<Divergence>>,
+}
+
+impl Replay {
+  /// Checks the inputs of the call in `log` against the next recorded call
+  /// and serves its outputs. Every call fails from the first divergence on.
+  fn next(&mut self, log: &mut Log) -> CK_RV {
+    if self.divergence.is_some() {
+      return CKR_GENERAL_ERROR;
+    }
+    let expected = self.calls.get(self.next);
+    self.divergence = Divergence::check(self.next + 1, expected, log.call()).map(Box::new);
+    match expected {
+      Some(expected) if self.divergence.is_none() => {
+        self.next += 1;
+        log.replay(expected);
+        expected.rv
+      }
+      _ => CKR_GENERAL_ERROR,
+    }
+  }
+}
+
+struct Spy {
+  target: Target,
+  output: Option<Mutex<Box<dyn Write + Send>>>,
+  calls: AtomicU64,
+}
+
+struct Config {
+  module: Option<OsString>,
+  output: Option<OsString>,
+  /// Logs to stderr without an `output`.
+  stderr: bool,
+  record: Option<OsString>,
+  replay: Option<OsString>,
+}
+
+static SPY: OnceLock<Spy> = OnceLock::new();
+static CONFIGURE: Mutex<()> = Mutex::new(());
+
+/// The spy, configured from the environment on first use.
+fn spy() -> Result<&'static Spy, CK_RV> {
+  if let Some(spy) = SPY.get() {
+    return Ok(spy);
+  }
+  let replay = env::var_os("PKCS11_SPY_REPLAY");
+  let module = env::var_os("PKCS11_SPY_MODULE");
+  if module.is_none() && replay.is_none() {
+    eprintln!("pkcs11-spy: PKCS11_SPY_MODULE is not set");
+    return Err(CKR_GENERAL_ERROR);
+  }
+  configure(Config {
+    module,
+    output: env::var_os("PKCS11_SPY_OUTPUT"),
+    stderr: true,
+    record: env::var_os("PKCS11_SPY_RECORD"),
+    replay,
+  })
+}
+
+fn configure(config: Config) -> Result<&'static Spy, CK_RV> {
+  let _guard = CONFIGURE.lock().unwrap_or_else(|e| e.into_inner());
+  if let Some(spy) = SPY.get() {
+    return Ok(spy);
+  }
+  let output: Option<Box<dyn Write + Send>> = match (config.output, config.stderr) {
+    (Some(path), _) => Some(Box::new(open(&path, OpenOptions::new().create(true).append(true))?)),
+    (None, true) => Some(Box::new(io::stderr())),
+    (None, false) => None,
+  };
+  let target = match (config.replay, config.module) {
+    (Some(path), _) => {
+      let trace: Trace = fs::read_to_string(&path).ok().and_then(|t| t.parse().ok()).ok_or_else(|| {
+        eprintln!("pkcs11-spy: cannot read the trace {:?}", path);
+        CKR_GENERAL_ERROR
+      })?;
+      Target::Replay(Mutex::new(Replay {
+        calls: trace.calls,
+        next: 0,
+        divergence: None,
+      }))
+    }
+    (None, Some(module)) => {
+      let record = match config.record {
+        Some(path) => {
+          let mut file = open(&path, OpenOptions::new().create(true).write(true).truncate(true))?;
+          writeln!(file, "{}", replay::HEADER).map_err(|_| CKR_GENERAL_ERROR)?;
+          Some(Mutex::new(file))
+        }
+        None => None,
+      };
+      let list = load(&module).inspect_err(|rv| eprintln!("pkcs11-spy: cannot load {:?}: {:#x}", module, rv))?;
+      Target::Module(list, record)
+    }
+    (None, None) => return Err(CKR_ARGUMENTS_BAD),
+  };
+  Ok(SPY.get_or_init(|| Spy {
+    target,
+    output: output.map(Mutex::new),
+    calls: AtomicU64::new(0),
+  }))
+}
+
+fn open(path: &OsString, options: &OpenOptions) -> Result<File, CK_RV> {
+  options.open(path).map_err(|e| {
+    eprintln!("pkcs11-spy: cannot open {:?}: {}", path, e);
+    CKR_GENERAL_ERROR
+  })
+}
+
+/// Loads the module to forward to. The library stays loaded for the rest of the process.
+fn load(path: &OsString) -> Result<&'static CK_FUNCTION_LIST, CK_RV> {
+  unsafe {
+    let lib = libloading::Library::new(path).map_err(|_| CKR_GENERAL_ERROR)?;
+    let list = {
+      let func: libloading::Symbol<unsafe extern "C" fn(CK_FUNCTION_LIST_PTR_PTR) -> CK_RV> = lib.get(b"C_GetFunctionList").map_err(|_| CKR_GENERAL_ERROR)?;
+      let mut list: CK_FUNCTION_LIST_PTR = ptr::null_mut();
+      match func(&mut list) {
+        CKR_OK if !list.is_null() => &*list,
+        CKR_OK => return Err(CKR_GENERAL_ERROR),
+        err => return Err(err),
+      }
+    };
+    mem::forget(lib);
+    Ok(list)
+  }
+}
+
+unsafe fn path(s: *const c_char) -> Option<OsString> {
+  if s.is_null() {
+    None
+  } else {
+    Some(OsString::from(CStr::from_ptr(s).to_string_lossy().into_owned()))
+  }
+}
+
+fn configure_once(config: Config) -> CK_RV {
+  if SPY.get().is_some() {
+    return CKR_CRYPTOKI_ALREADY_INITIALIZED;
+  }
+  match configure(config) {
+    Ok(_) => CKR_OK,
+    Err(rv) => rv,
+  }
+}
+
+/// Configures the spy without the environment, for callers that load it
+/// themselves. `output` may be null for stderr. Fails with
+/// `CKR_CRYPTOKI_ALREADY_INITIALIZED` once the spy was configured.
+///
+/// # Safety
+///
+/// `module` and `output` must be null or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_spy_configure(module: *const c_char, output: *const c_char) -> CK_RV {
+  if module.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  configure_once(Config {
+    module: path(module),
+    output: path(output),
+    stderr: true,
+    record: None,
+    replay: None,
+  })
+}
+
+/// Like `pkcs11_spy_configure`, but also records the calls to `trace`. No log
+/// is written with a null `output`.
+///
+/// # Safety
+///
+/// `module`, `trace` and `output` must be null or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_spy_record(module: *const c_char, trace: *const c_char, output: *const c_char) -> CK_RV {
+  if module.is_null() || trace.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  configure_once(Config {
+    module: path(module),
+    output: path(output),
+    stderr: false,
+    record: path(trace),
+    replay: None,
+  })
+}
+
+/// Configures the spy to answer all calls from `trace`. No log is written
+/// with a null `output`.
+///
+/// # Safety
+///
+/// `trace` and `output` must be null or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_spy_replay(trace: *const c_char, output: *const c_char) -> CK_RV {
+  if trace.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  configure_once(Config {
+    module: None,
+    output: path(output),
+    stderr: false,
+    record: None,
+    replay: path(trace),
+  })
+}
+
+/// The first divergence of a replay in the form of `Divergence::encode`, with
+/// the two-call convention. The length is 0 without a divergence.
+///
+/// # Safety
+///
+/// `len` must point to a `CK_ULONG` and `text` must be null or point to `*len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pkcs11_spy_divergence(text: *mut u8, len: *mut CK_ULONG) -> CK_RV {
+  if len.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  let replay = match SPY.get().map(|spy| &spy.target) {
+    Some(Target::Replay(replay)) => replay.lock().unwrap_or_else(|e| e.into_inner()),
+    _ => return CKR_FUNCTION_NOT_SUPPORTED,
+  };
+  let encoded = replay.divergence.as_deref().map(Divergence::encode).unwrap_or_default();
+  if !text.is_null() {
+    if (*len as usize) < encoded.len() {
+      *len = encoded.len() as CK_ULONG;
+      return CKR_BUFFER_TOO_SMALL;
+    }
+    ptr::copy_nonoverlapping(encoded.as_ptr(), text, encoded.len());
+  }
+  *len = encoded.len() as CK_ULONG;
+  CKR_OK
+}
+
+/// Runs one call: `f` logs the inputs, forwards or replays the call and logs
+/// the outputs. The entry goes to the log and the trace afterwards.
+fn call<F>(function: &'static str, f: F) -> CK_RV
+where
+  F: FnOnce(&'static Spy, &mut Log) -> CK_RV,
+{
+  let spy = match spy() {
+    Ok(spy) => spy,
+    Err(rv) => return rv,
+  };
+  let mut log = Log::new(spy.calls.fetch_add(1, Ordering::SeqCst), function);
+  let start = Instant::now();
+  let rv = panic::catch_unwind(AssertUnwindSafe(|| f(spy, &mut log))).unwrap_or(CKR_GENERAL_ERROR);
+  log.returned(rv, start.elapsed());
+  if let Target::Module(_, Some(ref record)) = spy.target {
+    let mut record = record.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = write!(record, "{}", log.call()).and_then(|_| record.flush());
+  }
+  if let Some(ref output) = spy.output {
+    let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = output.write_all(log.text().as_bytes()).and_then(|_| output.flush());
+  }
+  rv
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn C_GetFunctionList(ppFunctionList: CK_FUNCTION_LIST_PTR_PTR) -> CK_RV {
+  if ppFunctionList.is_null() {
+    return CKR_ARGUMENTS_BAD;
+  }
+  if let Err(rv) = spy() {
+    return rv;
+  }
+  unsafe {
+    *ppFunctionList = &FUNCTION_LIST as *const CK_FUNCTION_LIST as CK_FUNCTION_LIST_PTR;
+  }
+  CKR_OK
+}
+
+/// Generates the exported functions: `before` logs the inputs, then the call is
+/// forwarded or replayed and `after` logs the outputs with the return value
+/// bound to `rv`.
+macro_rules! spy_functions {
+  ($($name:ident($($arg:ident: $ty:ty),*) |$log:ident, $rv:ident| $before:block $after:block)*) => {
+    $(
+      #[no_mangle]
+      #[allow(unused_unsafe, clippy::not_unsafe_ptr_arg_deref, clippy::too_many_arguments)]
+      pub extern "C" fn $name($($arg: $ty),*) -> CK_RV {
+        call(stringify!($name), |spy, $log| unsafe {
+          $before
+          let $rv = match spy.target {
+            Target::Module(target, _) => match target.$name {
+              Some(f) => f($($arg),*),
+              None => CKR_FUNCTION_NOT_SUPPORTED,
+            },
+            Target::Replay(ref replay) => replay.lock().unwrap_or_else(|e| e.into_inner()).next($log),
+          };
+          $after
+          $rv
+        })
+      }
+    )*
+
+    static FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
+      version: CK_VERSION { major: 2, minor: 40 },
+      C_GetFunctionList: Some(C_GetFunctionList),
+      $($name: Some($name),)*
+    };
+  };
+}
+
+spy_functions! {
+  C_Initialize(pInitArgs: CK_C_INITIALIZE_ARGS_PTR) |log, rv| {
+    if pInitArgs.is_null() {
+      log.pointer("pInitArgs", pInitArgs);
+    } else {
+      log.handle("pInitArgs->flags", (*pInitArgs).flags);
+    }
+  } {}
+  C_Finalize(pReserved: CK_VOID_PTR) |log, rv| {
+    log.pointer("pReserved", pReserved);
+  } {}
+  C_GetInfo(pInfo: CK_INFO_PTR) |log, rv| {} {
+    log.info_out(rv, pInfo);
+  }
+  C_GetSlotList(tokenPresent: CK_BBOOL, pSlotList: CK_SLOT_ID_PTR, pulCount: CK_ULONG_PTR) |log, rv| {
+    log.ulong("tokenPresent", tokenPresent as CK_ULONG);
+    log.buffer("pSlotList", pSlotList, pulCount);
+  } {
+    log.list_out(rv, "pSlotList", pSlotList, pulCount, None);
+  }
+  C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+  } {
+    log.slot_info_out(rv, pInfo);
+  }
+  C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+  } {
+    log.token_info_out(rv, pInfo);
+  }
+  C_GetMechanismList(slotID: CK_SLOT_ID, pMechanismList: CK_MECHANISM_TYPE_PTR, pulCount: CK_ULONG_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+    log.buffer("pMechanismList", pMechanismList, pulCount);
+  } {
+    log.list_out(rv, "pMechanismList", pMechanismList, pulCount, Some(names::MECHANISMS));
+  }
+  C_GetMechanismInfo(slotID: CK_SLOT_ID, mechType: CK_MECHANISM_TYPE, pInfo: CK_MECHANISM_INFO_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+    log.named("type", names::MECHANISMS, mechType);
+  } {
+    log.mechanism_info_out(rv, pInfo);
+  }
+  C_InitToken(slotID: CK_SLOT_ID, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG, pLabel: CK_UTF8CHAR_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+    log.pin("pPin", pPin);
+    log.label("pLabel", pLabel);
+  } {}
+  C_InitPIN(hSession: CK_SESSION_HANDLE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.pin("pPin", pPin);
+  } {}
+  C_SetPIN(hSession: CK_SESSION_HANDLE, pOldPin: CK_UTF8CHAR_PTR, ulOldLen: CK_ULONG, pNewPin: CK_UTF8CHAR_PTR, ulNewLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.pin("pOldPin", pOldPin);
+    log.pin("pNewPin", pNewPin);
+  } {}
+  C_OpenSession(slotID: CK_SLOT_ID, flags: CK_FLAGS, pApplication: CK_VOID_PTR, Notify: CK_NOTIFY, phSession: CK_SESSION_HANDLE_PTR) |log, rv| {
+    log.handle("slotID", slotID);
+    log.handle("flags", flags);
+  } {
+    log.handle_out(rv, "phSession", phSession);
+  }
+  C_CloseSession(hSession: CK_SESSION_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+  } {}
+  C_CloseAllSessions(slotID: CK_SLOT_ID) |log, rv| {
+    log.handle("slotID", slotID);
+  } {}
+  C_GetSessionInfo(hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+  } {
+    log.session_info_out(rv, pInfo);
+  }
+  C_GetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, pulOperationStateLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("pOperationState", pOperationState, pulOperationStateLen);
+  } {
+    log.secret_output(rv, "pOperationState", pOperationState, pulOperationStateLen);
+  }
+  C_SetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, ulOperationStateLen: CK_ULONG, hEncryptionKey: CK_OBJECT_HANDLE, hAuthenticationKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.ulong("ulOperationStateLen", ulOperationStateLen);
+    log.handle("hEncryptionKey", hEncryptionKey);
+    log.handle("hAuthenticationKey", hAuthenticationKey);
+  } {}
+  C_Login(hSession: CK_SESSION_HANDLE, userType: CK_USER_TYPE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.named("userType", names::USER_TYPES, userType);
+    log.pin("pPin", pPin);
+  } {}
+  C_Logout(hSession: CK_SESSION_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+  } {}
+  C_CreateObject(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phObject: CK_OBJECT_HANDLE_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.template("pTemplate", pTemplate, ulCount);
+  } {
+    log.handle_out(rv, "phObject", phObject);
+  }
+  C_CopyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phNewObject: CK_OBJECT_HANDLE_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hObject", hObject);
+    log.template("pTemplate", pTemplate, ulCount);
+  } {
+    log.handle_out(rv, "phNewObject", phNewObject);
+  }
+  C_DestroyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hObject", hObject);
+  } {}
+  C_GetObjectSize(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pulSize: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hObject", hObject);
+  } {
+    log.ulong_out(rv, "pulSize", pulSize);
+  }
+  C_GetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hObject", hObject);
+    log.query("pTemplate", pTemplate, ulCount);
+  } {
+    log.template_out(rv, "pTemplate", pTemplate, ulCount);
+  }
+  C_SetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hObject", hObject);
+    log.template("pTemplate", pTemplate, ulCount);
+  } {}
+  C_FindObjectsInit(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.template("pTemplate", pTemplate, ulCount);
+  } {}
+  C_FindObjects(hSession: CK_SESSION_HANDLE, phObject: CK_OBJECT_HANDLE_PTR, ulMaxObjectCount: CK_ULONG, pulObjectCount: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.ulong("ulMaxObjectCount", ulMaxObjectCount);
+  } {
+    log.list_out(rv, "phObject", phObject, pulObjectCount, None);
+  }
+  C_FindObjectsFinal(hSession: CK_SESSION_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+  } {}
+  C_EncryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_Encrypt(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pEncryptedData: CK_BYTE_PTR, pulEncryptedDataLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pData", pData, ulDataLen);
+    log.buffer("pEncryptedData", pEncryptedData, pulEncryptedDataLen);
+  } {
+    log.output(rv, "pEncryptedData", pEncryptedData, pulEncryptedDataLen);
+  }
+  C_EncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+    log.buffer("pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  } {
+    log.output(rv, "pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  }
+  C_EncryptFinal(hSession: CK_SESSION_HANDLE, pLastEncryptedPart: CK_BYTE_PTR, pulLastEncryptedPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("pLastEncryptedPart", pLastEncryptedPart, pulLastEncryptedPartLen);
+  } {
+    log.output(rv, "pLastEncryptedPart", pLastEncryptedPart, pulLastEncryptedPartLen);
+  }
+  C_DecryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_Decrypt(hSession: CK_SESSION_HANDLE, pEncryptedData: CK_BYTE_PTR, ulEncryptedDataLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pEncryptedData", pEncryptedData, ulEncryptedDataLen);
+    log.buffer("pData", pData, pulDataLen);
+  } {
+    log.secret_output(rv, "pData", pData, pulDataLen);
+  }
+  C_DecryptUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pEncryptedPart", pEncryptedPart, ulEncryptedPartLen);
+    log.buffer("pPart", pPart, pulPartLen);
+  } {
+    log.secret_output(rv, "pPart", pPart, pulPartLen);
+  }
+  C_DecryptFinal(hSession: CK_SESSION_HANDLE, pLastPart: CK_BYTE_PTR, pulLastPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("pLastPart", pLastPart, pulLastPartLen);
+  } {
+    log.secret_output(rv, "pLastPart", pLastPart, pulLastPartLen);
+  }
+  C_DigestInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+  } {}
+  C_Digest(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pData", pData, ulDataLen);
+    log.buffer("pDigest", pDigest, pulDigestLen);
+  } {
+    log.output(rv, "pDigest", pDigest, pulDigestLen);
+  }
+  C_DigestUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+  } {}
+  C_DigestKey(hSession: CK_SESSION_HANDLE, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.handle("hKey", hKey);
+  } {}
+  C_DigestFinal(hSession: CK_SESSION_HANDLE, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("pDigest", pDigest, pulDigestLen);
+  } {
+    log.output(rv, "pDigest", pDigest, pulDigestLen);
+  }
+  C_SignInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_Sign(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pData", pData, ulDataLen);
+    log.buffer("pSignature", pSignature, pulSignatureLen);
+  } {
+    log.output(rv, "pSignature", pSignature, pulSignatureLen);
+  }
+  C_SignUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+  } {}
+  C_SignFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("pSignature", pSignature, pulSignatureLen);
+  } {
+    log.output(rv, "pSignature", pSignature, pulSignatureLen);
+  }
+  C_SignRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_SignRecover(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pData", pData, ulDataLen);
+    log.buffer("pSignature", pSignature, pulSignatureLen);
+  } {
+    log.output(rv, "pSignature", pSignature, pulSignatureLen);
+  }
+  C_VerifyInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_Verify(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pData", pData, ulDataLen);
+    log.bytes("pSignature", pSignature, ulSignatureLen);
+  } {}
+  C_VerifyUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+  } {}
+  C_VerifyFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pSignature", pSignature, ulSignatureLen);
+  } {}
+  C_VerifyRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hKey", hKey);
+  } {}
+  C_VerifyRecover(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pSignature", pSignature, ulSignatureLen);
+    log.buffer("pData", pData, pulDataLen);
+  } {
+    log.output(rv, "pData", pData, pulDataLen);
+  }
+  C_DigestEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+    log.buffer("pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  } {
+    log.output(rv, "pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  }
+  C_DecryptDigestUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pEncryptedPart", pEncryptedPart, ulEncryptedPartLen);
+    log.buffer("pPart", pPart, pulPartLen);
+  } {
+    log.secret_output(rv, "pPart", pPart, pulPartLen);
+  }
+  C_SignEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pPart", pPart, ulPartLen);
+    log.buffer("pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  } {
+    log.output(rv, "pEncryptedPart", pEncryptedPart, pulEncryptedPartLen);
+  }
+  C_DecryptVerifyUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.bytes("pEncryptedPart", pEncryptedPart, ulEncryptedPartLen);
+    log.buffer("pPart", pPart, pulPartLen);
+  } {
+    log.secret_output(rv, "pPart", pPart, pulPartLen);
+  }
+  C_GenerateKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.template("pTemplate", pTemplate, ulCount);
+  } {
+    log.handle_out(rv, "phKey", phKey);
+  }
+  C_GenerateKeyPair(
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    pPublicKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPublicKeyAttributeCount: CK_ULONG,
+    pPrivateKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPrivateKeyAttributeCount: CK_ULONG,
+    phPublicKey: CK_OBJECT_HANDLE_PTR,
+    phPrivateKey: CK_OBJECT_HANDLE_PTR
+  ) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.template("pPublicKeyTemplate", pPublicKeyTemplate, ulPublicKeyAttributeCount);
+    log.template("pPrivateKeyTemplate", pPrivateKeyTemplate, ulPrivateKeyAttributeCount);
+  } {
+    log.handle_out(rv, "phPublicKey", phPublicKey);
+    log.handle_out(rv, "phPrivateKey", phPrivateKey);
+  }
+  C_WrapKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hWrappingKey: CK_OBJECT_HANDLE, hKey: CK_OBJECT_HANDLE, pWrappedKey: CK_BYTE_PTR, pulWrappedKeyLen: CK_ULONG_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hWrappingKey", hWrappingKey);
+    log.handle("hKey", hKey);
+    log.buffer("pWrappedKey", pWrappedKey, pulWrappedKeyLen);
+  } {
+    log.output(rv, "pWrappedKey", pWrappedKey, pulWrappedKeyLen);
+  }
+  C_UnwrapKey(
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hUnwrappingKey: CK_OBJECT_HANDLE,
+    pWrappedKey: CK_BYTE_PTR,
+    ulWrappedKeyLen: CK_ULONG,
+    pTemplate: CK_ATTRIBUTE_PTR,
+    ulAttributeCount: CK_ULONG,
+    phKey: CK_OBJECT_HANDLE_PTR
+  ) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hUnwrappingKey", hUnwrappingKey);
+    log.bytes("pWrappedKey", pWrappedKey, ulWrappedKeyLen);
+    log.template("pTemplate", pTemplate, ulAttributeCount);
+  } {
+    log.handle_out(rv, "phKey", phKey);
+  }
+  C_DeriveKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hBaseKey: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulAttributeCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR) |log, rv| {
+    log.handle("hSession", hSession);
+    log.mechanism(pMechanism);
+    log.handle("hBaseKey", hBaseKey);
+    log.template("pTemplate", pTemplate, ulAttributeCount);
+  } {
+    log.handle_out(rv, "phKey", phKey);
+  }
+  C_SeedRandom(hSession: CK_SESSION_HANDLE, pSeed: CK_BYTE_PTR, ulSeedLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.ulong("ulSeedLen", ulSeedLen);
+  } {}
+  C_GenerateRandom(hSession: CK_SESSION_HANDLE, RandomData: CK_BYTE_PTR, ulRandomLen: CK_ULONG) |log, rv| {
+    log.handle("hSession", hSession);
+    log.buffer("RandomData", RandomData, &ulRandomLen);
+  } {
+    let mut len = ulRandomLen;
+    log.secret_output(rv, "RandomData", RandomData, &mut len);
+  }
+  C_GetFunctionStatus(hSession: CK_SESSION_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+  } {}
+  C_CancelFunction(hSession: CK_SESSION_HANDLE) |log, rv| {
+    log.handle("hSession", hSession);
+  } {}
+  C_WaitForSlotEvent(flags: CK_FLAGS, pSlot: CK_SLOT_ID_PTR, pRserved: CK_VOID_PTR) |log, rv| {
+    log.handle("flags", flags);
+  } {
+    log.handle_out(rv, "pSlot", pSlot);
+  }
+}
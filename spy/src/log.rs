@@ -0,0 +1,508 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The log entry of one call. Arguments are collected while the call runs and
+//! the entry is written in one piece, so calls from several threads do not
+//! interleave. The pointer arguments are read as the PKCS#11 caller passed them.
+//!
+//! Next to the text, every argument is collected for the trace of the call.
+//! When replaying, the outputs are written from the recorded call first and
+//! then logged like the outputs of a module.
+
+use std::cell::Cell;
+use std::fmt::{Display, Write};
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use pkcs11::errors::strerror;
+use pkcs11::replay::{Attribute, Call, Value};
+use pkcs11::types::*;
+
+use pkcs11::names::{self, name};
+
+/// Buffers are cut after this many bytes.
+const MAX_BYTES: usize = 64;
+
+/// Attributes whose values never show up in the log. `CKA_VALUE` is among them
+/// for every object, as most calls do not tell the object class.
+const SECRET_ATTRIBUTES: &[CK_ATTRIBUTE_TYPE] = &[CKA_VALUE, CKA_PRIVATE_EXPONENT, CKA_PRIME_1, CKA_PRIME_2, CKA_EXPONENT_1, CKA_EXPONENT_2, CKA_COEFFICIENT];
+
+/// Threads are numbered in the order of their first call. `thread::current()`
+/// would register a thread-local destructor, which crashes the thread on exit
+/// once the application has unloaded the spy.
+static THREADS: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+  static THREAD: Cell<u64> = const { Cell::new(0) };
+}
+
+fn thread() -> u64 {
+  THREAD.with(|id| {
+    if id.get() == 0 {
+      id.set(THREADS.fetch_add(1, Ordering::Relaxed));
+    }
+    id.get()
+  })
+}
+
+pub struct Log {
+  text: String,
+  call: Call,
+  /// The recorded outputs while replaying.
+  replayed: Option<Vec<(String, Value)>>,
+}
+
+fn hex(data: &[u8]) -> String {
+  let mut s = String::with_capacity(2 * data.len().min(MAX_BYTES) + 3);
+  for b in data.iter().take(MAX_BYTES) {
+    let _ = write!(s, "{:02x}", b);
+  }
+  if data.len() > MAX_BYTES {
+    s.push_str("...");
+  }
+  s
+}
+
+fn blank_padded(field: &[u8]) -> String {
+  format!("{:?}", String::from_utf8_lossy(field).trim_end())
+}
+
+fn version(v: CK_VERSION) -> String {
+  format!("{}.{}", v.major, v.minor)
+}
+
+/// An attribute for the trace, `with_value` unless it is an output buffer.
+/// Secret attributes only get their length recorded.
+unsafe fn trace_attribute(attr: &CK_ATTRIBUTE, with_value: bool) -> Attribute {
+  let with_value = with_value && attr.ulValueLen != CK_UNAVAILABLE_INFORMATION;
+  let secret = with_value && SECRET_ATTRIBUTES.contains(&attr.attrType);
+  let value = match (attr.pValue.is_null(), with_value && !secret) {
+    (true, _) => None,
+    (false, true) => Some(slice::from_raw_parts(attr.pValue as *const u8, attr.ulValueLen as usize).to_vec()),
+    (false, false) => Some(Vec::new()),
+  };
+  Attribute {
+    attr_type: attr.attrType,
+    len: attr.ulValueLen,
+    secret: secret && value.is_some(),
+    value,
+  }
+}
+
+unsafe fn ulong_value(data: *const CK_VOID, len: CK_ULONG) -> Option<CK_ULONG> {
+  if len as usize == ::std::mem::size_of::<CK_ULONG>() {
+    Some(ptr::read_unaligned(data as *const CK_ULONG))
+  } else {
+    None
+  }
+}
+
+/// The value of an attribute, or only its buffer for a `query` before `C_GetAttributeValue`.
+unsafe fn attribute_value(attr: &CK_ATTRIBUTE, query: bool) -> String {
+  let len = attr.ulValueLen;
+  if len == CK_UNAVAILABLE_INFORMATION {
+    return "unavailable".to_string();
+  }
+  if attr.pValue.is_null() {
+    return format!("[{}]", len);
+  }
+  if query {
+    return format!("<buffer of {} bytes>", len);
+  }
+  if SECRET_ATTRIBUTES.contains(&attr.attrType) {
+    return format!("<redacted, {} bytes>", len);
+  }
+  let table = match attr.attrType {
+    CKA_CLASS => Some(names::OBJECT_CLASSES),
+    CKA_KEY_TYPE => Some(names::KEY_TYPES),
+    CKA_CERTIFICATE_TYPE => Some(names::CERTIFICATE_TYPES),
+    CKA_KEY_GEN_MECHANISM => Some(names::MECHANISMS),
+    _ => None,
+  };
+  let value = slice::from_raw_parts(attr.pValue as *const u8, len as usize);
+  match (table, ulong_value(attr.pValue, len)) {
+    (Some(table), Some(v)) => return name(table, v),
+    (None, Some(v)) if attr.attrType == CKA_MODULUS_BITS || attr.attrType == CKA_VALUE_LEN => return v.to_string(),
+    _ => {}
+  }
+  match attr.attrType {
+    CKA_LABEL | CKA_APPLICATION | CKA_URL => match str::from_utf8(value) {
+      Ok(s) => format!("{:?}", s),
+      Err(_) => hex(value),
+    },
+    _ if value == [CK_TRUE] => "true".to_string(),
+    _ if value == [CK_FALSE] => "false".to_string(),
+    _ => format!("[{}] {}", len, hex(value)),
+  }
+}
+
+impl Log {
+  pub fn new(sequence: u64, function: &str) -> Log {
+    Log {
+      text: format!("{}: {} (thread {})\n", sequence, function, thread()),
+      call: Call::new(function),
+      replayed: None,
+    }
+  }
+
+  pub fn text(&self) -> &str {
+    &self.text
+  }
+
+  pub fn call(&self) -> &Call {
+    &self.call
+  }
+
+  /// Serves the outputs of `call` instead of reading those of a module.
+  pub fn replay(&mut self, call: &Call) {
+    self.replayed = Some(call.outputs.clone());
+  }
+
+  fn replayed(&self, name: &str) -> Option<Value> {
+    let outputs = self.replayed.as_ref()?;
+    outputs.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+  }
+
+  fn line(&mut self, direction: &str, name: &str, value: &dyn Display) {
+    let _ = writeln!(self.text, "  [{}] {} = {}", direction, name, value);
+  }
+
+  fn input(&mut self, name: &str, value: Value) {
+    self.call.inputs.push((name.to_string(), value));
+  }
+
+  fn output_value(&mut self, name: &str, value: Value) {
+    self.call.outputs.push((name.to_string(), value));
+  }
+
+  pub fn ulong(&mut self, name: &str, value: CK_ULONG) {
+    self.line("in", name, &value);
+    self.input(name, Value::Ulong(value));
+  }
+
+  pub fn handle(&mut self, name: &str, value: CK_ULONG) {
+    self.line("in", name, &format_args!("{:#x}", value));
+    self.input(name, Value::Ulong(value));
+  }
+
+  pub fn named(&mut self, name: &str, table: &[(CK_ULONG, &str)], value: CK_ULONG) {
+    self.line("in", name, &names::name(table, value));
+    self.input(name, Value::Ulong(value));
+  }
+
+  pub fn pointer<T>(&mut self, name: &str, value: *const T) {
+    self.line("in", name, &format_args!("{:p}", value));
+    self.input(name, if value.is_null() { Value::Null } else { Value::Pointer });
+  }
+
+  pub unsafe fn bytes(&mut self, name: &str, data: *const CK_BYTE, len: CK_ULONG) {
+    if data.is_null() {
+      self.line("in", name, &format_args!("NULL [{}]", len));
+      self.input(name, Value::Null);
+    } else {
+      let data = slice::from_raw_parts(data, len as usize);
+      self.line("in", name, &format_args!("[{}] {}", len, hex(data)));
+      self.input(name, Value::Bytes(data.to_vec()));
+    }
+  }
+
+  /// An output buffer as the caller passes it, which decides between a length query and the call itself.
+  pub unsafe fn buffer<T>(&mut self, name: &str, data: *const T, len: *const CK_ULONG) {
+    match (data.is_null(), len.is_null()) {
+      (_, true) => {
+        self.line("in", name, &"NULL length");
+        self.input(name, Value::Null);
+      }
+      (true, false) => {
+        self.line("in", name, &"NULL");
+        self.input(name, Value::Null);
+      }
+      (false, false) => {
+        self.line("in", name, &format_args!("<buffer of {}>", *len));
+        self.input(name, Value::Ulong(*len));
+      }
+    }
+  }
+
+  /// PINs are only logged as given or not, the latter means the protected authentication path.
+  pub fn pin(&mut self, name: &str, pin: *const CK_UTF8CHAR) {
+    self.line("in", name, &if pin.is_null() { "NULL" } else { "<redacted>" });
+    self.input(name, if pin.is_null() { Value::Null } else { Value::Redacted });
+  }
+
+  pub unsafe fn label(&mut self, name: &str, label: *const CK_UTF8CHAR) {
+    if label.is_null() {
+      self.line("in", name, &"NULL");
+      self.input(name, Value::Null);
+    } else {
+      let label = slice::from_raw_parts(label, 32);
+      self.line("in", name, &blank_padded(label));
+      self.input(name, Value::Bytes(label.to_vec()));
+    }
+  }
+
+  pub unsafe fn mechanism(&mut self, mechanism: *const CK_MECHANISM) {
+    if mechanism.is_null() {
+      self.line("in", "pMechanism", &"NULL");
+      return self.input("pMechanism", Value::Null);
+    }
+    let m = &*mechanism;
+    let parameter = if m.pParameter.is_null() {
+      &[][..]
+    } else {
+      slice::from_raw_parts(m.pParameter as *const u8, m.ulParameterLen as usize)
+    };
+    let shown = if parameter.is_empty() { String::new() } else { format!(", parameter [{}] {}", parameter.len(), hex(parameter)) };
+    self.line("in", "pMechanism", &format_args!("{}{}", names::name(names::MECHANISMS, m.mechanism), shown));
+    self.input("pMechanism", Value::Mechanism(m.mechanism, parameter.to_vec()));
+  }
+
+  unsafe fn attributes(&mut self, direction: &str, name: &str, attrs: *const CK_ATTRIBUTE, count: CK_ULONG, query: bool) -> Option<Vec<Attribute>> {
+    if attrs.is_null() {
+      self.line(direction, name, &format_args!("NULL [{}]", count));
+      return None;
+    }
+    self.line(direction, name, &format_args!("[{}]", count));
+    let attrs = slice::from_raw_parts(attrs, count as usize);
+    for attr in attrs {
+      let _ = writeln!(self.text, "      {} = {}", names::name(names::ATTRIBUTES, attr.attrType), attribute_value(attr, query));
+    }
+    Some(attrs.iter().map(|a| trace_attribute(a, !query)).collect())
+  }
+
+  pub unsafe fn template(&mut self, name: &str, attrs: *const CK_ATTRIBUTE, count: CK_ULONG) {
+    let value = self.attributes("in", name, attrs, count, false).map_or(Value::Null, Value::Template);
+    self.input(name, value);
+  }
+
+  /// The template of `C_GetAttributeValue` before the call, only the buffers count.
+  pub unsafe fn query(&mut self, name: &str, attrs: *const CK_ATTRIBUTE, count: CK_ULONG) {
+    let value = self.attributes("in", name, attrs, count, true).map_or(Value::Null, Value::Template);
+    self.input(name, value);
+  }
+
+  /// The template of `C_GetAttributeValue`, which is also filled in on some errors.
+  pub unsafe fn template_out(&mut self, rv: CK_RV, name: &str, attrs: *mut CK_ATTRIBUTE, count: CK_ULONG) {
+    if attrs.is_null() {
+      return;
+    }
+    if let Some(Value::Template(recorded)) = self.replayed(name) {
+      for (attr, recorded) in slice::from_raw_parts_mut(attrs, count as usize).iter_mut().zip(recorded) {
+        match (attr.pValue.is_null(), recorded.value) {
+          (false, Some(_)) if recorded.secret && recorded.len <= attr.ulValueLen => {
+            ptr::write_bytes(attr.pValue as *mut u8, 0, recorded.len as usize);
+          }
+          (false, Some(ref value)) if !recorded.secret && value.len() <= attr.ulValueLen as usize => {
+            ptr::copy_nonoverlapping(value.as_ptr(), attr.pValue as *mut u8, value.len());
+          }
+          _ => {}
+        }
+        attr.ulValueLen = recorded.len;
+      }
+    }
+    if let CKR_OK | CKR_ATTRIBUTE_SENSITIVE | CKR_ATTRIBUTE_TYPE_INVALID | CKR_BUFFER_TOO_SMALL = rv {
+      if let Some(attrs) = self.attributes("out", name, attrs, count, false) {
+        self.output_value(name, Value::Template(attrs));
+      }
+    }
+  }
+
+  /// Writes a recorded buffer with the two-call convention.
+  unsafe fn replay_buffer(&self, name: &str, data: *mut CK_BYTE, len: *mut CK_ULONG) {
+    match self.replayed(name) {
+      Some(Value::Bytes(value)) => {
+        if !data.is_null() && value.len() <= *len as usize {
+          ptr::copy_nonoverlapping(value.as_ptr(), data, value.len());
+        }
+        *len = value.len() as CK_ULONG;
+      }
+      // the secret itself was never recorded
+      Some(Value::Secret(value)) => {
+        if !data.is_null() && value <= *len {
+          ptr::write_bytes(data, 0, value as usize);
+        }
+        *len = value;
+      }
+      Some(Value::Ulong(value)) => *len = value,
+      _ => {}
+    }
+  }
+
+  /// An output buffer with the two-call convention.
+  pub unsafe fn output(&mut self, rv: CK_RV, name: &str, data: *mut CK_BYTE, len: *mut CK_ULONG) {
+    if len.is_null() || (rv != CKR_OK && rv != CKR_BUFFER_TOO_SMALL) {
+      return;
+    }
+    self.replay_buffer(name, data, len);
+    match (rv, data.is_null()) {
+      (CKR_OK, false) => {
+        let data = slice::from_raw_parts(data, *len as usize);
+        self.line("out", name, &format_args!("[{}] {}", data.len(), hex(data)));
+        self.output_value(name, Value::Bytes(data.to_vec()));
+      }
+      _ => {
+        self.line("out", name, &format_args!("[{}]", *len));
+        self.output_value(name, Value::Ulong(*len));
+      }
+    }
+  }
+
+  /// An output buffer that might hold key material or plaintext, only its
+  /// length is logged and recorded.
+  pub unsafe fn secret_output(&mut self, rv: CK_RV, name: &str, data: *mut CK_BYTE, len: *mut CK_ULONG) {
+    if len.is_null() || (rv != CKR_OK && rv != CKR_BUFFER_TOO_SMALL) {
+      return;
+    }
+    self.replay_buffer(name, data, len);
+    self.line("out", name, &format_args!("[{}] <redacted>", *len));
+    match (rv, data.is_null()) {
+      (CKR_OK, false) => self.output_value(name, Value::Secret(*len)),
+      _ => self.output_value(name, Value::Ulong(*len)),
+    }
+  }
+
+  unsafe fn ulong_output(&mut self, rv: CK_RV, name: &str, value: *mut CK_ULONG) -> bool {
+    if rv != CKR_OK || value.is_null() {
+      return false;
+    }
+    if let Some(Value::Ulong(recorded)) = self.replayed(name) {
+      *value = recorded;
+    }
+    self.output_value(name, Value::Ulong(*value));
+    true
+  }
+
+  pub unsafe fn handle_out(&mut self, rv: CK_RV, name: &str, value: *mut CK_ULONG) {
+    if self.ulong_output(rv, name, value) {
+      self.line("out", name, &format_args!("{:#x}", *value));
+    }
+  }
+
+  pub unsafe fn ulong_out(&mut self, rv: CK_RV, name: &str, value: *mut CK_ULONG) {
+    if self.ulong_output(rv, name, value) {
+      self.line("out", name, &*value);
+    }
+  }
+
+  /// A list with the two-call convention, with names from `table` if there is one.
+  pub unsafe fn list_out(&mut self, rv: CK_RV, name: &str, items: *mut CK_ULONG, count: *mut CK_ULONG, table: Option<&[(CK_ULONG, &str)]>) {
+    if count.is_null() || (rv != CKR_OK && rv != CKR_BUFFER_TOO_SMALL) {
+      return;
+    }
+    match self.replayed(name) {
+      // the inputs matched the recording, so the list fits like it did then
+      Some(Value::List(recorded)) => {
+        if !items.is_null() {
+          ptr::copy_nonoverlapping(recorded.as_ptr(), items, recorded.len());
+        }
+        *count = recorded.len() as CK_ULONG;
+      }
+      Some(Value::Ulong(recorded)) => *count = recorded,
+      _ => {}
+    }
+    if rv != CKR_OK || items.is_null() {
+      self.line("out", name, &format_args!("[{}]", *count));
+      return self.output_value(name, Value::Ulong(*count));
+    }
+    let items = slice::from_raw_parts(items, *count as usize);
+    let shown: Vec<String> = items
+      .iter()
+      .map(|&i| match table {
+        Some(table) => names::name(table, i),
+        None => format!("{:#x}", i),
+      })
+      .collect();
+    self.line("out", name, &format_args!("[{}] {}", shown.len(), shown.join(", ")));
+    self.output_value(name, Value::List(items.to_vec()));
+  }
+
+  /// Info structures go into the trace in their memory layout.
+  unsafe fn struct_output<T>(&mut self, rv: CK_RV, info: *mut T) -> bool {
+    if rv != CKR_OK || info.is_null() {
+      return false;
+    }
+    if let Some(Value::Bytes(recorded)) = self.replayed("pInfo") {
+      if recorded.len() == mem::size_of::<T>() {
+        ptr::copy_nonoverlapping(recorded.as_ptr(), info as *mut u8, recorded.len());
+      }
+    }
+    let bytes = slice::from_raw_parts(info as *const u8, mem::size_of::<T>());
+    self.output_value("pInfo", Value::Bytes(bytes.to_vec()));
+    true
+  }
+
+  pub unsafe fn info_out(&mut self, rv: CK_RV, info: *mut CK_INFO) {
+    if self.struct_output(rv, info) {
+      let i = &*info;
+      let value = format!(
+        "cryptoki {}, manufacturer {}, library {} {}",
+        version(i.cryptokiVersion),
+        blank_padded(&i.manufacturerID),
+        blank_padded(&i.libraryDescription),
+        version(i.libraryVersion)
+      );
+      self.line("out", "pInfo", &value);
+    }
+  }
+
+  pub unsafe fn slot_info_out(&mut self, rv: CK_RV, info: *mut CK_SLOT_INFO) {
+    if self.struct_output(rv, info) {
+      let i = &*info;
+      let value = format!("{}, manufacturer {}, flags {:#x}", blank_padded(&i.slotDescription), blank_padded(&i.manufacturerID), i.flags);
+      self.line("out", "pInfo", &value);
+    }
+  }
+
+  pub unsafe fn token_info_out(&mut self, rv: CK_RV, info: *mut CK_TOKEN_INFO) {
+    if self.struct_output(rv, info) {
+      let i = &*info;
+      let value = format!(
+        "label {}, manufacturer {}, model {}, serial {}, flags {:#x}, pin length {}..{}",
+        blank_padded(&i.label),
+        blank_padded(&i.manufacturerID),
+        blank_padded(&i.model),
+        blank_padded(&i.serialNumber),
+        i.flags,
+        i.ulMinPinLen,
+        i.ulMaxPinLen
+      );
+      self.line("out", "pInfo", &value);
+    }
+  }
+
+  pub unsafe fn mechanism_info_out(&mut self, rv: CK_RV, info: *mut CK_MECHANISM_INFO) {
+    if self.struct_output(rv, info) {
+      let i = &*info;
+      let value = format!("key size {}..{}, flags {:#x}", i.ulMinKeySize, i.ulMaxKeySize, i.flags);
+      self.line("out", "pInfo", &value);
+    }
+  }
+
+  pub unsafe fn session_info_out(&mut self, rv: CK_RV, info: *mut CK_SESSION_INFO) {
+    if self.struct_output(rv, info) {
+      let i = &*info;
+      let value = format!("slot {:#x}, state {}, flags {:#x}, device error {:#x}", i.slotID, i.state, i.flags, i.ulDeviceError);
+      self.line("out", "pInfo", &value);
+    }
+  }
+
+  pub fn returned(&mut self, rv: CK_RV, elapsed: Duration) {
+    self.call.rv = rv;
+    let _ = writeln!(self.text, "  returned {} ({:#x}) after {:?}", strerror(rv), rv, elapsed);
+  }
+}
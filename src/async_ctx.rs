@@ -0,0 +1,445 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `Ctx` calls as futures, for async code (feature `async`).
+//!
+//! Every `Ctx` method blocks until the module answers, which stalls an async
+//! runtime for as long as a slow `C_Sign` or `C_GenerateKeyPair` takes.
+//! [`AsyncCtx`] runs the calls on threads of its own instead and returns a
+//! [`Call`] future for the result, which works on any runtime:
+//!
+//! ```no_run,edition2018
+//! # use pkcs11::Ctx;
+//! # use pkcs11::async_ctx::AsyncCtx;
+//! # use pkcs11::provider::Mechanism;
+//! # use pkcs11::types::*;
+//! # async fn sign(key: CK_OBJECT_HANDLE) -> Result<(), pkcs11::errors::Error> {
+//! let ctx = AsyncCtx::new(Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so")?, 4)?;
+//! let session = ctx.open_session(0, CKF_SERIAL_SESSION).await?;
+//! session.login(CKU_USER, "1234").await?;
+//! let mechanism = Mechanism { mechanism: CKM_ECDSA, parameter: Vec::new() };
+//! let signature = session.sign(&mechanism, key, &[0; 32]).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The calls of one [`AsyncSession`] run one after the other in the order
+//! they were made, never on two threads at once, while the calls of
+//! different sessions run side by side on up to as many threads as the pool
+//! has. A call is queued when the method is called, not when the future is
+//! first polled; dropping the future does not cancel it.
+//!
+//! Templates and mechanisms are passed as the owned [`Attribute`] and
+//! [`Mechanism`] of the `provider` module, so mechanism parameters must not
+//! contain pointers.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use super::Ctx;
+use errors::Error;
+use provider::{Attribute, Mechanism};
+use secret::SecretPin;
+use types::*;
+
+type Job = Box<dyn FnOnce(&Ctx) + Send>;
+
+/// The queued calls of one session.
+#[derive(Default)]
+struct Lane {
+  jobs: VecDeque<Job>,
+  // whether the lane is queued or a thread is running one of its jobs
+  scheduled: bool,
+}
+
+enum Task {
+  Job(Job),
+  Lane(Arc<Mutex<Lane>>),
+}
+
+struct Queue {
+  tasks: VecDeque<Task>,
+  closed: bool,
+}
+
+struct Shared {
+  ctx: Arc<Ctx>,
+  queue: Mutex<Queue>,
+  ready: Condvar,
+}
+
+// jobs run outside of the locks and cannot poison them
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Shared {
+  fn push(&self, task: Task) {
+    lock(&self.queue).tasks.push_back(task);
+    self.ready.notify_one();
+  }
+
+  fn run(&self, job: Job) {
+    // the sender of a panicking job is dropped, which fails its call
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| job(&self.ctx)));
+  }
+
+  /// The loop of a pool thread, until the pool is closed and empty.
+  fn work(&self) {
+    loop {
+      let task = {
+        let mut queue = lock(&self.queue);
+        loop {
+          if let Some(task) = queue.tasks.pop_front() {
+            break task;
+          }
+          if queue.closed {
+            return;
+          }
+          queue = self.ready.wait(queue).unwrap_or_else(|e| e.into_inner());
+        }
+      };
+      match task {
+        Task::Job(job) => self.run(job),
+        Task::Lane(lane) => {
+          let job = lock(&lane).jobs.pop_front();
+          if let Some(job) = job {
+            self.run(job);
+          }
+          let mut jobs = lock(&lane);
+          if jobs.jobs.is_empty() {
+            jobs.scheduled = false;
+          } else {
+            // to the back of the queue, so that busy sessions take turns
+            drop(jobs);
+            self.push(Task::Lane(lane));
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Stops the pool threads once the last `AsyncCtx` and `AsyncSession` are
+/// gone and the queued calls are done.
+struct Pool {
+  shared: Arc<Shared>,
+}
+
+impl Drop for Pool {
+  fn drop(&mut self) {
+    lock(&self.shared.queue).closed = true;
+    self.shared.ready.notify_all();
+  }
+}
+
+/// The result of a call running on the pool.
+#[must_use = "the call runs anyway, but its result is lost"]
+pub struct Call<T> {
+  rx: oneshot::Receiver<Result<T, Error>>,
+}
+
+impl<T> fmt::Debug for Call<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Call").finish_non_exhaustive()
+  }
+}
+
+impl<T> Future for Call<T> {
+  type Output = Result<T, Error>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, Error>> {
+    Pin::new(&mut self.rx).poll(cx).map(|res| res.unwrap_or(Err(Error::Module("the PKCS#11 call panicked"))))
+  }
+}
+
+fn call<T, F>(f: F) -> (Job, Call<T>)
+where
+  T: Send + 'static,
+  F: FnOnce(&Ctx) -> Result<T, Error> + Send + 'static,
+{
+  let (tx, rx) = oneshot::channel();
+  let job: Job = Box::new(move |ctx| {
+    let _ = tx.send(f(ctx));
+  });
+  (job, Call { rx })
+}
+
+fn template(attributes: &[Attribute]) -> Vec<CK_ATTRIBUTE> {
+  attributes.iter().map(|a| CK_ATTRIBUTE::new(a.attr_type).with_bytes(&a.value)).collect()
+}
+
+fn mechanism(mechanism: &Mechanism) -> CK_MECHANISM {
+  CK_MECHANISM {
+    mechanism: mechanism.mechanism,
+    pParameter: if mechanism.parameter.is_empty() { ptr::null_mut() } else { mechanism.parameter.as_ptr() as CK_VOID_PTR },
+    ulParameterLen: mechanism.parameter.len() as CK_ULONG,
+  }
+}
+
+/// A `Ctx` whose calls run on a pool of threads, see the
+/// [module documentation](self).
+#[derive(Clone)]
+pub struct AsyncCtx {
+  pool: Arc<Pool>,
+}
+
+impl fmt::Debug for AsyncCtx {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("AsyncCtx").field("ctx", &self.pool.shared.ctx).finish()
+  }
+}
+
+impl AsyncCtx {
+  /// Runs the calls on `ctx` on `threads` threads of its own.
+  pub fn new<C: Into<Arc<Ctx>>>(ctx: C, threads: usize) -> Result<AsyncCtx, Error> {
+    if threads == 0 {
+      return Err(Error::InvalidInput("the thread pool needs at least one thread"));
+    }
+    let shared = Arc::new(Shared {
+      ctx: ctx.into(),
+      queue: Mutex::new(Queue {
+        tasks: VecDeque::new(),
+        closed: false,
+      }),
+      ready: Condvar::new(),
+    });
+    let pool = Arc::new(Pool { shared: shared.clone() });
+    for i in 0..threads {
+      let shared = shared.clone();
+      thread::Builder::new().name(format!("pkcs11-{}", i)).spawn(move || shared.work())?;
+    }
+    Ok(AsyncCtx { pool })
+  }
+
+  /// The `Ctx` itself, for calls that may block.
+  pub fn ctx(&self) -> &Arc<Ctx> {
+    &self.pool.shared.ctx
+  }
+
+  /// Runs `f` on the pool.
+  pub fn call<T, F>(&self, f: F) -> Call<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Ctx) -> Result<T, Error> + Send + 'static,
+  {
+    let (job, call) = call(f);
+    self.pool.shared.push(Task::Job(job));
+    call
+  }
+
+  pub fn get_info(&self) -> Call<CK_INFO> {
+    self.call(|ctx| ctx.get_info())
+  }
+
+  pub fn get_slot_list(&self, token_present: bool) -> Call<Vec<CK_SLOT_ID>> {
+    self.call(move |ctx| ctx.get_slot_list(token_present))
+  }
+
+  pub fn get_slot_info(&self, slot_id: CK_SLOT_ID) -> Call<CK_SLOT_INFO> {
+    self.call(move |ctx| ctx.get_slot_info(slot_id))
+  }
+
+  pub fn get_token_info(&self, slot_id: CK_SLOT_ID) -> Call<CK_TOKEN_INFO> {
+    self.call(move |ctx| ctx.get_token_info(slot_id))
+  }
+
+  pub fn get_mechanism_list(&self, slot_id: CK_SLOT_ID) -> Call<Vec<CK_MECHANISM_TYPE>> {
+    self.call(move |ctx| ctx.get_mechanism_list(slot_id))
+  }
+
+  pub fn get_mechanism_info(&self, slot_id: CK_SLOT_ID, mechanism_type: CK_MECHANISM_TYPE) -> Call<CK_MECHANISM_INFO> {
+    self.call(move |ctx| ctx.get_mechanism_info(slot_id, mechanism_type))
+  }
+
+  /// Opens a session whose calls run one at a time; it is closed when
+  /// dropped.
+  pub fn open_session(&self, slot_id: CK_SLOT_ID, flags: CK_FLAGS) -> Call<AsyncSession> {
+    let pool = self.pool.clone();
+    self.call(move |ctx| {
+      let session = ctx.open_session(slot_id, flags, None, None)?;
+      Ok(AsyncSession {
+        pool,
+        session,
+        lane: Arc::default(),
+        closed: false,
+      })
+    })
+  }
+}
+
+/// A session of an [`AsyncCtx`], whose calls run in order and one at a
+/// time.
+pub struct AsyncSession {
+  pool: Arc<Pool>,
+  session: CK_SESSION_HANDLE,
+  lane: Arc<Mutex<Lane>>,
+  closed: bool,
+}
+
+impl fmt::Debug for AsyncSession {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("AsyncSession").field("session", &self.session).finish_non_exhaustive()
+  }
+}
+
+impl AsyncSession {
+  pub fn handle(&self) -> CK_SESSION_HANDLE {
+    self.session
+  }
+
+  /// Runs `f` with the session, after the calls made on it before.
+  pub fn call<T, F>(&self, f: F) -> Call<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Ctx, CK_SESSION_HANDLE) -> Result<T, Error> + Send + 'static,
+  {
+    let session = self.session;
+    let (job, call) = call(move |ctx| f(ctx, session));
+    let mut lane = lock(&self.lane);
+    lane.jobs.push_back(job);
+    if !lane.scheduled {
+      lane.scheduled = true;
+      self.pool.shared.push(Task::Lane(self.lane.clone()));
+    }
+    call
+  }
+
+  pub fn get_session_info(&self) -> Call<CK_SESSION_INFO> {
+    self.call(|ctx, session| ctx.get_session_info(session))
+  }
+
+  pub fn login(&self, user_type: CK_USER_TYPE, pin: &str) -> Call<()> {
+    let pin = SecretPin::from(pin);
+    self.call(move |ctx, session| ctx.login_secret(session, user_type, Some(&pin)))
+  }
+
+  pub fn logout(&self) -> Call<()> {
+    self.call(|ctx, session| ctx.logout(session))
+  }
+
+  pub fn create_object(&self, attributes: &[Attribute]) -> Call<CK_OBJECT_HANDLE> {
+    let attributes = attributes.to_vec();
+    self.call(move |ctx, session| ctx.create_object(session, &template(&attributes)))
+  }
+
+  pub fn destroy_object(&self, object: CK_OBJECT_HANDLE) -> Call<()> {
+    self.call(move |ctx, session| ctx.destroy_object(session, object))
+  }
+
+  pub fn get_attribute_bytes(&self, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Call<Vec<CK_BYTE>> {
+    self.call(move |ctx, session| ctx.get_attribute_bytes(session, object, attr_type))
+  }
+
+  pub fn get_attribute_ulong(&self, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Call<CK_ULONG> {
+    self.call(move |ctx, session| ctx.get_attribute_ulong(session, object, attr_type))
+  }
+
+  /// All objects matching `attributes`, as one call.
+  pub fn find_objects(&self, attributes: &[Attribute]) -> Call<Vec<CK_OBJECT_HANDLE>> {
+    let attributes = attributes.to_vec();
+    self.call(move |ctx, session| {
+      ctx.find_objects_init(session, &template(&attributes))?;
+      let mut objects = Vec::new();
+      let res = loop {
+        match ctx.find_objects(session, 64) {
+          Ok(ref found) if found.is_empty() => break Ok(objects),
+          Ok(found) => objects.extend(found),
+          Err(err) => break Err(err),
+        }
+      };
+      ctx.find_objects_final(session)?;
+      res
+    })
+  }
+
+  pub fn generate_key(&self, mech: &Mechanism, attributes: &[Attribute]) -> Call<CK_OBJECT_HANDLE> {
+    let (mech, attributes) = (mech.clone(), attributes.to_vec());
+    self.call(move |ctx, session| ctx.generate_key(session, &mechanism(&mech), &template(&attributes)))
+  }
+
+  pub fn generate_key_pair(&self, mech: &Mechanism, public: &[Attribute], private: &[Attribute]) -> Call<(CK_OBJECT_HANDLE, CK_OBJECT_HANDLE)> {
+    let (mech, public, private) = (mech.clone(), public.to_vec(), private.to_vec());
+    self.call(move |ctx, session| ctx.generate_key_pair(session, &mechanism(&mech), &template(&public), &template(&private)))
+  }
+
+  /// Initializes and finishes a signature as one call.
+  pub fn sign(&self, mech: &Mechanism, key: CK_OBJECT_HANDLE, data: &[CK_BYTE]) -> Call<Vec<CK_BYTE>> {
+    let (mech, data) = (mech.clone(), data.to_vec());
+    self.call(move |ctx, session| {
+      ctx.sign_init(session, &mechanism(&mech), key)?;
+      ctx.sign(session, &data)
+    })
+  }
+
+  pub fn verify(&self, mech: &Mechanism, key: CK_OBJECT_HANDLE, data: &[CK_BYTE], signature: &[CK_BYTE]) -> Call<()> {
+    let (mech, data, signature) = (mech.clone(), data.to_vec(), signature.to_vec());
+    self.call(move |ctx, session| {
+      ctx.verify_init(session, &mechanism(&mech), key)?;
+      ctx.verify(session, &data, &signature)
+    })
+  }
+
+  pub fn encrypt(&self, mech: &Mechanism, key: CK_OBJECT_HANDLE, data: &[CK_BYTE]) -> Call<Vec<CK_BYTE>> {
+    let (mech, data) = (mech.clone(), data.to_vec());
+    self.call(move |ctx, session| {
+      ctx.encrypt_init(session, &mechanism(&mech), key)?;
+      ctx.encrypt(session, &data)
+    })
+  }
+
+  pub fn decrypt(&self, mech: &Mechanism, key: CK_OBJECT_HANDLE, data: &[CK_BYTE]) -> Call<Vec<CK_BYTE>> {
+    let (mech, data) = (mech.clone(), data.to_vec());
+    self.call(move |ctx, session| {
+      ctx.decrypt_init(session, &mechanism(&mech), key)?;
+      ctx.decrypt(session, &data)
+    })
+  }
+
+  pub fn digest(&self, mech: &Mechanism, data: &[CK_BYTE]) -> Call<Vec<CK_BYTE>> {
+    let (mech, data) = (mech.clone(), data.to_vec());
+    self.call(move |ctx, session| {
+      ctx.digest_init(session, &mechanism(&mech))?;
+      ctx.digest(session, &data)
+    })
+  }
+
+  pub fn generate_random(&self, len: CK_ULONG) -> Call<Vec<CK_BYTE>> {
+    self.call(move |ctx, session| ctx.generate_random(session, len))
+  }
+
+  /// Closes the session after the calls made on it before.
+  pub fn close(mut self) -> Call<()> {
+    self.closed = true;
+    self.call(|ctx, session| ctx.close_session(session))
+  }
+}
+
+impl Drop for AsyncSession {
+  fn drop(&mut self) {
+    if !self.closed {
+      // the call runs without anyone waiting for it
+      drop(self.call(|ctx, session| ctx.close_session(session)));
+    }
+  }
+}
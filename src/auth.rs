@@ -0,0 +1,88 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signing and decrypting with keys that want the PIN for every operation.
+//!
+//! A key with `CKA_ALWAYS_AUTHENTICATE` needs a `C_Login` as
+//! `CKU_CONTEXT_SPECIFIC` after each `C_SignInit` or `C_DecryptInit`, or the
+//! operation fails with `CKR_USER_NOT_LOGGED_IN`. [`sign`] and [`decrypt`]
+//! look at the key and log in with the PIN from a [`PinSource`] only when it
+//! is needed:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::auth;
+//! # use pkcs11::pin::EnvPin;
+//! # use pkcs11::types::*;
+//! # use std::ptr;
+//! # let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! # let (session, key) = (1, 2);
+//! let mechanism = CK_MECHANISM { mechanism: CKM_SHA256_RSA_PKCS, pParameter: ptr::null_mut(), ulParameterLen: 0 };
+//! let signature = auth::sign(&ctx, session, &mechanism, key, b"data", &EnvPin::new("SIGNING_PIN")).unwrap();
+//! ```
+//!
+//! The session must be logged in as `CKU_USER` already. Binary PINs, which
+//! `Ctx::login_with_raw` takes, work as a `SecretPin::from_bytes`. If the
+//! context specific login fails, the operation is still active, so the
+//! session is best closed.
+
+use super::Ctx;
+use errors::Error;
+use pin::{self, PinSource};
+use secret::{SecretBytes, SecretPin};
+use types::*;
+
+/// Whether `key` has `CKA_ALWAYS_AUTHENTICATE` set. Modules that do not know
+/// the attribute have no such keys.
+pub fn always_authenticate(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<bool, Error> {
+  match ctx.get_attribute_bytes(session, key, CKA_ALWAYS_AUTHENTICATE) {
+    Ok(value) => Ok(value.iter().any(|&b| b != 0)),
+    Err(Error::Pkcs11(CKR_ATTRIBUTE_TYPE_INVALID)) => Ok(false),
+    Err(err) => Err(err),
+  }
+}
+
+/// Signs `data` with `key`, logging in for the key if it needs it.
+pub fn sign(ctx: &Ctx, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE, data: &[CK_BYTE], source: &dyn PinSource) -> Result<Vec<CK_BYTE>, Error> {
+  let pin = pin_for(ctx, session, key, source)?;
+  ctx.sign_init(session, mechanism, key)?;
+  login(ctx, session, pin)?;
+  ctx.sign(session, data)
+}
+
+/// Decrypts `data` with `key`, logging in for the key if it needs it.
+pub fn decrypt(ctx: &Ctx, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE, data: &[CK_BYTE], source: &dyn PinSource) -> Result<SecretBytes, Error> {
+  let pin = pin_for(ctx, session, key, source)?;
+  ctx.decrypt_init(session, mechanism, key)?;
+  login(ctx, session, pin)?;
+  ctx.decrypt_secret(session, data)
+}
+
+/// The PIN for `key` if it needs one, `Some(None)` on the protected
+/// authentication path. Everything is read and asked before the init, as
+/// some modules refuse other calls during an operation.
+fn pin_for(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, source: &dyn PinSource) -> Result<Option<Option<SecretPin>>, Error> {
+  if !always_authenticate(ctx, session, key)? {
+    return Ok(None);
+  }
+  let (pin, _) = pin::resolve(ctx, session, CKU_CONTEXT_SPECIFIC, source)?;
+  Ok(Some(pin))
+}
+
+fn login(ctx: &Ctx, session: CK_SESSION_HANDLE, pin: Option<Option<SecretPin>>) -> Result<(), Error> {
+  match pin {
+    Some(pin) => ctx.login_secret(session, CKU_CONTEXT_SPECIFIC, pin.as_ref()),
+    None => Ok(()),
+  }
+}
@@ -0,0 +1,513 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::ptr;
+
+use pkcs11::der;
+use pkcs11::errors::Error;
+use pkcs11::info::{MechanismInfo, SlotInfo, TokenInfo};
+use pkcs11::keygen::{AesKey, Curve, EcKeyPair, RsaKeyPair};
+use pkcs11::names::{self, name, MechanismType, ObjectClass};
+use pkcs11::types::*;
+use pkcs11::x509::{Certificate, SubjectPublicKeyInfo};
+use pkcs11::Ctx;
+
+use output::Json;
+use {failed, hex, parse_hex, parse_ulong, session, slot, usage, Args, Result};
+
+const SELECT: &[&str] = &["--label", "--id", "--handle", "--class"];
+
+pub fn slots(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&[])?;
+  let mut slots = Vec::new();
+  for slot in ctx.get_slot_list(false)? {
+    let info = SlotInfo::from(ctx.get_slot_info(slot)?);
+    slots.push(
+      Json::object()
+        .with("slot", slot as u64)
+        .with("description", info.description)
+        .with("manufacturer", info.manufacturer)
+        .with("flags", info.flags)
+        .with("hardware_version", info.hardware_version.to_string())
+        .with("firmware_version", info.firmware_version.to_string()),
+    );
+  }
+  Ok(Json::Array(slots))
+}
+
+fn token_json(slot: CK_SLOT_ID, info: CK_TOKEN_INFO) -> Json {
+  let info = TokenInfo::from(info);
+  Json::object()
+    .with("slot", slot as u64)
+    .with("label", info.label)
+    .with("manufacturer", info.manufacturer)
+    .with("model", info.model)
+    .with("serial", info.serial_number)
+    .with("flags", info.flags)
+    .with("pin_min_length", info.min_pin_len as u64)
+    .with("pin_max_length", info.max_pin_len as u64)
+    .with("hardware_version", info.hardware_version.to_string())
+    .with("firmware_version", info.firmware_version.to_string())
+}
+
+pub fn tokens(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&[])?;
+  let mut tokens = Vec::new();
+  for slot in ctx.get_slot_list(true)? {
+    tokens.push(token_json(slot, ctx.get_token_info(slot)?));
+  }
+  Ok(Json::Array(tokens))
+}
+
+pub fn mechanisms(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&[])?;
+  let slot = slot(ctx, args)?;
+  let mut mechanisms = Vec::new();
+  for mechanism in ctx.get_mechanism_list(slot)? {
+    let info = MechanismInfo::from(ctx.get_mechanism_info(slot, mechanism)?);
+    mechanisms.push(
+      Json::object()
+        .with("mechanism", name(names::MECHANISMS, mechanism))
+        .with("min_key_size", info.min_key_size as u64)
+        .with("max_key_size", info.max_key_size as u64)
+        .with("flags", info.flags),
+    );
+  }
+  Ok(Json::Array(mechanisms))
+}
+
+pub fn init_token(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--so-pin"])?;
+  let slot = slot(ctx, args)?;
+  let label = args.required("--label")?;
+  ctx.init_token(slot, Some(args.required("--so-pin")?), label)?;
+  Ok(token_json(slot, ctx.get_token_info(slot)?))
+}
+
+pub fn init_pin(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--so-pin"])?;
+  let pin = match args.pin() {
+    Some(pin) => pin,
+    None => return usage("init-pin sets the PIN given with --pin"),
+  };
+  let sh = session(ctx, args, false)?;
+  ctx.login(sh, CKU_SO, Some(args.required("--so-pin")?))?;
+  ctx.init_pin(sh, Some(&pin))?;
+  ctx.logout(sh)?;
+  let slot = ctx.get_session_info(sh)?.slotID;
+  Ok(token_json(slot, ctx.get_token_info(slot)?))
+}
+
+/// The objects matching the selection options, restricted to `class` if
+/// there is no `--class`.
+fn find(ctx: &Ctx, sh: CK_SESSION_HANDLE, args: &Args, class: Option<CK_OBJECT_CLASS>) -> Result<Vec<CK_OBJECT_HANDLE>> {
+  if let Some(handle) = args.value("--handle") {
+    return Ok(vec![parse_ulong(handle)?]);
+  }
+  let class = match args.value("--class") {
+    Some(c) => match c.parse::<ObjectClass>() {
+      Ok(c) => Some(c.0),
+      Err(_) => return usage(&format!("unknown object class {}", c)),
+    },
+    None => class,
+  };
+  let id = args.value("--id").map(parse_hex).transpose()?;
+  let mut template = Vec::new();
+  if let Some(ref class) = class {
+    template.push(CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(class));
+  }
+  if let Some(label) = args.value("--label") {
+    template.push(CK_ATTRIBUTE::new(CKA_LABEL).with_string(label));
+  }
+  if let Some(ref id) = id {
+    template.push(CK_ATTRIBUTE::new(CKA_ID).with_bytes(id));
+  }
+  ctx.find_objects_init(sh, &template)?;
+  let mut objects = Vec::new();
+  let res = loop {
+    match ctx.find_objects(sh, 64) {
+      Ok(ref found) if found.is_empty() => break Ok(()),
+      Ok(found) => objects.extend(found),
+      Err(err) => break Err(err),
+    }
+  };
+  ctx.find_objects_final(sh)?;
+  res?;
+  Ok(objects)
+}
+
+fn selected(args: &Args) -> bool {
+  SELECT.iter().any(|o| args.value(o).is_some())
+}
+
+/// Exactly one object of the selection.
+fn find_one(ctx: &Ctx, sh: CK_SESSION_HANDLE, args: &Args, class: Option<CK_OBJECT_CLASS>) -> Result<CK_OBJECT_HANDLE> {
+  if !selected(args) {
+    return usage("select an object with --label, --id or --handle");
+  }
+  match find(ctx, sh, args, class)?.as_slice() {
+    [] => failed("no object matches"),
+    [object] => Ok(*object),
+    objects => failed(&format!("{} objects match, narrow the selection with --id, --class or --handle", objects.len())),
+  }
+}
+
+/// The key for an operation: one of the key pair class, else a secret key.
+fn find_key(ctx: &Ctx, sh: CK_SESSION_HANDLE, args: &Args, class: CK_OBJECT_CLASS) -> Result<CK_OBJECT_HANDLE> {
+  if args.value("--class").is_some() || args.value("--handle").is_some() || !find(ctx, sh, args, Some(class))?.is_empty() {
+    find_one(ctx, sh, args, Some(class))
+  } else {
+    find_one(ctx, sh, args, Some(CKO_SECRET_KEY))
+  }
+}
+
+fn ulong_attribute(ctx: &Ctx, sh: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Option<CK_ULONG> {
+  ctx.get_attribute_ulong(sh, object, attr_type).ok()
+}
+
+fn bytes_attribute(ctx: &Ctx, sh: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Option<Vec<u8>> {
+  ctx.get_attribute_bytes(sh, object, attr_type).ok()
+}
+
+fn object_json(ctx: &Ctx, sh: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE) -> Json {
+  let class = ulong_attribute(ctx, sh, object, CKA_CLASS);
+  let key_type = ulong_attribute(ctx, sh, object, CKA_KEY_TYPE);
+  let label = bytes_attribute(ctx, sh, object, CKA_LABEL);
+  let id = bytes_attribute(ctx, sh, object, CKA_ID);
+  Json::object()
+    .with("handle", object as u64)
+    .with("class", class.map(|c| name(names::OBJECT_CLASSES, c)))
+    .with("key_type", key_type.map(|k| name(names::KEY_TYPES, k)))
+    .with("label", label.map(|l| String::from_utf8_lossy(&l).into_owned()))
+    .with("id", id.map(|i| hex(&i)))
+}
+
+pub fn objects(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(SELECT)?;
+  let sh = session(ctx, args, true)?;
+  let objects = find(ctx, sh, args, None)?;
+  Ok(Json::Array(objects.into_iter().map(|o| object_json(ctx, sh, o)).collect()))
+}
+
+enum Kind {
+  Bool,
+  Number,
+  Name(&'static [(CK_ULONG, &'static str)]),
+  Text,
+  Bytes,
+}
+
+/// The attributes `dump` shows, if the object has them.
+const ATTRIBUTES: &[(CK_ATTRIBUTE_TYPE, Kind)] = &[
+  (CKA_CLASS, Kind::Name(names::OBJECT_CLASSES)),
+  (CKA_TOKEN, Kind::Bool),
+  (CKA_PRIVATE, Kind::Bool),
+  (CKA_MODIFIABLE, Kind::Bool),
+  (CKA_LABEL, Kind::Text),
+  (CKA_APPLICATION, Kind::Text),
+  (CKA_ID, Kind::Bytes),
+  (CKA_KEY_TYPE, Kind::Name(names::KEY_TYPES)),
+  (CKA_CERTIFICATE_TYPE, Kind::Name(names::CERTIFICATE_TYPES)),
+  (CKA_SUBJECT, Kind::Bytes),
+  (CKA_ISSUER, Kind::Bytes),
+  (CKA_SERIAL_NUMBER, Kind::Bytes),
+  (CKA_LOCAL, Kind::Bool),
+  (CKA_SENSITIVE, Kind::Bool),
+  (CKA_ALWAYS_SENSITIVE, Kind::Bool),
+  (CKA_EXTRACTABLE, Kind::Bool),
+  (CKA_NEVER_EXTRACTABLE, Kind::Bool),
+  (CKA_ENCRYPT, Kind::Bool),
+  (CKA_DECRYPT, Kind::Bool),
+  (CKA_SIGN, Kind::Bool),
+  (CKA_VERIFY, Kind::Bool),
+  (CKA_WRAP, Kind::Bool),
+  (CKA_UNWRAP, Kind::Bool),
+  (CKA_DERIVE, Kind::Bool),
+  (CKA_MODULUS_BITS, Kind::Number),
+  (CKA_MODULUS, Kind::Bytes),
+  (CKA_PUBLIC_EXPONENT, Kind::Bytes),
+  (CKA_EC_PARAMS, Kind::Bytes),
+  (CKA_EC_POINT, Kind::Bytes),
+  (CKA_VALUE_LEN, Kind::Number),
+  (CKA_VALUE, Kind::Bytes),
+];
+
+pub fn dump(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(SELECT)?;
+  let sh = session(ctx, args, true)?;
+  let object = find_one(ctx, sh, args, None)?;
+  let mut dump = Json::object().with("handle", object as u64);
+  for &(attr_type, ref kind) in ATTRIBUTES {
+    let attr_name = &name(names::ATTRIBUTES, attr_type);
+    let value = match ctx.get_attribute_bytes(sh, object, attr_type) {
+      Ok(value) => value,
+      Err(Error::Pkcs11(CKR_ATTRIBUTE_SENSITIVE)) => {
+        dump = dump.with(attr_name, "<sensitive>");
+        continue;
+      }
+      Err(_) => continue,
+    };
+    let number = || {
+      let mut bytes = [0; std::mem::size_of::<CK_ULONG>()];
+      let len = bytes.len().min(value.len());
+      bytes[..len].copy_from_slice(&value[..len]);
+      CK_ULONG::from_ne_bytes(bytes)
+    };
+    let json = match *kind {
+      Kind::Bool => Json::Bool(value.first().is_some_and(|&b| b != CK_FALSE)),
+      Kind::Number => Json::from(number() as u64),
+      Kind::Name(table) => Json::from(name(table, number())),
+      Kind::Text => Json::from(String::from_utf8_lossy(&value).into_owned()),
+      Kind::Bytes => Json::from(hex(&value)),
+    };
+    dump = dump.with(attr_name, json);
+  }
+  Ok(dump)
+}
+
+pub fn delete(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--class", "--all"])?;
+  let sh = session(ctx, args, true)?;
+  let objects = if args.flag("--all") {
+    if !selected(args) {
+      return usage("delete --all needs a selection too");
+    }
+    find(ctx, sh, args, None)?
+  } else {
+    vec![find_one(ctx, sh, args, None)?]
+  };
+  for &object in &objects {
+    ctx.destroy_object(sh, object)?;
+  }
+  Ok(Json::object().with("deleted", objects.into_iter().map(|o| o as u64).collect::<Vec<u64>>()))
+}
+
+fn curve(name: &str) -> Result<Curve> {
+  match name.to_lowercase().as_str() {
+    "p256" | "p-256" | "secp256r1" | "prime256v1" => Ok(Curve::P256),
+    "p384" | "p-384" | "secp384r1" => Ok(Curve::P384),
+    "p521" | "p-521" | "secp521r1" => Ok(Curve::P521),
+    _ => usage(&format!("unknown curve {}, use p256, p384 or p521", name)),
+  }
+}
+
+fn mechanism(mechanism: CK_MECHANISM_TYPE) -> CK_MECHANISM {
+  CK_MECHANISM {
+    mechanism,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  }
+}
+
+enum KeySpec {
+  Rsa(CK_ULONG),
+  Ec(Curve),
+  Aes(CK_ULONG),
+}
+
+/// Parses `rsa:BITS`, `ec:CURVE` or `aes:BITS`, the size being optional.
+fn key_spec(spec: &str) -> Result<KeySpec> {
+  let (kind, size) = spec.split_once(':').unwrap_or((spec, ""));
+  match kind {
+    "rsa" => Ok(KeySpec::Rsa(if size.is_empty() { 2048 } else { parse_ulong(size)? })),
+    "ec" => Ok(KeySpec::Ec(curve(if size.is_empty() { "p256" } else { size })?)),
+    "aes" => match if size.is_empty() { 256 } else { parse_ulong(size)? } {
+      bits @ (128 | 192 | 256) => Ok(KeySpec::Aes(bits)),
+      _ => usage("AES keys have 128, 192 or 256 bits"),
+    },
+    _ => usage(&format!("unknown key spec {}, use rsa:BITS, ec:CURVE or aes:BITS", spec)),
+  }
+}
+
+pub fn generate(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id"])?;
+  let spec = match args.operands() {
+    [spec] => key_spec(spec)?,
+    _ => return usage("generate takes one key spec such as rsa:2048, ec:p256 or aes:256"),
+  };
+  let label = args.required("--label")?;
+  let id = args.value("--id").map(parse_hex).transpose()?;
+  let sh = session(ctx, args, true)?;
+  let id = match id {
+    Some(id) => id,
+    None => ctx.generate_random(sh, 8)?,
+  };
+  let result = Json::object().with("label", label).with("id", hex(&id));
+  let pair = match spec {
+    KeySpec::Rsa(bits) => RsaKeyPair::new(bits).label(label).id(&id).decrypt(true).generate(ctx, sh)?,
+    KeySpec::Ec(curve) => EcKeyPair::new(curve).label(label).id(&id).generate(ctx, sh)?,
+    KeySpec::Aes(bits) => {
+      let key = AesKey::new(bits).label(label).id(&id).generate(ctx, sh)?;
+      return Ok(result.with("secret_key", key.handle() as u64));
+    }
+  };
+  Ok(result.with("public_key", pair.public.handle() as u64).with("private_key", pair.private.handle() as u64))
+}
+
+/// The `--mechanism`, or the default for the key type.
+fn operation_mechanism(ctx: &Ctx, sh: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, args: &Args, sign: bool) -> Result<CK_MECHANISM_TYPE> {
+  if let Some(m) = args.value("--mechanism") {
+    return match m.parse::<MechanismType>() {
+      Ok(m) => Ok(m.0),
+      Err(_) => usage(&format!("unknown mechanism {}", m)),
+    };
+  }
+  match (ctx.get_attribute_ulong(sh, key, CKA_KEY_TYPE)?, sign) {
+    (CKK_RSA, true) => Ok(CKM_SHA256_RSA_PKCS),
+    (CKK_RSA, false) => Ok(CKM_RSA_PKCS),
+    (CKK_EC, true) => Ok(CKM_ECDSA_SHA256),
+    (CKK_AES, false) => Ok(CKM_AES_CBC_PAD),
+    (CKK_GENERIC_SECRET, true) => Ok(CKM_SHA256_HMAC),
+    _ => usage("no default mechanism for this key, use --mechanism"),
+  }
+}
+
+/// Writes to `--out`, or returns the output in hex.
+fn output(args: &Args, field: &str, data: &[u8]) -> Result<Json> {
+  match args.value("--out") {
+    Some(path) => {
+      fs::write(path, data)?;
+      Ok(Json::object().with("written", path).with("bytes", data.len()))
+    }
+    None => Ok(Json::object().with(field, hex(data))),
+  }
+}
+
+pub fn sign(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--class", "--in", "--out", "--mechanism"])?;
+  let data = fs::read(args.required("--in")?)?;
+  let sh = session(ctx, args, true)?;
+  let key = find_key(ctx, sh, args, CKO_PRIVATE_KEY)?;
+  let m = operation_mechanism(ctx, sh, key, args, true)?;
+  ctx.sign_init(sh, &mechanism(m), key)?;
+  let signature = ctx.sign(sh, &data)?;
+  output(args, "signature", &signature)
+}
+
+pub fn verify(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--class", "--in", "--signature", "--mechanism"])?;
+  let data = fs::read(args.required("--in")?)?;
+  let signature = fs::read(args.required("--signature")?)?;
+  let sh = session(ctx, args, true)?;
+  let key = find_key(ctx, sh, args, CKO_PUBLIC_KEY)?;
+  let m = operation_mechanism(ctx, sh, key, args, true)?;
+  ctx.verify_init(sh, &mechanism(m), key)?;
+  match ctx.verify(sh, &data, &signature) {
+    Ok(()) => Ok(Json::object().with("valid", true)),
+    Err(Error::Pkcs11(CKR_SIGNATURE_INVALID)) | Err(Error::Pkcs11(CKR_SIGNATURE_LEN_RANGE)) => failed("the signature is invalid"),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Encrypts or decrypts. CBC modes without `--iv` use a random IV, which
+/// goes in front of the ciphertext.
+pub fn crypt(ctx: &Ctx, args: &Args, encrypt: bool) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--class", "--in", "--out", "--mechanism", "--iv"])?;
+  let mut data = fs::read(args.required("--in")?)?;
+  let sh = session(ctx, args, true)?;
+  let key = find_key(ctx, sh, args, if encrypt { CKO_PUBLIC_KEY } else { CKO_PRIVATE_KEY })?;
+  let m = operation_mechanism(ctx, sh, key, args, false)?;
+  let mut prefix = Vec::new();
+  let mut iv = match args.value("--iv") {
+    Some(iv) => Some(parse_hex(iv)?),
+    None => None,
+  };
+  if iv.is_none() && (m == CKM_AES_CBC || m == CKM_AES_CBC_PAD) {
+    if encrypt {
+      prefix = ctx.generate_random(sh, 16)?;
+      iv = Some(prefix.clone());
+    } else if data.len() >= 16 {
+      iv = Some(data.drain(..16).collect());
+    } else {
+      return failed("the input is too short to start with an IV");
+    }
+  }
+  let mut params = mechanism(m);
+  if let Some(ref mut iv) = iv {
+    params.pParameter = iv.as_mut_ptr() as CK_VOID_PTR;
+    params.ulParameterLen = iv.len() as CK_ULONG;
+  }
+  let result = if encrypt {
+    ctx.encrypt_init(sh, &params, key)?;
+    prefix.extend(ctx.encrypt(sh, &data)?);
+    prefix
+  } else {
+    ctx.decrypt_init(sh, &params, key)?;
+    ctx.decrypt(sh, &data)?
+  };
+  output(args, if encrypt { "ciphertext" } else { "plaintext" }, &result)
+}
+
+/// PEM with `label`, or DER.
+fn read_pem_or_der(path: &str, label: &str) -> Result<Vec<u8>> {
+  let data = fs::read(path)?;
+  match String::from_utf8(data) {
+    Ok(ref text) if text.contains("-----BEGIN") => Ok(der::pem_decode(label, text)?),
+    Ok(text) => Ok(text.into_bytes()),
+    Err(err) => Ok(err.into_bytes()),
+  }
+}
+
+fn export(args: &Args, label: &str, data: &[u8]) -> Result<Json> {
+  let der = args.flag("--der");
+  let encoded = if der { data.to_vec() } else { der::pem_encode(label, data).into_bytes() };
+  match (args.value("--out"), der) {
+    (Some(path), _) => {
+      fs::write(path, &encoded)?;
+      Ok(Json::object().with("written", path).with("bytes", encoded.len()))
+    }
+    (None, true) => Ok(Json::object().with("der", hex(data))),
+    (None, false) => Ok(Json::String(String::from_utf8_lossy(&encoded).trim_end().to_string())),
+  }
+}
+
+pub fn import_cert(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--in", "--label", "--id"])?;
+  let cert = Certificate::from_der(&read_pem_or_der(args.required("--in")?, "CERTIFICATE")?)?;
+  let label = args.required("--label")?;
+  let id = match args.value("--id") {
+    Some(id) => parse_hex(id)?,
+    None => cert.subject_key_identifier().unwrap_or_default(),
+  };
+  let sh = session(ctx, args, true)?;
+  let handle = cert.store(ctx, sh, label, &id)?;
+  Ok(Json::object().with("handle", handle as u64).with("label", label).with("id", hex(&id)))
+}
+
+pub fn export_cert(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--out", "--der"])?;
+  let sh = session(ctx, args, true)?;
+  let object = find_one(ctx, sh, args, Some(CKO_CERTIFICATE))?;
+  let cert = Certificate::from_token(ctx, sh, object)?;
+  export(args, "CERTIFICATE", cert.as_der())
+}
+
+pub fn import_pubkey(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--in", "--label", "--id"])?;
+  let key = SubjectPublicKeyInfo::from_der(&read_pem_or_der(args.required("--in")?, "PUBLIC KEY")?)?;
+  let label = args.required("--label")?;
+  let id = args.value("--id").map(parse_hex).transpose()?.unwrap_or_default();
+  let sh = session(ctx, args, true)?;
+  let handle = key.store(ctx, sh, label, &id)?;
+  Ok(Json::object().with("handle", handle as u64).with("label", label).with("id", hex(&id)))
+}
+
+pub fn export_pubkey(ctx: &Ctx, args: &Args) -> Result<Json> {
+  args.allow(&["--label", "--id", "--handle", "--out", "--der"])?;
+  let sh = session(ctx, args, true)?;
+  let key = find_key(ctx, sh, args, CKO_PUBLIC_KEY)?;
+  let key = SubjectPublicKeyInfo::from_token(ctx, sh, key)?;
+  export(args, "PUBLIC KEY", key.as_der())
+}
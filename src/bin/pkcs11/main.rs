@@ -0,0 +1,287 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `pkcs11`, a command-line tool for slots, tokens, objects and keys on top
+//! of `Ctx`, along the lines of OpenSC's `pkcs11-tool`.
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+extern crate pkcs11;
+
+mod commands;
+mod output;
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use pkcs11::errors::Error;
+use pkcs11::select::{SelectError, TokenSelector};
+use pkcs11::types::*;
+use pkcs11::Ctx;
+
+use output::Json;
+
+const USAGE: &str = "\
+Usage: pkcs11 [--module PATH] [--slot ID | --token LABEL] [--pin PIN] [--json] COMMAND [OPTIONS]
+
+The module defaults to $PKCS11_MODULE and the user PIN to $PKCS11_PIN.
+Without --slot or --token, the first slot with a token is used.
+
+Commands:
+  slots                                    list the slots
+  tokens                                   list the tokens
+  mechanisms                               list the mechanisms of the token
+  init-token --label LABEL --so-pin PIN    initialize the token
+  init-pin --so-pin PIN                    set the user PIN to --pin
+  objects [OBJECT]                         list objects, all without a selection
+  dump OBJECT                              print the attributes of an object
+  delete OBJECT [--all]                    delete an object, or all selected ones
+  generate rsa:BITS|ec:CURVE|aes:BITS --label LABEL [--id HEX]
+                                           generate a key pair or secret key
+  sign KEY --in FILE [--out FILE] [--mechanism M]
+  verify KEY --in FILE --signature FILE [--mechanism M]
+  encrypt KEY --in FILE [--out FILE] [--mechanism M] [--iv HEX]
+  decrypt KEY --in FILE [--out FILE] [--mechanism M] [--iv HEX]
+  import-cert --in FILE --label LABEL [--id HEX]
+  export-cert OBJECT [--out FILE] [--der]
+  import-pubkey --in FILE --label LABEL [--id HEX]
+  export-pubkey KEY [--out FILE] [--der]
+
+OBJECT and KEY select with --label LABEL, --id HEX, --handle H and --class C.
+Mechanisms are given as e.g. CKM_SHA256_RSA_PKCS or sha256-rsa-pkcs. Without
+--out, outputs are printed in hex, or in PEM for exports.
+";
+
+/// Options without a value.
+const FLAGS: &[&str] = &["--json", "--der", "--all", "--help"];
+const GLOBAL: &[&str] = &["--module", "--slot", "--token", "--pin", "--json", "--help"];
+
+pub enum Failure {
+  Usage(String),
+  Failed(String),
+}
+
+impl From<Error> for Failure {
+  fn from(err: Error) -> Failure {
+    Failure::Failed(err.to_string())
+  }
+}
+
+impl From<io::Error> for Failure {
+  fn from(err: io::Error) -> Failure {
+    Failure::Failed(err.to_string())
+  }
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Failure::Usage(ref msg) | Failure::Failed(ref msg) => f.write_str(msg),
+    }
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Failure>;
+
+pub fn usage<T>(msg: &str) -> Result<T> {
+  Err(Failure::Usage(msg.to_string()))
+}
+
+pub fn failed<T>(msg: &str) -> Result<T> {
+  Err(Failure::Failed(msg.to_string()))
+}
+
+/// The command line: positional arguments and `--name value` options.
+pub struct Args {
+  positional: Vec<String>,
+  options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+  fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args> {
+    let mut parsed = Args {
+      positional: Vec::new(),
+      options: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+      if !arg.starts_with("--") {
+        parsed.positional.push(arg);
+      } else if FLAGS.contains(&arg.as_str()) {
+        parsed.options.push((arg, None));
+      } else {
+        match args.next() {
+          Some(value) => parsed.options.push((arg, Some(value))),
+          None => return usage(&format!("{} needs a value", arg)),
+        }
+      }
+    }
+    Ok(parsed)
+  }
+
+  pub fn value(&self, name: &str) -> Option<&str> {
+    self.options.iter().rev().find(|(n, _)| n == name).and_then(|(_, v)| v.as_deref())
+  }
+
+  pub fn required(&self, name: &str) -> Result<&str> {
+    match self.value(name) {
+      Some(value) => Ok(value),
+      None => usage(&format!("{} is missing", name)),
+    }
+  }
+
+  pub fn flag(&self, name: &str) -> bool {
+    self.options.iter().any(|(n, _)| n == name)
+  }
+
+  /// Rejects options that neither the command nor the tool knows.
+  pub fn allow(&self, options: &[&str]) -> Result<()> {
+    match self.options.iter().find(|(n, _)| !options.contains(&n.as_str()) && !GLOBAL.contains(&n.as_str())) {
+      Some((name, _)) => usage(&format!("{} is not an option of {}", name, self.positional[0])),
+      None => Ok(()),
+    }
+  }
+
+  /// The positional arguments after the command.
+  pub fn operands(&self) -> &[String] {
+    &self.positional[1..]
+  }
+
+  pub fn pin(&self) -> Option<String> {
+    self.value("--pin").map(str::to_string).or_else(|| env::var("PKCS11_PIN").ok())
+  }
+}
+
+pub fn parse_ulong(s: &str) -> Result<CK_ULONG> {
+  let res = match s.strip_prefix("0x") {
+    Some(hex) => CK_ULONG::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  res.or_else(|_| usage(&format!("{} is not a number", s)))
+}
+
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+  let digits = s.strip_prefix("0x").unwrap_or(s);
+  // from_str_radix would take a sign
+  if digits.len() & 1 == 1 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+    return usage(&format!("{} is not hex", s));
+  }
+  Ok((0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default()).collect())
+}
+
+pub fn hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The slot from `--slot` or `--token`, else the first one with a token.
+pub fn slot(ctx: &Ctx, args: &Args) -> Result<CK_SLOT_ID> {
+  if let Some(slot) = args.value("--slot") {
+    if args.value("--token").is_some() {
+      return usage("--slot and --token cannot be used together");
+    }
+    return parse_ulong(slot);
+  }
+  match args.value("--token") {
+    Some(label) => match TokenSelector::new().label(label).select(ctx) {
+      Ok(slot) => Ok(slot),
+      Err(SelectError::Pkcs11(err)) => Err(err.into()),
+      Err(err) => failed(&err.to_string()),
+    },
+    None => match ctx.get_slot_list(true)?.first() {
+      Some(&slot) => Ok(slot),
+      None => failed("no slot with a token"),
+    },
+  }
+}
+
+/// A read-write session on the selected token, logged in as user with the
+/// PIN or the protected authentication path if `login` is set.
+pub fn session(ctx: &Ctx, args: &Args, login: bool) -> Result<CK_SESSION_HANDLE> {
+  let slot = slot(ctx, args)?;
+  let session = ctx.open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)?;
+  if login {
+    let pin = args.pin();
+    if pin.is_some() || ctx.get_token_info(slot)?.flags & CKF_PROTECTED_AUTHENTICATION_PATH != 0 {
+      match ctx.login(session, CKU_USER, pin.as_deref()) {
+        Ok(()) | Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => {}
+        Err(err) => return Err(err.into()),
+      }
+    }
+  }
+  Ok(session)
+}
+
+fn module(args: &Args) -> Result<PathBuf> {
+  match args.value("--module").map(PathBuf::from).or_else(|| env::var_os("PKCS11_MODULE").map(PathBuf::from)) {
+    Some(path) => Ok(path),
+    None => usage("no module, use --module or set PKCS11_MODULE"),
+  }
+}
+
+fn run(args: &Args) -> Result<Json> {
+  let command = match args.positional.first() {
+    Some(command) => command.as_str(),
+    None => return usage("no command given"),
+  };
+  let ctx = Ctx::new_and_initialize(module(args)?)?;
+  match command {
+    "slots" => commands::slots(&ctx, args),
+    "tokens" => commands::tokens(&ctx, args),
+    "mechanisms" => commands::mechanisms(&ctx, args),
+    "init-token" => commands::init_token(&ctx, args),
+    "init-pin" => commands::init_pin(&ctx, args),
+    "objects" => commands::objects(&ctx, args),
+    "dump" => commands::dump(&ctx, args),
+    "delete" => commands::delete(&ctx, args),
+    "generate" => commands::generate(&ctx, args),
+    "sign" => commands::sign(&ctx, args),
+    "verify" => commands::verify(&ctx, args),
+    "encrypt" => commands::crypt(&ctx, args, true),
+    "decrypt" => commands::crypt(&ctx, args, false),
+    "import-cert" => commands::import_cert(&ctx, args),
+    "export-cert" => commands::export_cert(&ctx, args),
+    "import-pubkey" => commands::import_pubkey(&ctx, args),
+    "export-pubkey" => commands::export_pubkey(&ctx, args),
+    _ => usage(&format!("unknown command {}", command)),
+  }
+}
+
+fn main() {
+  let args = match Args::parse(env::args().skip(1)) {
+    Ok(args) => args,
+    Err(err) => {
+      eprintln!("pkcs11: {}\n\n{}", err, USAGE);
+      process::exit(2);
+    }
+  };
+  if args.flag("--help") || args.positional.first().map(String::as_str) == Some("help") {
+    print!("{}", USAGE);
+    return;
+  }
+  match run(&args) {
+    Ok(result) if args.flag("--json") => println!("{}", result),
+    Ok(result) => print!("{}", result.text()),
+    Err(Failure::Usage(msg)) => {
+      eprintln!("pkcs11: {}\n\n{}", msg, USAGE);
+      process::exit(2);
+    }
+    Err(Failure::Failed(msg)) => {
+      eprintln!("pkcs11: {}", msg);
+      process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,223 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command results, printed as JSON or as indented text.
+
+use std::fmt::{self, Write};
+
+/// A command result. Objects keep the order of their fields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(u64),
+  String(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  pub fn object() -> Json {
+    Json::Object(Vec::new())
+  }
+
+  /// Adds a field to an object.
+  pub fn with<V: Into<Json>>(mut self, name: &str, value: V) -> Json {
+    if let Json::Object(ref mut fields) = self {
+      fields.push((name.to_string(), value.into()));
+    }
+    self
+  }
+
+  /// The indented text form: the first field of every object in a list
+  /// heads its block, lists of values go on one line.
+  pub fn text(&self) -> String {
+    let mut out = String::new();
+    match *self {
+      Json::Null => {}
+      Json::Array(ref items) => {
+        for item in items {
+          match *item {
+            Json::Object(ref fields) => block(&mut out, fields),
+            ref value => {
+              let _ = writeln!(out, "{}", scalar(value));
+            }
+          }
+        }
+      }
+      Json::Object(ref fields) => fields_text(&mut out, fields, 0),
+      ref value => {
+        let _ = writeln!(out, "{}", scalar(value));
+      }
+    }
+    out
+  }
+}
+
+fn block(out: &mut String, fields: &[(String, Json)]) {
+  if let Some((first, rest)) = fields.split_first() {
+    let _ = writeln!(out, "{} {}", title(&first.0), scalar(&first.1));
+    fields_text(out, rest, 2);
+  }
+}
+
+fn fields_text(out: &mut String, fields: &[(String, Json)], indent: usize) {
+  for (name, value) in fields {
+    match *value {
+      Json::Null => {}
+      Json::Object(ref inner) => {
+        let _ = writeln!(out, "{:indent$}{}:", "", name, indent = indent);
+        fields_text(out, inner, indent + 2);
+      }
+      Json::Array(ref items) if items.iter().any(|i| matches!(*i, Json::Object(_))) => {
+        let _ = writeln!(out, "{:indent$}{}:", "", name, indent = indent);
+        for item in items {
+          if let Json::Object(ref inner) = *item {
+            let mut nested = String::new();
+            block(&mut nested, inner);
+            for line in nested.lines() {
+              let _ = writeln!(out, "{:indent$}{}", "", line, indent = indent + 2);
+            }
+          }
+        }
+      }
+      ref value => {
+        let _ = writeln!(out, "{:indent$}{}: {}", "", name, scalar(value), indent = indent);
+      }
+    }
+  }
+}
+
+/// `slot_id` heads its block as "Slot".
+fn title(name: &str) -> String {
+  let name = name.split('_').next().unwrap_or(name);
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(c) => c.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  }
+}
+
+fn scalar(value: &Json) -> String {
+  match *value {
+    Json::Null => "-".to_string(),
+    Json::Bool(b) => if b { "yes" } else { "no" }.to_string(),
+    Json::Number(n) => n.to_string(),
+    Json::String(ref s) => s.clone(),
+    Json::Array(ref items) => items.iter().map(scalar).collect::<Vec<_>>().join(", "),
+    Json::Object(_) => value.to_string(),
+  }
+}
+
+fn escape(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+  f.write_char('"')?;
+  for c in s.chars() {
+    match c {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c => f.write_char(c)?,
+    }
+  }
+  f.write_char('"')
+}
+
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Json::Null => f.write_str("null"),
+      Json::Bool(b) => write!(f, "{}", b),
+      Json::Number(n) => write!(f, "{}", n),
+      Json::String(ref s) => escape(f, s),
+      Json::Array(ref items) => {
+        f.write_char('[')?;
+        for (i, item) in items.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          write!(f, "{}", item)?;
+        }
+        f.write_char(']')
+      }
+      Json::Object(ref fields) => {
+        f.write_char('{')?;
+        for (i, (name, value)) in fields.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          escape(f, name)?;
+          write!(f, ":{}", value)?;
+        }
+        f.write_char('}')
+      }
+    }
+  }
+}
+
+impl From<bool> for Json {
+  fn from(b: bool) -> Json {
+    Json::Bool(b)
+  }
+}
+
+impl From<u64> for Json {
+  fn from(n: u64) -> Json {
+    Json::Number(n)
+  }
+}
+
+impl From<usize> for Json {
+  fn from(n: usize) -> Json {
+    Json::Number(n as u64)
+  }
+}
+
+impl From<u32> for Json {
+  fn from(n: u32) -> Json {
+    Json::Number(n.into())
+  }
+}
+
+impl From<u8> for Json {
+  fn from(n: u8) -> Json {
+    Json::Number(n.into())
+  }
+}
+
+impl<'a> From<&'a str> for Json {
+  fn from(s: &'a str) -> Json {
+    Json::String(s.to_string())
+  }
+}
+
+impl From<String> for Json {
+  fn from(s: String) -> Json {
+    Json::String(s)
+  }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+  fn from(items: Vec<T>) -> Json {
+    Json::Array(items.into_iter().map(Into::into).collect())
+  }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+  fn from(value: Option<T>) -> Json {
+    value.map_or(Json::Null, Into::into)
+  }
+}
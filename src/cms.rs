@@ -0,0 +1,522 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Read;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use super::Ctx;
+use der;
+use der::Reader;
+use errors::Error;
+use types::*;
+use x509::{mechanism, Certificate, SignatureAlgorithm};
+
+pub const OID_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+pub const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+pub const OID_CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+pub const OID_MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+pub const OID_SIGNING_TIME: &[u64] = &[1, 2, 840, 113549, 1, 9, 5];
+pub const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+pub const OID_SHA384: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 2];
+pub const OID_SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The content digest algorithms supported in `SignedData`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+  Sha256,
+  Sha384,
+  Sha512,
+}
+
+impl DigestAlgorithm {
+  pub fn oid(self) -> &'static [u64] {
+    match self {
+      DigestAlgorithm::Sha256 => OID_SHA256,
+      DigestAlgorithm::Sha384 => OID_SHA384,
+      DigestAlgorithm::Sha512 => OID_SHA512,
+    }
+  }
+
+  pub fn from_oid(oid: &[u64]) -> Result<DigestAlgorithm, Error> {
+    [DigestAlgorithm::Sha256, DigestAlgorithm::Sha384, DigestAlgorithm::Sha512]
+      .iter()
+      .find(|alg| alg.oid() == oid)
+      .cloned()
+      .ok_or(Error::InvalidInput("unsupported digest algorithm"))
+  }
+
+  pub fn mechanism(self) -> CK_MECHANISM_TYPE {
+    match self {
+      DigestAlgorithm::Sha256 => CKM_SHA256,
+      DigestAlgorithm::Sha384 => CKM_SHA384,
+      DigestAlgorithm::Sha512 => CKM_SHA512,
+    }
+  }
+
+  /// The digest that goes with a signature algorithm.
+  pub fn for_signature(alg: SignatureAlgorithm) -> DigestAlgorithm {
+    match alg.digest_mechanism() {
+      CKM_SHA384 => DigestAlgorithm::Sha384,
+      CKM_SHA512 => DigestAlgorithm::Sha512,
+      _ => DigestAlgorithm::Sha256,
+    }
+  }
+
+  /// The DER `AlgorithmIdentifier` with absent parameters, as RFC 5754 recommends.
+  pub fn algorithm_identifier(self) -> Vec<u8> {
+    der::sequence(&[der::oid(self.oid())])
+  }
+
+  /// Hashes `data` in software.
+  pub fn hash(self, data: &[u8]) -> Vec<u8> {
+    match self {
+      DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+      DigestAlgorithm::Sha384 => Sha384::digest(data).to_vec(),
+      DigestAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+    }
+  }
+
+  /// Hashes everything `reader` yields, either in software or with
+  /// `C_DigestUpdate` calls on the token.
+  pub fn hash_reader<R: Read>(self, token: Option<(&Ctx, CK_SESSION_HANDLE)>, reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    match token {
+      Some((ctx, session)) => {
+        ctx.digest_init(session, &mechanism(self.mechanism()))?;
+        loop {
+          let n = reader.read(&mut buf)?;
+          if n == 0 {
+            break;
+          }
+          ctx.digest_update(session, &buf[..n])?;
+        }
+        ctx.digest_final(session)
+      }
+      None => {
+        let mut hasher = LocalHasher::new(self);
+        loop {
+          let n = reader.read(&mut buf)?;
+          if n == 0 {
+            break;
+          }
+          hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finish())
+      }
+    }
+  }
+}
+
+enum LocalHasher {
+  Sha256(Sha256),
+  Sha384(Sha384),
+  Sha512(Sha512),
+}
+
+impl LocalHasher {
+  fn new(alg: DigestAlgorithm) -> LocalHasher {
+    match alg {
+      DigestAlgorithm::Sha256 => LocalHasher::Sha256(Sha256::new()),
+      DigestAlgorithm::Sha384 => LocalHasher::Sha384(Sha384::new()),
+      DigestAlgorithm::Sha512 => LocalHasher::Sha512(Sha512::new()),
+    }
+  }
+
+  fn update(&mut self, data: &[u8]) {
+    match *self {
+      LocalHasher::Sha256(ref mut h) => h.update(data),
+      LocalHasher::Sha384(ref mut h) => h.update(data),
+      LocalHasher::Sha512(ref mut h) => h.update(data),
+    }
+  }
+
+  fn finish(self) -> Vec<u8> {
+    match self {
+      LocalHasher::Sha256(h) => h.finalize().to_vec(),
+      LocalHasher::Sha384(h) => h.finalize().to_vec(),
+      LocalHasher::Sha512(h) => h.finalize().to_vec(),
+    }
+  }
+}
+
+fn attribute(oid: &[u64], value: Vec<u8>) -> Vec<u8> {
+  der::sequence(&[der::oid(oid), der::set_of(&[value])])
+}
+
+/// Creates CMS `SignedData` structures (RFC 5652) with a private key on a token.
+///
+/// The signer certificate is embedded and referenced by issuer and serial
+/// number. Signed attributes always carry the content type and the message
+/// digest, plus the signing time unless it is switched off. The content is
+/// hashed in software by default; `digest_on_token` moves that to
+/// `C_DigestUpdate` calls instead.
+#[derive(Debug, Clone)]
+pub struct SignedDataBuilder {
+  certificate: Certificate,
+  chain: Vec<Certificate>,
+  signature_algorithm: Option<SignatureAlgorithm>,
+  detached: bool,
+  digest_on_token: bool,
+  signing_time: Option<SystemTime>,
+  include_signing_time: bool,
+}
+
+impl SignedDataBuilder {
+  pub fn new(certificate: Certificate) -> SignedDataBuilder {
+    SignedDataBuilder {
+      certificate,
+      chain: Vec::new(),
+      signature_algorithm: None,
+      detached: false,
+      digest_on_token: false,
+      signing_time: None,
+      include_signing_time: true,
+    }
+  }
+
+  /// Uses the certificate stored on the token next to `key` (same `CKA_ID`) as signer certificate.
+  pub fn for_key(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<SignedDataBuilder, Error> {
+    Ok(SignedDataBuilder::new(Certificate::find_next_to(ctx, session, key)?))
+  }
+
+  /// Adds an intermediate certificate to the `certificates` field.
+  pub fn add_certificate(mut self, certificate: Certificate) -> SignedDataBuilder {
+    self.chain.push(certificate);
+    self
+  }
+
+  /// Defaults to SHA-256 with the family of the signing key.
+  pub fn signature_algorithm(mut self, alg: SignatureAlgorithm) -> SignedDataBuilder {
+    self.signature_algorithm = Some(alg);
+    self
+  }
+
+  /// Leaves the content out of the structure, e.g. for signatures shipped next to firmware images.
+  pub fn detached(mut self, detached: bool) -> SignedDataBuilder {
+    self.detached = detached;
+    self
+  }
+
+  pub fn digest_on_token(mut self, on_token: bool) -> SignedDataBuilder {
+    self.digest_on_token = on_token;
+    self
+  }
+
+  /// Defaults to the time of signing.
+  pub fn signing_time(mut self, time: SystemTime) -> SignedDataBuilder {
+    self.signing_time = Some(time);
+    self
+  }
+
+  pub fn without_signing_time(mut self) -> SignedDataBuilder {
+    self.include_signing_time = false;
+    self
+  }
+
+  /// Signs `content` and returns the DER encoded `ContentInfo`.
+  pub fn sign(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, content: &[u8]) -> Result<Vec<u8>, Error> {
+    let alg = self.resolve_algorithm(ctx, session, key)?;
+    let digest_alg = DigestAlgorithm::for_signature(alg);
+    let message_digest = if self.digest_on_token {
+      digest_alg.hash_reader(Some((ctx, session)), &mut &content[..])?
+    } else {
+      digest_alg.hash(content)
+    };
+    let encapsulated = if self.detached { None } else { Some(content) };
+    self.sign_digest_with(ctx, session, key, alg, &message_digest, encapsulated)
+  }
+
+  /// Signs everything `reader` yields without holding it in memory. Only
+  /// detached signatures can be produced this way.
+  pub fn sign_reader<R: Read>(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, reader: &mut R) -> Result<Vec<u8>, Error> {
+    if !self.detached {
+      return Err(Error::InvalidInput("streaming signatures must be detached"));
+    }
+    let alg = self.resolve_algorithm(ctx, session, key)?;
+    let token = if self.digest_on_token { Some((ctx, session)) } else { None };
+    let message_digest = DigestAlgorithm::for_signature(alg).hash_reader(token, reader)?;
+    self.sign_digest_with(ctx, session, key, alg, &message_digest, None)
+  }
+
+  fn resolve_algorithm(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<SignatureAlgorithm, Error> {
+    match self.signature_algorithm {
+      Some(alg) => Ok(alg),
+      None => SignatureAlgorithm::for_key(ctx, session, key),
+    }
+  }
+
+  fn sign_digest_with(
+    &self,
+    ctx: &Ctx,
+    session: CK_SESSION_HANDLE,
+    key: CK_OBJECT_HANDLE,
+    alg: SignatureAlgorithm,
+    message_digest: &[u8],
+    content: Option<&[u8]>,
+  ) -> Result<Vec<u8>, Error> {
+    let digest_alg = DigestAlgorithm::for_signature(alg);
+    let mut attrs = vec![
+      attribute(OID_CONTENT_TYPE, der::oid(OID_DATA)),
+      attribute(OID_MESSAGE_DIGEST, der::octet_string(message_digest)),
+    ];
+    if self.include_signing_time {
+      attrs.push(attribute(OID_SIGNING_TIME, der::time(self.signing_time.unwrap_or_else(SystemTime::now))));
+    }
+    // the signature covers the attributes with a SET OF tag, the encoding
+    // in SignerInfo uses the IMPLICIT [0] tag instead
+    let signed_attrs = der::set_of(&attrs);
+    let signature = alg.sign(ctx, session, key, &signed_attrs)?;
+    let mut tagged_attrs = signed_attrs;
+    tagged_attrs[0] = der::tag_explicit(0);
+
+    let signer_info = der::sequence(&[
+      der::small_integer(1),
+      der::sequence(&[self.certificate.issuer().to_vec(), der::unsigned_integer(self.certificate.serial_number())]),
+      digest_alg.algorithm_identifier(),
+      tagged_attrs,
+      alg.algorithm_identifier(),
+      der::octet_string(&signature),
+    ]);
+
+    let mut encap = vec![der::oid(OID_DATA)];
+    if let Some(content) = content {
+      encap.push(der::explicit(0, &der::octet_string(content)));
+    }
+    let certificates: Vec<Vec<u8>> = Some(&self.certificate).into_iter().chain(self.chain.iter()).map(|c| c.as_der().to_vec()).collect();
+    let signed_data = der::sequence(&[
+      der::small_integer(1),
+      der::set_of(&[digest_alg.algorithm_identifier()]),
+      der::sequence(&encap),
+      der::tlv(der::tag_explicit(0), &certificates.concat()),
+      der::set_of(&[signer_info]),
+    ]);
+    Ok(der::sequence(&[der::oid(OID_SIGNED_DATA), der::explicit(0, &signed_data)]))
+  }
+}
+
+/// One `SignerInfo` of a parsed `SignedData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignerInfo {
+  issuer: Vec<u8>,
+  serial_number: Vec<u8>,
+  digest_algorithm: DigestAlgorithm,
+  signed_attrs: Option<Vec<u8>>,
+  message_digest: Option<Vec<u8>>,
+  content_type: Option<Vec<u64>>,
+  signature_algorithm: SignatureAlgorithm,
+  signature: Vec<u8>,
+}
+
+impl SignerInfo {
+  fn from_der(der: &[u8]) -> Result<SignerInfo, Error> {
+    let mut seq = Reader::new(der).read_sequence()?;
+    if seq.read_unsigned_integer()? != [1] {
+      return Err(Error::InvalidInput("only issuer and serial number signer identifiers are supported"));
+    }
+    let mut sid = seq.read_sequence()?;
+    let issuer = sid.read_element(der::TAG_SEQUENCE)?.to_vec();
+    let serial_number = sid.read_unsigned_integer()?.to_vec();
+    let digest_algorithm = DigestAlgorithm::from_oid(&seq.read_sequence()?.read_oid()?)?;
+
+    let mut signed_attrs = None;
+    let mut message_digest = None;
+    let mut content_type = None;
+    if seq.peek_tag() == Some(der::tag_explicit(0)) {
+      let mut full = seq.read_element(der::tag_explicit(0))?.to_vec();
+      full[0] = der::TAG_SET;
+      let mut attrs = Reader::new(&full).read(der::TAG_SET).map(Reader::new)?;
+      while !attrs.is_empty() {
+        let mut attr = attrs.read_sequence()?;
+        let oid = attr.read_oid()?;
+        let mut values = Reader::new(attr.read(der::TAG_SET)?);
+        if oid == OID_MESSAGE_DIGEST {
+          message_digest = Some(values.read(der::TAG_OCTET_STRING)?.to_vec());
+        } else if oid == OID_CONTENT_TYPE {
+          content_type = Some(values.read_oid()?);
+        }
+      }
+      if message_digest.is_none() {
+        return Err(Error::InvalidInput("signed attributes lack the message digest"));
+      }
+      if content_type.is_none() {
+        return Err(Error::InvalidInput("signed attributes lack the content type"));
+      }
+      signed_attrs = Some(full);
+    }
+
+    let signature_algorithm = SignatureAlgorithm::from_algorithm_identifier(seq.read_element(der::TAG_SEQUENCE)?)?;
+    let signature = seq.read(der::TAG_OCTET_STRING)?.to_vec();
+    Ok(SignerInfo {
+      issuer,
+      serial_number,
+      digest_algorithm,
+      signed_attrs,
+      message_digest,
+      content_type,
+      signature_algorithm,
+      signature,
+    })
+  }
+
+  /// The DER encoded issuer name of the signer certificate.
+  pub fn issuer(&self) -> &[u8] {
+    &self.issuer
+  }
+
+  pub fn serial_number(&self) -> &[u8] {
+    &self.serial_number
+  }
+
+  pub fn digest_algorithm(&self) -> DigestAlgorithm {
+    self.digest_algorithm
+  }
+
+  pub fn signature_algorithm(&self) -> SignatureAlgorithm {
+    self.signature_algorithm
+  }
+
+  pub fn signature(&self) -> &[u8] {
+    &self.signature
+  }
+
+  /// Whether `cert` is the certificate this signer refers to.
+  pub fn matches(&self, cert: &Certificate) -> bool {
+    self.issuer == cert.issuer() && self.serial_number == cert.serial_number()
+  }
+
+  /// Checks the signature against `content` with a public key object on the token.
+  pub fn verify(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, public_key: CK_OBJECT_HANDLE, content: &[u8]) -> Result<(), Error> {
+    match (&self.signed_attrs, &self.message_digest) {
+      (Some(attrs), Some(message_digest)) => {
+        if self.digest_algorithm.hash(content) != *message_digest {
+          return Err(Error::Pkcs11(CKR_SIGNATURE_INVALID));
+        }
+        self.signature_algorithm.verify(ctx, session, public_key, attrs, &self.signature)
+      }
+      _ => self.signature_algorithm.verify(ctx, session, public_key, content, &self.signature),
+    }
+  }
+}
+
+/// A parsed CMS `SignedData` structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedData {
+  content: Option<Vec<u8>>,
+  certificates: Vec<Certificate>,
+  signer_infos: Vec<SignerInfo>,
+}
+
+impl SignedData {
+  /// Parses a DER encoded `ContentInfo` wrapping `SignedData`.
+  pub fn from_der(der: &[u8]) -> Result<SignedData, Error> {
+    let mut content_info = Reader::new(der).read_sequence()?;
+    if content_info.read_oid()? != OID_SIGNED_DATA {
+      return Err(Error::InvalidInput("content is not signed data"));
+    }
+    let mut seq = Reader::new(content_info.read(der::tag_explicit(0))?).read_sequence()?;
+    seq.read_unsigned_integer()?;
+    seq.read(der::TAG_SET)?;
+
+    let mut encap = seq.read_sequence()?;
+    let content_type = encap.read_oid()?;
+    if content_type != OID_DATA {
+      return Err(Error::InvalidInput("only id-data content is supported"));
+    }
+    let content = match encap.read_optional(der::tag_explicit(0))? {
+      Some(explicit) => Some(Reader::new(explicit).read(der::TAG_OCTET_STRING)?.to_vec()),
+      None => None,
+    };
+
+    let mut certificates = Vec::new();
+    if let Some(certs) = seq.read_optional(der::tag_explicit(0))? {
+      let mut certs = Reader::new(certs);
+      while !certs.is_empty() {
+        certificates.push(Certificate::from_der(certs.read_element(der::TAG_SEQUENCE)?)?);
+      }
+    }
+    seq.read_optional(der::tag_explicit(1))?;
+
+    let mut signers = Reader::new(seq.read(der::TAG_SET)?);
+    let mut signer_infos = Vec::new();
+    while !signers.is_empty() {
+      let signer = SignerInfo::from_der(signers.read_element(der::TAG_SEQUENCE)?)?;
+      // the signed content type has to be the one encapsulated
+      if signer.content_type.as_ref().is_some_and(|oid| *oid != content_type) {
+        return Err(Error::InvalidInput("content type attribute does not match the encapsulated content type"));
+      }
+      signer_infos.push(signer);
+    }
+    Ok(SignedData {
+      content,
+      certificates,
+      signer_infos,
+    })
+  }
+
+  pub fn from_pem(pem: &str) -> Result<SignedData, Error> {
+    SignedData::from_der(&der::pem_decode("CMS", pem)?)
+  }
+
+  /// The encapsulated content, `None` for detached signatures.
+  pub fn content(&self) -> Option<&[u8]> {
+    self.content.as_ref().map(|c| &c[..])
+  }
+
+  pub fn certificates(&self) -> &[Certificate] {
+    &self.certificates
+  }
+
+  pub fn signer_infos(&self) -> &[SignerInfo] {
+    &self.signer_infos
+  }
+
+  /// The embedded certificate of the first signer.
+  pub fn signer_certificate(&self) -> Option<&Certificate> {
+    let signer = self.signer_infos.first()?;
+    self.certificates.iter().find(|cert| signer.matches(cert))
+  }
+
+  fn resolve_content<'a>(&'a self, detached_content: Option<&'a [u8]>) -> Result<&'a [u8], Error> {
+    match (detached_content, self.content()) {
+      (Some(content), None) | (None, Some(content)) => Ok(content),
+      (None, None) => Err(Error::InvalidInput("detached signature needs the signed content")),
+      (Some(_), Some(_)) => Err(Error::InvalidInput("signed data encapsulates its content, it cannot be given as well")),
+    }
+  }
+
+  /// Verifies the first signer with a public key object on the token. Pass
+  /// the content for detached signatures only.
+  pub fn verify(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, public_key: CK_OBJECT_HANDLE, detached_content: Option<&[u8]>) -> Result<(), Error> {
+    let content = self.resolve_content(detached_content)?;
+    match self.signer_infos.first() {
+      Some(signer) => signer.verify(ctx, session, public_key, content),
+      None => Err(Error::InvalidInput("signed data has no signers")),
+    }
+  }
+
+  /// Verifies the first signer with the public key of its embedded
+  /// certificate, imported as a session object. Whether that certificate is
+  /// trusted is up to the caller.
+  pub fn verify_with_embedded_certificate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, detached_content: Option<&[u8]>) -> Result<(), Error> {
+    let cert = self.signer_certificate().ok_or(Error::InvalidInput("signer certificate is not embedded"))?;
+    let key = cert.public_key().import(ctx, session)?;
+    let result = self.verify(ctx, session, key, detached_content);
+    ctx.destroy_object(session, key)?;
+    result
+  }
+}
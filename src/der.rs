@@ -0,0 +1,418 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use errors::Error;
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0c;
+pub const TAG_PRINTABLE_STRING: u8 = 0x13;
+pub const TAG_IA5_STRING: u8 = 0x16;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// context-specific, constructed tag `[n]` as used for EXPLICIT tagging
+pub fn tag_explicit(n: u8) -> u8 {
+  0xa0 | n
+}
+
+/// context-specific, primitive tag `[n]` as used for IMPLICIT tagging of primitive types
+pub fn tag_implicit(n: u8) -> u8 {
+  0x80 | n
+}
+
+/// Encodes a single TLV with the given tag and content.
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(content.len() + 6);
+  out.push(tag);
+  let len = content.len();
+  if len < 0x80 {
+    out.push(len as u8);
+  } else {
+    let bytes = (len as u64).to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    out.push(0x80 | (bytes.len() - skip) as u8);
+    out.extend_from_slice(&bytes[skip..]);
+  }
+  out.extend_from_slice(content);
+  out
+}
+
+pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+  tlv(TAG_SEQUENCE, &items.concat())
+}
+
+/// Encodes a `SET OF`, sorting the elements as DER requires.
+pub fn set_of(items: &[Vec<u8>]) -> Vec<u8> {
+  let mut sorted = items.to_vec();
+  sorted.sort();
+  tlv(TAG_SET, &sorted.concat())
+}
+
+pub fn explicit(n: u8, inner: &[u8]) -> Vec<u8> {
+  tlv(tag_explicit(n), inner)
+}
+
+pub fn null() -> Vec<u8> {
+  vec![TAG_NULL, 0]
+}
+
+pub fn boolean(b: bool) -> Vec<u8> {
+  tlv(TAG_BOOLEAN, &[if b { 0xff } else { 0x00 }])
+}
+
+/// Encodes a non-negative INTEGER from its big-endian magnitude.
+pub fn unsigned_integer(be: &[u8]) -> Vec<u8> {
+  let skip = be.iter().take_while(|b| **b == 0).count();
+  let mut content = Vec::with_capacity(be.len() - skip + 1);
+  match be.get(skip) {
+    None => content.push(0),
+    Some(b) if b & 0x80 != 0 => {
+      content.push(0);
+      content.extend_from_slice(&be[skip..]);
+    }
+    Some(_) => content.extend_from_slice(&be[skip..]),
+  }
+  tlv(TAG_INTEGER, &content)
+}
+
+pub fn small_integer(v: u64) -> Vec<u8> {
+  unsigned_integer(&v.to_be_bytes())
+}
+
+/// Encodes an OBJECT IDENTIFIER from its arcs, e.g. `&[1, 2, 840, 113549, 1, 1, 11]`.
+pub fn oid(arcs: &[u64]) -> Vec<u8> {
+  let mut content = Vec::new();
+  if arcs.len() >= 2 {
+    push_base128(&mut content, arcs[0] * 40 + arcs[1]);
+    for arc in &arcs[2..] {
+      push_base128(&mut content, *arc);
+    }
+  }
+  tlv(TAG_OID, &content)
+}
+
+fn push_base128(out: &mut Vec<u8>, mut v: u64) {
+  let mut tmp = vec![(v & 0x7f) as u8];
+  v >>= 7;
+  while v > 0 {
+    tmp.push(0x80 | (v & 0x7f) as u8);
+    v >>= 7;
+  }
+  tmp.reverse();
+  out.extend_from_slice(&tmp);
+}
+
+pub fn octet_string(data: &[u8]) -> Vec<u8> {
+  tlv(TAG_OCTET_STRING, data)
+}
+
+/// Encodes a BIT STRING without unused bits.
+pub fn bit_string(data: &[u8]) -> Vec<u8> {
+  let mut content = Vec::with_capacity(data.len() + 1);
+  content.push(0);
+  content.extend_from_slice(data);
+  tlv(TAG_BIT_STRING, &content)
+}
+
+/// Encodes a named bit list (e.g. KeyUsage) with trailing zero bits removed.
+pub fn named_bits(bits: u16) -> Vec<u8> {
+  if bits == 0 {
+    return tlv(TAG_BIT_STRING, &[0]);
+  }
+  // bit 0 is the most significant bit of the first octet
+  let highest = 15 - bits.trailing_zeros() as usize;
+  let len = highest / 8 + 1;
+  let bytes = bits.to_be_bytes();
+  let unused = (8 - (highest + 1) % 8) % 8;
+  let mut content = vec![unused as u8];
+  content.extend_from_slice(&bytes[..len]);
+  tlv(TAG_BIT_STRING, &content)
+}
+
+pub fn utf8_string(s: &str) -> Vec<u8> {
+  tlv(TAG_UTF8_STRING, s.as_bytes())
+}
+
+pub fn printable_string(s: &str) -> Vec<u8> {
+  tlv(TAG_PRINTABLE_STRING, s.as_bytes())
+}
+
+pub fn ia5_string(s: &str) -> Vec<u8> {
+  tlv(TAG_IA5_STRING, s.as_bytes())
+}
+
+/// Encodes a point in time the way RFC 5280 wants it: UTCTime from 1950 up
+/// to 2049, GeneralizedTime before and after.
+pub fn time(t: SystemTime) -> Vec<u8> {
+  let (year, month, day, hour, min, sec) = civil_time(t);
+  if (1950..2050).contains(&year) {
+    let s = format!("{:02}{:02}{:02}{:02}{:02}{:02}Z", year % 100, month, day, hour, min, sec);
+    tlv(TAG_UTC_TIME, s.as_bytes())
+  } else {
+    generalized_time(t)
+  }
+}
+
+pub fn generalized_time(t: SystemTime) -> Vec<u8> {
+  let (year, month, day, hour, min, sec) = civil_time(t);
+  let s = format!("{:04}{:02}{:02}{:02}{:02}{:02}Z", year, month, day, hour, min, sec);
+  tlv(TAG_GENERALIZED_TIME, s.as_bytes())
+}
+
+/// Splits a point in time into (year, month, day, hour, minute, second) in UTC.
+pub fn civil_time(t: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+  let secs = match t.duration_since(UNIX_EPOCH) {
+    Ok(d) => d.as_secs() as i64,
+    Err(e) => -(e.duration().as_secs() as i64),
+  };
+  let days = secs.div_euclid(86400);
+  let rem = secs.rem_euclid(86400);
+  // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day, (rem / 3600) as u32, ((rem % 3600) / 60) as u32, (rem % 60) as u32)
+}
+
+/// Converts the raw `r || s` signature returned by `CKM_ECDSA*` into a DER `Ecdsa-Sig-Value`.
+pub fn ecdsa_signature_from_raw(raw: &[u8]) -> Result<Vec<u8>, Error> {
+  if raw.is_empty() || raw.len() & 1 == 1 {
+    return Err(Error::InvalidInput("ECDSA signature must consist of two equally long integers"));
+  }
+  let (r, s) = raw.split_at(raw.len() / 2);
+  Ok(sequence(&[unsigned_integer(r), unsigned_integer(s)]))
+}
+
+/// Converts a DER `Ecdsa-Sig-Value` into the raw `r || s` form where each
+/// integer is left-padded to `width` bytes.
+pub fn ecdsa_signature_to_raw(der: &[u8], width: usize) -> Result<Vec<u8>, Error> {
+  let mut seq = Reader::new(der).read_sequence()?;
+  let r = seq.read_unsigned_integer()?;
+  let s = seq.read_unsigned_integer()?;
+  if r.len() > width || s.len() > width {
+    return Err(Error::InvalidInput("ECDSA signature integer is too large for the curve"));
+  }
+  let mut out = vec![0; 2 * width];
+  out[width - r.len()..width].copy_from_slice(r);
+  out[2 * width - s.len()..].copy_from_slice(s);
+  Ok(out)
+}
+
+/// A cursor over DER encoded data.
+#[derive(Debug, Clone, Copy)]
+pub struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  pub fn new(data: &'a [u8]) -> Reader<'a> {
+    Reader { data }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.data.is_empty()
+  }
+
+  pub fn peek_tag(&self) -> Option<u8> {
+    self.data.first().cloned()
+  }
+
+  /// Reads the next TLV and returns its tag, its content and the complete
+  /// encoding including the header.
+  pub fn read_any(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), Error> {
+    let malformed = Error::InvalidInput("malformed DER encoding");
+    if self.data.len() < 2 {
+      return Err(malformed);
+    }
+    let tag = self.data[0];
+    let first = self.data[1] as usize;
+    let (len, header) = if first < 0x80 {
+      (first, 2)
+    } else {
+      let n = first & 0x7f;
+      if n == 0 || n > 8 || self.data.len() < 2 + n {
+        return Err(malformed);
+      }
+      let mut len = 0usize;
+      for b in &self.data[2..2 + n] {
+        len = (len << 8) | *b as usize;
+      }
+      (len, 2 + n)
+    };
+    if self.data.len() - header < len {
+      return Err(malformed);
+    }
+    let full = &self.data[..header + len];
+    let content = &self.data[header..header + len];
+    self.data = &self.data[header + len..];
+    Ok((tag, content, full))
+  }
+
+  /// Reads the next TLV, which must carry `tag`, and returns its content.
+  pub fn read(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+    match self.read_any()? {
+      (t, content, _) if t == tag => Ok(content),
+      _ => Err(Error::InvalidInput("unexpected DER tag")),
+    }
+  }
+
+  /// Like `read`, but returns the complete encoding.
+  pub fn read_element(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+    match self.read_any()? {
+      (t, _, full) if t == tag => Ok(full),
+      _ => Err(Error::InvalidInput("unexpected DER tag")),
+    }
+  }
+
+  /// Reads the next element only if it carries `tag`.
+  pub fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+    if self.peek_tag() == Some(tag) {
+      self.read(tag).map(Some)
+    } else {
+      Ok(None)
+    }
+  }
+
+  pub fn read_sequence(&mut self) -> Result<Reader<'a>, Error> {
+    self.read(TAG_SEQUENCE).map(Reader::new)
+  }
+
+  /// Reads a non-negative INTEGER and returns its big-endian magnitude without leading zeros.
+  pub fn read_unsigned_integer(&mut self) -> Result<&'a [u8], Error> {
+    let content = self.read(TAG_INTEGER)?;
+    match content.first() {
+      None => Err(Error::InvalidInput("empty DER integer")),
+      Some(b) if b & 0x80 != 0 => Err(Error::InvalidInput("negative DER integer")),
+      Some(_) => {
+        let skip = content.iter().take_while(|b| **b == 0).count();
+        Ok(&content[skip..])
+      }
+    }
+  }
+
+  pub fn read_oid(&mut self) -> Result<Vec<u64>, Error> {
+    let content = self.read(TAG_OID)?;
+    let mut arcs = Vec::new();
+    let mut v: u64 = 0;
+    for b in content {
+      v = (v << 7) | (b & 0x7f) as u64;
+      if b & 0x80 == 0 {
+        if arcs.is_empty() {
+          let first = if v < 80 { v / 40 } else { 2 };
+          arcs.push(first);
+          arcs.push(v - first * 40);
+        } else {
+          arcs.push(v);
+        }
+        v = 0;
+      }
+    }
+    if content.is_empty() || content[content.len() - 1] & 0x80 != 0 {
+      return Err(Error::InvalidInput("malformed DER object identifier"));
+    }
+    Ok(arcs)
+  }
+
+  /// Reads a BIT STRING without unused bits.
+  pub fn read_bit_string(&mut self) -> Result<&'a [u8], Error> {
+    let content = self.read(TAG_BIT_STRING)?;
+    match content.split_first() {
+      Some((0, rest)) => Ok(rest),
+      _ => Err(Error::InvalidInput("unsupported DER bit string")),
+    }
+  }
+}
+
+/// Wraps DER data into a PEM block with the given label.
+pub fn pem_encode(label: &str, der: &[u8]) -> String {
+  let b64 = base64_encode(der, false);
+  let mut out = format!("-----BEGIN {}-----\n", label);
+  for chunk in b64.as_bytes().chunks(64) {
+    out.push_str(&String::from_utf8_lossy(chunk));
+    out.push('\n');
+  }
+  out.push_str(&format!("-----END {}-----\n", label));
+  out
+}
+
+/// Extracts the DER data of the first PEM block with the given label.
+pub fn pem_decode(label: &str, pem: &str) -> Result<Vec<u8>, Error> {
+  let begin = format!("-----BEGIN {}-----", label);
+  let end = format!("-----END {}-----", label);
+  let start = pem.find(&begin).ok_or(Error::InvalidInput("PEM begin marker not found"))? + begin.len();
+  let stop = pem[start..].find(&end).ok_or(Error::InvalidInput("PEM end marker not found"))? + start;
+  let body: String = pem[start..stop].chars().filter(|c| !c.is_whitespace()).collect();
+  base64_decode(&body)
+}
+
+const BASE64_STD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Base64 encoding, either standard with padding or URL-safe without padding.
+pub fn base64_encode(data: &[u8], url_safe: bool) -> String {
+  let alphabet = if url_safe { BASE64_URL } else { BASE64_STD };
+  let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+  for chunk in data.chunks(3) {
+    let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    for i in 0..4 {
+      if i <= chunk.len() {
+        out.push(alphabet[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+      } else if !url_safe {
+        out.push('=');
+      }
+    }
+  }
+  out
+}
+
+/// Base64 decoding accepting both alphabets, with or without padding.
+pub fn base64_decode(s: &str) -> Result<Vec<u8>, Error> {
+  let mut out = Vec::with_capacity(s.len() * 3 / 4);
+  let mut acc: u32 = 0;
+  let mut bits = 0;
+  for c in s.trim_end_matches('=').bytes() {
+    let v = match c {
+      b'A'..=b'Z' => c - b'A',
+      b'a'..=b'z' => c - b'a' + 26,
+      b'0'..=b'9' => c - b'0' + 52,
+      b'+' | b'-' => 62,
+      b'/' | b'_' => 63,
+      _ => return Err(Error::InvalidInput("invalid base64 character")),
+    };
+    acc = (acc << 6) | v as u32;
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      out.push((acc >> bits) as u8);
+      acc &= (1 << bits) - 1;
+    }
+  }
+  Ok(out)
+}
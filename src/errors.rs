@@ -50,7 +50,8 @@ impl std::error::Error for Error {
   }
 }
 
-fn strerror(err: CK_RV) -> &'static str {
+/// The name of a return value, e.g. `"CKR_PIN_INCORRECT"`, or `"unknown"`.
+pub fn strerror(err: CK_RV) -> &'static str {
   match err {
     CKR_OK => "CKR_OK",
     CKR_CANCEL => "CKR_CANCEL",
@@ -0,0 +1,390 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fault injection for the mock module of the `mock` workspace member.
+//!
+//! Every call through the mock module passes a set of [`Rules`] first, which
+//! can fail the call with any `CK_RV` or delay it. The mock either serves the
+//! calls from its in-memory token or forwards them to another module, so any
+//! real module can be made to fail deterministically:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use pkcs11::Ctx;
+//! # use pkcs11::fault::{FaultInjector, Rules};
+//! # use pkcs11::types::*;
+//! let faults = FaultInjector::new("target/debug/libpkcs11_mock.so").unwrap();
+//! faults.wrap("/usr/lib/softhsm/libsofthsm2.so").unwrap();
+//! faults
+//!   .set_rules(&Rules::new().fail_nth("C_Sign", 3, CKR_DEVICE_REMOVED).delay("C_Login", Duration::from_secs(2)))
+//!   .unwrap();
+//! let ctx = Ctx::new_and_initialize("target/debug/libpkcs11_mock.so").unwrap();
+//! ```
+//!
+//! Without the Rust API, the mock reads the module to wrap from
+//! `PKCS11_MOCK_WRAP` and the rules in their text form from
+//! `PKCS11_MOCK_FAULTS`, e.g. `C_Sign#3 fail 0x32; C_Login delay 2s`.
+
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use libloading;
+
+use errors::Error;
+use types::*;
+
+/// The names of all functions in `CK_FUNCTION_LIST`, in list order.
+pub const FUNCTION_NAMES: &[&str] = &[
+  "C_Initialize",
+  "C_Finalize",
+  "C_GetInfo",
+  "C_GetFunctionList",
+  "C_GetSlotList",
+  "C_GetSlotInfo",
+  "C_GetTokenInfo",
+  "C_GetMechanismList",
+  "C_GetMechanismInfo",
+  "C_InitToken",
+  "C_InitPIN",
+  "C_SetPIN",
+  "C_OpenSession",
+  "C_CloseSession",
+  "C_CloseAllSessions",
+  "C_GetSessionInfo",
+  "C_GetOperationState",
+  "C_SetOperationState",
+  "C_Login",
+  "C_Logout",
+  "C_CreateObject",
+  "C_CopyObject",
+  "C_DestroyObject",
+  "C_GetObjectSize",
+  "C_GetAttributeValue",
+  "C_SetAttributeValue",
+  "C_FindObjectsInit",
+  "C_FindObjects",
+  "C_FindObjectsFinal",
+  "C_EncryptInit",
+  "C_Encrypt",
+  "C_EncryptUpdate",
+  "C_EncryptFinal",
+  "C_DecryptInit",
+  "C_Decrypt",
+  "C_DecryptUpdate",
+  "C_DecryptFinal",
+  "C_DigestInit",
+  "C_Digest",
+  "C_DigestUpdate",
+  "C_DigestKey",
+  "C_DigestFinal",
+  "C_SignInit",
+  "C_Sign",
+  "C_SignUpdate",
+  "C_SignFinal",
+  "C_SignRecoverInit",
+  "C_SignRecover",
+  "C_VerifyInit",
+  "C_Verify",
+  "C_VerifyUpdate",
+  "C_VerifyFinal",
+  "C_VerifyRecoverInit",
+  "C_VerifyRecover",
+  "C_DigestEncryptUpdate",
+  "C_DecryptDigestUpdate",
+  "C_SignEncryptUpdate",
+  "C_DecryptVerifyUpdate",
+  "C_GenerateKey",
+  "C_GenerateKeyPair",
+  "C_WrapKey",
+  "C_UnwrapKey",
+  "C_DeriveKey",
+  "C_SeedRandom",
+  "C_GenerateRandom",
+  "C_GetFunctionStatus",
+  "C_CancelFunction",
+  "C_WaitForSlotEvent",
+];
+
+/// The calls of a function a rule applies to, counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calls {
+  /// Only the n-th call.
+  Nth(u64),
+  /// The n-th and all later calls.
+  From(u64),
+  Every,
+}
+
+impl Calls {
+  pub fn contains(self, call: u64) -> bool {
+    match self {
+      Calls::Nth(n) => call == n,
+      Calls::From(n) => call >= n,
+      Calls::Every => true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  /// Returns the error without calling the module.
+  Fail(CK_RV),
+  /// Sleeps before the call.
+  Delay(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+  pub function: String,
+  pub calls: Calls,
+  pub action: Action,
+}
+
+impl fmt::Display for Rule {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.function)?;
+    match self.calls {
+      Calls::Nth(n) => write!(f, "#{}", n)?,
+      Calls::From(n) => write!(f, "#{}..", n)?,
+      Calls::Every => {}
+    }
+    match self.action {
+      Action::Fail(rv) => write!(f, " fail 0x{:x}", rv),
+      Action::Delay(d) => write!(f, " delay {}ms", d.as_millis()),
+    }
+  }
+}
+
+impl FromStr for Rule {
+  type Err = Error;
+
+  /// Parses the form `Display` writes: `C_Sign#3 fail 0x32`, `C_Sign#3.. fail 0x32`,
+  /// `C_Sign fail 50` or `C_Login delay 2s` (or `2000ms`).
+  fn from_str(s: &str) -> Result<Rule, Error> {
+    let mut words = s.split_whitespace();
+    let (function, calls) = match words.next() {
+      Some(target) => match target.find('#') {
+        Some(pos) => {
+          let calls = &target[pos + 1..];
+          let calls = match calls.strip_suffix("..") {
+            Some(n) => Calls::From(parse_number(n)?),
+            None => Calls::Nth(parse_number(calls)?),
+          };
+          (&target[..pos], calls)
+        }
+        None => (target, Calls::Every),
+      },
+      None => return Err(Error::InvalidInput("empty fault rule")),
+    };
+    let action = match (words.next(), words.next(), words.next()) {
+      (Some("fail"), Some(rv), None) => Action::Fail(parse_number(rv)? as CK_RV),
+      (Some("delay"), Some(d), None) => Action::Delay(parse_duration(d)?),
+      _ => return Err(Error::InvalidInput("fault rules need a `fail <rv>` or `delay <duration>` action")),
+    };
+    let rule = Rule {
+      function: function.to_string(),
+      calls,
+      action,
+    };
+    rule.validate()?;
+    Ok(rule)
+  }
+}
+
+impl Rule {
+  fn validate(&self) -> Result<(), Error> {
+    if !FUNCTION_NAMES.contains(&self.function.as_str()) {
+      return Err(Error::InvalidInput("unknown PKCS#11 function in fault rule"));
+    }
+    match self.calls {
+      Calls::Nth(0) | Calls::From(0) => Err(Error::InvalidInput("calls in fault rules are counted from 1")),
+      _ => Ok(()),
+    }
+  }
+}
+
+fn parse_number(s: &str) -> Result<u64, Error> {
+  let res = match s.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  res.map_err(|_| Error::InvalidInput("invalid number in fault rule"))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+  match (s.strip_suffix("ms"), s.strip_suffix('s')) {
+    (Some(ms), _) => Ok(Duration::from_millis(parse_number(ms)?)),
+    (None, Some(secs)) => Ok(Duration::from_secs(parse_number(secs)?)),
+    _ => Err(Error::InvalidInput("delays in fault rules need a `ms` or `s` unit")),
+  }
+}
+
+/// An ordered set of fault rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rules {
+  rules: Vec<Rule>,
+}
+
+impl Rules {
+  pub fn new() -> Rules {
+    Rules::default()
+  }
+
+  pub fn rule(mut self, function: &str, calls: Calls, action: Action) -> Rules {
+    self.rules.push(Rule {
+      function: function.to_string(),
+      calls,
+      action,
+    });
+    self
+  }
+
+  /// Fails the `call`-th call of `function`, e.g. the 3rd `C_Sign`.
+  pub fn fail_nth(self, function: &str, call: u64, rv: CK_RV) -> Rules {
+    self.rule(function, Calls::Nth(call), Action::Fail(rv))
+  }
+
+  /// Fails the `call`-th and every later call of `function`, like a token that stays removed.
+  pub fn fail_from(self, function: &str, call: u64, rv: CK_RV) -> Rules {
+    self.rule(function, Calls::From(call), Action::Fail(rv))
+  }
+
+  pub fn fail_always(self, function: &str, rv: CK_RV) -> Rules {
+    self.rule(function, Calls::Every, Action::Fail(rv))
+  }
+
+  pub fn delay(self, function: &str, duration: Duration) -> Rules {
+    self.rule(function, Calls::Every, Action::Delay(duration))
+  }
+
+  pub fn delay_nth(self, function: &str, call: u64, duration: Duration) -> Rules {
+    self.rule(function, Calls::Nth(call), Action::Delay(duration))
+  }
+
+  pub fn rules(&self) -> &[Rule] {
+    &self.rules
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  /// What happens to the `call`-th call of `function`: the delays of all
+  /// matching rules add up, the first matching failure wins.
+  pub fn check(&self, function: &str, call: u64) -> (Duration, Option<CK_RV>) {
+    let mut delay = Duration::from_secs(0);
+    let mut fail = None;
+    for rule in self.rules.iter().filter(|r| r.function == function && r.calls.contains(call)) {
+      match rule.action {
+        Action::Delay(d) => delay += d,
+        Action::Fail(rv) => {
+          fail.get_or_insert(rv);
+        }
+      }
+    }
+    (delay, fail)
+  }
+}
+
+impl fmt::Display for Rules {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, rule) in self.rules.iter().enumerate() {
+      if i > 0 {
+        write!(f, "; ")?;
+      }
+      write!(f, "{}", rule)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Rules {
+  type Err = Error;
+
+  /// Parses rules separated by `;` or newlines.
+  fn from_str(s: &str) -> Result<Rules, Error> {
+    let rules = s.split([';', '\n']).map(str::trim).filter(|r| !r.is_empty()).map(str::parse).collect::<Result<_, _>>()?;
+    Ok(Rules { rules })
+  }
+}
+
+/// Controls the fault injection of a loaded mock module.
+///
+/// The injector and a `Ctx` created on the same path share one instance of
+/// the library, so the injector has to stay alive as long as the rules
+/// should apply. Load a private copy of the library to keep the faults of
+/// concurrent tests apart.
+pub struct FaultInjector {
+  lib: libloading::Library,
+}
+
+impl FaultInjector {
+  pub fn new<P>(mock_module: P) -> Result<FaultInjector, Error>
+  where
+    P: AsRef<Path>,
+  {
+    let lib = libloading::Library::new(mock_module.as_ref())?;
+    unsafe {
+      lib.get::<unsafe extern "C" fn(*const c_char) -> CK_RV>(b"pkcs11_mock_set_faults")
+        .map_err(|_| Error::Module("not the mock module: pkcs11_mock_set_faults function not found"))?;
+    }
+    Ok(FaultInjector { lib })
+  }
+
+  /// Forwards all calls to `module` instead of the in-memory token. This has
+  /// to happen before a `Ctx` gets created on the mock module.
+  pub fn wrap<P>(&self, module: P) -> Result<(), Error>
+  where
+    P: AsRef<Path>,
+  {
+    let path = module.as_ref().to_str().ok_or(Error::InvalidInput("module path is not valid UTF-8"))?;
+    self.call_with_str(b"pkcs11_mock_wrap", path)
+  }
+
+  /// Replaces the rules and restarts counting the calls.
+  pub fn set_rules(&self, rules: &Rules) -> Result<(), Error> {
+    for rule in &rules.rules {
+      rule.validate()?;
+    }
+    self.call_with_str(b"pkcs11_mock_set_faults", &rules.to_string())
+  }
+
+  pub fn clear(&self) -> Result<(), Error> {
+    self.set_rules(&Rules::new())
+  }
+
+  /// How often `function` was called since the rules were last set.
+  pub fn call_count(&self, function: &str) -> Result<u64, Error> {
+    let name = CString::new(function).map_err(|_| Error::InvalidInput("function name contains a NUL byte"))?;
+    unsafe {
+      let func = self.lib.get::<unsafe extern "C" fn(*const c_char) -> CK_ULONG>(b"pkcs11_mock_call_count")?;
+      Ok(func(name.as_ptr()) as u64)
+    }
+  }
+
+  fn call_with_str(&self, symbol: &[u8], arg: &str) -> Result<(), Error> {
+    let arg = CString::new(arg).map_err(|_| Error::InvalidInput("argument contains a NUL byte"))?;
+    unsafe {
+      let func = self.lib.get::<unsafe extern "C" fn(*const c_char) -> CK_RV>(symbol)?;
+      match func(arg.as_ptr()) {
+        CKR_OK => Ok(()),
+        err => Err(Error::Pkcs11(err)),
+      }
+    }
+  }
+}
@@ -0,0 +1,430 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Owned forms of the info structures and an inventory of a whole module.
+//!
+//! `CK_INFO`, `CK_SLOT_INFO`, `CK_TOKEN_INFO`, `CK_SESSION_INFO` and
+//! `CK_MECHANISM_INFO` convert into [`Info`], [`SlotInfo`], [`TokenInfo`],
+//! [`SessionInfo`] and [`MechanismInfo`], which have trimmed strings, flags
+//! decoded by name and versions. With the `serde` feature, they and the
+//! [`Inventory`] of [`inventory`] implement `Serialize` and `Deserialize`:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! let inventory = pkcs11::info::inventory(&ctx).unwrap();
+//! for slot in &inventory.slots {
+//!   if let Some(ref token) = slot.token {
+//!     println!("{}: {} objects", token.info.label, token.objects.len());
+//!   }
+//! }
+//! ```
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::Ctx;
+use errors::Error;
+use types::*;
+
+/// The flags of `CK_SLOT_INFO` by name.
+pub const SLOT_FLAGS: &[(CK_FLAGS, &str)] = &[(CKF_TOKEN_PRESENT, "CKF_TOKEN_PRESENT"), (CKF_REMOVABLE_DEVICE, "CKF_REMOVABLE_DEVICE"), (CKF_HW_SLOT, "CKF_HW_SLOT")];
+
+/// The flags of `CK_TOKEN_INFO` by name.
+pub const TOKEN_FLAGS: &[(CK_FLAGS, &str)] = &[
+  (CKF_RNG, "CKF_RNG"),
+  (CKF_WRITE_PROTECTED, "CKF_WRITE_PROTECTED"),
+  (CKF_LOGIN_REQUIRED, "CKF_LOGIN_REQUIRED"),
+  (CKF_USER_PIN_INITIALIZED, "CKF_USER_PIN_INITIALIZED"),
+  (CKF_RESTORE_KEY_NOT_NEEDED, "CKF_RESTORE_KEY_NOT_NEEDED"),
+  (CKF_CLOCK_ON_TOKEN, "CKF_CLOCK_ON_TOKEN"),
+  (CKF_PROTECTED_AUTHENTICATION_PATH, "CKF_PROTECTED_AUTHENTICATION_PATH"),
+  (CKF_DUAL_CRYPTO_OPERATIONS, "CKF_DUAL_CRYPTO_OPERATIONS"),
+  (CKF_TOKEN_INITIALIZED, "CKF_TOKEN_INITIALIZED"),
+  (CKF_SECONDARY_AUTHENTICATION, "CKF_SECONDARY_AUTHENTICATION"),
+  (CKF_USER_PIN_COUNT_LOW, "CKF_USER_PIN_COUNT_LOW"),
+  (CKF_USER_PIN_FINAL_TRY, "CKF_USER_PIN_FINAL_TRY"),
+  (CKF_USER_PIN_LOCKED, "CKF_USER_PIN_LOCKED"),
+  (CKF_USER_PIN_TO_BE_CHANGED, "CKF_USER_PIN_TO_BE_CHANGED"),
+  (CKF_SO_PIN_COUNT_LOW, "CKF_SO_PIN_COUNT_LOW"),
+  (CKF_SO_PIN_FINAL_TRY, "CKF_SO_PIN_FINAL_TRY"),
+  (CKF_SO_PIN_LOCKED, "CKF_SO_PIN_LOCKED"),
+  (CKF_SO_PIN_TO_BE_CHANGED, "CKF_SO_PIN_TO_BE_CHANGED"),
+  (CKF_ERROR_STATE, "CKF_ERROR_STATE"),
+];
+
+/// The flags of `CK_SESSION_INFO` by name.
+pub const SESSION_FLAGS: &[(CK_FLAGS, &str)] = &[(CKF_RW_SESSION, "CKF_RW_SESSION"), (CKF_SERIAL_SESSION, "CKF_SERIAL_SESSION")];
+
+/// The flags of `CK_MECHANISM_INFO` by name.
+pub const MECHANISM_FLAGS: &[(CK_FLAGS, &str)] = &[
+  (CKF_HW, "CKF_HW"),
+  (CKF_ENCRYPT, "CKF_ENCRYPT"),
+  (CKF_DECRYPT, "CKF_DECRYPT"),
+  (CKF_DIGEST, "CKF_DIGEST"),
+  (CKF_SIGN, "CKF_SIGN"),
+  (CKF_SIGN_RECOVER, "CKF_SIGN_RECOVER"),
+  (CKF_VERIFY, "CKF_VERIFY"),
+  (CKF_VERIFY_RECOVER, "CKF_VERIFY_RECOVER"),
+  (CKF_GENERATE, "CKF_GENERATE"),
+  (CKF_GENERATE_KEY_PAIR, "CKF_GENERATE_KEY_PAIR"),
+  (CKF_WRAP, "CKF_WRAP"),
+  (CKF_UNWRAP, "CKF_UNWRAP"),
+  (CKF_DERIVE, "CKF_DERIVE"),
+  (CKF_EC_F_P, "CKF_EC_F_P"),
+  (CKF_EC_F_2M, "CKF_EC_F_2M"),
+  (CKF_EC_ECPARAMETERS, "CKF_EC_ECPARAMETERS"),
+  (CKF_EC_NAMEDCURVE, "CKF_EC_NAMEDCURVE"),
+  (CKF_EC_UNCOMPRESS, "CKF_EC_UNCOMPRESS"),
+  (CKF_EC_COMPRESS, "CKF_EC_COMPRESS"),
+  (CKF_EXTENSION, "CKF_EXTENSION"),
+];
+
+/// The session states by name.
+const SESSION_STATES: &[(CK_ULONG, &str)] = &[
+  (CKS_RO_PUBLIC_SESSION, "CKS_RO_PUBLIC_SESSION"),
+  (CKS_RO_USER_FUNCTIONS, "CKS_RO_USER_FUNCTIONS"),
+  (CKS_RW_PUBLIC_SESSION, "CKS_RW_PUBLIC_SESSION"),
+  (CKS_RW_USER_FUNCTIONS, "CKS_RW_USER_FUNCTIONS"),
+  (CKS_RW_SO_FUNCTIONS, "CKS_RW_SO_FUNCTIONS"),
+];
+
+/// The names of the flags set in `flags`. Bits without a name in `table`
+/// come last, together as one hex number.
+pub fn flag_names(table: &[(CK_FLAGS, &str)], flags: CK_FLAGS) -> Vec<String> {
+  let mut names: Vec<String> = table.iter().filter(|&&(bit, _)| flags & bit == bit).map(|&(_, name)| name.to_string()).collect();
+  let unknown = table.iter().fold(flags, |rest, &(bit, _)| rest & !bit);
+  if unknown != 0 {
+    names.push(format!("{:#x}", unknown));
+  }
+  names
+}
+
+/// A blank padded field of an info structure, without the padding.
+pub fn trimmed(field: &[u8]) -> String {
+  String::from_utf8_lossy(field).trim_end_matches([' ', '\0']).to_string()
+}
+
+/// `CK_UNAVAILABLE_INFORMATION` as `None`.
+fn available(value: CK_ULONG) -> Option<CK_ULONG> {
+  if value == CK_UNAVAILABLE_INFORMATION {
+    None
+  } else {
+    Some(value)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Version {
+  pub major: u8,
+  pub minor: u8,
+}
+
+impl From<CK_VERSION> for Version {
+  fn from(version: CK_VERSION) -> Version {
+    Version {
+      major: version.major,
+      minor: version.minor,
+    }
+  }
+}
+
+impl fmt::Display for Version {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}.{}", self.major, self.minor)
+  }
+}
+
+/// `CK_INFO`, the module as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Info {
+  pub cryptoki_version: Version,
+  pub manufacturer: String,
+  pub library_description: String,
+  pub library_version: Version,
+}
+
+impl From<CK_INFO> for Info {
+  fn from(info: CK_INFO) -> Info {
+    Info {
+      cryptoki_version: info.cryptokiVersion.into(),
+      manufacturer: trimmed(&{ info.manufacturerID }),
+      library_description: trimmed(&{ info.libraryDescription }),
+      library_version: info.libraryVersion.into(),
+    }
+  }
+}
+
+/// `CK_SLOT_INFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SlotInfo {
+  pub description: String,
+  pub manufacturer: String,
+  pub flags: Vec<String>,
+  pub hardware_version: Version,
+  pub firmware_version: Version,
+}
+
+impl From<CK_SLOT_INFO> for SlotInfo {
+  fn from(info: CK_SLOT_INFO) -> SlotInfo {
+    SlotInfo {
+      description: trimmed(&{ info.slotDescription }),
+      manufacturer: trimmed(&{ info.manufacturerID }),
+      flags: flag_names(SLOT_FLAGS, info.flags),
+      hardware_version: info.hardwareVersion.into(),
+      firmware_version: info.firmwareVersion.into(),
+    }
+  }
+}
+
+/// `CK_TOKEN_INFO`. Counts and sizes the token does not tell are `None`;
+/// maximum session counts of 0 stand for no limit, as in `CK_TOKEN_INFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TokenInfo {
+  pub label: String,
+  pub manufacturer: String,
+  pub model: String,
+  pub serial_number: String,
+  pub flags: Vec<String>,
+  pub max_session_count: Option<CK_ULONG>,
+  pub session_count: Option<CK_ULONG>,
+  pub max_rw_session_count: Option<CK_ULONG>,
+  pub rw_session_count: Option<CK_ULONG>,
+  pub max_pin_len: CK_ULONG,
+  pub min_pin_len: CK_ULONG,
+  pub total_public_memory: Option<CK_ULONG>,
+  pub free_public_memory: Option<CK_ULONG>,
+  pub total_private_memory: Option<CK_ULONG>,
+  pub free_private_memory: Option<CK_ULONG>,
+  pub hardware_version: Version,
+  pub firmware_version: Version,
+  /// The time on the token, `YYYYMMDDhhmmss`, if it has a clock.
+  pub utc_time: Option<String>,
+}
+
+impl From<CK_TOKEN_INFO> for TokenInfo {
+  fn from(info: CK_TOKEN_INFO) -> TokenInfo {
+    TokenInfo {
+      label: trimmed(&{ info.label }),
+      manufacturer: trimmed(&{ info.manufacturerID }),
+      model: trimmed(&{ info.model }),
+      serial_number: trimmed(&{ info.serialNumber }),
+      flags: flag_names(TOKEN_FLAGS, info.flags),
+      max_session_count: available(info.ulMaxSessionCount),
+      session_count: available(info.ulSessionCount),
+      max_rw_session_count: available(info.ulMaxRwSessionCount),
+      rw_session_count: available(info.ulRwSessionCount),
+      max_pin_len: info.ulMaxPinLen,
+      min_pin_len: info.ulMinPinLen,
+      total_public_memory: available(info.ulTotalPublicMemory),
+      free_public_memory: available(info.ulFreePublicMemory),
+      total_private_memory: available(info.ulTotalPrivateMemory),
+      free_private_memory: available(info.ulFreePrivateMemory),
+      hardware_version: info.hardwareVersion.into(),
+      firmware_version: info.firmwareVersion.into(),
+      utc_time: if info.flags & CKF_CLOCK_ON_TOKEN != 0 { Some(trimmed(&{ info.utcTime })) } else { None },
+    }
+  }
+}
+
+/// `CK_SESSION_INFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SessionInfo {
+  pub slot_id: CK_SLOT_ID,
+  /// The `CKS_` name of the state.
+  pub state: String,
+  pub flags: Vec<String>,
+  pub device_error: CK_ULONG,
+}
+
+impl From<CK_SESSION_INFO> for SessionInfo {
+  fn from(info: CK_SESSION_INFO) -> SessionInfo {
+    let state = info.state;
+    SessionInfo {
+      slot_id: info.slotID,
+      state: match SESSION_STATES.iter().find(|&&(s, _)| s == state) {
+        Some(&(_, name)) => name.to_string(),
+        None => format!("{:#x}", state),
+      },
+      flags: flag_names(SESSION_FLAGS, info.flags),
+      device_error: info.ulDeviceError,
+    }
+  }
+}
+
+/// `CK_MECHANISM_INFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MechanismInfo {
+  pub min_key_size: CK_ULONG,
+  pub max_key_size: CK_ULONG,
+  pub flags: Vec<String>,
+}
+
+impl From<CK_MECHANISM_INFO> for MechanismInfo {
+  fn from(info: CK_MECHANISM_INFO) -> MechanismInfo {
+    MechanismInfo {
+      min_key_size: info.ulMinKeySize,
+      max_key_size: info.ulMaxKeySize,
+      flags: flag_names(MECHANISM_FLAGS, info.flags),
+    }
+  }
+}
+
+/// Everything [`inventory`] finds in a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Inventory {
+  pub library: Info,
+  pub slots: Vec<SlotInventory>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SlotInventory {
+  pub slot_id: CK_SLOT_ID,
+  /// `None` if it could not be read, see `error`.
+  pub info: Option<SlotInfo>,
+  /// The token in the slot, if there is one and it could be read.
+  pub token: Option<TokenInventory>,
+  /// Why the slot or its token could not be read.
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TokenInventory {
+  pub info: TokenInfo,
+  pub mechanisms: Vec<Mechanism>,
+  /// The objects visible without logging in.
+  pub objects: Vec<Object>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Mechanism {
+  pub mechanism_type: CK_MECHANISM_TYPE,
+  pub info: MechanismInfo,
+}
+
+/// A public object, with the attributes that identify it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Object {
+  pub handle: CK_OBJECT_HANDLE,
+  pub class: CK_OBJECT_CLASS,
+  pub key_type: Option<CK_KEY_TYPE>,
+  pub label: Option<String>,
+  pub id: Option<Vec<u8>>,
+}
+
+/// Walks all slots of the module with their tokens, the mechanisms of the
+/// tokens and their public objects. Tokens that are not initialized yet
+/// have no objects. A slot or token that fails to be read does not stop the
+/// walk, the error is kept in its [`SlotInventory`].
+pub fn inventory(ctx: &Ctx) -> Result<Inventory, Error> {
+  let slots = ctx.get_slot_list(false)?.into_iter().map(|slot_id| slot_inventory(ctx, slot_id)).collect();
+  Ok(Inventory {
+    library: ctx.get_info()?.into(),
+    slots,
+  })
+}
+
+fn slot_inventory(ctx: &Ctx, slot_id: CK_SLOT_ID) -> SlotInventory {
+  let mut slot = SlotInventory {
+    slot_id,
+    info: None,
+    token: None,
+    error: None,
+  };
+  let info = match ctx.get_slot_info(slot_id) {
+    Ok(info) => info,
+    Err(err) => {
+      slot.error = Some(err.to_string());
+      return slot;
+    }
+  };
+  let token_present = info.flags & CKF_TOKEN_PRESENT != 0;
+  slot.info = Some(info.into());
+  if token_present {
+    match token_inventory(ctx, slot_id) {
+      Ok(token) => slot.token = Some(token),
+      Err(err) => slot.error = Some(err.to_string()),
+    }
+  }
+  slot
+}
+
+fn token_inventory(ctx: &Ctx, slot_id: CK_SLOT_ID) -> Result<TokenInventory, Error> {
+  let info = ctx.get_token_info(slot_id)?;
+  let mut mechanisms = Vec::new();
+  for mechanism_type in ctx.get_mechanism_list(slot_id)? {
+    mechanisms.push(Mechanism {
+      mechanism_type,
+      info: ctx.get_mechanism_info(slot_id, mechanism_type)?.into(),
+    });
+  }
+  let objects = if info.flags & CKF_TOKEN_INITIALIZED != 0 { public_objects(ctx, slot_id)? } else { Vec::new() };
+  Ok(TokenInventory {
+    info: info.into(),
+    mechanisms,
+    objects,
+  })
+}
+
+fn public_objects(ctx: &Ctx, slot_id: CK_SLOT_ID) -> Result<Vec<Object>, Error> {
+  let session = ctx.open_session(slot_id, CKF_SERIAL_SESSION, None, None)?;
+  let res = find_all(ctx, session).and_then(|handles| handles.into_iter().map(|handle| object(ctx, session, handle)).collect());
+  ctx.close_session(session)?;
+  res
+}
+
+fn find_all(ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
+  ctx.find_objects_init(session, &[])?;
+  let mut handles = Vec::new();
+  let res = loop {
+    match ctx.find_objects(session, 64) {
+      Ok(ref found) if found.is_empty() => break Ok(handles),
+      Ok(found) => handles.extend(found),
+      Err(err) => break Err(err),
+    }
+  };
+  ctx.find_objects_final(session)?;
+  res
+}
+
+fn object(ctx: &Ctx, session: CK_SESSION_HANDLE, handle: CK_OBJECT_HANDLE) -> Result<Object, Error> {
+  let class = ctx.get_attribute_ulong(session, handle, CKA_CLASS)?;
+  let key_type = match class {
+    CKO_PUBLIC_KEY | CKO_PRIVATE_KEY | CKO_SECRET_KEY => ctx.get_attribute_ulong(session, handle, CKA_KEY_TYPE).ok(),
+    _ => None,
+  };
+  Ok(Object {
+    handle,
+    class,
+    key_type,
+    label: ctx.get_attribute_bytes(session, handle, CKA_LABEL).ok().map(|label| String::from_utf8_lossy(&label).into_owned()),
+    id: ctx.get_attribute_bytes(session, handle, CKA_ID).ok(),
+  })
+}
@@ -0,0 +1,605 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem;
+
+use super::Ctx;
+use cms::DigestAlgorithm;
+use der;
+use der::Reader;
+use errors::Error;
+use types::*;
+use x509::{self, mechanism, OID_SECP256R1, OID_SECP384R1, OID_SECP521R1};
+
+/// The Ed25519 curve as it appears in `CKA_EC_PARAMS`, either as OID or as printable string.
+pub const OID_ED25519: &[u64] = &[1, 3, 101, 112];
+const ED25519_NAME: &str = "edwards25519";
+
+/// The JWS `alg` values (RFC 7518, RFC 8037) that can be computed with a token key.
+///
+/// RSA signatures use the combined `CKM_SHA*_RSA_PKCS` and
+/// `CKM_SHA*_RSA_PKCS_PSS` mechanisms, ECDSA hashes with `C_Digest` and signs
+/// with `CKM_ECDSA`, and EdDSA uses `CKM_EDDSA` over the signing input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+  RS256,
+  RS384,
+  RS512,
+  PS256,
+  PS384,
+  PS512,
+  ES256,
+  ES384,
+  ES512,
+  EdDSA,
+}
+
+const ALGORITHMS: &[Algorithm] = &[
+  Algorithm::RS256,
+  Algorithm::RS384,
+  Algorithm::RS512,
+  Algorithm::PS256,
+  Algorithm::PS384,
+  Algorithm::PS512,
+  Algorithm::ES256,
+  Algorithm::ES384,
+  Algorithm::ES512,
+  Algorithm::EdDSA,
+];
+
+impl Algorithm {
+  pub fn name(self) -> &'static str {
+    match self {
+      Algorithm::RS256 => "RS256",
+      Algorithm::RS384 => "RS384",
+      Algorithm::RS512 => "RS512",
+      Algorithm::PS256 => "PS256",
+      Algorithm::PS384 => "PS384",
+      Algorithm::PS512 => "PS512",
+      Algorithm::ES256 => "ES256",
+      Algorithm::ES384 => "ES384",
+      Algorithm::ES512 => "ES512",
+      Algorithm::EdDSA => "EdDSA",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Result<Algorithm, Error> {
+    ALGORITHMS
+      .iter()
+      .find(|alg| alg.name() == name)
+      .cloned()
+      .ok_or(Error::InvalidInput("unsupported JWS algorithm"))
+  }
+
+  /// The hash used by the algorithm; EdDSA hashes internally and has none.
+  pub fn digest_algorithm(self) -> Option<DigestAlgorithm> {
+    match self {
+      Algorithm::RS256 | Algorithm::PS256 | Algorithm::ES256 => Some(DigestAlgorithm::Sha256),
+      Algorithm::RS384 | Algorithm::PS384 | Algorithm::ES384 => Some(DigestAlgorithm::Sha384),
+      Algorithm::RS512 | Algorithm::PS512 | Algorithm::ES512 => Some(DigestAlgorithm::Sha512),
+      Algorithm::EdDSA => None,
+    }
+  }
+
+  /// The size of `r` and `s` in the JOSE signature of the ECDSA algorithms.
+  pub fn ecdsa_width(self) -> Option<usize> {
+    match self {
+      Algorithm::ES256 => Some(32),
+      Algorithm::ES384 => Some(48),
+      Algorithm::ES512 => Some(66),
+      _ => None,
+    }
+  }
+
+  /// The key type the algorithm needs.
+  pub fn key_type(self) -> CK_KEY_TYPE {
+    match self {
+      Algorithm::ES256 | Algorithm::ES384 | Algorithm::ES512 => CKK_EC,
+      Algorithm::EdDSA => CKK_EC_EDWARDS,
+      _ => CKK_RSA,
+    }
+  }
+
+  /// Picks the algorithm for a token key: RS256 for RSA, the ES variant
+  /// matching the curve for EC and EdDSA for Edwards keys.
+  pub fn for_key(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<Algorithm, Error> {
+    match ctx.get_attribute_ulong(session, key, CKA_KEY_TYPE)? {
+      CKK_RSA => Ok(Algorithm::RS256),
+      CKK_EC => match x509::curve_width(&ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?)? {
+        32 => Ok(Algorithm::ES256),
+        48 => Ok(Algorithm::ES384),
+        _ => Ok(Algorithm::ES512),
+      },
+      CKK_EC_EDWARDS => Ok(Algorithm::EdDSA),
+      _ => Err(Error::InvalidInput("key type is not supported for JWS")),
+    }
+  }
+
+  fn check_key(self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    if ctx.get_attribute_ulong(session, key, CKA_KEY_TYPE)? != self.key_type() {
+      return Err(Error::InvalidInput("key type does not match the JWS algorithm"));
+    }
+    if let Some(width) = self.ecdsa_width() {
+      if x509::curve_width(&ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?)? != width {
+        return Err(Error::InvalidInput("curve does not match the JWS algorithm"));
+      }
+    }
+    Ok(())
+  }
+
+  fn pss_params(self) -> CK_RSA_PKCS_PSS_PARAMS {
+    let (hashAlg, mgf, sLen) = match self {
+      Algorithm::PS384 => (CKM_SHA384, CKG_MGF1_SHA384, 48),
+      Algorithm::PS512 => (CKM_SHA512, CKG_MGF1_SHA512, 64),
+      _ => (CKM_SHA256, CKG_MGF1_SHA256, 32),
+    };
+    CK_RSA_PKCS_PSS_PARAMS { hashAlg, mgf, sLen }
+  }
+
+  fn rsa_mechanism(self) -> CK_MECHANISM_TYPE {
+    match self {
+      Algorithm::RS256 => CKM_SHA256_RSA_PKCS,
+      Algorithm::RS384 => CKM_SHA384_RSA_PKCS,
+      Algorithm::RS512 => CKM_SHA512_RSA_PKCS,
+      Algorithm::PS256 => CKM_SHA256_RSA_PKCS_PSS,
+      Algorithm::PS384 => CKM_SHA384_RSA_PKCS_PSS,
+      _ => CKM_SHA512_RSA_PKCS_PSS,
+    }
+  }
+
+  /// Signs the JWS signing input and returns the signature in JOSE form.
+  pub fn sign(self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, data: &[u8]) -> Result<Vec<u8>, Error> {
+    self.check_key(ctx, session, key)?;
+    match self {
+      Algorithm::ES256 | Algorithm::ES384 | Algorithm::ES512 => {
+        let hash = x509::digest(ctx, session, self.digest_algorithm().unwrap().mechanism(), data)?;
+        ctx.sign_init(session, &mechanism(CKM_ECDSA), key)?;
+        ecdsa_to_jose(&ctx.sign(session, &hash)?, self.ecdsa_width().unwrap())
+      }
+      Algorithm::EdDSA => {
+        ctx.sign_init(session, &mechanism(CKM_EDDSA), key)?;
+        ctx.sign(session, data)
+      }
+      Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+        let mut params = self.pss_params();
+        ctx.sign_init(session, &pss_mechanism(self.rsa_mechanism(), &mut params), key)?;
+        ctx.sign(session, data)
+      }
+      _ => {
+        ctx.sign_init(session, &mechanism(self.rsa_mechanism()), key)?;
+        ctx.sign(session, data)
+      }
+    }
+  }
+
+  /// Verifies a JOSE signature over the JWS signing input with a public key object on the token.
+  pub fn verify(self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    self.check_key(ctx, session, key)?;
+    match self {
+      Algorithm::ES256 | Algorithm::ES384 | Algorithm::ES512 => {
+        if signature.len() != 2 * self.ecdsa_width().unwrap() {
+          return Err(Error::Pkcs11(CKR_SIGNATURE_LEN_RANGE));
+        }
+        let hash = x509::digest(ctx, session, self.digest_algorithm().unwrap().mechanism(), data)?;
+        ctx.verify_init(session, &mechanism(CKM_ECDSA), key)?;
+        ctx.verify(session, &hash, signature)
+      }
+      Algorithm::EdDSA => {
+        ctx.verify_init(session, &mechanism(CKM_EDDSA), key)?;
+        ctx.verify(session, data, signature)
+      }
+      Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+        let mut params = self.pss_params();
+        ctx.verify_init(session, &pss_mechanism(self.rsa_mechanism(), &mut params), key)?;
+        ctx.verify(session, data, signature)
+      }
+      _ => {
+        ctx.verify_init(session, &mechanism(self.rsa_mechanism()), key)?;
+        ctx.verify(session, data, signature)
+      }
+    }
+  }
+}
+
+fn pss_mechanism(mechanism: CK_MECHANISM_TYPE, params: &mut CK_RSA_PKCS_PSS_PARAMS) -> CK_MECHANISM {
+  CK_MECHANISM {
+    mechanism,
+    pParameter: params as *mut CK_RSA_PKCS_PSS_PARAMS as CK_VOID_PTR,
+    ulParameterLen: mem::size_of::<CK_RSA_PKCS_PSS_PARAMS>() as CK_ULONG,
+  }
+}
+
+/// Brings an ECDSA signature into the fixed-width `r || s` form JOSE
+/// requires. Accepts raw signatures whose halves are shorter than `width`
+/// as well as DER `Ecdsa-Sig-Value` encodings.
+pub fn ecdsa_to_jose(signature: &[u8], width: usize) -> Result<Vec<u8>, Error> {
+  if signature.len() == 2 * width {
+    return Ok(signature.to_vec());
+  }
+  if signature.first() == Some(&der::TAG_SEQUENCE) {
+    if let Ok(raw) = der::ecdsa_signature_to_raw(signature, width) {
+      return Ok(raw);
+    }
+  }
+  if signature.is_empty() || signature.len() & 1 == 1 || signature.len() > 2 * width {
+    return Err(Error::InvalidInput("ECDSA signature does not fit the curve"));
+  }
+  let (r, s) = signature.split_at(signature.len() / 2);
+  let mut out = vec![0; 2 * width];
+  out[width - r.len()..width].copy_from_slice(r);
+  out[2 * width - s.len()..].copy_from_slice(s);
+  Ok(out)
+}
+
+/// Converts a JOSE ECDSA signature into a DER `Ecdsa-Sig-Value`.
+pub fn ecdsa_from_jose(signature: &[u8]) -> Result<Vec<u8>, Error> {
+  der::ecdsa_signature_from_raw(signature)
+}
+
+fn json_escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+/// The members of a flat JSON object in order, with the values of the string
+/// members; the others are skipped over. Only as much JSON as JWS headers
+/// need is supported.
+pub(crate) fn json_members(json: &str) -> Result<Vec<(String, Option<String>)>, Error> {
+  let malformed = Error::InvalidInput("malformed JSON object");
+  let mut chars = json.trim().chars().peekable();
+  if chars.next() != Some('{') {
+    return Err(malformed);
+  }
+  skip_whitespace(&mut chars);
+  if chars.peek() == Some(&'}') {
+    chars.next();
+    return if chars.next().is_none() { Ok(Vec::new()) } else { Err(malformed) };
+  }
+  let mut members = Vec::new();
+  loop {
+    // a member, also after a comma: no trailing commas
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('"') {
+      return Err(malformed);
+    }
+    let key = json_read_string(&mut chars)?;
+    skip_whitespace(&mut chars);
+    if chars.next() != Some(':') {
+      return Err(malformed);
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'"') {
+      chars.next();
+      let value = json_read_string(&mut chars)?;
+      members.push((key, Some(value)));
+    } else {
+      members.push((key, None));
+      let mut depth = 0;
+      while let Some(&c) = chars.peek() {
+        match c {
+          '"' => {
+            chars.next();
+            json_read_string(&mut chars)?;
+            continue;
+          }
+          '{' | '[' => depth += 1,
+          '}' | ']' if depth == 0 => break,
+          '}' | ']' => depth -= 1,
+          ',' if depth == 0 => break,
+          _ => {}
+        }
+        chars.next();
+      }
+    }
+    skip_whitespace(&mut chars);
+    match chars.next() {
+      Some(',') => {}
+      Some('}') => return Ok(members),
+      _ => return Err(malformed),
+    }
+  }
+}
+
+fn skip_whitespace<I: Iterator<Item = char>>(chars: &mut ::std::iter::Peekable<I>) {
+  while chars.peek().is_some_and(|c| c.is_whitespace()) {
+    chars.next();
+  }
+}
+
+fn json_read_string<I: Iterator<Item = char>>(chars: &mut I) -> Result<String, Error> {
+  let malformed = Error::InvalidInput("malformed JSON string");
+  let mut out = String::new();
+  loop {
+    match chars.next() {
+      None => return Err(malformed),
+      Some('"') => return Ok(out),
+      Some('\\') => match chars.next() {
+        Some('"') => out.push('"'),
+        Some('\\') => out.push('\\'),
+        Some('/') => out.push('/'),
+        Some('b') => out.push('\u{8}'),
+        Some('f') => out.push('\u{c}'),
+        Some('n') => out.push('\n'),
+        Some('r') => out.push('\r'),
+        Some('t') => out.push('\t'),
+        Some('u') => {
+          let hex: String = chars.by_ref().take(4).collect();
+          let code = u32::from_str_radix(&hex, 16).map_err(|_| Error::InvalidInput("malformed JSON string"))?;
+          out.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+        }
+        _ => return Err(malformed),
+      },
+      Some(c) => out.push(c),
+    }
+  }
+}
+
+/// The protected header of a JWS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+  pub alg: Algorithm,
+  pub typ: Option<String>,
+  pub kid: Option<String>,
+}
+
+impl Header {
+  pub fn new(alg: Algorithm) -> Header {
+    Header { alg, typ: None, kid: None }
+  }
+
+  /// A header with `"typ": "JWT"`.
+  pub fn jwt(alg: Algorithm) -> Header {
+    Header::new(alg).with_typ("JWT")
+  }
+
+  pub fn with_typ(mut self, typ: &str) -> Header {
+    self.typ = Some(typ.to_string());
+    self
+  }
+
+  pub fn with_kid(mut self, kid: &str) -> Header {
+    self.kid = Some(kid.to_string());
+    self
+  }
+
+  pub fn to_json(&self) -> String {
+    let mut members = vec![format!("\"alg\":{}", json_escape(self.alg.name()))];
+    if let Some(ref typ) = self.typ {
+      members.push(format!("\"typ\":{}", json_escape(typ)));
+    }
+    if let Some(ref kid) = self.kid {
+      members.push(format!("\"kid\":{}", json_escape(kid)));
+    }
+    format!("{{{}}}", members.join(","))
+  }
+
+  /// Parses a protected header. Headers with a `crit` member are refused,
+  /// as none of the extensions it may list are understood (RFC 7515,
+  /// section 4.1.11).
+  pub fn from_json(json: &str) -> Result<Header, Error> {
+    let members = json_members(json)?;
+    if members.iter().any(|(key, _)| key == "crit") {
+      return Err(Error::InvalidInput("JWS header has critical extensions"));
+    }
+    // the last string member of a name counts
+    let member = |name: &str| members.iter().rev().filter(|(key, _)| key == name).find_map(|(_, value)| value.clone());
+    let alg = member("alg").ok_or(Error::InvalidInput("JWS header lacks alg"))?;
+    Ok(Header {
+      alg: Algorithm::from_name(&alg)?,
+      typ: member("typ"),
+      kid: member("kid"),
+    })
+  }
+}
+
+/// Creates a JWS in compact serialization over `payload`.
+pub fn sign_compact(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, header: &Header, payload: &[u8]) -> Result<String, Error> {
+  let signing_input = format!("{}.{}", der::base64_encode(header.to_json().as_bytes(), true), der::base64_encode(payload, true));
+  let signature = header.alg.sign(ctx, session, key, signing_input.as_bytes())?;
+  Ok(format!("{}.{}", signing_input, der::base64_encode(&signature, true)))
+}
+
+/// Creates a JWT from the JSON encoded claims set.
+pub fn sign_jwt(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, alg: Algorithm, kid: Option<&str>, claims: &str) -> Result<String, Error> {
+  let mut header = Header::jwt(alg);
+  header.kid = kid.map(|kid| kid.to_string());
+  sign_compact(ctx, session, key, &header, claims.as_bytes())
+}
+
+/// A JWS in compact serialization, split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactJws {
+  header: Header,
+  payload: Vec<u8>,
+  signing_input: String,
+  signature: Vec<u8>,
+}
+
+impl CompactJws {
+  pub fn parse(token: &str) -> Result<CompactJws, Error> {
+    let parts: Vec<&str> = token.trim().split('.').collect();
+    if parts.len() != 3 {
+      return Err(Error::InvalidInput("JWS compact serialization needs three parts"));
+    }
+    let header_json = String::from_utf8(der::base64_decode(parts[0])?).map_err(|_| Error::InvalidInput("JWS header is not UTF-8"))?;
+    Ok(CompactJws {
+      header: Header::from_json(&header_json)?,
+      payload: der::base64_decode(parts[1])?,
+      signing_input: format!("{}.{}", parts[0], parts[1]),
+      signature: der::base64_decode(parts[2])?,
+    })
+  }
+
+  pub fn header(&self) -> &Header {
+    &self.header
+  }
+
+  /// The payload; not to be trusted before `verify` succeeded.
+  pub fn payload(&self) -> &[u8] {
+    &self.payload
+  }
+
+  pub fn signature(&self) -> &[u8] {
+    &self.signature
+  }
+
+  /// Verifies the signature with a public key object on the token. The
+  /// header must name `expected` so that a token cannot pick a weaker algorithm.
+  pub fn verify(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, public_key: CK_OBJECT_HANDLE, expected: Algorithm) -> Result<(), Error> {
+    if self.header.alg != expected {
+      return Err(Error::InvalidInput("JWS algorithm does not match the expected one"));
+    }
+    expected.verify(ctx, session, public_key, self.signing_input.as_bytes(), &self.signature)
+  }
+}
+
+/// Parses and verifies a compact JWS, returning its payload.
+pub fn verify_compact(ctx: &Ctx, session: CK_SESSION_HANDLE, public_key: CK_OBJECT_HANDLE, expected: Algorithm, token: &str) -> Result<Vec<u8>, Error> {
+  let jws = CompactJws::parse(token)?;
+  jws.verify(ctx, session, public_key, expected)?;
+  Ok(jws.payload)
+}
+
+/// A big-endian unsigned integer without leading zero bytes, as JWK wants
+/// it (RFC 7518, section 6.3.1). Zero stays one byte.
+fn unsigned(be: &[u8]) -> &[u8] {
+  let zeros = be.iter().take_while(|b| **b == 0).count();
+  &be[zeros.min(be.len().saturating_sub(1))..]
+}
+
+/// How the `kid` of an exported JWK is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyId {
+  /// The hex encoded `CKA_ID`, falling back to the thumbprint if it is empty.
+  CkaId,
+  /// The RFC 7638 SHA-256 thumbprint.
+  Thumbprint,
+}
+
+/// A public JSON Web Key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+  /// The required members in lexicographic order, values base64url encoded where binary.
+  members: Vec<(&'static str, String)>,
+  kid: String,
+}
+
+impl Jwk {
+  /// Exports the public part of a token key; works on public and private key objects.
+  pub fn from_token(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, key_id: KeyId) -> Result<Jwk, Error> {
+    let members = match ctx.get_attribute_ulong(session, key, CKA_KEY_TYPE)? {
+      CKK_RSA => vec![
+        ("e", der::base64_encode(unsigned(&ctx.get_attribute_bytes(session, key, CKA_PUBLIC_EXPONENT)?), true)),
+        ("kty", "RSA".to_string()),
+        ("n", der::base64_encode(unsigned(&ctx.get_attribute_bytes(session, key, CKA_MODULUS)?), true)),
+      ],
+      CKK_EC => {
+        let params = ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?;
+        let width = x509::curve_width(&params)?;
+        let point = x509::unwrap_ec_point(&ctx.get_attribute_bytes(session, key, CKA_EC_POINT)?);
+        if point.len() != 1 + 2 * width || point[0] != 0x04 {
+          return Err(Error::InvalidInput("EC point is not an uncompressed point"));
+        }
+        let oid = Reader::new(&params).read_oid()?;
+        let crv = if oid == OID_SECP256R1 {
+          "P-256"
+        } else if oid == OID_SECP384R1 {
+          "P-384"
+        } else if oid == OID_SECP521R1 {
+          "P-521"
+        } else {
+          return Err(Error::InvalidInput("unsupported elliptic curve"));
+        };
+        vec![
+          ("crv", crv.to_string()),
+          ("kty", "EC".to_string()),
+          ("x", der::base64_encode(&point[1..1 + width], true)),
+          ("y", der::base64_encode(&point[1 + width..], true)),
+        ]
+      }
+      CKK_EC_EDWARDS => {
+        let params = ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?;
+        let mut reader = Reader::new(&params);
+        let is_ed25519 = match reader.read_any()? {
+          (der::TAG_OID, _, full) => Reader::new(full).read_oid()? == OID_ED25519,
+          (der::TAG_PRINTABLE_STRING, name, _) => name == ED25519_NAME.as_bytes(),
+          _ => false,
+        };
+        if !is_ed25519 {
+          return Err(Error::InvalidInput("only Ed25519 keys can be exported"));
+        }
+        let point = ctx.get_attribute_bytes(session, key, CKA_EC_POINT)?;
+        let mut reader = Reader::new(&point);
+        let raw = match reader.read(der::TAG_OCTET_STRING) {
+          Ok(inner) if reader.is_empty() && inner.len() == 32 => inner.to_vec(),
+          _ => point.clone(),
+        };
+        vec![("crv", "Ed25519".to_string()), ("kty", "OKP".to_string()), ("x", der::base64_encode(&raw, true))]
+      }
+      _ => return Err(Error::InvalidInput("key type cannot be exported as JWK")),
+    };
+    let mut jwk = Jwk { members, kid: String::new() };
+    jwk.kid = match key_id {
+      KeyId::CkaId => {
+        let id = ctx.get_attribute_bytes(session, key, CKA_ID)?;
+        if id.is_empty() {
+          jwk.thumbprint()
+        } else {
+          id.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+      }
+      KeyId::Thumbprint => jwk.thumbprint(),
+    };
+    Ok(jwk)
+  }
+
+  pub fn kid(&self) -> &str {
+    &self.kid
+  }
+
+  /// The value of a required member such as `kty`, `n` or `crv`.
+  pub fn member(&self, name: &str) -> Option<&str> {
+    self.members.iter().find(|&&(n, _)| n == name).map(|(_, v)| &v[..])
+  }
+
+  /// The RFC 7638 thumbprint: base64url SHA-256 over the canonical required members.
+  pub fn thumbprint(&self) -> String {
+    let canonical = self.members.iter().map(|&(n, ref v)| format!("{}:{}", json_escape(n), json_escape(v))).collect::<Vec<_>>().join(",");
+    der::base64_encode(&DigestAlgorithm::Sha256.hash(format!("{{{}}}", canonical).as_bytes()), true)
+  }
+
+  /// The JWK as JSON object, including `kid`, and `alg` if given.
+  pub fn to_json(&self, alg: Option<Algorithm>) -> String {
+    let mut members: Vec<String> = self.members.iter().map(|&(n, ref v)| format!("{}:{}", json_escape(n), json_escape(v))).collect();
+    members.push(format!("\"kid\":{}", json_escape(&self.kid)));
+    if let Some(alg) = alg {
+      members.push(format!("\"alg\":{}", json_escape(alg.name())));
+      members.push("\"use\":\"sig\"".to_string());
+    }
+    format!("{{{}}}", members.join(","))
+  }
+}
@@ -0,0 +1,534 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key generation without hand written templates.
+//!
+//! [`RsaKeyPair`], [`EcKeyPair`], [`AesKey`], [`HmacKey`] and [`Des3Key`]
+//! put together the mechanism and the templates for `Ctx::generate_key_pair`
+//! and `Ctx::generate_key`, and hand back typed handles:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::keygen::{AesKey, EcKeyPair, RsaKeyPair};
+//! # let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! # let session = 1;
+//! let rsa = RsaKeyPair::new(3072).label("signer").id(&[1]).sign(true).generate(&ctx, session).unwrap();
+//! let ec = EcKeyPair::p256().label("tls").generate(&ctx, session).unwrap();
+//! let aes = AesKey::new(256).label("backup").wrap(true).generate(&ctx, session).unwrap();
+//! println!("{:?} {:?} {:?}", rsa.private, ec.public, aes);
+//! ```
+//!
+//! Unless told otherwise, keys are token objects, and private and secret keys
+//! are private, sensitive and not extractable. Public keys are not private,
+//! so they can be read without logging in. Each builder allows one use by
+//! default: signing for key pairs and HMAC keys, encryption for AES and DES3
+//! keys. Other uses have to be asked for.
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+use std::ptr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::Ctx;
+use der;
+use errors::Error;
+use types::*;
+use x509;
+
+/// The named curves of [`EcKeyPair`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "lowercase"))]
+pub enum Curve {
+  P256,
+  P384,
+  P521,
+}
+
+impl Curve {
+  pub fn oid(self) -> &'static [u64] {
+    match self {
+      Curve::P256 => x509::OID_SECP256R1,
+      Curve::P384 => x509::OID_SECP384R1,
+      Curve::P521 => x509::OID_SECP521R1,
+    }
+  }
+}
+
+/// The public half of a generated key pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(CK_OBJECT_HANDLE);
+
+/// The private half of a generated key pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PrivateKey(CK_OBJECT_HANDLE);
+
+/// A generated secret key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SecretKey(CK_OBJECT_HANDLE);
+
+/// The handles of a generated key pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyPair {
+  pub public: PublicKey,
+  pub private: PrivateKey,
+}
+
+macro_rules! handle {
+  ($handle:ident) => {
+    impl $handle {
+      pub fn handle(self) -> CK_OBJECT_HANDLE {
+        self.0
+      }
+    }
+
+    impl From<$handle> for CK_OBJECT_HANDLE {
+      fn from(key: $handle) -> CK_OBJECT_HANDLE {
+        key.0
+      }
+    }
+  };
+}
+
+handle!(PublicKey);
+handle!(PrivateKey);
+handle!(SecretKey);
+
+/// What all keys have in common.
+#[derive(Debug, Clone)]
+struct Options {
+  label: Option<String>,
+  id: Vec<u8>,
+  token: bool,
+  private: bool,
+  sensitive: bool,
+  extractable: bool,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      label: None,
+      id: Vec::new(),
+      token: true,
+      private: true,
+      sensitive: true,
+      extractable: false,
+    }
+  }
+}
+
+impl Options {
+  /// `CKA_TOKEN`, `CKA_LABEL` and `CKA_ID`, for the public and the private
+  /// template alike.
+  fn common(&self) -> Vec<CK_ATTRIBUTE> {
+    let mut template = vec![flag(CKA_TOKEN, self.token)];
+    if let Some(ref label) = self.label {
+      template.push(CK_ATTRIBUTE::new(CKA_LABEL).with_string(label));
+    }
+    if !self.id.is_empty() {
+      template.push(CK_ATTRIBUTE::new(CKA_ID).with_bytes(&self.id));
+    }
+    template
+  }
+
+  /// The common attributes and the protection of a private or secret key.
+  fn secret(&self) -> Vec<CK_ATTRIBUTE> {
+    let mut template = self.common();
+    template.extend(vec![
+      flag(CKA_PRIVATE, self.private),
+      flag(CKA_SENSITIVE, self.sensitive),
+      flag(CKA_EXTRACTABLE, self.extractable),
+    ]);
+    template
+  }
+
+  fn public(&self) -> Vec<CK_ATTRIBUTE> {
+    let mut template = self.common();
+    template.push(flag(CKA_PRIVATE, false));
+    template
+  }
+}
+
+fn flag(attribute: CK_ATTRIBUTE_TYPE, on: bool) -> CK_ATTRIBUTE {
+  CK_ATTRIBUTE::new(attribute).with_bool(if on { &CK_TRUE } else { &CK_FALSE })
+}
+
+fn mechanism(mechanism: CK_MECHANISM_TYPE) -> CK_MECHANISM {
+  CK_MECHANISM {
+    mechanism,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  }
+}
+
+macro_rules! options {
+  ($builder:ident) => {
+    impl $builder {
+      /// `CKA_LABEL`, none by default.
+      pub fn label(mut self, label: &str) -> Self {
+        self.options.label = Some(label.to_string());
+        self
+      }
+
+      /// `CKA_ID`, none by default.
+      pub fn id(mut self, id: &[u8]) -> Self {
+        self.options.id = id.to_vec();
+        self
+      }
+
+      /// `CKA_TOKEN`, `true` by default. Session objects are gone with the
+      /// session.
+      pub fn token(mut self, token: bool) -> Self {
+        self.options.token = token;
+        self
+      }
+
+      /// `CKA_PRIVATE` of the private or secret key, `true` by default.
+      pub fn private(mut self, private: bool) -> Self {
+        self.options.private = private;
+        self
+      }
+
+      /// `CKA_SENSITIVE` of the private or secret key, `true` by default.
+      pub fn sensitive(mut self, sensitive: bool) -> Self {
+        self.options.sensitive = sensitive;
+        self
+      }
+
+      /// `CKA_EXTRACTABLE` of the private or secret key, `false` by default.
+      pub fn extractable(mut self, extractable: bool) -> Self {
+        self.options.extractable = extractable;
+        self
+      }
+    }
+  };
+}
+
+/// An RSA key pair, with the public exponent 65537 unless set.
+#[derive(Debug, Clone)]
+pub struct RsaKeyPair {
+  bits: CK_ULONG,
+  exponent: Vec<u8>,
+  sign: bool,
+  decrypt: bool,
+  wrap: bool,
+  options: Options,
+}
+
+options!(RsaKeyPair);
+
+impl RsaKeyPair {
+  pub fn new(bits: CK_ULONG) -> RsaKeyPair {
+    RsaKeyPair {
+      bits,
+      exponent: vec![0x01, 0x00, 0x01],
+      sign: true,
+      decrypt: false,
+      wrap: false,
+      options: Options::default(),
+    }
+  }
+
+  /// The public exponent, big endian.
+  pub fn public_exponent(mut self, exponent: &[u8]) -> RsaKeyPair {
+    self.exponent = exponent.to_vec();
+    self
+  }
+
+  /// Signing and verifying, `true` by default.
+  pub fn sign(mut self, sign: bool) -> RsaKeyPair {
+    self.sign = sign;
+    self
+  }
+
+  /// Encrypting and decrypting, `false` by default.
+  pub fn decrypt(mut self, decrypt: bool) -> RsaKeyPair {
+    self.decrypt = decrypt;
+    self
+  }
+
+  /// Wrapping and unwrapping keys, `false` by default.
+  pub fn wrap(mut self, wrap: bool) -> RsaKeyPair {
+    self.wrap = wrap;
+    self
+  }
+
+  pub fn generate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<KeyPair, Error> {
+    let mut public = self.options.public();
+    public.extend(vec![
+      CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&self.bits),
+      CK_ATTRIBUTE::new(CKA_PUBLIC_EXPONENT).with_bytes(&self.exponent),
+      flag(CKA_VERIFY, self.sign),
+      flag(CKA_ENCRYPT, self.decrypt),
+      flag(CKA_WRAP, self.wrap),
+    ]);
+    let mut private = self.options.secret();
+    private.extend(vec![flag(CKA_SIGN, self.sign), flag(CKA_DECRYPT, self.decrypt), flag(CKA_UNWRAP, self.wrap)]);
+    generate_pair(ctx, session, CKM_RSA_PKCS_KEY_PAIR_GEN, &public, &private)
+  }
+}
+
+/// An EC key pair on a named curve.
+#[derive(Debug, Clone)]
+pub struct EcKeyPair {
+  curve: Curve,
+  sign: bool,
+  derive: bool,
+  options: Options,
+}
+
+options!(EcKeyPair);
+
+impl EcKeyPair {
+  pub fn new(curve: Curve) -> EcKeyPair {
+    EcKeyPair {
+      curve,
+      sign: true,
+      derive: false,
+      options: Options::default(),
+    }
+  }
+
+  pub fn p256() -> EcKeyPair {
+    EcKeyPair::new(Curve::P256)
+  }
+
+  pub fn p384() -> EcKeyPair {
+    EcKeyPair::new(Curve::P384)
+  }
+
+  pub fn p521() -> EcKeyPair {
+    EcKeyPair::new(Curve::P521)
+  }
+
+  /// Signing and verifying, `true` by default.
+  pub fn sign(mut self, sign: bool) -> EcKeyPair {
+    self.sign = sign;
+    self
+  }
+
+  /// Key agreement with ECDH, `false` by default.
+  pub fn derive(mut self, derive: bool) -> EcKeyPair {
+    self.derive = derive;
+    self
+  }
+
+  pub fn generate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<KeyPair, Error> {
+    let params = der::oid(self.curve.oid());
+    let mut public = self.options.public();
+    public.extend(vec![CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(&params), flag(CKA_VERIFY, self.sign)]);
+    let mut private = self.options.secret();
+    private.extend(vec![flag(CKA_SIGN, self.sign), flag(CKA_DERIVE, self.derive)]);
+    generate_pair(ctx, session, CKM_EC_KEY_PAIR_GEN, &public, &private)
+  }
+}
+
+fn generate_pair(ctx: &Ctx, session: CK_SESSION_HANDLE, mechanism_type: CK_MECHANISM_TYPE, public: &[CK_ATTRIBUTE], private: &[CK_ATTRIBUTE]) -> Result<KeyPair, Error> {
+  let (public, private) = ctx.generate_key_pair(session, &mechanism(mechanism_type), public, private)?;
+  Ok(KeyPair {
+    public: PublicKey(public),
+    private: PrivateKey(private),
+  })
+}
+
+/// An AES key of 128, 192 or 256 bits.
+#[derive(Debug, Clone)]
+pub struct AesKey {
+  bits: CK_ULONG,
+  encrypt: bool,
+  wrap: bool,
+  derive: bool,
+  options: Options,
+}
+
+options!(AesKey);
+
+impl AesKey {
+  pub fn new(bits: CK_ULONG) -> AesKey {
+    AesKey {
+      bits,
+      encrypt: true,
+      wrap: false,
+      derive: false,
+      options: Options::default(),
+    }
+  }
+
+  /// Encrypting and decrypting, `true` by default.
+  pub fn encrypt(mut self, encrypt: bool) -> AesKey {
+    self.encrypt = encrypt;
+    self
+  }
+
+  /// Wrapping and unwrapping keys, `false` by default.
+  pub fn wrap(mut self, wrap: bool) -> AesKey {
+    self.wrap = wrap;
+    self
+  }
+
+  /// Deriving other keys, `false` by default.
+  pub fn derive(mut self, derive: bool) -> AesKey {
+    self.derive = derive;
+    self
+  }
+
+  /// Fails with `Error::InvalidInput` for other sizes than 128, 192 and 256
+  /// bits.
+  pub fn generate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<SecretKey, Error> {
+    if self.bits != 128 && self.bits != 192 && self.bits != 256 {
+      return Err(Error::InvalidInput("AES keys have 128, 192 or 256 bits"));
+    }
+    let len = self.bits / 8;
+    let mut template = self.options.secret();
+    template.extend(vec![
+      CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&len),
+      flag(CKA_ENCRYPT, self.encrypt),
+      flag(CKA_DECRYPT, self.encrypt),
+      flag(CKA_WRAP, self.wrap),
+      flag(CKA_UNWRAP, self.wrap),
+      flag(CKA_DERIVE, self.derive),
+    ]);
+    generate_secret(ctx, session, CKM_AES_KEY_GEN, &template)
+  }
+}
+
+/// A generic secret for HMAC, as long as the output of the hash function.
+#[derive(Debug, Clone)]
+pub struct HmacKey {
+  len: CK_ULONG,
+  sign: bool,
+  derive: bool,
+  options: Options,
+}
+
+options!(HmacKey);
+
+impl HmacKey {
+  /// A key of `len` bytes.
+  pub fn new(len: CK_ULONG) -> HmacKey {
+    HmacKey {
+      len,
+      sign: true,
+      derive: false,
+      options: Options::default(),
+    }
+  }
+
+  /// For `CKM_SHA_1_HMAC`.
+  pub fn sha1() -> HmacKey {
+    HmacKey::new(20)
+  }
+
+  /// For `CKM_SHA256_HMAC`.
+  pub fn sha256() -> HmacKey {
+    HmacKey::new(32)
+  }
+
+  /// For `CKM_SHA384_HMAC`.
+  pub fn sha384() -> HmacKey {
+    HmacKey::new(48)
+  }
+
+  /// For `CKM_SHA512_HMAC`.
+  pub fn sha512() -> HmacKey {
+    HmacKey::new(64)
+  }
+
+  /// Signing and verifying, `true` by default.
+  pub fn sign(mut self, sign: bool) -> HmacKey {
+    self.sign = sign;
+    self
+  }
+
+  /// Deriving other keys, `false` by default.
+  pub fn derive(mut self, derive: bool) -> HmacKey {
+    self.derive = derive;
+    self
+  }
+
+  /// Fails with `Error::InvalidInput` for an empty key.
+  pub fn generate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<SecretKey, Error> {
+    if self.len == 0 {
+      return Err(Error::InvalidInput("HMAC keys need at least one byte"));
+    }
+    let key_type = CKK_GENERIC_SECRET;
+    let mut template = self.options.secret();
+    template.extend(vec![
+      CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+      CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&self.len),
+      flag(CKA_SIGN, self.sign),
+      flag(CKA_VERIFY, self.sign),
+      flag(CKA_DERIVE, self.derive),
+    ]);
+    generate_secret(ctx, session, CKM_GENERIC_SECRET_KEY_GEN, &template)
+  }
+}
+
+/// A triple DES key, for tokens and protocols that still need one.
+#[derive(Debug, Clone)]
+pub struct Des3Key {
+  encrypt: bool,
+  wrap: bool,
+  options: Options,
+}
+
+options!(Des3Key);
+
+impl Default for Des3Key {
+  fn default() -> Des3Key {
+    Des3Key::new()
+  }
+}
+
+impl Des3Key {
+  pub fn new() -> Des3Key {
+    Des3Key {
+      encrypt: true,
+      wrap: false,
+      options: Options::default(),
+    }
+  }
+
+  /// Encrypting and decrypting, `true` by default.
+  pub fn encrypt(mut self, encrypt: bool) -> Des3Key {
+    self.encrypt = encrypt;
+    self
+  }
+
+  /// Wrapping and unwrapping keys, `false` by default.
+  pub fn wrap(mut self, wrap: bool) -> Des3Key {
+    self.wrap = wrap;
+    self
+  }
+
+  pub fn generate(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<SecretKey, Error> {
+    let key_type = CKK_DES3;
+    let mut template = self.options.secret();
+    template.extend(vec![
+      CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+      flag(CKA_ENCRYPT, self.encrypt),
+      flag(CKA_DECRYPT, self.encrypt),
+      flag(CKA_WRAP, self.wrap),
+      flag(CKA_UNWRAP, self.wrap),
+    ]);
+    generate_secret(ctx, session, CKM_DES3_KEY_GEN, &template)
+  }
+}
+
+fn generate_secret(ctx: &Ctx, session: CK_SESSION_HANDLE, mechanism_type: CK_MECHANISM_TYPE, template: &[CK_ATTRIBUTE]) -> Result<SecretKey, Error> {
+  ctx.generate_key(session, &mechanism(mechanism_type), template).map(SecretKey)
+}
@@ -15,6 +15,14 @@
 
 extern crate libloading;
 extern crate num_bigint;
+extern crate sha2;
+extern crate zeroize;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 #[cfg(test)]
 #[macro_use] extern crate serial_test_derive;
@@ -28,18 +36,115 @@ pub mod types;
 pub mod functions;
 /// The error types are defined here - they are used throughout the crate.
 pub mod errors;
+/// Minimal DER, PEM and base64 helpers for the certificate and signature code.
+pub mod der;
+/// X.509 certificate generation and signing with keys that live on a token.
+pub mod x509;
+/// CMS (PKCS#7) SignedData creation and verification with token keys.
+pub mod cms;
+/// JSON Web Signatures and JWK export for keys that live on a token.
+pub mod jose;
+/// Scriptable fault injection for tests, through the mock module or wrapping a real one.
+pub mod fault;
+/// Writing PKCS#11 modules in Rust: a provider trait and the macro exporting it.
+#[macro_use]
+pub mod provider;
+/// Recording PKCS#11 call traces and replaying them without the module, through the spy module.
+pub mod replay;
+/// Names of the PKCS#11 constants, and parsing them from names.
+pub mod names;
+/// Owned, optionally serializable forms of the info structures and an inventory of a whole module.
+pub mod info;
+/// RFC 7512 `pkcs11:` URIs and finding the objects they refer to.
+pub mod uri;
+/// Selecting the slot of a token by label, serial number, manufacturer or model.
+pub mod select;
+/// A pool of logged-in sessions on one token, shared by many threads.
+pub mod pool;
+/// Sessions that reopen, log in again and retry after the token or the session was lost.
+pub mod resilient;
+/// `Ctx` calls as futures, run on a thread pool with one call per session at a time.
+#[cfg(feature = "async")]
+pub mod async_ctx;
+/// Spans around the `Ctx` methods, with the secrets left out.
+#[cfg(feature = "tracing")]
+pub mod trace;
+/// PINs and plaintext that are wiped from memory when dropped.
+pub mod secret;
+/// PINs from the environment, files, commands, callbacks or the PIN pad, and a login that checks the PIN flags first.
+pub mod pin;
+/// Length and complexity rules for new PINs, checked before the module sees them.
+pub mod pin_policy;
+/// Signing and decrypting with `CKA_ALWAYS_AUTHENTICATE` keys, logging in for each operation.
+pub mod auth;
+/// Builders for RSA, EC, AES, HMAC and DES3 keys with safe default templates and typed handles.
+pub mod keygen;
+/// Declarative, repeatable token provisioning: initialization, PINs and baseline objects.
+pub mod provision;
+/// Call counts, errors and latencies of the PKCS#11 functions, exported in the Prometheus text format.
+pub mod metrics;
+/// Loading the modules registered in the p11-kit configuration.
+pub mod p11kit;
 
 use types::*;
 use functions::*;
 use errors::Error;
+use secret::{SecretBytes, SecretPin};
 
 
+use std::collections::HashMap;
+use std::fmt;
 use std::mem;
 use std::path::Path;
 use std::ptr;
-use std::ffi::CString;
+use std::sync::{Arc, Mutex, MutexGuard};
 //use libc::c_uchar;
 
+/// What a call is about, for the [`metrics::CallObserver`].
+#[derive(Clone, Copy)]
+enum On {
+  Module,
+  Slot(CK_SLOT_ID),
+  Session(CK_SESSION_HANDLE),
+}
+
+// Enters a span named after the PKCS#11 function of a `Ctx` method for the
+// rest of the method with the `tracing` feature, see the `trace` module.
+#[cfg(feature = "tracing")]
+macro_rules! traced {
+  ($function:expr, []) => {
+    let _span = trace::Call::enter(tracing::debug_span!(target: "pkcs11", $function, rv = tracing::field::Empty, duration_us = tracing::field::Empty, error = tracing::field::Empty));
+  };
+  ($function:expr, [$($field:tt)+]) => {
+    let _span = trace::Call::enter(tracing::debug_span!(target: "pkcs11", $function, $($field)+, rv = tracing::field::Empty, duration_us = tracing::field::Empty, error = tracing::field::Empty));
+  };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! traced {
+  ($function:expr, [$($field:tt)*]) => {};
+}
+
+// Calls a function of the module, telling the metrics observer about it, see
+// the `metrics` module.
+macro_rules! ffi {
+  ($ctx:ident, $on:expr, $function:ident($($arg:expr),* $(,)*)) => {
+    recorded(metrics::observe($ctx.observed(stringify!($function), $on), || ($ctx.$function)($($arg),*)))
+  };
+}
+
+/// The return value of a call into the module, recorded in the span of the
+/// method with the `tracing` feature.
+#[cfg(feature = "tracing")]
+fn recorded(rv: CK_RV) -> CK_RV {
+  trace::record(rv);
+  rv
+}
+
+#[cfg(not(feature = "tracing"))]
+fn recorded(rv: CK_RV) -> CK_RV {
+  rv
+}
 
 trait CkFrom<T> {
   fn from(T) -> Self;
@@ -64,6 +169,24 @@ impl CkFrom<CK_BBOOL> for bool {
   }
 }
 
+/// A `&str` PIN as a `SecretPin`, refusing nul bytes like a C string would.
+fn str_pin(pin: Option<&str>, nul_error: &'static str) -> Result<Option<SecretPin>, Error> {
+  match pin {
+    Some(pin) if pin.contains('\0') => Err(Error::InvalidInput(nul_error)),
+    Some(pin) => Ok(Some(SecretPin::from(pin))),
+    None => Ok(None),
+  }
+}
+
+/// The pointer and length to pass for a PIN, NULL for the protected
+/// authentication path. The modules only read the PIN.
+fn pin_ptr(pin: Option<&SecretPin>) -> (CK_UTF8CHAR_PTR, CK_ULONG) {
+  match pin {
+    Some(pin) => (pin.as_bytes().as_ptr() as CK_UTF8CHAR_PTR, pin.len() as CK_ULONG),
+    None => (ptr::null_mut(), 0),
+  }
+}
+
 fn label_from_str(label: &str) -> [CK_UTF8CHAR; 32] {
   // initialize a fixed-size array with whitespace characters
   let mut lab: [CK_UTF8CHAR; 32] = [32; 32];
@@ -157,6 +280,19 @@ pub struct Ctx {
   C_CancelFunction: C_CancelFunction,
   // Functions added in for Cryptoki Version 2.01 or later
   C_WaitForSlotEvent: Option<C_WaitForSlotEvent>,
+  observer: Option<Observer>,
+  // the slots of the open sessions, only kept for the observer
+  sessions: Mutex<HashMap<CK_SESSION_HANDLE, CK_SLOT_ID>>,
+}
+
+/// The observer of a `Ctx`, which need not be `Debug`.
+#[derive(Clone)]
+struct Observer(Arc<dyn metrics::CallObserver>);
+
+impl fmt::Debug for Observer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("CallObserver")
+  }
 }
 
 impl Ctx {
@@ -250,6 +386,8 @@ impl Ctx {
         C_CancelFunction: (*list_ptr).C_CancelFunction.ok_or(Error::Module("C_CancelFunction function not found"))?,
         // Functions added in for Cryptoki Version 2.01 or later
         C_WaitForSlotEvent: (*list_ptr).C_WaitForSlotEvent,
+        observer: None,
+        sessions: Mutex::new(HashMap::new()),
       })
     }
   }
@@ -263,6 +401,33 @@ impl Ctx {
     Ok(ctx)
   }
 
+  /// Reports every call into the module to `observer` from now on, see the
+  /// `metrics` module.
+  pub fn set_observer(&mut self, observer: Arc<dyn metrics::CallObserver>) {
+    self.observer = Some(Observer(observer));
+  }
+
+  /// Stops reporting calls.
+  pub fn clear_observer(&mut self) {
+    self.observer = None;
+  }
+
+  // a panicking observer poisons the lock, the map is still usable
+  fn lock_sessions(&self) -> MutexGuard<'_, HashMap<CK_SESSION_HANDLE, CK_SLOT_ID>> {
+    self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// The observer and what to tell it about a call, if there is one.
+  fn observed(&self, function: &'static str, on: On) -> Option<(Arc<dyn metrics::CallObserver>, metrics::CallInfo)> {
+    let observer = self.observer.as_ref()?;
+    let (slot_id, session) = match on {
+      On::Module => (None, None),
+      On::Slot(slot_id) => (Some(slot_id), None),
+      On::Session(session) => (self.lock_sessions().get(&session).cloned(), Some(session)),
+    };
+    Some((observer.0.clone(), metrics::CallInfo { function, slot_id, session }))
+  }
+
   pub fn is_initialized(&self) -> bool {
     self._is_initialized
   }
@@ -284,13 +449,14 @@ impl Ctx {
   }
 
   pub fn initialize(&mut self, init_args: Option<CK_C_INITIALIZE_ARGS>) -> Result<(), Error> {
+    traced!("C_Initialize", []);
     self.not_initialized()?;
     // if no args are specified, library expects NULL
     let init_args = match init_args {
         Some(mut args) => &mut args,
         None => ptr::null_mut()
     };
-    match (self.C_Initialize)(init_args) {
+    match ffi!(self, On::Module, C_Initialize(init_args)) {
       CKR_OK => {
         self._is_initialized = true;
         Ok(())
@@ -300,10 +466,12 @@ impl Ctx {
   }
 
   pub fn finalize(&mut self) -> Result<(), Error> {
+    traced!("C_Finalize", []);
     self.initialized()?;
-    match (self.C_Finalize)(ptr::null_mut()) {
+    match ffi!(self, On::Module, C_Finalize(ptr::null_mut())) {
       CKR_OK => {
         self._is_initialized = false;
+        self.lock_sessions().clear();
         Ok(())
       }
       err => Err(Error::Pkcs11(err)),
@@ -311,26 +479,29 @@ impl Ctx {
   }
 
   pub fn get_info(&self) -> Result<CK_INFO, Error> {
+    traced!("C_GetInfo", []);
     self.initialized()?;
     let mut info = CK_INFO::new();
-    match (self.C_GetInfo)(&mut info) {
+    match ffi!(self, On::Module, C_GetInfo(&mut info)) {
       CKR_OK => Ok(info),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_function_list(&self) -> Result<CK_FUNCTION_LIST, Error> {
+    traced!("C_GetFunctionList", []);
     let mut list = mem::MaybeUninit::uninit();
-    match (self.C_GetFunctionList)(&mut list.as_mut_ptr()) {
+    match ffi!(self, On::Module, C_GetFunctionList(&mut list.as_mut_ptr())) {
       CKR_OK => unsafe { Ok(*list.as_ptr()) },
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_slot_list(&self, token_present: bool) -> Result<Vec<CK_SLOT_ID>, Error> {
+    traced!("C_GetSlotList", []);
     self.initialized()?;
     let mut slots_len: CK_ULONG = 0;
-    match (self.C_GetSlotList)(CkFrom::from(token_present), ptr::null_mut(), &mut slots_len) {
+    match ffi!(self, On::Module, C_GetSlotList(CkFrom::from(token_present), ptr::null_mut(), &mut slots_len)) {
       CKR_OK => {
         // now slots_len contains the number of slots,
         // and we can generate a vector with the right capacity
@@ -339,7 +510,7 @@ impl Ctx {
         // in slots is.
         let mut slots = Vec::<CK_SLOT_ID>::with_capacity(slots_len as usize);
         let slots_ptr = slots.as_mut_ptr();
-        match (self.C_GetSlotList)(CkFrom::from(token_present), slots_ptr, &mut slots_len) {
+        match ffi!(self, On::Module, C_GetSlotList(CkFrom::from(token_present), slots_ptr, &mut slots_len)) {
           CKR_OK => {
             unsafe {
               slots.set_len(slots_len as usize);
@@ -354,32 +525,35 @@ impl Ctx {
   }
 
   pub fn get_slot_info(&self, slot_id: CK_SLOT_ID) -> Result<CK_SLOT_INFO, Error> {
+    traced!("C_GetSlotInfo", [slot_id]);
     self.initialized()?;
     let mut info: CK_SLOT_INFO = Default::default();
-    match (self.C_GetSlotInfo)(slot_id, &mut info) {
+    match ffi!(self, On::Slot(slot_id), C_GetSlotInfo(slot_id, &mut info)) {
       CKR_OK => Ok(info),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_token_info(&self, slot_id: CK_SLOT_ID) -> Result<CK_TOKEN_INFO, Error> {
+    traced!("C_GetTokenInfo", [slot_id]);
     self.initialized()?;
     let mut info: CK_TOKEN_INFO = Default::default();
-    match (self.C_GetTokenInfo)(slot_id, &mut info) {
+    match ffi!(self, On::Slot(slot_id), C_GetTokenInfo(slot_id, &mut info)) {
       CKR_OK => Ok(info),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_mechanism_list(&self, slot_id: CK_SLOT_ID) -> Result<Vec<CK_MECHANISM_TYPE>, Error> {
+    traced!("C_GetMechanismList", [slot_id]);
     self.initialized()?;
     let mut count: CK_ULONG = 0;
-    match (self.C_GetMechanismList)(slot_id, ptr::null_mut(), &mut count) {
+    match ffi!(self, On::Slot(slot_id), C_GetMechanismList(slot_id, ptr::null_mut(), &mut count)) {
       CKR_OK => {
         // see get_slot_list() for an explanation - it works the same way
         let mut list = Vec::<CK_MECHANISM_TYPE>::with_capacity(count as usize);
         let list_ptr = list.as_mut_ptr();
-        match (self.C_GetMechanismList)(slot_id, list_ptr, &mut count) {
+        match ffi!(self, On::Slot(slot_id), C_GetMechanismList(slot_id, list_ptr, &mut count)) {
           CKR_OK => {
             unsafe {
               list.set_len(count as usize);
@@ -394,126 +568,126 @@ impl Ctx {
   }
 
   pub fn get_mechanism_info(&self, slot_id: CK_SLOT_ID, mechanism_type: CK_MECHANISM_TYPE) -> Result<CK_MECHANISM_INFO, Error> {
+    traced!("C_GetMechanismInfo", [slot_id, mechanism = %names::MechanismType::from(mechanism_type)]);
     self.initialized()?;
     let mut info: CK_MECHANISM_INFO = Default::default();
-    match (self.C_GetMechanismInfo)(slot_id, mechanism_type, &mut info) {
+    match ffi!(self, On::Slot(slot_id), C_GetMechanismInfo(slot_id, mechanism_type, &mut info)) {
       CKR_OK => Ok(info),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn init_token<'a, 'b>(&self, slot_id: CK_SLOT_ID, pin: Option<&'a str>, label: &'b str) -> Result<(), Error> {
+    let pin = str_pin(pin, "PIN contains a nul byte")?;
+    self.init_token_secret(slot_id, pin.as_ref(), label)
+  }
+
+  /// `init_token` with a PIN that is wiped once it is no longer needed. No
+  /// PIN means the protected authentication path of the token.
+  pub fn init_token_secret(&self, slot_id: CK_SLOT_ID, pin: Option<&SecretPin>, label: &str) -> Result<(), Error> {
+    traced!("C_InitToken", [slot_id]);
     self.initialized()?;
     let mut formatted_label = label_from_str(label).to_vec();
-    let formatted_label_ptr = formatted_label.as_mut_ptr();
-    match pin {
-      Some(pin) => if let Ok(cpin) = CString::new(pin) {
-        let mut cpin_bytes = cpin.into_bytes();
-        match (self.C_InitToken)(slot_id, cpin_bytes.as_mut_ptr(), cpin_bytes.len() as CK_ULONG, formatted_label_ptr) {
-          CKR_OK => Ok(()),
-          err => Err(Error::Pkcs11(err)),
-        }
-      } else {
-        Err(Error::InvalidInput("PIN contains a nul byte"))
-      },
-      None => {
-        // CKF_PROTECTED_AUTHENTICATION_PATH requires a NULL pointer
-        match (self.C_InitToken)(slot_id, ptr::null_mut(), 0, formatted_label_ptr) {
-          CKR_OK => Ok(()),
-          err => Err(Error::Pkcs11(err)),
-        }
-      }
+    let (pin_ptr, pin_len) = pin_ptr(pin);
+    match ffi!(self, On::Slot(slot_id), C_InitToken(slot_id, pin_ptr, pin_len, formatted_label.as_mut_ptr())) {
+      CKR_OK => Ok(()),
+      err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn init_pin<'a>(&self, session: CK_SESSION_HANDLE, pin: Option<&'a str>) -> Result<(), Error> {
+    let pin = str_pin(pin, "PIN contains a nul byte")?;
+    self.init_pin_secret(session, pin.as_ref())
+  }
+
+  /// `init_pin` with a PIN that is wiped once it is no longer needed.
+  pub fn init_pin_secret(&self, session: CK_SESSION_HANDLE, pin: Option<&SecretPin>) -> Result<(), Error> {
+    traced!("C_InitPIN", [session]);
     self.initialized()?;
-    match pin {
-      Some(pin) => if let Ok(cpin) = CString::new(pin) {
-        let mut cpin_bytes = cpin.into_bytes();
-        match (self.C_InitPIN)(session, cpin_bytes.as_mut_ptr(), cpin_bytes.len() as CK_ULONG) {
-          CKR_OK => Ok(()),
-          err => Err(Error::Pkcs11(err)),
-        }
-      } else {
-        Err(Error::InvalidInput("PIN contains a nul byte"))
-      },
-      None => match (self.C_InitPIN)(session, ptr::null_mut(), 0) {
-        CKR_OK => Ok(()),
-        err => Err(Error::Pkcs11(err)),
-      },
+    let (pin_ptr, pin_len) = pin_ptr(pin);
+    match ffi!(self, On::Session(session), C_InitPIN(session, pin_ptr, pin_len)) {
+      CKR_OK => Ok(()),
+      err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn set_pin<'a, 'b>(&self, session: CK_SESSION_HANDLE, old_pin: Option<&'a str>, new_pin: Option<&'b str>) -> Result<(), Error> {
+    let old_pin = str_pin(old_pin, "Old PIN contains a nul byte")?;
+    let new_pin = str_pin(new_pin, "New PIN contains a nul byte")?;
+    self.set_pin_secret(session, old_pin.as_ref(), new_pin.as_ref())
+  }
+
+  /// `set_pin` with PINs that are wiped once they are no longer needed.
+  pub fn set_pin_secret(&self, session: CK_SESSION_HANDLE, old_pin: Option<&SecretPin>, new_pin: Option<&SecretPin>) -> Result<(), Error> {
+    traced!("C_SetPIN", [session]);
     self.initialized()?;
-    if old_pin.is_none() && new_pin.is_none() {
-      match (self.C_SetPIN)(session, ptr::null_mut(), 0, ptr::null_mut(), 0) {
-        CKR_OK => Ok(()),
-        err => Err(Error::Pkcs11(err)),
-      }
-    } else if old_pin.is_some() && new_pin.is_some() {
-      let old_cpin_res = CString::new(old_pin.unwrap());
-      let new_cpin_res = CString::new(new_pin.unwrap());
-      if old_cpin_res.is_err() {
-        return Err(Error::InvalidInput("Old PIN contains a nul byte"));
-      }
-      if new_cpin_res.is_err() {
-        return Err(Error::InvalidInput("New PIN contains a nul byte"));
-      }
-      let mut old_cpin = old_cpin_res.unwrap().into_bytes();
-      let mut new_cpin = new_cpin_res.unwrap().into_bytes();
-      match (self.C_SetPIN)(session, old_cpin.as_mut_ptr(), old_cpin.len() as CK_ULONG, new_cpin.as_mut_ptr(), new_cpin.len() as CK_ULONG) {
-        CKR_OK => Ok(()),
-        err => Err(Error::Pkcs11(err)),
-      }
-    } else {
-      Err(Error::InvalidInput("both PINs must be either set or unset"))
+    if old_pin.is_some() != new_pin.is_some() {
+      return Err(Error::InvalidInput("both PINs must be either set or unset"));
+    }
+    let (old_ptr, old_len) = pin_ptr(old_pin);
+    let (new_ptr, new_len) = pin_ptr(new_pin);
+    match ffi!(self, On::Session(session), C_SetPIN(session, old_ptr, old_len, new_ptr, new_len)) {
+      CKR_OK => Ok(()),
+      err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn open_session(&self, slot_id: CK_SLOT_ID, flags: CK_FLAGS, application: Option<CK_VOID_PTR>, notify: CK_NOTIFY) -> Result<CK_SESSION_HANDLE, Error> {
+    traced!("C_OpenSession", [slot_id, flags]);
     self.initialized()?;
     let mut session: CK_SESSION_HANDLE = 0;
-    match (self.C_OpenSession)(slot_id, flags, application.unwrap_or(ptr::null_mut()), notify, &mut session) {
-      CKR_OK => Ok(session),
+    match ffi!(self, On::Slot(slot_id), C_OpenSession(slot_id, flags, application.unwrap_or(ptr::null_mut()), notify, &mut session)) {
+      CKR_OK => {
+        self.lock_sessions().insert(session, slot_id);
+        Ok(session)
+      }
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn close_session(&self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+    traced!("C_CloseSession", [session]);
     self.initialized()?;
-    match (self.C_CloseSession)(session) {
+    let rv = ffi!(self, On::Session(session), C_CloseSession(session));
+    // a handle the module does not know any more is gone either way
+    self.lock_sessions().remove(&session);
+    match rv {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn close_all_sessions(&self, slot_id: CK_SLOT_ID) -> Result<(), Error> {
+    traced!("C_CloseAllSessions", [slot_id]);
     self.initialized()?;
-    match (self.C_CloseAllSessions)(slot_id) {
-      CKR_OK => Ok(()),
+    match ffi!(self, On::Slot(slot_id), C_CloseAllSessions(slot_id)) {
+      CKR_OK => {
+        self.lock_sessions().retain(|_, slot| *slot != slot_id);
+        Ok(())
+      }
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_session_info(&self, session: CK_SESSION_HANDLE) -> Result<CK_SESSION_INFO, Error> {
+    traced!("C_GetSessionInfo", [session]);
     self.initialized()?;
     let mut info: CK_SESSION_INFO = Default::default();
-    match (self.C_GetSessionInfo)(session, &mut info) {
+    match ffi!(self, On::Session(session), C_GetSessionInfo(session, &mut info)) {
       CKR_OK => Ok(info),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_operation_state(&self, session: CK_SESSION_HANDLE) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_GetOperationState", [session]);
     self.initialized()?;
     let mut state_length: CK_ULONG = 0;
-    match (self.C_GetOperationState)(session, ptr::null_mut(), &mut state_length) {
+    match ffi!(self, On::Session(session), C_GetOperationState(session, ptr::null_mut(), &mut state_length)) {
       CKR_OK => {
         let mut state: Vec<CK_BYTE> = Vec::with_capacity(state_length as usize);
         let state_ptr = state.as_mut_ptr();
-        match (self.C_GetOperationState)(session, state_ptr, &mut state_length) {
+        match ffi!(self, On::Session(session), C_GetOperationState(session, state_ptr, &mut state_length)) {
           CKR_OK => {
             unsafe {
               state.set_len(state_length as usize);
@@ -534,30 +708,29 @@ impl Ctx {
     encryption_key: Option<CK_OBJECT_HANDLE>,
     authentication_key: Option<CK_OBJECT_HANDLE>,
   ) -> Result<(), Error> {
+    traced!("C_SetOperationState", [session, operation_state_len = operation_state.len()]);
     self.initialized()?;
     let mut operation_state = operation_state;
-    match (self.C_SetOperationState)(session, operation_state.as_mut_ptr(), operation_state.len() as CK_ULONG, encryption_key.unwrap_or(0), authentication_key.unwrap_or(0)) {
+    match ffi!(self, On::Session(session), C_SetOperationState(session, operation_state.as_mut_ptr(), operation_state.len() as CK_ULONG, encryption_key.unwrap_or(0), authentication_key.unwrap_or(0))) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn login<'a>(&self, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, pin: Option<&'a str>) -> Result<(), Error> {
+    let pin = str_pin(pin, "PIN contains a nul byte")?;
+    self.login_secret(session, user_type, pin.as_ref())
+  }
+
+  /// `login` with a PIN that is wiped once it is no longer needed. No PIN
+  /// means the protected authentication path of the token.
+  pub fn login_secret(&self, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, pin: Option<&SecretPin>) -> Result<(), Error> {
+    traced!("C_Login", [session, user_type = %names::UserType::from(user_type)]);
     self.initialized()?;
-    match pin {
-      Some(pin) => if let Ok(cpin) = CString::new(pin) {
-        let mut cpin_bytes = cpin.into_bytes();
-        match (self.C_Login)(session, user_type, cpin_bytes.as_mut_ptr(), cpin_bytes.len() as CK_ULONG) {
-          CKR_OK => Ok(()),
-          err => Err(Error::Pkcs11(err)),
-        }
-      } else {
-        Err(Error::InvalidInput("PIN contains a nul byte"))
-      },
-      None => match (self.C_Login)(session, user_type, ptr::null_mut(), 0) {
-        CKR_OK => Ok(()),
-        err => Err(Error::Pkcs11(err)),
-      },
+    let (pin_ptr, pin_len) = pin_ptr(pin);
+    match ffi!(self, On::Session(session), C_Login(session, user_type, pin_ptr, pin_len)) {
+      CKR_OK => Ok(()),
+      err => Err(Error::Pkcs11(err)),
     }
   }
 
@@ -566,68 +739,62 @@ impl Ctx {
   /// algorithms which insert NULs into the PIN, you might need a way to supply
   /// raw bytes for a PIN, instead of converting from a UTF8 string as per spec
   pub fn login_with_raw(&self, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, pin: Option<&[CK_BYTE]>) -> Result<(), Error> {
-    self.initialized()?;
-    match pin {
-      Some(pin) => {
-        let mut pin = pin.to_vec();
-        match (self.C_Login)(session, user_type, pin.as_mut_ptr(), pin.len() as CK_ULONG) {
-          CKR_OK => Ok(()),
-          err => Err(Error::Pkcs11(err)),
-        }
-      },
-      None => match (self.C_Login)(session, user_type, ptr::null_mut(), 0) {
-        CKR_OK => Ok(()),
-        err => Err(Error::Pkcs11(err)),
-      },
-    }
+    let pin = pin.map(|pin| SecretPin::from_bytes(pin.to_vec()));
+    self.login_secret(session, user_type, pin.as_ref())
   }
 
   pub fn logout(&self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+    traced!("C_Logout", [session]);
     self.initialized()?;
-    match (self.C_Logout)(session) {
+    match ffi!(self, On::Session(session), C_Logout(session)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn create_object(&self, session: CK_SESSION_HANDLE, template: &[CK_ATTRIBUTE]) -> Result<CK_OBJECT_HANDLE, Error> {
+    traced!("C_CreateObject", [session, template_len = template.len()]);
     self.initialized()?;
     let mut template = template.to_vec();
     let mut oh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_CreateObject)(session, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh) {
+    match ffi!(self, On::Session(session), C_CreateObject(session, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh)) {
       CKR_OK => Ok(oh),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn copy_object(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, template: &[CK_ATTRIBUTE]) -> Result<CK_OBJECT_HANDLE, Error> {
+    traced!("C_CopyObject", [session, object, template_len = template.len()]);
     self.initialized()?;
     let mut template = template.to_vec();
     let mut oh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_CopyObject)(session, object, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh) {
+    match ffi!(self, On::Session(session), C_CopyObject(session, object, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh)) {
       CKR_OK => Ok(oh),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn destroy_object(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_DestroyObject", [session, object]);
     self.initialized()?;
-    match (self.C_DestroyObject)(session, object) {
+    match ffi!(self, On::Session(session), C_DestroyObject(session, object)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_object_size(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE) -> Result<CK_ULONG, Error> {
+    traced!("C_GetObjectSize", [session, object]);
     self.initialized()?;
     let mut size: CK_ULONG = 0;
-    match (self.C_GetObjectSize)(session, object, &mut size) {
+    match ffi!(self, On::Session(session), C_GetObjectSize(session, object, &mut size)) {
       CKR_OK => Ok(size),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn get_attribute_value<'a>(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, template: &'a mut Vec<CK_ATTRIBUTE>) -> Result<(CK_RV, &'a Vec<CK_ATTRIBUTE>), Error> {
+    traced!("C_GetAttributeValue", [session, object, template_len = template.len()]);
     self.initialized()?;
     /*
       Note that the error codes CKR_ATTRIBUTE_SENSITIVE, CKR_ATTRIBUTE_TYPE_INVALID, and CKR_BUFFER_TOO_SMALL
@@ -636,38 +803,79 @@ impl Ctx {
       C_GetAttributeValue.  Each attribute in the template whose value can be returned by the call to
       C_GetAttributeValue will be returned by the call to C_GetAttributeValue.
     */
-    match (self.C_GetAttributeValue)(session, object, template.as_mut_ptr(), template.len() as CK_ULONG) {
-      CKR_OK => Ok((CKR_OK, template)),
-      CKR_ATTRIBUTE_SENSITIVE => Ok((CKR_ATTRIBUTE_SENSITIVE, template)),
-      CKR_ATTRIBUTE_TYPE_INVALID => Ok((CKR_ATTRIBUTE_TYPE_INVALID, template)),
-      CKR_BUFFER_TOO_SMALL => Ok((CKR_BUFFER_TOO_SMALL, template)),
+    match ffi!(self, On::Session(session), C_GetAttributeValue(session, object, template.as_mut_ptr(), template.len() as CK_ULONG)) {
+      CKR_OK => Ok((CKR_OK, &*template)),
+      CKR_ATTRIBUTE_SENSITIVE => Ok((CKR_ATTRIBUTE_SENSITIVE, &*template)),
+      CKR_ATTRIBUTE_TYPE_INVALID => Ok((CKR_ATTRIBUTE_TYPE_INVALID, &*template)),
+      CKR_BUFFER_TOO_SMALL => Ok((CKR_BUFFER_TOO_SMALL, &*template)),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
+  /// Reads the value of a single attribute. The first call determines the
+  /// length, the second one fetches the value. Sensitive or invalid attributes
+  /// are reported as the respective `Error::Pkcs11` code.
+  pub fn get_attribute_bytes(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Result<Vec<CK_BYTE>, Error> {
+    self.get_attribute_secret(session, object, attr_type).map(SecretBytes::into_vec)
+  }
+
+  /// `get_attribute_bytes` with the value wiped when dropped, for the
+  /// `CKA_VALUE` of extractable secret keys and the like.
+  pub fn get_attribute_secret(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Result<SecretBytes, Error> {
+    let mut template = vec![CK_ATTRIBUTE::new(attr_type)];
+    match self.get_attribute_value(session, object, &mut template)? {
+      (CKR_OK, _) => (),
+      (rv, _) => return Err(Error::Pkcs11(rv)),
+    }
+    let mut value = SecretBytes(vec![0; template[0].ulValueLen as usize]);
+    template[0].pValue = value.0.as_mut_ptr() as CK_VOID_PTR;
+    match self.get_attribute_value(session, object, &mut template)? {
+      (CKR_OK, _) => {
+        value.0.truncate(template[0].ulValueLen as usize);
+        Ok(value)
+      }
+      (rv, _) => Err(Error::Pkcs11(rv)),
+    }
+  }
+
+  /// Reads a single `CK_ULONG` attribute such as `CKA_CLASS` or `CKA_KEY_TYPE`.
+  pub fn get_attribute_ulong(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Result<CK_ULONG, Error> {
+    let mut val: CK_ULONG = 0;
+    let mut template = vec![CK_ATTRIBUTE::new(attr_type)];
+    template[0].pValue = &mut val as *mut CK_ULONG as CK_VOID_PTR;
+    template[0].ulValueLen = mem::size_of::<CK_ULONG>() as CK_ULONG;
+    match self.get_attribute_value(session, object, &mut template)? {
+      (CKR_OK, _) => Ok(val),
+      (rv, _) => Err(Error::Pkcs11(rv)),
+    }
+  }
+
   pub fn set_attribute_value(&self, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE, template: &[CK_ATTRIBUTE]) -> Result<(), Error> {
+    traced!("C_SetAttributeValue", [session, object, template_len = template.len()]);
     self.initialized()?;
     let mut template = template.to_vec();
-    match (self.C_SetAttributeValue)(session, object, template.as_mut_ptr(), template.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_SetAttributeValue(session, object, template.as_mut_ptr(), template.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn find_objects_init(&self, session: CK_SESSION_HANDLE, template: &[CK_ATTRIBUTE]) -> Result<(), Error> {
+    traced!("C_FindObjectsInit", [session, template_len = template.len()]);
     self.initialized()?;
     let mut template = template.to_vec();
-    match (self.C_FindObjectsInit)(session, template.as_mut_ptr(), template.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_FindObjectsInit(session, template.as_mut_ptr(), template.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn find_objects(&self, session: CK_SESSION_HANDLE, max_object_count: CK_ULONG) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
+    traced!("C_FindObjects", [session, max_object_count]);
     self.initialized()?;
     let mut list: Vec<CK_OBJECT_HANDLE> = Vec::with_capacity(max_object_count as usize);
     let mut count: CK_ULONG = 0;
-    match (self.C_FindObjects)(session, list.as_mut_ptr(), max_object_count, &mut count) {
+    match ffi!(self, On::Session(session), C_FindObjects(session, list.as_mut_ptr(), max_object_count, &mut count)) {
       CKR_OK => {
         unsafe {
           list.set_len(count as usize);
@@ -679,30 +887,33 @@ impl Ctx {
   }
 
   pub fn find_objects_final(&self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+    traced!("C_FindObjectsFinal", [session]);
     self.initialized()?;
-    match (self.C_FindObjectsFinal)(session) {
+    match ffi!(self, On::Session(session), C_FindObjectsFinal(session)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn encrypt_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_EncryptInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_EncryptInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_EncryptInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn encrypt(&self, session: CK_SESSION_HANDLE, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_Encrypt", [session, data_len = data.len()]);
     self.initialized()?;
     let mut data = data.to_vec();
     let mut encryptedDataLen: CK_ULONG = 0;
-    match (self.C_Encrypt)(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut encryptedDataLen) {
+    match ffi!(self, On::Session(session), C_Encrypt(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut encryptedDataLen)) {
       CKR_OK => {
         let mut encryptedData: Vec<CK_BYTE> = Vec::with_capacity(encryptedDataLen as usize);
-        match (self.C_Encrypt)(session, data.as_mut_ptr(), data.len() as CK_ULONG, encryptedData.as_mut_ptr(), &mut encryptedDataLen) {
+        match ffi!(self, On::Session(session), C_Encrypt(session, data.as_mut_ptr(), data.len() as CK_ULONG, encryptedData.as_mut_ptr(), &mut encryptedDataLen)) {
           CKR_OK => {
             unsafe {
               encryptedData.set_len(encryptedDataLen as usize);
@@ -717,13 +928,14 @@ impl Ctx {
   }
 
   pub fn encrypt_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_EncryptUpdate", [session, part_len = part.len()]);
     self.initialized()?;
     let mut part = part.to_vec();
     let mut encryptedPartLen: CK_ULONG = 0;
-    match (self.C_EncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen) {
+    match ffi!(self, On::Session(session), C_EncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen)) {
       CKR_OK => {
         let mut encryptedPart: Vec<CK_BYTE> = Vec::with_capacity(encryptedPartLen as usize);
-        match (self.C_EncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen) {
+        match ffi!(self, On::Session(session), C_EncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen)) {
           CKR_OK => {
             unsafe {
               encryptedPart.set_len(encryptedPartLen as usize);
@@ -738,15 +950,16 @@ impl Ctx {
   }
 
   pub fn encrypt_final(&self, session: CK_SESSION_HANDLE) -> Result<Option<Vec<CK_BYTE>>, Error> {
+    traced!("C_EncryptFinal", [session]);
     self.initialized()?;
     let mut lastEncryptedPartLen: CK_ULONG = 0;
-    match (self.C_EncryptFinal)(session, ptr::null_mut(), &mut lastEncryptedPartLen) {
+    match ffi!(self, On::Session(session), C_EncryptFinal(session, ptr::null_mut(), &mut lastEncryptedPartLen)) {
       CKR_OK => {
         if lastEncryptedPartLen == 0 {
           Ok(None)
         } else {
           let mut lastEncryptedPart: Vec<CK_BYTE> = Vec::with_capacity(lastEncryptedPartLen as usize);
-          match (self.C_EncryptFinal)(session, lastEncryptedPart.as_mut_ptr(), &mut lastEncryptedPartLen) {
+          match ffi!(self, On::Session(session), C_EncryptFinal(session, lastEncryptedPart.as_mut_ptr(), &mut lastEncryptedPartLen)) {
             CKR_OK => {
               unsafe {
                 lastEncryptedPart.set_len(lastEncryptedPartLen as usize);
@@ -762,25 +975,32 @@ impl Ctx {
   }
 
   pub fn decrypt_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_DecryptInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_DecryptInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_DecryptInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn decrypt(&self, session: CK_SESSION_HANDLE, encryptedData: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.decrypt_secret(session, encryptedData).map(SecretBytes::into_vec)
+  }
+
+  /// `decrypt` with the plaintext wiped when dropped.
+  pub fn decrypt_secret(&self, session: CK_SESSION_HANDLE, encryptedData: &[CK_BYTE]) -> Result<SecretBytes, Error> {
+    traced!("C_Decrypt", [session, encrypted_data_len = encryptedData.len()]);
     self.initialized()?;
     let mut encrypted_data = encryptedData.to_vec();
     let mut dataLen: CK_ULONG = 0;
-    match (self.C_Decrypt)(session, encrypted_data.as_mut_ptr(), encrypted_data.len() as CK_ULONG, ptr::null_mut(), &mut dataLen) {
+    match ffi!(self, On::Session(session), C_Decrypt(session, encrypted_data.as_mut_ptr(), encrypted_data.len() as CK_ULONG, ptr::null_mut(), &mut dataLen)) {
       CKR_OK => {
-        let mut data: Vec<CK_BYTE> = Vec::with_capacity(dataLen as usize);
-        match (self.C_Decrypt)(session, encrypted_data.as_mut_ptr(), encrypted_data.len() as CK_ULONG, data.as_mut_ptr(), &mut dataLen) {
+        let mut data = SecretBytes(Vec::with_capacity(dataLen as usize));
+        match ffi!(self, On::Session(session), C_Decrypt(session, encrypted_data.as_mut_ptr(), encrypted_data.len() as CK_ULONG, data.0.as_mut_ptr(), &mut dataLen)) {
           CKR_OK => {
             unsafe {
-              data.set_len(dataLen as usize);
+              data.0.set_len(dataLen as usize);
             }
             Ok(data)
           },
@@ -792,16 +1012,22 @@ impl Ctx {
   }
 
   pub fn decrypt_update(&self, session: CK_SESSION_HANDLE, encryptedPart: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.decrypt_update_secret(session, encryptedPart).map(SecretBytes::into_vec)
+  }
+
+  /// `decrypt_update` with the plaintext wiped when dropped.
+  pub fn decrypt_update_secret(&self, session: CK_SESSION_HANDLE, encryptedPart: &[CK_BYTE]) -> Result<SecretBytes, Error> {
+    traced!("C_DecryptUpdate", [session, encrypted_part_len = encryptedPart.len()]);
     self.initialized()?;
     let mut encrypted_part = encryptedPart.to_vec();
     let mut partLen: CK_ULONG = 0;
-    match (self.C_DecryptUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen) {
+    match ffi!(self, On::Session(session), C_DecryptUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen)) {
       CKR_OK => {
-        let mut part: Vec<CK_BYTE> = Vec::with_capacity(partLen as usize);
-        match (self.C_DecryptUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.as_mut_ptr(), &mut partLen) {
+        let mut part = SecretBytes(Vec::with_capacity(partLen as usize));
+        match ffi!(self, On::Session(session), C_DecryptUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.0.as_mut_ptr(), &mut partLen)) {
           CKR_OK => {
             unsafe {
-              part.set_len(partLen as usize);
+              part.0.set_len(partLen as usize);
             }
             Ok(part)
           },
@@ -813,18 +1039,24 @@ impl Ctx {
   }
 
   pub fn decrypt_final(&self, session: CK_SESSION_HANDLE) -> Result<Option<Vec<CK_BYTE>>, Error> {
+    Ok(self.decrypt_final_secret(session)?.map(SecretBytes::into_vec))
+  }
+
+  /// `decrypt_final` with the plaintext wiped when dropped.
+  pub fn decrypt_final_secret(&self, session: CK_SESSION_HANDLE) -> Result<Option<SecretBytes>, Error> {
+    traced!("C_DecryptFinal", [session]);
     self.initialized()?;
     let mut lastPartLen: CK_ULONG = 0;
-    match (self.C_DecryptFinal)(session, ptr::null_mut(), &mut lastPartLen) {
+    match ffi!(self, On::Session(session), C_DecryptFinal(session, ptr::null_mut(), &mut lastPartLen)) {
       CKR_OK => {
         if lastPartLen == 0 {
           Ok(None)
         } else {
-          let mut lastPart: Vec<CK_BYTE> = Vec::with_capacity(lastPartLen as usize);
-          match (self.C_DecryptFinal)(session, lastPart.as_mut_ptr(), &mut lastPartLen) {
+          let mut lastPart = SecretBytes(Vec::with_capacity(lastPartLen as usize));
+          match ffi!(self, On::Session(session), C_DecryptFinal(session, lastPart.0.as_mut_ptr(), &mut lastPartLen)) {
             CKR_OK => {
               unsafe {
-                lastPart.set_len(lastPartLen as usize);
+                lastPart.0.set_len(lastPartLen as usize);
               }
               Ok(Some(lastPart))
             },
@@ -837,22 +1069,24 @@ impl Ctx {
   }
 
   pub fn digest_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM) -> Result<(), Error> {
+    traced!("C_DigestInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism)]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_DigestInit)(session, &mut mechanism) {
+    match ffi!(self, On::Session(session), C_DigestInit(session, &mut mechanism)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn digest(&self, session: CK_SESSION_HANDLE, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_Digest", [session, data_len = data.len()]);
     self.initialized()?;
     let mut data = data.to_vec();
     let mut digestLen: CK_ULONG = 0;
-    match (self.C_Digest)(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut digestLen) {
+    match ffi!(self, On::Session(session), C_Digest(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut digestLen)) {
       CKR_OK => {
         let mut digest: Vec<CK_BYTE> = Vec::with_capacity(digestLen as usize);
-        match (self.C_Digest)(session, data.as_mut_ptr(), data.len() as CK_ULONG, digest.as_mut_ptr(), &mut digestLen) {
+        match ffi!(self, On::Session(session), C_Digest(session, data.as_mut_ptr(), data.len() as CK_ULONG, digest.as_mut_ptr(), &mut digestLen)) {
           CKR_OK => {
             unsafe {
               digest.set_len(digestLen as usize);
@@ -867,28 +1101,31 @@ impl Ctx {
   }
 
   pub fn digest_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_DigestUpdate", [session, part_len = part.len()]);
     let mut part = part.to_vec();
-    match (self.C_DigestUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_DigestUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn digest_key(&self, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_DigestKey", [session, key]);
     self.initialized()?;
-    match (self.C_DigestKey)(session, key) {
+    match ffi!(self, On::Session(session), C_DigestKey(session, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn digest_final(&self, session: CK_SESSION_HANDLE) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_DigestFinal", [session]);
     self.initialized()?;
     let mut digestLen: CK_ULONG = 0;
-    match (self.C_DigestFinal)(session, ptr::null_mut(), &mut digestLen) {
+    match ffi!(self, On::Session(session), C_DigestFinal(session, ptr::null_mut(), &mut digestLen)) {
       CKR_OK => {
         let mut digest: Vec<CK_BYTE> = Vec::with_capacity(digestLen as usize);
-        match (self.C_DigestFinal)(session, digest.as_mut_ptr(), &mut digestLen) {
+        match ffi!(self, On::Session(session), C_DigestFinal(session, digest.as_mut_ptr(), &mut digestLen)) {
           CKR_OK => {
             unsafe {
               digest.set_len(digestLen as usize);
@@ -903,22 +1140,24 @@ impl Ctx {
   }
 
   pub fn sign_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_SignInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_SignInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_SignInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn sign(&self, session: CK_SESSION_HANDLE, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_Sign", [session, data_len = data.len()]);
     self.initialized()?;
     let mut data = data.to_vec();
     let mut signatureLen: CK_ULONG = 0;
-    match (self.C_Sign)(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut signatureLen) {
+    match ffi!(self, On::Session(session), C_Sign(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut signatureLen)) {
       CKR_OK => {
         let mut signature: Vec<CK_BYTE> = Vec::with_capacity(signatureLen as usize);
-        match (self.C_Sign)(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), &mut signatureLen) {
+        match ffi!(self, On::Session(session), C_Sign(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), &mut signatureLen)) {
           CKR_OK => {
             unsafe {
               signature.set_len(signatureLen as usize);
@@ -933,21 +1172,23 @@ impl Ctx {
   }
 
   pub fn sign_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_SignUpdate", [session, part_len = part.len()]);
     self.initialized()?;
     let mut part = part.to_vec();
-    match (self.C_SignUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_SignUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn sign_final(&self, session: CK_SESSION_HANDLE) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_SignFinal", [session]);
     self.initialized()?;
     let mut signatureLen: CK_ULONG = 0;
-    match (self.C_SignFinal)(session, ptr::null_mut(), &mut signatureLen) {
+    match ffi!(self, On::Session(session), C_SignFinal(session, ptr::null_mut(), &mut signatureLen)) {
       CKR_OK => {
         let mut signature: Vec<CK_BYTE> = Vec::with_capacity(signatureLen as usize);
-        match (self.C_SignFinal)(session, signature.as_mut_ptr(), &mut signatureLen) {
+        match ffi!(self, On::Session(session), C_SignFinal(session, signature.as_mut_ptr(), &mut signatureLen)) {
           CKR_OK => {
             unsafe {
               signature.set_len(signatureLen as usize);
@@ -962,22 +1203,24 @@ impl Ctx {
   }
 
   pub fn sign_recover_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_SignRecoverInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_SignRecoverInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_SignRecoverInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err))
     }
   }
 
   pub fn sign_recover(&self, session: CK_SESSION_HANDLE, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_SignRecover", [session, data_len = data.len()]);
     self.initialized()?;
     let mut data = data.to_vec();
     let mut signatureLen: CK_ULONG = 0;
-    match (self.C_SignRecover)(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut signatureLen) {
+    match ffi!(self, On::Session(session), C_SignRecover(session, data.as_mut_ptr(), data.len() as CK_ULONG, ptr::null_mut(), &mut signatureLen)) {
       CKR_OK => {
         let mut signature: Vec<CK_BYTE> = Vec::with_capacity(signatureLen as usize);
-        match (self.C_SignRecover)(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), &mut signatureLen) {
+        match ffi!(self, On::Session(session), C_SignRecover(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), &mut signatureLen)) {
           CKR_OK => {
             unsafe {
               signature.set_len(signatureLen as usize);
@@ -992,59 +1235,65 @@ impl Ctx {
   }
 
   pub fn verify_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_VerifyInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_VerifyInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_VerifyInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn verify(&self, session: CK_SESSION_HANDLE, data: &[CK_BYTE], signature: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_Verify", [session, data_len = data.len(), signature_len = signature.len()]);
     self.initialized()?;
     let mut data = data.to_vec();
     let mut signature = signature.to_vec();
-    match (self.C_Verify)(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), signature.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_Verify(session, data.as_mut_ptr(), data.len() as CK_ULONG, signature.as_mut_ptr(), signature.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn verify_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_VerifyUpdate", [session, part_len = part.len()]);
     self.initialized()?;
     let mut part = part.to_vec();
-    match (self.C_VerifyUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_VerifyUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn verify_final(&self, session: CK_SESSION_HANDLE, signature: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_VerifyFinal", [session, signature_len = signature.len()]);
     self.initialized()?;
     let mut signature = signature.to_vec();
-    match (self.C_VerifyFinal)(session, signature.as_mut_ptr(), signature.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_VerifyFinal(session, signature.as_mut_ptr(), signature.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn verify_recover_init(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, key: CK_OBJECT_HANDLE) -> Result<(), Error> {
+    traced!("C_VerifyRecoverInit", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
-    match (self.C_VerifyRecoverInit)(session, &mut mechanism, key) {
+    match ffi!(self, On::Session(session), C_VerifyRecoverInit(session, &mut mechanism, key)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn verify_recover(&self, session: CK_SESSION_HANDLE, signature: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_VerifyRecover", [session, signature_len = signature.len()]);
     self.initialized()?;
     let mut signature = signature.to_vec();
     let mut dataLen: CK_ULONG = 0;
-    match (self.C_VerifyRecover)(session, signature.as_mut_ptr(), signature.len() as CK_ULONG, ptr::null_mut(), &mut dataLen) {
+    match ffi!(self, On::Session(session), C_VerifyRecover(session, signature.as_mut_ptr(), signature.len() as CK_ULONG, ptr::null_mut(), &mut dataLen)) {
       CKR_OK => {
         let mut data: Vec<CK_BYTE> = Vec::with_capacity(dataLen as usize);
-        match (self.C_VerifyRecover)(session, signature.as_mut_ptr(), signature.len() as CK_ULONG, data.as_mut_ptr(), &mut dataLen) {
+        match ffi!(self, On::Session(session), C_VerifyRecover(session, signature.as_mut_ptr(), signature.len() as CK_ULONG, data.as_mut_ptr(), &mut dataLen)) {
           CKR_OK => {
             unsafe {
               data.set_len(dataLen as usize);
@@ -1059,13 +1308,14 @@ impl Ctx {
   }
 
   pub fn digest_encrypt_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_DigestEncryptUpdate", [session, part_len = part.len()]);
     self.initialized()?;
     let mut part = part.to_vec();
     let mut encryptedPartLen: CK_ULONG = 0;
-    match (self.C_DigestEncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen) {
+    match ffi!(self, On::Session(session), C_DigestEncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen)) {
       CKR_OK => {
         let mut encryptedPart: Vec<CK_BYTE> = Vec::with_capacity(encryptedPartLen as usize);
-        match (self.C_DigestEncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen) {
+        match ffi!(self, On::Session(session), C_DigestEncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen)) {
           CKR_OK => {
             unsafe {
               encryptedPart.set_len(encryptedPartLen as usize);
@@ -1080,16 +1330,22 @@ impl Ctx {
   }
 
   pub fn decrypt_digest_update(&self, session: CK_SESSION_HANDLE, encryptedPart: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.decrypt_digest_update_secret(session, encryptedPart).map(SecretBytes::into_vec)
+  }
+
+  /// `decrypt_digest_update` with the plaintext wiped when dropped.
+  pub fn decrypt_digest_update_secret(&self, session: CK_SESSION_HANDLE, encryptedPart: &[CK_BYTE]) -> Result<SecretBytes, Error> {
+    traced!("C_DecryptDigestUpdate", [session, encrypted_part_len = encryptedPart.len()]);
     self.initialized()?;
     let mut encrypted_part = encryptedPart.to_vec();
     let mut partLen: CK_ULONG = 0;
-    match (self.C_DecryptDigestUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen) {
+    match ffi!(self, On::Session(session), C_DecryptDigestUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen)) {
       CKR_OK => {
-        let mut part: Vec<CK_BYTE> = Vec::with_capacity(partLen as usize);
-        match (self.C_DecryptDigestUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.as_mut_ptr(), &mut partLen) {
+        let mut part = SecretBytes(Vec::with_capacity(partLen as usize));
+        match ffi!(self, On::Session(session), C_DecryptDigestUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.0.as_mut_ptr(), &mut partLen)) {
           CKR_OK => {
             unsafe {
-              part.set_len(partLen as usize);
+              part.0.set_len(partLen as usize);
             }
             Ok(part)
           },
@@ -1101,13 +1357,14 @@ impl Ctx {
   }
 
   pub fn sign_encrypt_update(&self, session: CK_SESSION_HANDLE, part: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_SignEncryptUpdate", [session, part_len = part.len()]);
     self.initialized()?;
     let mut part = part.to_vec();
     let mut encryptedPartLen: CK_ULONG = 0;
-    match (self.C_SignEncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen) {
+    match ffi!(self, On::Session(session), C_SignEncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, ptr::null_mut(), &mut encryptedPartLen)) {
       CKR_OK => {
         let mut encryptedPart: Vec<CK_BYTE> = Vec::with_capacity(encryptedPartLen as usize);
-        match (self.C_SignEncryptUpdate)(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen) {
+        match ffi!(self, On::Session(session), C_SignEncryptUpdate(session, part.as_mut_ptr(), part.len() as CK_ULONG, encryptedPart.as_mut_ptr(), &mut encryptedPartLen)) {
           CKR_OK => {
             unsafe {
               encryptedPart.set_len(encryptedPartLen as usize);
@@ -1122,16 +1379,22 @@ impl Ctx {
   }
 
   pub fn decrypt_verify_update(&self, session: CK_SESSION_HANDLE, encryptedPart: Vec<CK_BYTE>) -> Result<Vec<CK_BYTE>, Error> {
+    self.decrypt_verify_update_secret(session, encryptedPart).map(SecretBytes::into_vec)
+  }
+
+  /// `decrypt_verify_update` with the plaintext wiped when dropped.
+  pub fn decrypt_verify_update_secret(&self, session: CK_SESSION_HANDLE, encryptedPart: Vec<CK_BYTE>) -> Result<SecretBytes, Error> {
+    traced!("C_DecryptVerifyUpdate", [session, encrypted_part_len = encryptedPart.len()]);
     self.initialized()?;
     let mut encrypted_part = encryptedPart;
     let mut partLen: CK_ULONG = 0;
-    match (self.C_DecryptVerifyUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen) {
+    match ffi!(self, On::Session(session), C_DecryptVerifyUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, ptr::null_mut(), &mut partLen)) {
       CKR_OK => {
-        let mut part: Vec<CK_BYTE> = Vec::with_capacity(partLen as usize);
-        match (self.C_DecryptVerifyUpdate)(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.as_mut_ptr(), &mut partLen) {
+        let mut part = SecretBytes(Vec::with_capacity(partLen as usize));
+        match ffi!(self, On::Session(session), C_DecryptVerifyUpdate(session, encrypted_part.as_mut_ptr(), encrypted_part.len() as CK_ULONG, part.0.as_mut_ptr(), &mut partLen)) {
           CKR_OK => {
             unsafe {
-              part.set_len(partLen as usize);
+              part.0.set_len(partLen as usize);
             }
             Ok(part)
           },
@@ -1143,11 +1406,12 @@ impl Ctx {
   }
 
   pub fn generate_key(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, template: &[CK_ATTRIBUTE]) -> Result<CK_OBJECT_HANDLE, Error> {
+    traced!("C_GenerateKey", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), template_len = template.len()]);
     self.initialized()?;
     let mut mechanism = *mechanism;
     let mut template = template.to_vec();
     let mut object: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_GenerateKey)(session, &mut mechanism, template.as_mut_ptr(), template.len() as CK_ULONG, &mut object) {
+    match ffi!(self, On::Session(session), C_GenerateKey(session, &mut mechanism, template.as_mut_ptr(), template.len() as CK_ULONG, &mut object)) {
       CKR_OK => Ok(object),
       err => Err(Error::Pkcs11(err)),
     }
@@ -1160,13 +1424,14 @@ impl Ctx {
     publicKeyTemplate: &[CK_ATTRIBUTE],
     privateKeyTemplate: &[CK_ATTRIBUTE],
   ) -> Result<(CK_OBJECT_HANDLE, CK_OBJECT_HANDLE), Error> {
+    traced!("C_GenerateKeyPair", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), public_template_len = publicKeyTemplate.len(), private_template_len = privateKeyTemplate.len()]);
     self.initialized()?;
     let mut mechanism = *mechanism;
     let mut public_key_template = publicKeyTemplate.to_vec();
     let mut private_key_template = privateKeyTemplate.to_vec();
     let mut pubOh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
     let mut privOh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_GenerateKeyPair)(
+    match ffi!(self, On::Session(session), C_GenerateKeyPair(
       session,
       &mut mechanism,
       public_key_template.as_mut_ptr(),
@@ -1175,20 +1440,21 @@ impl Ctx {
       private_key_template.len() as CK_ULONG,
       &mut pubOh,
       &mut privOh,
-    ) {
+    )) {
       CKR_OK => Ok((pubOh, privOh)),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn wrap_key(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, wrappingKey: CK_OBJECT_HANDLE, key: CK_OBJECT_HANDLE) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_WrapKey", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), wrapping_key = wrappingKey, key]);
     self.initialized()?;
     let mut mechanism = *mechanism;
     let mut length: CK_ULONG = 0;
-    match (self.C_WrapKey)(session, &mut mechanism, wrappingKey, key, ptr::null_mut(), &mut length) {
+    match ffi!(self, On::Session(session), C_WrapKey(session, &mut mechanism, wrappingKey, key, ptr::null_mut(), &mut length)) {
       CKR_OK => if length > 0 {
         let mut out: Vec<CK_BYTE> = Vec::with_capacity(length as usize);
-        match (self.C_WrapKey)(session, &mut mechanism, wrappingKey, key, out.as_mut_ptr(), &mut length) {
+        match ffi!(self, On::Session(session), C_WrapKey(session, &mut mechanism, wrappingKey, key, out.as_mut_ptr(), &mut length)) {
           CKR_OK => {
             unsafe {
               out.set_len(length as usize);
@@ -1212,12 +1478,13 @@ impl Ctx {
     wrappedKey: &[CK_BYTE],
     template: &[CK_ATTRIBUTE],
   ) -> Result<CK_OBJECT_HANDLE, Error> {
+    traced!("C_UnwrapKey", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), unwrapping_key = unwrappingKey, wrapped_key_len = wrappedKey.len(), template_len = template.len()]);
     self.initialized()?;
     let mut mechanism= *mechanism;
     let mut wrapped_key = wrappedKey.to_vec();
     let mut template = template.to_vec();
     let mut oh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_UnwrapKey)(
+    match ffi!(self, On::Session(session), C_UnwrapKey(
       session,
       &mut mechanism,
       unwrappingKey,
@@ -1226,34 +1493,37 @@ impl Ctx {
       template.as_mut_ptr(),
       template.len() as CK_ULONG,
       &mut oh
-    ) {
+    )) {
       CKR_OK => Ok(oh),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn derive_key(&self, session: CK_SESSION_HANDLE, mechanism: &CK_MECHANISM, baseKey: CK_OBJECT_HANDLE, template: &[CK_ATTRIBUTE]) -> Result<CK_OBJECT_HANDLE, Error> {
+    traced!("C_DeriveKey", [session, mechanism = %names::MechanismType::from(mechanism.mechanism), base_key = baseKey, template_len = template.len()]);
     self.initialized()?;
     let mut mechanism = *mechanism;
     let mut template = template.to_vec();
     let mut oh: CK_OBJECT_HANDLE = CK_INVALID_HANDLE;
-    match (self.C_DeriveKey)(session, &mut mechanism, baseKey, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh) {
+    match ffi!(self, On::Session(session), C_DeriveKey(session, &mut mechanism, baseKey, template.as_mut_ptr(), template.len() as CK_ULONG, &mut oh)) {
       CKR_OK => Ok(oh),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn seed_random(&self, session: CK_SESSION_HANDLE, seed: &[CK_BYTE]) -> Result<(), Error> {
+    traced!("C_SeedRandom", [session, seed_len = seed.len()]);
     let mut seed = seed.to_vec();
-    match (self.C_SeedRandom)(session, seed.as_mut_ptr(), seed.len() as CK_ULONG) {
+    match ffi!(self, On::Session(session), C_SeedRandom(session, seed.as_mut_ptr(), seed.len() as CK_ULONG)) {
       CKR_OK => Ok(()),
       err => Err(Error::Pkcs11(err)),
     }
   }
 
   pub fn generate_random(&self, session: CK_SESSION_HANDLE, randomLength: CK_ULONG) -> Result<Vec<CK_BYTE>, Error> {
+    traced!("C_GenerateRandom", [session, random_len = randomLength]);
     let mut data: Vec<CK_BYTE> = Vec::with_capacity(randomLength as usize);
-    match (self.C_GenerateRandom)(session, data.as_mut_ptr(), randomLength) {
+    match ffi!(self, On::Session(session), C_GenerateRandom(session, data.as_mut_ptr(), randomLength)) {
       CKR_OK => {
         unsafe {
           data.set_len(randomLength as usize);
@@ -1265,7 +1535,8 @@ impl Ctx {
   }
 
   pub fn get_function_status(&self, session: CK_SESSION_HANDLE) -> Result<CK_RV, Error> {
-    match (self.C_GetFunctionStatus)(session) {
+    traced!("C_GetFunctionStatus", [session]);
+    match ffi!(self, On::Session(session), C_GetFunctionStatus(session)) {
       CKR_OK => Ok(CKR_OK),
       CKR_FUNCTION_NOT_PARALLEL => Ok(CKR_FUNCTION_NOT_PARALLEL),
       err => Err(Error::Pkcs11(err)),
@@ -1273,7 +1544,8 @@ impl Ctx {
   }
 
   pub fn cancel_function(&self, session: CK_SESSION_HANDLE) -> Result<CK_RV, Error> {
-    match (self.C_CancelFunction)(session) {
+    traced!("C_CancelFunction", [session]);
+    match ffi!(self, On::Session(session), C_CancelFunction(session)) {
       CKR_OK => Ok(CKR_OK),
       CKR_FUNCTION_NOT_PARALLEL => Ok(CKR_FUNCTION_NOT_PARALLEL),
       err => Err(Error::Pkcs11(err)),
@@ -1281,6 +1553,7 @@ impl Ctx {
   }
 
   pub fn wait_for_slot_event(&self, flags: CK_FLAGS) -> Result<CK_SLOT_ID, Error> {
+    traced!("C_WaitForSlotEvent", [flags]);
     let mut slotID: CK_SLOT_ID = 0;
     let C_WaitForSlotEvent = self.C_WaitForSlotEvent.ok_or(Error::Module("C_WaitForSlotEvent function not found"))?;
     match C_WaitForSlotEvent(flags, &mut slotID, ptr::null_mut()) {
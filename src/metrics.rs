@@ -0,0 +1,261 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Call counts, errors and latencies of the PKCS#11 functions.
+//!
+//! A [`CallObserver`] set with `Ctx::set_observer` is told about every call
+//! into the module, before and after it. Methods such as `Ctx::sign` that
+//! ask for the length first make two calls. Methods that fail before they
+//! reach the module, e.g. on a bad argument, make none. The
+//! [`MetricsCollector`] is an observer that keeps, per function and slot,
+//! the number of calls, the errors by return value and a latency histogram,
+//! and exports them in the Prometheus text format:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use pkcs11::Ctx;
+//! # use pkcs11::metrics::MetricsCollector;
+//! let mut ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! let metrics = Arc::new(MetricsCollector::new());
+//! ctx.set_observer(metrics.clone());
+//! let slots = ctx.get_slot_list(true).unwrap();
+//! print!("{}", metrics.snapshot().to_prometheus());
+//! ```
+//!
+//! Calls on a session count for the slot it was opened on, and calls that
+//! are about no slot, such as `C_GetSlotList`, for none.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use errors::strerror;
+use types::*;
+
+/// What a call into the module is about.
+#[derive(Debug, Clone)]
+pub struct CallInfo {
+  /// The PKCS#11 function, e.g. `C_Sign`.
+  pub function: &'static str,
+  /// The slot, if the call is about one or about a session on one.
+  pub slot_id: Option<CK_SLOT_ID>,
+  /// The session, if the call is about one.
+  pub session: Option<CK_SESSION_HANDLE>,
+}
+
+/// Told about every call a `Ctx` makes into the module.
+///
+/// The observer is called from every thread that uses the `Ctx`, so it should
+/// be quick and must not call the `Ctx` itself.
+pub trait CallObserver: Send + Sync {
+  /// Before the call.
+  fn before(&self, _call: &CallInfo) {}
+
+  /// After the call, with its return value.
+  fn after(&self, call: &CallInfo, rv: CK_RV, elapsed: Duration);
+}
+
+/// Calls `f`, the function of the module, telling the observer about it if
+/// there is one.
+pub(crate) fn observe<F>(observed: Option<(Arc<dyn CallObserver>, CallInfo)>, f: F) -> CK_RV
+where
+  F: FnOnce() -> CK_RV,
+{
+  let (observer, call) = match observed {
+    Some(observed) => observed,
+    None => return f(),
+  };
+  observer.before(&call);
+  let start = Instant::now();
+  let rv = f();
+  observer.after(&call, rv, start.elapsed());
+  rv
+}
+
+/// The upper bounds of the default latency buckets, in seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Counts {
+  calls: u64,
+  errors: BTreeMap<CK_RV, u64>,
+  // not cumulative, one more than there are bounds for the calls above all
+  buckets: Vec<u64>,
+  seconds: f64,
+}
+
+/// A [`CallObserver`] that counts the calls in memory.
+#[derive(Debug)]
+pub struct MetricsCollector {
+  bounds: Vec<f64>,
+  counts: Mutex<HashMap<(&'static str, Option<CK_SLOT_ID>), Counts>>,
+}
+
+impl Default for MetricsCollector {
+  fn default() -> MetricsCollector {
+    MetricsCollector::new()
+  }
+}
+
+impl MetricsCollector {
+  /// A collector with the [`DEFAULT_BUCKETS`].
+  pub fn new() -> MetricsCollector {
+    MetricsCollector::with_buckets(DEFAULT_BUCKETS)
+  }
+
+  /// A collector with latency buckets up to each of `bounds`, in seconds.
+  pub fn with_buckets(bounds: &[f64]) -> MetricsCollector {
+    let mut bounds = bounds.to_vec();
+    bounds.retain(|bound| bound.is_finite());
+    bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    bounds.dedup();
+    MetricsCollector {
+      bounds,
+      counts: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // a panic while the lock is held leaves at most one call half counted
+  fn lock(&self) -> MutexGuard<'_, HashMap<(&'static str, Option<CK_SLOT_ID>), Counts>> {
+    self.counts.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// The counts so far, sorted by function and slot.
+  pub fn snapshot(&self) -> Snapshot {
+    let counts = self.lock();
+    let mut calls: Vec<CallStats> = counts
+      .iter()
+      .map(|(&(function, slot_id), counts)| {
+        let mut total = 0;
+        let buckets = self
+          .bounds
+          .iter()
+          .zip(&counts.buckets)
+          .map(|(&bound, &count)| {
+            total += count;
+            (bound, total)
+          })
+          .collect();
+        CallStats {
+          function,
+          slot_id,
+          calls: counts.calls,
+          errors: counts.errors.clone(),
+          buckets,
+          seconds: counts.seconds,
+        }
+      })
+      .collect();
+    calls.sort_by(|a, b| (a.function, a.slot_id).cmp(&(b.function, b.slot_id)));
+    Snapshot { calls }
+  }
+
+  /// Forgets all counts.
+  pub fn reset(&self) {
+    self.lock().clear();
+  }
+}
+
+impl CallObserver for MetricsCollector {
+  fn after(&self, call: &CallInfo, rv: CK_RV, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let bucket = self.bounds.iter().position(|&bound| seconds <= bound).unwrap_or(self.bounds.len());
+    let mut counts = self.lock();
+    let counts = counts.entry((call.function, call.slot_id)).or_insert_with(|| Counts {
+      buckets: vec![0; self.bounds.len() + 1],
+      ..Counts::default()
+    });
+    counts.calls += 1;
+    counts.buckets[bucket] += 1;
+    counts.seconds += seconds;
+    if rv != CKR_OK {
+      *counts.errors.entry(rv).or_insert(0) += 1;
+    }
+  }
+}
+
+/// The counts of one function on one slot.
+#[derive(Debug, Clone)]
+pub struct CallStats {
+  pub function: &'static str,
+  pub slot_id: Option<CK_SLOT_ID>,
+  /// All calls, failed or not.
+  pub calls: u64,
+  /// The failed calls by return value.
+  pub errors: BTreeMap<CK_RV, u64>,
+  /// The calls that took at most each bound, in seconds, cumulative.
+  pub buckets: Vec<(f64, u64)>,
+  /// How long all calls took together, in seconds.
+  pub seconds: f64,
+}
+
+/// The counts of a [`MetricsCollector`] at one point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+  pub calls: Vec<CallStats>,
+}
+
+impl Snapshot {
+  /// The counts of `function` on `slot_id`, if it was called.
+  pub fn get(&self, function: &str, slot_id: Option<CK_SLOT_ID>) -> Option<&CallStats> {
+    self.calls.iter().find(|stats| stats.function == function && stats.slot_id == slot_id)
+  }
+
+  /// The counts as `pkcs11_calls_total`, `pkcs11_errors_total` and the
+  /// `pkcs11_call_duration_seconds` histogram, labelled with `function` and
+  /// `slot`, which is empty for calls about no slot. The errors are also
+  /// labelled with `rv`, the name of the return value.
+  pub fn to_prometheus(&self) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP pkcs11_calls_total PKCS#11 function calls.\n");
+    out.push_str("# TYPE pkcs11_calls_total counter\n");
+    for stats in &self.calls {
+      let _ = writeln!(out, "pkcs11_calls_total{{{}}} {}", labels(stats), stats.calls);
+    }
+    out.push_str("# HELP pkcs11_errors_total Failed PKCS#11 function calls.\n");
+    out.push_str("# TYPE pkcs11_errors_total counter\n");
+    for stats in &self.calls {
+      for (&rv, count) in &stats.errors {
+        let _ = writeln!(out, "pkcs11_errors_total{{{},rv=\"{}\"}} {}", labels(stats), rv_name(rv), count);
+      }
+    }
+    out.push_str("# HELP pkcs11_call_duration_seconds How long PKCS#11 function calls took.\n");
+    out.push_str("# TYPE pkcs11_call_duration_seconds histogram\n");
+    for stats in &self.calls {
+      let labels = labels(stats);
+      for &(bound, count) in &stats.buckets {
+        let _ = writeln!(out, "pkcs11_call_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+      }
+      let _ = writeln!(out, "pkcs11_call_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.calls);
+      let _ = writeln!(out, "pkcs11_call_duration_seconds_sum{{{}}} {}", labels, stats.seconds);
+      let _ = writeln!(out, "pkcs11_call_duration_seconds_count{{{}}} {}", labels, stats.calls);
+    }
+    out
+  }
+}
+
+fn labels(stats: &CallStats) -> String {
+  match stats.slot_id {
+    Some(slot_id) => format!("function=\"{}\",slot=\"{}\"", stats.function, slot_id),
+    None => format!("function=\"{}\",slot=\"\"", stats.function),
+  }
+}
+
+fn rv_name(rv: CK_RV) -> String {
+  match strerror(rv) {
+    "unknown" => format!("0x{:08X}", rv),
+    name => name.to_string(),
+  }
+}
@@ -0,0 +1,647 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Names of the `CKA_*`, `CKM_*`, `CKO_*`, `CKK_*`, `CKC_*` and `CKU_*` constants.
+//!
+//! The tables map every value of `types` to its name. Where two names share a
+//! value, the later one in `pkcs11t.h` wins, which skips the deprecated
+//! aliases; parsing accepts both. [`MechanismType`], [`AttributeType`],
+//! [`ObjectClass`], [`KeyType`], [`CertificateType`] and [`UserType`] wrap a
+//! value to display it by name and parse it from one, in full or short:
+//!
+//! ```
+//! # use pkcs11::names::MechanismType;
+//! # use pkcs11::types::*;
+//! let mechanism: MechanismType = "sha256-rsa-pkcs".parse().unwrap();
+//! assert_eq!(mechanism.0, CKM_SHA256_RSA_PKCS);
+//! assert_eq!(MechanismType(CKM_ECDSA).to_string(), "CKM_ECDSA");
+//! assert_eq!(MechanismType(0x8000_0001).to_string(), "0x80000001");
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use errors::Error;
+use types::*;
+
+pub const ATTRIBUTES: &[(CK_ATTRIBUTE_TYPE, &str)] = &[
+  (CKA_CLASS, "CKA_CLASS"),
+  (CKA_TOKEN, "CKA_TOKEN"),
+  (CKA_PRIVATE, "CKA_PRIVATE"),
+  (CKA_LABEL, "CKA_LABEL"),
+  (CKA_APPLICATION, "CKA_APPLICATION"),
+  (CKA_VALUE, "CKA_VALUE"),
+  (CKA_OBJECT_ID, "CKA_OBJECT_ID"),
+  (CKA_CERTIFICATE_TYPE, "CKA_CERTIFICATE_TYPE"),
+  (CKA_ISSUER, "CKA_ISSUER"),
+  (CKA_SERIAL_NUMBER, "CKA_SERIAL_NUMBER"),
+  (CKA_AC_ISSUER, "CKA_AC_ISSUER"),
+  (CKA_OWNER, "CKA_OWNER"),
+  (CKA_ATTR_TYPES, "CKA_ATTR_TYPES"),
+  (CKA_TRUSTED, "CKA_TRUSTED"),
+  (CKA_CERTIFICATE_CATEGORY, "CKA_CERTIFICATE_CATEGORY"),
+  (CKA_JAVA_MIDP_SECURITY_DOMAIN, "CKA_JAVA_MIDP_SECURITY_DOMAIN"),
+  (CKA_URL, "CKA_URL"),
+  (CKA_HASH_OF_SUBJECT_PUBLIC_KEY, "CKA_HASH_OF_SUBJECT_PUBLIC_KEY"),
+  (CKA_HASH_OF_ISSUER_PUBLIC_KEY, "CKA_HASH_OF_ISSUER_PUBLIC_KEY"),
+  (CKA_NAME_HASH_ALGORITHM, "CKA_NAME_HASH_ALGORITHM"),
+  (CKA_CHECK_VALUE, "CKA_CHECK_VALUE"),
+  (CKA_KEY_TYPE, "CKA_KEY_TYPE"),
+  (CKA_SUBJECT, "CKA_SUBJECT"),
+  (CKA_ID, "CKA_ID"),
+  (CKA_SENSITIVE, "CKA_SENSITIVE"),
+  (CKA_ENCRYPT, "CKA_ENCRYPT"),
+  (CKA_DECRYPT, "CKA_DECRYPT"),
+  (CKA_WRAP, "CKA_WRAP"),
+  (CKA_UNWRAP, "CKA_UNWRAP"),
+  (CKA_SIGN, "CKA_SIGN"),
+  (CKA_SIGN_RECOVER, "CKA_SIGN_RECOVER"),
+  (CKA_VERIFY, "CKA_VERIFY"),
+  (CKA_VERIFY_RECOVER, "CKA_VERIFY_RECOVER"),
+  (CKA_DERIVE, "CKA_DERIVE"),
+  (CKA_START_DATE, "CKA_START_DATE"),
+  (CKA_END_DATE, "CKA_END_DATE"),
+  (CKA_MODULUS, "CKA_MODULUS"),
+  (CKA_MODULUS_BITS, "CKA_MODULUS_BITS"),
+  (CKA_PUBLIC_EXPONENT, "CKA_PUBLIC_EXPONENT"),
+  (CKA_PRIVATE_EXPONENT, "CKA_PRIVATE_EXPONENT"),
+  (CKA_PRIME_1, "CKA_PRIME_1"),
+  (CKA_PRIME_2, "CKA_PRIME_2"),
+  (CKA_EXPONENT_1, "CKA_EXPONENT_1"),
+  (CKA_EXPONENT_2, "CKA_EXPONENT_2"),
+  (CKA_COEFFICIENT, "CKA_COEFFICIENT"),
+  (CKA_PUBLIC_KEY_INFO, "CKA_PUBLIC_KEY_INFO"),
+  (CKA_PRIME, "CKA_PRIME"),
+  (CKA_SUBPRIME, "CKA_SUBPRIME"),
+  (CKA_BASE, "CKA_BASE"),
+  (CKA_PRIME_BITS, "CKA_PRIME_BITS"),
+  (CKA_SUBPRIME_BITS, "CKA_SUBPRIME_BITS"),
+  (CKA_SUB_PRIME_BITS, "CKA_SUB_PRIME_BITS"),
+  (CKA_VALUE_BITS, "CKA_VALUE_BITS"),
+  (CKA_VALUE_LEN, "CKA_VALUE_LEN"),
+  (CKA_EXTRACTABLE, "CKA_EXTRACTABLE"),
+  (CKA_LOCAL, "CKA_LOCAL"),
+  (CKA_NEVER_EXTRACTABLE, "CKA_NEVER_EXTRACTABLE"),
+  (CKA_ALWAYS_SENSITIVE, "CKA_ALWAYS_SENSITIVE"),
+  (CKA_KEY_GEN_MECHANISM, "CKA_KEY_GEN_MECHANISM"),
+  (CKA_MODIFIABLE, "CKA_MODIFIABLE"),
+  (CKA_COPYABLE, "CKA_COPYABLE"),
+  (CKA_DESTROYABLE, "CKA_DESTROYABLE"),
+  (CKA_ECDSA_PARAMS, "CKA_ECDSA_PARAMS"),
+  (CKA_EC_PARAMS, "CKA_EC_PARAMS"),
+  (CKA_EC_POINT, "CKA_EC_POINT"),
+  (CKA_SECONDARY_AUTH, "CKA_SECONDARY_AUTH"),
+  (CKA_AUTH_PIN_FLAGS, "CKA_AUTH_PIN_FLAGS"),
+  (CKA_ALWAYS_AUTHENTICATE, "CKA_ALWAYS_AUTHENTICATE"),
+  (CKA_WRAP_WITH_TRUSTED, "CKA_WRAP_WITH_TRUSTED"),
+  (CKA_WRAP_TEMPLATE, "CKA_WRAP_TEMPLATE"),
+  (CKA_UNWRAP_TEMPLATE, "CKA_UNWRAP_TEMPLATE"),
+  (CKA_DERIVE_TEMPLATE, "CKA_DERIVE_TEMPLATE"),
+  (CKA_OTP_FORMAT, "CKA_OTP_FORMAT"),
+  (CKA_OTP_LENGTH, "CKA_OTP_LENGTH"),
+  (CKA_OTP_TIME_INTERVAL, "CKA_OTP_TIME_INTERVAL"),
+  (CKA_OTP_USER_FRIENDLY_MODE, "CKA_OTP_USER_FRIENDLY_MODE"),
+  (CKA_OTP_CHALLENGE_REQUIREMENT, "CKA_OTP_CHALLENGE_REQUIREMENT"),
+  (CKA_OTP_TIME_REQUIREMENT, "CKA_OTP_TIME_REQUIREMENT"),
+  (CKA_OTP_COUNTER_REQUIREMENT, "CKA_OTP_COUNTER_REQUIREMENT"),
+  (CKA_OTP_PIN_REQUIREMENT, "CKA_OTP_PIN_REQUIREMENT"),
+  (CKA_OTP_COUNTER, "CKA_OTP_COUNTER"),
+  (CKA_OTP_TIME, "CKA_OTP_TIME"),
+  (CKA_OTP_USER_IDENTIFIER, "CKA_OTP_USER_IDENTIFIER"),
+  (CKA_OTP_SERVICE_IDENTIFIER, "CKA_OTP_SERVICE_IDENTIFIER"),
+  (CKA_OTP_SERVICE_LOGO, "CKA_OTP_SERVICE_LOGO"),
+  (CKA_OTP_SERVICE_LOGO_TYPE, "CKA_OTP_SERVICE_LOGO_TYPE"),
+  (CKA_GOSTR3410_PARAMS, "CKA_GOSTR3410_PARAMS"),
+  (CKA_GOSTR3411_PARAMS, "CKA_GOSTR3411_PARAMS"),
+  (CKA_GOST28147_PARAMS, "CKA_GOST28147_PARAMS"),
+  (CKA_HW_FEATURE_TYPE, "CKA_HW_FEATURE_TYPE"),
+  (CKA_RESET_ON_INIT, "CKA_RESET_ON_INIT"),
+  (CKA_HAS_RESET, "CKA_HAS_RESET"),
+  (CKA_PIXEL_X, "CKA_PIXEL_X"),
+  (CKA_PIXEL_Y, "CKA_PIXEL_Y"),
+  (CKA_RESOLUTION, "CKA_RESOLUTION"),
+  (CKA_CHAR_ROWS, "CKA_CHAR_ROWS"),
+  (CKA_CHAR_COLUMNS, "CKA_CHAR_COLUMNS"),
+  (CKA_COLOR, "CKA_COLOR"),
+  (CKA_BITS_PER_PIXEL, "CKA_BITS_PER_PIXEL"),
+  (CKA_CHAR_SETS, "CKA_CHAR_SETS"),
+  (CKA_ENCODING_METHODS, "CKA_ENCODING_METHODS"),
+  (CKA_MIME_TYPES, "CKA_MIME_TYPES"),
+  (CKA_MECHANISM_TYPE, "CKA_MECHANISM_TYPE"),
+  (CKA_REQUIRED_CMS_ATTRIBUTES, "CKA_REQUIRED_CMS_ATTRIBUTES"),
+  (CKA_DEFAULT_CMS_ATTRIBUTES, "CKA_DEFAULT_CMS_ATTRIBUTES"),
+  (CKA_SUPPORTED_CMS_ATTRIBUTES, "CKA_SUPPORTED_CMS_ATTRIBUTES"),
+  (CKA_ALLOWED_MECHANISMS, "CKA_ALLOWED_MECHANISMS"),
+  (CKA_VENDOR_DEFINED, "CKA_VENDOR_DEFINED"),
+];
+
+pub const MECHANISMS: &[(CK_MECHANISM_TYPE, &str)] = &[
+  (CKM_RSA_PKCS_KEY_PAIR_GEN, "CKM_RSA_PKCS_KEY_PAIR_GEN"),
+  (CKM_RSA_PKCS, "CKM_RSA_PKCS"),
+  (CKM_RSA_9796, "CKM_RSA_9796"),
+  (CKM_RSA_X_509, "CKM_RSA_X_509"),
+  (CKM_MD2_RSA_PKCS, "CKM_MD2_RSA_PKCS"),
+  (CKM_MD5_RSA_PKCS, "CKM_MD5_RSA_PKCS"),
+  (CKM_SHA1_RSA_PKCS, "CKM_SHA1_RSA_PKCS"),
+  (CKM_RIPEMD128_RSA_PKCS, "CKM_RIPEMD128_RSA_PKCS"),
+  (CKM_RIPEMD160_RSA_PKCS, "CKM_RIPEMD160_RSA_PKCS"),
+  (CKM_RSA_PKCS_OAEP, "CKM_RSA_PKCS_OAEP"),
+  (CKM_RSA_X9_31_KEY_PAIR_GEN, "CKM_RSA_X9_31_KEY_PAIR_GEN"),
+  (CKM_RSA_X9_31, "CKM_RSA_X9_31"),
+  (CKM_SHA1_RSA_X9_31, "CKM_SHA1_RSA_X9_31"),
+  (CKM_RSA_PKCS_PSS, "CKM_RSA_PKCS_PSS"),
+  (CKM_SHA1_RSA_PKCS_PSS, "CKM_SHA1_RSA_PKCS_PSS"),
+  (CKM_DSA_KEY_PAIR_GEN, "CKM_DSA_KEY_PAIR_GEN"),
+  (CKM_DSA, "CKM_DSA"),
+  (CKM_DSA_SHA1, "CKM_DSA_SHA1"),
+  (CKM_DSA_SHA224, "CKM_DSA_SHA224"),
+  (CKM_DSA_SHA256, "CKM_DSA_SHA256"),
+  (CKM_DSA_SHA384, "CKM_DSA_SHA384"),
+  (CKM_DSA_SHA512, "CKM_DSA_SHA512"),
+  (CKM_DH_PKCS_KEY_PAIR_GEN, "CKM_DH_PKCS_KEY_PAIR_GEN"),
+  (CKM_DH_PKCS_DERIVE, "CKM_DH_PKCS_DERIVE"),
+  (CKM_X9_42_DH_KEY_PAIR_GEN, "CKM_X9_42_DH_KEY_PAIR_GEN"),
+  (CKM_X9_42_DH_DERIVE, "CKM_X9_42_DH_DERIVE"),
+  (CKM_X9_42_DH_HYBRID_DERIVE, "CKM_X9_42_DH_HYBRID_DERIVE"),
+  (CKM_X9_42_MQV_DERIVE, "CKM_X9_42_MQV_DERIVE"),
+  (CKM_SHA256_RSA_PKCS, "CKM_SHA256_RSA_PKCS"),
+  (CKM_SHA384_RSA_PKCS, "CKM_SHA384_RSA_PKCS"),
+  (CKM_SHA512_RSA_PKCS, "CKM_SHA512_RSA_PKCS"),
+  (CKM_SHA256_RSA_PKCS_PSS, "CKM_SHA256_RSA_PKCS_PSS"),
+  (CKM_SHA384_RSA_PKCS_PSS, "CKM_SHA384_RSA_PKCS_PSS"),
+  (CKM_SHA512_RSA_PKCS_PSS, "CKM_SHA512_RSA_PKCS_PSS"),
+  (CKM_SHA224_RSA_PKCS, "CKM_SHA224_RSA_PKCS"),
+  (CKM_SHA224_RSA_PKCS_PSS, "CKM_SHA224_RSA_PKCS_PSS"),
+  (CKM_SHA512_224, "CKM_SHA512_224"),
+  (CKM_SHA512_224_HMAC, "CKM_SHA512_224_HMAC"),
+  (CKM_SHA512_224_HMAC_GENERAL, "CKM_SHA512_224_HMAC_GENERAL"),
+  (CKM_SHA512_224_KEY_DERIVATION, "CKM_SHA512_224_KEY_DERIVATION"),
+  (CKM_SHA512_256, "CKM_SHA512_256"),
+  (CKM_SHA512_256_HMAC, "CKM_SHA512_256_HMAC"),
+  (CKM_SHA512_256_HMAC_GENERAL, "CKM_SHA512_256_HMAC_GENERAL"),
+  (CKM_SHA512_256_KEY_DERIVATION, "CKM_SHA512_256_KEY_DERIVATION"),
+  (CKM_SHA512_T, "CKM_SHA512_T"),
+  (CKM_SHA512_T_HMAC, "CKM_SHA512_T_HMAC"),
+  (CKM_SHA512_T_HMAC_GENERAL, "CKM_SHA512_T_HMAC_GENERAL"),
+  (CKM_SHA512_T_KEY_DERIVATION, "CKM_SHA512_T_KEY_DERIVATION"),
+  (CKM_RC2_KEY_GEN, "CKM_RC2_KEY_GEN"),
+  (CKM_RC2_ECB, "CKM_RC2_ECB"),
+  (CKM_RC2_CBC, "CKM_RC2_CBC"),
+  (CKM_RC2_MAC, "CKM_RC2_MAC"),
+  (CKM_RC2_MAC_GENERAL, "CKM_RC2_MAC_GENERAL"),
+  (CKM_RC2_CBC_PAD, "CKM_RC2_CBC_PAD"),
+  (CKM_RC4_KEY_GEN, "CKM_RC4_KEY_GEN"),
+  (CKM_RC4, "CKM_RC4"),
+  (CKM_DES_KEY_GEN, "CKM_DES_KEY_GEN"),
+  (CKM_DES_ECB, "CKM_DES_ECB"),
+  (CKM_DES_CBC, "CKM_DES_CBC"),
+  (CKM_DES_MAC, "CKM_DES_MAC"),
+  (CKM_DES_MAC_GENERAL, "CKM_DES_MAC_GENERAL"),
+  (CKM_DES_CBC_PAD, "CKM_DES_CBC_PAD"),
+  (CKM_DES2_KEY_GEN, "CKM_DES2_KEY_GEN"),
+  (CKM_DES3_KEY_GEN, "CKM_DES3_KEY_GEN"),
+  (CKM_DES3_ECB, "CKM_DES3_ECB"),
+  (CKM_DES3_CBC, "CKM_DES3_CBC"),
+  (CKM_DES3_MAC, "CKM_DES3_MAC"),
+  (CKM_DES3_MAC_GENERAL, "CKM_DES3_MAC_GENERAL"),
+  (CKM_DES3_CBC_PAD, "CKM_DES3_CBC_PAD"),
+  (CKM_DES3_CMAC_GENERAL, "CKM_DES3_CMAC_GENERAL"),
+  (CKM_DES3_CMAC, "CKM_DES3_CMAC"),
+  (CKM_CDMF_KEY_GEN, "CKM_CDMF_KEY_GEN"),
+  (CKM_CDMF_ECB, "CKM_CDMF_ECB"),
+  (CKM_CDMF_CBC, "CKM_CDMF_CBC"),
+  (CKM_CDMF_MAC, "CKM_CDMF_MAC"),
+  (CKM_CDMF_MAC_GENERAL, "CKM_CDMF_MAC_GENERAL"),
+  (CKM_CDMF_CBC_PAD, "CKM_CDMF_CBC_PAD"),
+  (CKM_DES_OFB64, "CKM_DES_OFB64"),
+  (CKM_DES_OFB8, "CKM_DES_OFB8"),
+  (CKM_DES_CFB64, "CKM_DES_CFB64"),
+  (CKM_DES_CFB8, "CKM_DES_CFB8"),
+  (CKM_MD2, "CKM_MD2"),
+  (CKM_MD2_HMAC, "CKM_MD2_HMAC"),
+  (CKM_MD2_HMAC_GENERAL, "CKM_MD2_HMAC_GENERAL"),
+  (CKM_MD5, "CKM_MD5"),
+  (CKM_MD5_HMAC, "CKM_MD5_HMAC"),
+  (CKM_MD5_HMAC_GENERAL, "CKM_MD5_HMAC_GENERAL"),
+  (CKM_SHA_1, "CKM_SHA_1"),
+  (CKM_SHA_1_HMAC, "CKM_SHA_1_HMAC"),
+  (CKM_SHA_1_HMAC_GENERAL, "CKM_SHA_1_HMAC_GENERAL"),
+  (CKM_RIPEMD128, "CKM_RIPEMD128"),
+  (CKM_RIPEMD128_HMAC, "CKM_RIPEMD128_HMAC"),
+  (CKM_RIPEMD128_HMAC_GENERAL, "CKM_RIPEMD128_HMAC_GENERAL"),
+  (CKM_RIPEMD160, "CKM_RIPEMD160"),
+  (CKM_RIPEMD160_HMAC, "CKM_RIPEMD160_HMAC"),
+  (CKM_RIPEMD160_HMAC_GENERAL, "CKM_RIPEMD160_HMAC_GENERAL"),
+  (CKM_SHA256, "CKM_SHA256"),
+  (CKM_SHA256_HMAC, "CKM_SHA256_HMAC"),
+  (CKM_SHA256_HMAC_GENERAL, "CKM_SHA256_HMAC_GENERAL"),
+  (CKM_SHA224, "CKM_SHA224"),
+  (CKM_SHA224_HMAC, "CKM_SHA224_HMAC"),
+  (CKM_SHA224_HMAC_GENERAL, "CKM_SHA224_HMAC_GENERAL"),
+  (CKM_SHA384, "CKM_SHA384"),
+  (CKM_SHA384_HMAC, "CKM_SHA384_HMAC"),
+  (CKM_SHA384_HMAC_GENERAL, "CKM_SHA384_HMAC_GENERAL"),
+  (CKM_SHA512, "CKM_SHA512"),
+  (CKM_SHA512_HMAC, "CKM_SHA512_HMAC"),
+  (CKM_SHA512_HMAC_GENERAL, "CKM_SHA512_HMAC_GENERAL"),
+  (CKM_SECURID_KEY_GEN, "CKM_SECURID_KEY_GEN"),
+  (CKM_SECURID, "CKM_SECURID"),
+  (CKM_HOTP_KEY_GEN, "CKM_HOTP_KEY_GEN"),
+  (CKM_HOTP, "CKM_HOTP"),
+  (CKM_ACTI, "CKM_ACTI"),
+  (CKM_ACTI_KEY_GEN, "CKM_ACTI_KEY_GEN"),
+  (CKM_CAST_KEY_GEN, "CKM_CAST_KEY_GEN"),
+  (CKM_CAST_ECB, "CKM_CAST_ECB"),
+  (CKM_CAST_CBC, "CKM_CAST_CBC"),
+  (CKM_CAST_MAC, "CKM_CAST_MAC"),
+  (CKM_CAST_MAC_GENERAL, "CKM_CAST_MAC_GENERAL"),
+  (CKM_CAST_CBC_PAD, "CKM_CAST_CBC_PAD"),
+  (CKM_CAST3_KEY_GEN, "CKM_CAST3_KEY_GEN"),
+  (CKM_CAST3_ECB, "CKM_CAST3_ECB"),
+  (CKM_CAST3_CBC, "CKM_CAST3_CBC"),
+  (CKM_CAST3_MAC, "CKM_CAST3_MAC"),
+  (CKM_CAST3_MAC_GENERAL, "CKM_CAST3_MAC_GENERAL"),
+  (CKM_CAST3_CBC_PAD, "CKM_CAST3_CBC_PAD"),
+  (CKM_CAST5_KEY_GEN, "CKM_CAST5_KEY_GEN"),
+  (CKM_CAST128_KEY_GEN, "CKM_CAST128_KEY_GEN"),
+  (CKM_CAST5_ECB, "CKM_CAST5_ECB"),
+  (CKM_CAST128_ECB, "CKM_CAST128_ECB"),
+  (CKM_CAST5_CBC, "CKM_CAST5_CBC"),
+  (CKM_CAST128_CBC, "CKM_CAST128_CBC"),
+  (CKM_CAST5_MAC, "CKM_CAST5_MAC"),
+  (CKM_CAST128_MAC, "CKM_CAST128_MAC"),
+  (CKM_CAST5_MAC_GENERAL, "CKM_CAST5_MAC_GENERAL"),
+  (CKM_CAST128_MAC_GENERAL, "CKM_CAST128_MAC_GENERAL"),
+  (CKM_CAST5_CBC_PAD, "CKM_CAST5_CBC_PAD"),
+  (CKM_CAST128_CBC_PAD, "CKM_CAST128_CBC_PAD"),
+  (CKM_RC5_KEY_GEN, "CKM_RC5_KEY_GEN"),
+  (CKM_RC5_ECB, "CKM_RC5_ECB"),
+  (CKM_RC5_CBC, "CKM_RC5_CBC"),
+  (CKM_RC5_MAC, "CKM_RC5_MAC"),
+  (CKM_RC5_MAC_GENERAL, "CKM_RC5_MAC_GENERAL"),
+  (CKM_RC5_CBC_PAD, "CKM_RC5_CBC_PAD"),
+  (CKM_IDEA_KEY_GEN, "CKM_IDEA_KEY_GEN"),
+  (CKM_IDEA_ECB, "CKM_IDEA_ECB"),
+  (CKM_IDEA_CBC, "CKM_IDEA_CBC"),
+  (CKM_IDEA_MAC, "CKM_IDEA_MAC"),
+  (CKM_IDEA_MAC_GENERAL, "CKM_IDEA_MAC_GENERAL"),
+  (CKM_IDEA_CBC_PAD, "CKM_IDEA_CBC_PAD"),
+  (CKM_GENERIC_SECRET_KEY_GEN, "CKM_GENERIC_SECRET_KEY_GEN"),
+  (CKM_CONCATENATE_BASE_AND_KEY, "CKM_CONCATENATE_BASE_AND_KEY"),
+  (CKM_CONCATENATE_BASE_AND_DATA, "CKM_CONCATENATE_BASE_AND_DATA"),
+  (CKM_CONCATENATE_DATA_AND_BASE, "CKM_CONCATENATE_DATA_AND_BASE"),
+  (CKM_XOR_BASE_AND_DATA, "CKM_XOR_BASE_AND_DATA"),
+  (CKM_EXTRACT_KEY_FROM_KEY, "CKM_EXTRACT_KEY_FROM_KEY"),
+  (CKM_SSL3_PRE_MASTER_KEY_GEN, "CKM_SSL3_PRE_MASTER_KEY_GEN"),
+  (CKM_SSL3_MASTER_KEY_DERIVE, "CKM_SSL3_MASTER_KEY_DERIVE"),
+  (CKM_SSL3_KEY_AND_MAC_DERIVE, "CKM_SSL3_KEY_AND_MAC_DERIVE"),
+  (CKM_SSL3_MASTER_KEY_DERIVE_DH, "CKM_SSL3_MASTER_KEY_DERIVE_DH"),
+  (CKM_TLS_PRE_MASTER_KEY_GEN, "CKM_TLS_PRE_MASTER_KEY_GEN"),
+  (CKM_TLS_MASTER_KEY_DERIVE, "CKM_TLS_MASTER_KEY_DERIVE"),
+  (CKM_TLS_KEY_AND_MAC_DERIVE, "CKM_TLS_KEY_AND_MAC_DERIVE"),
+  (CKM_TLS_MASTER_KEY_DERIVE_DH, "CKM_TLS_MASTER_KEY_DERIVE_DH"),
+  (CKM_TLS_PRF, "CKM_TLS_PRF"),
+  (CKM_SSL3_MD5_MAC, "CKM_SSL3_MD5_MAC"),
+  (CKM_SSL3_SHA1_MAC, "CKM_SSL3_SHA1_MAC"),
+  (CKM_MD5_KEY_DERIVATION, "CKM_MD5_KEY_DERIVATION"),
+  (CKM_MD2_KEY_DERIVATION, "CKM_MD2_KEY_DERIVATION"),
+  (CKM_SHA1_KEY_DERIVATION, "CKM_SHA1_KEY_DERIVATION"),
+  (CKM_SHA256_KEY_DERIVATION, "CKM_SHA256_KEY_DERIVATION"),
+  (CKM_SHA384_KEY_DERIVATION, "CKM_SHA384_KEY_DERIVATION"),
+  (CKM_SHA512_KEY_DERIVATION, "CKM_SHA512_KEY_DERIVATION"),
+  (CKM_SHA224_KEY_DERIVATION, "CKM_SHA224_KEY_DERIVATION"),
+  (CKM_PBE_MD2_DES_CBC, "CKM_PBE_MD2_DES_CBC"),
+  (CKM_PBE_MD5_DES_CBC, "CKM_PBE_MD5_DES_CBC"),
+  (CKM_PBE_MD5_CAST_CBC, "CKM_PBE_MD5_CAST_CBC"),
+  (CKM_PBE_MD5_CAST3_CBC, "CKM_PBE_MD5_CAST3_CBC"),
+  (CKM_PBE_MD5_CAST5_CBC, "CKM_PBE_MD5_CAST5_CBC"),
+  (CKM_PBE_MD5_CAST128_CBC, "CKM_PBE_MD5_CAST128_CBC"),
+  (CKM_PBE_SHA1_CAST5_CBC, "CKM_PBE_SHA1_CAST5_CBC"),
+  (CKM_PBE_SHA1_CAST128_CBC, "CKM_PBE_SHA1_CAST128_CBC"),
+  (CKM_PBE_SHA1_RC4_128, "CKM_PBE_SHA1_RC4_128"),
+  (CKM_PBE_SHA1_RC4_40, "CKM_PBE_SHA1_RC4_40"),
+  (CKM_PBE_SHA1_DES3_EDE_CBC, "CKM_PBE_SHA1_DES3_EDE_CBC"),
+  (CKM_PBE_SHA1_DES2_EDE_CBC, "CKM_PBE_SHA1_DES2_EDE_CBC"),
+  (CKM_PBE_SHA1_RC2_128_CBC, "CKM_PBE_SHA1_RC2_128_CBC"),
+  (CKM_PBE_SHA1_RC2_40_CBC, "CKM_PBE_SHA1_RC2_40_CBC"),
+  (CKM_PKCS5_PBKD2, "CKM_PKCS5_PBKD2"),
+  (CKM_PBA_SHA1_WITH_SHA1_HMAC, "CKM_PBA_SHA1_WITH_SHA1_HMAC"),
+  (CKM_WTLS_PRE_MASTER_KEY_GEN, "CKM_WTLS_PRE_MASTER_KEY_GEN"),
+  (CKM_WTLS_MASTER_KEY_DERIVE, "CKM_WTLS_MASTER_KEY_DERIVE"),
+  (CKM_WTLS_MASTER_KEY_DERIVE_DH_ECC, "CKM_WTLS_MASTER_KEY_DERIVE_DH_ECC"),
+  (CKM_WTLS_PRF, "CKM_WTLS_PRF"),
+  (CKM_WTLS_SERVER_KEY_AND_MAC_DERIVE, "CKM_WTLS_SERVER_KEY_AND_MAC_DERIVE"),
+  (CKM_WTLS_CLIENT_KEY_AND_MAC_DERIVE, "CKM_WTLS_CLIENT_KEY_AND_MAC_DERIVE"),
+  (CKM_TLS10_MAC_SERVER, "CKM_TLS10_MAC_SERVER"),
+  (CKM_TLS10_MAC_CLIENT, "CKM_TLS10_MAC_CLIENT"),
+  (CKM_TLS12_MAC, "CKM_TLS12_MAC"),
+  (CKM_TLS12_KDF, "CKM_TLS12_KDF"),
+  (CKM_TLS12_MASTER_KEY_DERIVE, "CKM_TLS12_MASTER_KEY_DERIVE"),
+  (CKM_TLS12_KEY_AND_MAC_DERIVE, "CKM_TLS12_KEY_AND_MAC_DERIVE"),
+  (CKM_TLS12_MASTER_KEY_DERIVE_DH, "CKM_TLS12_MASTER_KEY_DERIVE_DH"),
+  (CKM_TLS12_KEY_SAFE_DERIVE, "CKM_TLS12_KEY_SAFE_DERIVE"),
+  (CKM_TLS_MAC, "CKM_TLS_MAC"),
+  (CKM_TLS_KDF, "CKM_TLS_KDF"),
+  (CKM_KEY_WRAP_LYNKS, "CKM_KEY_WRAP_LYNKS"),
+  (CKM_KEY_WRAP_SET_OAEP, "CKM_KEY_WRAP_SET_OAEP"),
+  (CKM_CMS_SIG, "CKM_CMS_SIG"),
+  (CKM_KIP_DERIVE, "CKM_KIP_DERIVE"),
+  (CKM_KIP_WRAP, "CKM_KIP_WRAP"),
+  (CKM_KIP_MAC, "CKM_KIP_MAC"),
+  (CKM_CAMELLIA_KEY_GEN, "CKM_CAMELLIA_KEY_GEN"),
+  (CKM_CAMELLIA_ECB, "CKM_CAMELLIA_ECB"),
+  (CKM_CAMELLIA_CBC, "CKM_CAMELLIA_CBC"),
+  (CKM_CAMELLIA_MAC, "CKM_CAMELLIA_MAC"),
+  (CKM_CAMELLIA_MAC_GENERAL, "CKM_CAMELLIA_MAC_GENERAL"),
+  (CKM_CAMELLIA_CBC_PAD, "CKM_CAMELLIA_CBC_PAD"),
+  (CKM_CAMELLIA_ECB_ENCRYPT_DATA, "CKM_CAMELLIA_ECB_ENCRYPT_DATA"),
+  (CKM_CAMELLIA_CBC_ENCRYPT_DATA, "CKM_CAMELLIA_CBC_ENCRYPT_DATA"),
+  (CKM_CAMELLIA_CTR, "CKM_CAMELLIA_CTR"),
+  (CKM_ARIA_KEY_GEN, "CKM_ARIA_KEY_GEN"),
+  (CKM_ARIA_ECB, "CKM_ARIA_ECB"),
+  (CKM_ARIA_CBC, "CKM_ARIA_CBC"),
+  (CKM_ARIA_MAC, "CKM_ARIA_MAC"),
+  (CKM_ARIA_MAC_GENERAL, "CKM_ARIA_MAC_GENERAL"),
+  (CKM_ARIA_CBC_PAD, "CKM_ARIA_CBC_PAD"),
+  (CKM_ARIA_ECB_ENCRYPT_DATA, "CKM_ARIA_ECB_ENCRYPT_DATA"),
+  (CKM_ARIA_CBC_ENCRYPT_DATA, "CKM_ARIA_CBC_ENCRYPT_DATA"),
+  (CKM_SEED_KEY_GEN, "CKM_SEED_KEY_GEN"),
+  (CKM_SEED_ECB, "CKM_SEED_ECB"),
+  (CKM_SEED_CBC, "CKM_SEED_CBC"),
+  (CKM_SEED_MAC, "CKM_SEED_MAC"),
+  (CKM_SEED_MAC_GENERAL, "CKM_SEED_MAC_GENERAL"),
+  (CKM_SEED_CBC_PAD, "CKM_SEED_CBC_PAD"),
+  (CKM_SEED_ECB_ENCRYPT_DATA, "CKM_SEED_ECB_ENCRYPT_DATA"),
+  (CKM_SEED_CBC_ENCRYPT_DATA, "CKM_SEED_CBC_ENCRYPT_DATA"),
+  (CKM_SKIPJACK_KEY_GEN, "CKM_SKIPJACK_KEY_GEN"),
+  (CKM_SKIPJACK_ECB64, "CKM_SKIPJACK_ECB64"),
+  (CKM_SKIPJACK_CBC64, "CKM_SKIPJACK_CBC64"),
+  (CKM_SKIPJACK_OFB64, "CKM_SKIPJACK_OFB64"),
+  (CKM_SKIPJACK_CFB64, "CKM_SKIPJACK_CFB64"),
+  (CKM_SKIPJACK_CFB32, "CKM_SKIPJACK_CFB32"),
+  (CKM_SKIPJACK_CFB16, "CKM_SKIPJACK_CFB16"),
+  (CKM_SKIPJACK_CFB8, "CKM_SKIPJACK_CFB8"),
+  (CKM_SKIPJACK_WRAP, "CKM_SKIPJACK_WRAP"),
+  (CKM_SKIPJACK_PRIVATE_WRAP, "CKM_SKIPJACK_PRIVATE_WRAP"),
+  (CKM_SKIPJACK_RELAYX, "CKM_SKIPJACK_RELAYX"),
+  (CKM_KEA_KEY_PAIR_GEN, "CKM_KEA_KEY_PAIR_GEN"),
+  (CKM_KEA_KEY_DERIVE, "CKM_KEA_KEY_DERIVE"),
+  (CKM_KEA_DERIVE, "CKM_KEA_DERIVE"),
+  (CKM_FORTEZZA_TIMESTAMP, "CKM_FORTEZZA_TIMESTAMP"),
+  (CKM_BATON_KEY_GEN, "CKM_BATON_KEY_GEN"),
+  (CKM_BATON_ECB128, "CKM_BATON_ECB128"),
+  (CKM_BATON_ECB96, "CKM_BATON_ECB96"),
+  (CKM_BATON_CBC128, "CKM_BATON_CBC128"),
+  (CKM_BATON_COUNTER, "CKM_BATON_COUNTER"),
+  (CKM_BATON_SHUFFLE, "CKM_BATON_SHUFFLE"),
+  (CKM_BATON_WRAP, "CKM_BATON_WRAP"),
+  (CKM_ECDSA_KEY_PAIR_GEN, "CKM_ECDSA_KEY_PAIR_GEN"),
+  (CKM_EC_KEY_PAIR_GEN, "CKM_EC_KEY_PAIR_GEN"),
+  (CKM_ECDSA, "CKM_ECDSA"),
+  (CKM_ECDSA_SHA1, "CKM_ECDSA_SHA1"),
+  (CKM_ECDSA_SHA224, "CKM_ECDSA_SHA224"),
+  (CKM_ECDSA_SHA256, "CKM_ECDSA_SHA256"),
+  (CKM_ECDSA_SHA384, "CKM_ECDSA_SHA384"),
+  (CKM_ECDSA_SHA512, "CKM_ECDSA_SHA512"),
+  (CKM_ECDH1_DERIVE, "CKM_ECDH1_DERIVE"),
+  (CKM_ECDH1_COFACTOR_DERIVE, "CKM_ECDH1_COFACTOR_DERIVE"),
+  (CKM_ECMQV_DERIVE, "CKM_ECMQV_DERIVE"),
+  (CKM_ECDH_AES_KEY_WRAP, "CKM_ECDH_AES_KEY_WRAP"),
+  (CKM_RSA_AES_KEY_WRAP, "CKM_RSA_AES_KEY_WRAP"),
+  (CKM_EC_EDWARDS_KEY_PAIR_GEN, "CKM_EC_EDWARDS_KEY_PAIR_GEN"),
+  (CKM_EDDSA, "CKM_EDDSA"),
+  (CKM_JUNIPER_KEY_GEN, "CKM_JUNIPER_KEY_GEN"),
+  (CKM_JUNIPER_ECB128, "CKM_JUNIPER_ECB128"),
+  (CKM_JUNIPER_CBC128, "CKM_JUNIPER_CBC128"),
+  (CKM_JUNIPER_COUNTER, "CKM_JUNIPER_COUNTER"),
+  (CKM_JUNIPER_SHUFFLE, "CKM_JUNIPER_SHUFFLE"),
+  (CKM_JUNIPER_WRAP, "CKM_JUNIPER_WRAP"),
+  (CKM_FASTHASH, "CKM_FASTHASH"),
+  (CKM_AES_KEY_GEN, "CKM_AES_KEY_GEN"),
+  (CKM_AES_ECB, "CKM_AES_ECB"),
+  (CKM_AES_CBC, "CKM_AES_CBC"),
+  (CKM_AES_MAC, "CKM_AES_MAC"),
+  (CKM_AES_MAC_GENERAL, "CKM_AES_MAC_GENERAL"),
+  (CKM_AES_CBC_PAD, "CKM_AES_CBC_PAD"),
+  (CKM_AES_CTR, "CKM_AES_CTR"),
+  (CKM_AES_GCM, "CKM_AES_GCM"),
+  (CKM_AES_CCM, "CKM_AES_CCM"),
+  (CKM_AES_CTS, "CKM_AES_CTS"),
+  (CKM_AES_CMAC, "CKM_AES_CMAC"),
+  (CKM_AES_CMAC_GENERAL, "CKM_AES_CMAC_GENERAL"),
+  (CKM_AES_XCBC_MAC, "CKM_AES_XCBC_MAC"),
+  (CKM_AES_XCBC_MAC_96, "CKM_AES_XCBC_MAC_96"),
+  (CKM_AES_GMAC, "CKM_AES_GMAC"),
+  (CKM_BLOWFISH_KEY_GEN, "CKM_BLOWFISH_KEY_GEN"),
+  (CKM_BLOWFISH_CBC, "CKM_BLOWFISH_CBC"),
+  (CKM_TWOFISH_KEY_GEN, "CKM_TWOFISH_KEY_GEN"),
+  (CKM_TWOFISH_CBC, "CKM_TWOFISH_CBC"),
+  (CKM_BLOWFISH_CBC_PAD, "CKM_BLOWFISH_CBC_PAD"),
+  (CKM_TWOFISH_CBC_PAD, "CKM_TWOFISH_CBC_PAD"),
+  (CKM_DES_ECB_ENCRYPT_DATA, "CKM_DES_ECB_ENCRYPT_DATA"),
+  (CKM_DES_CBC_ENCRYPT_DATA, "CKM_DES_CBC_ENCRYPT_DATA"),
+  (CKM_DES3_ECB_ENCRYPT_DATA, "CKM_DES3_ECB_ENCRYPT_DATA"),
+  (CKM_DES3_CBC_ENCRYPT_DATA, "CKM_DES3_CBC_ENCRYPT_DATA"),
+  (CKM_AES_ECB_ENCRYPT_DATA, "CKM_AES_ECB_ENCRYPT_DATA"),
+  (CKM_AES_CBC_ENCRYPT_DATA, "CKM_AES_CBC_ENCRYPT_DATA"),
+  (CKM_GOSTR3410_KEY_PAIR_GEN, "CKM_GOSTR3410_KEY_PAIR_GEN"),
+  (CKM_GOSTR3410, "CKM_GOSTR3410"),
+  (CKM_GOSTR3410_WITH_GOSTR3411, "CKM_GOSTR3410_WITH_GOSTR3411"),
+  (CKM_GOSTR3410_KEY_WRAP, "CKM_GOSTR3410_KEY_WRAP"),
+  (CKM_GOSTR3410_DERIVE, "CKM_GOSTR3410_DERIVE"),
+  (CKM_GOSTR3411, "CKM_GOSTR3411"),
+  (CKM_GOSTR3411_HMAC, "CKM_GOSTR3411_HMAC"),
+  (CKM_GOST28147_KEY_GEN, "CKM_GOST28147_KEY_GEN"),
+  (CKM_GOST28147_ECB, "CKM_GOST28147_ECB"),
+  (CKM_GOST28147, "CKM_GOST28147"),
+  (CKM_GOST28147_MAC, "CKM_GOST28147_MAC"),
+  (CKM_GOST28147_KEY_WRAP, "CKM_GOST28147_KEY_WRAP"),
+  (CKM_DSA_PARAMETER_GEN, "CKM_DSA_PARAMETER_GEN"),
+  (CKM_DH_PKCS_PARAMETER_GEN, "CKM_DH_PKCS_PARAMETER_GEN"),
+  (CKM_X9_42_DH_PARAMETER_GEN, "CKM_X9_42_DH_PARAMETER_GEN"),
+  (CKM_DSA_PROBABLISTIC_PARAMETER_GEN, "CKM_DSA_PROBABLISTIC_PARAMETER_GEN"),
+  (CKM_DSA_SHAWE_TAYLOR_PARAMETER_GEN, "CKM_DSA_SHAWE_TAYLOR_PARAMETER_GEN"),
+  (CKM_AES_OFB, "CKM_AES_OFB"),
+  (CKM_AES_CFB64, "CKM_AES_CFB64"),
+  (CKM_AES_CFB8, "CKM_AES_CFB8"),
+  (CKM_AES_CFB128, "CKM_AES_CFB128"),
+  (CKM_AES_CFB1, "CKM_AES_CFB1"),
+  (CKM_AES_KEY_WRAP, "CKM_AES_KEY_WRAP"),
+  (CKM_AES_KEY_WRAP_PAD, "CKM_AES_KEY_WRAP_PAD"),
+  (CKM_RSA_PKCS_TPM_1_1, "CKM_RSA_PKCS_TPM_1_1"),
+  (CKM_RSA_PKCS_OAEP_TPM_1_1, "CKM_RSA_PKCS_OAEP_TPM_1_1"),
+  (CKM_VENDOR_DEFINED, "CKM_VENDOR_DEFINED"),
+];
+
+pub const OBJECT_CLASSES: &[(CK_OBJECT_CLASS, &str)] = &[
+  (CKO_DATA, "CKO_DATA"),
+  (CKO_CERTIFICATE, "CKO_CERTIFICATE"),
+  (CKO_PUBLIC_KEY, "CKO_PUBLIC_KEY"),
+  (CKO_PRIVATE_KEY, "CKO_PRIVATE_KEY"),
+  (CKO_SECRET_KEY, "CKO_SECRET_KEY"),
+  (CKO_HW_FEATURE, "CKO_HW_FEATURE"),
+  (CKO_DOMAIN_PARAMETERS, "CKO_DOMAIN_PARAMETERS"),
+  (CKO_MECHANISM, "CKO_MECHANISM"),
+  (CKO_OTP_KEY, "CKO_OTP_KEY"),
+  (CKO_VENDOR_DEFINED, "CKO_VENDOR_DEFINED"),
+];
+
+pub const KEY_TYPES: &[(CK_KEY_TYPE, &str)] = &[
+  (CKK_RSA, "CKK_RSA"),
+  (CKK_DSA, "CKK_DSA"),
+  (CKK_DH, "CKK_DH"),
+  (CKK_ECDSA, "CKK_ECDSA"),
+  (CKK_EC, "CKK_EC"),
+  (CKK_X9_42_DH, "CKK_X9_42_DH"),
+  (CKK_KEA, "CKK_KEA"),
+  (CKK_GENERIC_SECRET, "CKK_GENERIC_SECRET"),
+  (CKK_RC2, "CKK_RC2"),
+  (CKK_RC4, "CKK_RC4"),
+  (CKK_DES, "CKK_DES"),
+  (CKK_DES2, "CKK_DES2"),
+  (CKK_DES3, "CKK_DES3"),
+  (CKK_CAST, "CKK_CAST"),
+  (CKK_CAST3, "CKK_CAST3"),
+  (CKK_CAST5, "CKK_CAST5"),
+  (CKK_CAST128, "CKK_CAST128"),
+  (CKK_RC5, "CKK_RC5"),
+  (CKK_IDEA, "CKK_IDEA"),
+  (CKK_SKIPJACK, "CKK_SKIPJACK"),
+  (CKK_BATON, "CKK_BATON"),
+  (CKK_JUNIPER, "CKK_JUNIPER"),
+  (CKK_CDMF, "CKK_CDMF"),
+  (CKK_AES, "CKK_AES"),
+  (CKK_BLOWFISH, "CKK_BLOWFISH"),
+  (CKK_TWOFISH, "CKK_TWOFISH"),
+  (CKK_SECURID, "CKK_SECURID"),
+  (CKK_HOTP, "CKK_HOTP"),
+  (CKK_ACTI, "CKK_ACTI"),
+  (CKK_CAMELLIA, "CKK_CAMELLIA"),
+  (CKK_ARIA, "CKK_ARIA"),
+  (CKK_MD5_HMAC, "CKK_MD5_HMAC"),
+  (CKK_SHA_1_HMAC, "CKK_SHA_1_HMAC"),
+  (CKK_RIPEMD128_HMAC, "CKK_RIPEMD128_HMAC"),
+  (CKK_RIPEMD160_HMAC, "CKK_RIPEMD160_HMAC"),
+  (CKK_SHA256_HMAC, "CKK_SHA256_HMAC"),
+  (CKK_SHA384_HMAC, "CKK_SHA384_HMAC"),
+  (CKK_SHA512_HMAC, "CKK_SHA512_HMAC"),
+  (CKK_SHA224_HMAC, "CKK_SHA224_HMAC"),
+  (CKK_SEED, "CKK_SEED"),
+  (CKK_GOSTR3410, "CKK_GOSTR3410"),
+  (CKK_GOSTR3411, "CKK_GOSTR3411"),
+  (CKK_GOST28147, "CKK_GOST28147"),
+  (CKK_EC_EDWARDS, "CKK_EC_EDWARDS"),
+  (CKK_VENDOR_DEFINED, "CKK_VENDOR_DEFINED"),
+];
+
+pub const CERTIFICATE_TYPES: &[(CK_CERTIFICATE_TYPE, &str)] = &[
+  (CKC_X_509, "CKC_X_509"),
+  (CKC_X_509_ATTR_CERT, "CKC_X_509_ATTR_CERT"),
+  (CKC_WTLS, "CKC_WTLS"),
+  (CKC_VENDOR_DEFINED, "CKC_VENDOR_DEFINED"),
+];
+
+pub const USER_TYPES: &[(CK_USER_TYPE, &str)] = &[
+  (CKU_SO, "CKU_SO"),
+  (CKU_USER, "CKU_USER"),
+  (CKU_CONTEXT_SPECIFIC, "CKU_CONTEXT_SPECIFIC"),
+];
+
+/// The name of `value` in `table`.
+pub fn lookup(table: &[(CK_ULONG, &'static str)], value: CK_ULONG) -> Option<&'static str> {
+  table.iter().rev().find(|&&(v, _)| v == value).map(|&(_, name)| name)
+}
+
+/// The name of `value` in `table`, or its value in hex.
+pub fn name(table: &[(CK_ULONG, &str)], value: CK_ULONG) -> String {
+  match table.iter().rev().find(|&&(v, _)| v == value) {
+    Some(&(_, name)) => name.to_string(),
+    None => format!("{:#x}", value),
+  }
+}
+
+/// The value of a name in `table`, given in full as "CKM_SHA256_RSA_PKCS" or
+/// without the `prefix` in any case and with dashes as "sha256-rsa-pkcs".
+/// Hex numbers such as "0x80000001" stand for themselves.
+pub fn parse(table: &[(CK_ULONG, &str)], prefix: &str, s: &str) -> Option<CK_ULONG> {
+  if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    return CK_ULONG::from_str_radix(hex, 16).ok();
+  }
+  let name = s.to_uppercase().replace('-', "_");
+  let name = if name.starts_with(prefix) { name } else { format!("{}{}", prefix, name) };
+  table.iter().find(|&&(_, n)| n == name).map(|&(v, _)| v)
+}
+
+macro_rules! named {
+  ($(#[$meta:meta])* $name:ident, $value:ty, $table:ident, $prefix:expr, $err:expr) => {
+    $(#[$meta])*
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct $name(pub $value);
+
+    impl $name {
+      /// The name, if the value has one.
+      pub fn name(&self) -> Option<&'static str> {
+        lookup($table, self.0)
+      }
+    }
+
+    impl fmt::Display for $name {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+          Some(name) => f.write_str(name),
+          None => write!(f, "{:#x}", self.0),
+        }
+      }
+    }
+
+    impl FromStr for $name {
+      type Err = Error;
+
+      fn from_str(s: &str) -> Result<$name, Error> {
+        parse($table, $prefix, s).map($name).ok_or(Error::InvalidInput($err))
+      }
+    }
+
+    impl From<$value> for $name {
+      fn from(value: $value) -> $name {
+        $name(value)
+      }
+    }
+  };
+}
+
+named!(
+  /// A `CKM_*` value.
+  MechanismType, CK_MECHANISM_TYPE, MECHANISMS, "CKM_", "unknown mechanism name"
+);
+named!(
+  /// A `CKA_*` value.
+  AttributeType, CK_ATTRIBUTE_TYPE, ATTRIBUTES, "CKA_", "unknown attribute name"
+);
+named!(
+  /// A `CKO_*` value.
+  ObjectClass, CK_OBJECT_CLASS, OBJECT_CLASSES, "CKO_", "unknown object class name"
+);
+named!(
+  /// A `CKK_*` value.
+  KeyType, CK_KEY_TYPE, KEY_TYPES, "CKK_", "unknown key type name"
+);
+named!(
+  /// A `CKC_*` value.
+  CertificateType, CK_CERTIFICATE_TYPE, CERTIFICATE_TYPES, "CKC_", "unknown certificate type name"
+);
+named!(
+  /// A `CKU_*` value.
+  UserType, CK_USER_TYPE, USER_TYPES, "CKU_", "unknown user type name"
+);
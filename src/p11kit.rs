@@ -0,0 +1,304 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The modules registered with p11-kit, loaded side by side.
+//!
+//! p11-kit reads one `NAME.module` file per module from the package
+//! directory `/usr/share/p11-kit/modules`, the system directory
+//! `/etc/pkcs11/modules` and the user directory `~/.config/pkcs11/modules`,
+//! with lines such as:
+//!
+//! ```text
+//! module: libsofthsm2.so
+//! priority: 10
+//! disable-in: firefox, thunderbird
+//! critical: yes
+//! ```
+//!
+//! [`Config`] finds and parses these files, and [`Config::load`] builds a
+//! `Ctx` for every module enabled for the program:
+//!
+//! ```no_run
+//! # use pkcs11::p11kit::Config;
+//! let modules = Config::new().load().unwrap();
+//! for token in modules.tokens().tokens {
+//!   println!("{}: {}", token.module.config.name, token.info.label);
+//! }
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::Ctx;
+use errors::Error;
+use info::TokenInfo;
+use types::*;
+
+/// Where p11-kit looks for modules given without a directory.
+pub const MODULE_DIR: &str = "/usr/lib/pkcs11";
+
+/// The settings of one module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleConfig {
+  /// The file name without `.module`.
+  pub name: String,
+  pub module: Option<PathBuf>,
+  /// Modules with a higher priority come first.
+  pub priority: i64,
+  /// The only programs that load the module, if set.
+  pub enable_in: Option<Vec<String>>,
+  /// The programs that do not load the module.
+  pub disable_in: Vec<String>,
+  /// Whether a module that fails to load fails the whole loading.
+  pub critical: bool,
+  /// All settings as they are in the file, later ones overriding earlier ones.
+  pub options: Vec<(String, String)>,
+}
+
+fn yes(value: &str) -> Result<bool, Error> {
+  match value {
+    "yes" | "true" => Ok(true),
+    "no" | "false" => Ok(false),
+    _ => Err(Error::InvalidInput("p11-kit boolean is neither yes nor no")),
+  }
+}
+
+fn programs(value: &str) -> Vec<String> {
+  value.split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect()
+}
+
+impl ModuleConfig {
+  /// An empty configuration that only has a name.
+  pub fn new(name: &str) -> ModuleConfig {
+    ModuleConfig {
+      name: name.to_string(),
+      module: None,
+      priority: 0,
+      enable_in: None,
+      disable_in: Vec::new(),
+      critical: false,
+      options: Vec::new(),
+    }
+  }
+
+  /// Parses the text of `name.module`.
+  pub fn parse(name: &str, text: &str) -> Result<ModuleConfig, Error> {
+    let mut config = ModuleConfig::new(name);
+    config.merge(text)?;
+    Ok(config)
+  }
+
+  /// Applies the settings of `text` on top of the current ones, the way a
+  /// user configuration overrides a system one.
+  pub fn merge(&mut self, text: &str) -> Result<(), Error> {
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let (key, value) = line.split_once(':').ok_or(Error::InvalidInput("p11-kit config line is not `key: value`"))?;
+      let (key, value) = (key.trim(), value.trim());
+      match key {
+        "module" => self.module = if value.is_empty() { None } else { Some(PathBuf::from(value)) },
+        "priority" => self.priority = value.parse().map_err(|_| Error::InvalidInput("p11-kit priority is not a number"))?,
+        "enable-in" => self.enable_in = Some(programs(value)),
+        "disable-in" => self.disable_in = programs(value),
+        "critical" => self.critical = yes(value)?,
+        _ => {}
+      }
+      self.options.retain(|(k, _)| k != key);
+      self.options.push((key.to_string(), value.to_string()));
+    }
+    Ok(())
+  }
+
+  /// Whether `program` loads the module.
+  pub fn is_enabled_for(&self, program: &str) -> bool {
+    self.module.is_some() && self.enable_in.as_ref().is_none_or(|p| p.iter().any(|p| p == program)) && !self.disable_in.iter().any(|p| p == program)
+  }
+
+  /// The path of the module, relative ones being in `module_dir`.
+  pub fn module_path(&self, module_dir: &Path) -> Option<PathBuf> {
+    self.module.as_ref().map(|m| if m.is_absolute() { m.clone() } else { module_dir.join(m) })
+  }
+}
+
+/// Where the configuration comes from and whom it is for.
+#[derive(Debug, Clone)]
+pub struct Config {
+  dirs: Vec<PathBuf>,
+  module_dir: PathBuf,
+  program: String,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config::new()
+  }
+}
+
+impl Config {
+  /// The directories p11-kit reads, for the running program.
+  pub fn new() -> Config {
+    let mut dirs = vec![PathBuf::from("/usr/share/p11-kit/modules"), PathBuf::from("/etc/pkcs11/modules")];
+    // p11-kit has the user directory fixed under HOME, not XDG_CONFIG_HOME
+    if let Some(home) = env::var_os("HOME") {
+      dirs.push(Path::new(&home).join(".config/pkcs11/modules"));
+    }
+    let program = env::current_exe().ok().and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned())).unwrap_or_default();
+    Config {
+      dirs,
+      module_dir: PathBuf::from(MODULE_DIR),
+      program,
+    }
+  }
+
+  /// Reads the configuration from `dirs` instead, later directories
+  /// overriding the settings of earlier ones.
+  pub fn with_dirs<P: AsRef<Path>>(dirs: &[P]) -> Config {
+    Config {
+      dirs: dirs.iter().map(|d| d.as_ref().to_path_buf()).collect(),
+      ..Config::new()
+    }
+  }
+
+  /// The directory of modules given without one.
+  pub fn module_dir<P: AsRef<Path>>(mut self, dir: P) -> Config {
+    self.module_dir = dir.as_ref().to_path_buf();
+    self
+  }
+
+  /// The program name `enable-in` and `disable-in` are checked against.
+  pub fn program(mut self, program: &str) -> Config {
+    self.program = program.to_string();
+    self
+  }
+
+  /// All configured modules by priority, enabled or not. Directories that
+  /// do not exist are skipped.
+  pub fn modules(&self) -> Result<Vec<ModuleConfig>, Error> {
+    let mut modules: Vec<ModuleConfig> = Vec::new();
+    for dir in &self.dirs {
+      let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(err) => return Err(err.into()),
+      };
+      let mut files = Vec::new();
+      for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "module") {
+          files.push(path);
+        }
+      }
+      files.sort();
+      for path in files {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let text = fs::read_to_string(&path)?;
+        match modules.iter_mut().find(|m| m.name == name) {
+          Some(config) => config.merge(&text)?,
+          None => modules.push(ModuleConfig::parse(&name, &text)?),
+        }
+      }
+    }
+    modules.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| a.name.cmp(&b.name)));
+    Ok(modules)
+  }
+
+  /// Loads and initializes every module enabled for the program. Modules
+  /// that fail are skipped and reported, unless they are critical.
+  pub fn load(&self) -> Result<Modules, Error> {
+    let mut loaded = Modules {
+      modules: Vec::new(),
+      failed: Vec::new(),
+    };
+    for config in self.modules()?.into_iter().filter(|c| c.is_enabled_for(&self.program)) {
+      let path = config.module_path(&self.module_dir).unwrap_or_default();
+      match Ctx::new_and_initialize(&path) {
+        Ok(ctx) => loaded.modules.push(Module { config, path, ctx }),
+        Err(err) if config.critical => return Err(err),
+        Err(err) => loaded.failed.push((config, err)),
+      }
+    }
+    Ok(loaded)
+  }
+}
+
+/// A loaded module.
+#[derive(Debug)]
+pub struct Module {
+  pub config: ModuleConfig,
+  pub path: PathBuf,
+  pub ctx: Ctx,
+}
+
+/// The loaded modules by priority, and the ones that failed to load.
+#[derive(Debug)]
+pub struct Modules {
+  pub modules: Vec<Module>,
+  pub failed: Vec<(ModuleConfig, Error)>,
+}
+
+/// A token of one of the modules.
+#[derive(Debug)]
+pub struct ModuleToken<'a> {
+  pub module: &'a Module,
+  pub slot_id: CK_SLOT_ID,
+  pub info: TokenInfo,
+}
+
+/// The tokens of the modules by module priority, and the modules whose
+/// tokens could not be listed.
+#[derive(Debug)]
+pub struct ModuleTokens<'a> {
+  pub tokens: Vec<ModuleToken<'a>>,
+  pub failed: Vec<(&'a Module, Error)>,
+}
+
+impl Modules {
+  /// The module of a name, such as the `module-name` of a `pkcs11:` URI.
+  pub fn get(&self, name: &str) -> Option<&Module> {
+    self.modules.iter().find(|m| m.config.name == name)
+  }
+
+  /// The tokens of all modules, by module priority. A module failing to
+  /// list its tokens is reported in `failed` and leaves out none of the
+  /// others.
+  pub fn tokens(&self) -> ModuleTokens<'_> {
+    let mut tokens = ModuleTokens {
+      tokens: Vec::new(),
+      failed: Vec::new(),
+    };
+    for module in &self.modules {
+      match module_tokens(module) {
+        Ok(found) => tokens.tokens.extend(found),
+        Err(err) => tokens.failed.push((module, err)),
+      }
+    }
+    tokens
+  }
+}
+
+fn module_tokens(module: &Module) -> Result<Vec<ModuleToken<'_>>, Error> {
+  let mut tokens = Vec::new();
+  for slot_id in module.ctx.get_slot_list(true)? {
+    tokens.push(ModuleToken {
+      module,
+      slot_id,
+      info: module.ctx.get_token_info(slot_id)?.into(),
+    });
+  }
+  Ok(tokens)
+}
@@ -0,0 +1,279 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where PINs come from, and a login that looks at the token first.
+//!
+//! A [`PinSource`] hands out the PIN for a login: [`EnvPin`] reads an
+//! environment variable, [`FilePin`] a file only its owner can read,
+//! [`CommandPin`] the output of a command, [`CallbackPin`] asks a closure,
+//! e.g. to prompt the user, and [`ProtectedPath`] leaves the PIN to the PIN
+//! pad of the token. [`login`] reads the token flags before it asks:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::pin::{self, CommandPin, PinWarning};
+//! # use pkcs11::types::*;
+//! # let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! # let session = 1;
+//! let source = CommandPin::new("pass").arg("show").arg("hsm/user-pin");
+//! if let Some(PinWarning::FinalTry) = pin::login(&ctx, session, CKU_USER, &source).unwrap() {
+//!   eprintln!("the PIN was wrong before, one more try locks it");
+//! }
+//! ```
+//!
+//! Tokens with `CKF_PROTECTED_AUTHENTICATION_PATH` are always logged in on
+//! the protected path and the source is not asked. A locked PIN fails with
+//! `CKR_PIN_LOCKED` without another attempt; a PIN that was entered wrong
+//! before comes back as a [`PinWarning`] and is passed to the source too.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::Ctx;
+use errors::Error;
+use secret::SecretPin;
+use types::*;
+
+/// A PIN that was entered wrong before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinWarning {
+  /// `CKF_USER_PIN_COUNT_LOW` or `CKF_SO_PIN_COUNT_LOW`.
+  CountLow,
+  /// `CKF_USER_PIN_FINAL_TRY` or `CKF_SO_PIN_FINAL_TRY`: a wrong PIN now
+  /// locks it.
+  FinalTry,
+}
+
+/// What a [`PinSource`] is asked for.
+#[derive(Debug)]
+pub struct PinRequest<'a> {
+  pub slot_id: CK_SLOT_ID,
+  pub user_type: CK_USER_TYPE,
+  pub token: &'a CK_TOKEN_INFO,
+  pub warning: Option<PinWarning>,
+}
+
+/// Hands out PINs.
+pub trait PinSource {
+  /// The PIN to log in with, or `None` for the protected authentication
+  /// path.
+  fn pin(&self, request: &PinRequest) -> Result<Option<SecretPin>, Error>;
+}
+
+impl PinSource for SecretPin {
+  fn pin(&self, _request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    Ok(Some(self.clone()))
+  }
+}
+
+/// The PIN in an environment variable.
+#[derive(Debug, Clone)]
+pub struct EnvPin {
+  name: String,
+}
+
+impl EnvPin {
+  pub fn new(name: &str) -> EnvPin {
+    EnvPin { name: name.to_string() }
+  }
+}
+
+impl PinSource for EnvPin {
+  fn pin(&self, _request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    match env::var(&self.name) {
+      Ok(pin) => Ok(Some(SecretPin::from(pin))),
+      Err(env::VarError::NotPresent) => Err(io::Error::new(io::ErrorKind::NotFound, format!("PIN variable {} is not set", self.name)).into()),
+      Err(env::VarError::NotUnicode(_)) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("PIN variable {} is not UTF-8", self.name)).into()),
+    }
+  }
+}
+
+/// The PIN in the first line of a file.
+///
+/// On Unix, a file the group or others may read or write is refused with
+/// an `Error::Io` of kind `PermissionDenied`.
+#[derive(Debug, Clone)]
+pub struct FilePin {
+  path: PathBuf,
+}
+
+impl FilePin {
+  pub fn new<P: AsRef<Path>>(path: P) -> FilePin {
+    FilePin { path: path.as_ref().to_path_buf() }
+  }
+}
+
+impl PinSource for FilePin {
+  fn pin(&self, _request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    check_permissions(&self.path)?;
+    Ok(Some(first_line(fs::read(&self.path)?)))
+  }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  let mode = fs::metadata(path)?.permissions().mode();
+  if mode & 0o077 != 0 {
+    return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("PIN file {} has mode {:o}, it must not be accessible by group or others", path.display(), mode & 0o777)));
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+/// The PIN a command prints on its first line, e.g. from a password manager.
+///
+/// The command inherits stdin and stderr, so it may prompt. Failing commands
+/// give an `Error::Io`.
+#[derive(Debug, Clone)]
+pub struct CommandPin {
+  program: String,
+  args: Vec<String>,
+}
+
+impl CommandPin {
+  pub fn new(program: &str) -> CommandPin {
+    CommandPin {
+      program: program.to_string(),
+      args: Vec::new(),
+    }
+  }
+
+  pub fn arg(mut self, arg: &str) -> CommandPin {
+    self.args.push(arg.to_string());
+    self
+  }
+}
+
+impl PinSource for CommandPin {
+  fn pin(&self, _request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    let output = Command::new(&self.program).args(&self.args).stdin(Stdio::inherit()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+      // wipe whatever it printed
+      drop(SecretPin::from_bytes(output.stdout));
+      return Err(io::Error::other(format!("PIN command {} failed: {}", self.program, output.status)).into());
+    }
+    Ok(Some(first_line(output.stdout)))
+  }
+}
+
+/// The PIN a closure returns, e.g. after prompting with the token label and
+/// the [`PinWarning`] of the request.
+pub struct CallbackPin<F> {
+  callback: F,
+}
+
+impl<F> CallbackPin<F>
+where
+  F: Fn(&PinRequest) -> Result<SecretPin, Error>,
+{
+  pub fn new(callback: F) -> CallbackPin<F> {
+    CallbackPin { callback }
+  }
+}
+
+impl<F> fmt::Debug for CallbackPin<F> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CallbackPin").finish_non_exhaustive()
+  }
+}
+
+impl<F> PinSource for CallbackPin<F>
+where
+  F: Fn(&PinRequest) -> Result<SecretPin, Error>,
+{
+  fn pin(&self, request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    (self.callback)(request).map(Some)
+  }
+}
+
+/// No PIN: the token asks for it on its own PIN pad or reader.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtectedPath;
+
+impl PinSource for ProtectedPath {
+  fn pin(&self, _request: &PinRequest) -> Result<Option<SecretPin>, Error> {
+    Ok(None)
+  }
+}
+
+/// Everything up to the first line break, which the PIN does not include.
+fn first_line(mut bytes: Vec<u8>) -> SecretPin {
+  if let Some(end) = bytes.iter().position(|&b| b == b'\n') {
+    let end = if end > 0 && bytes[end - 1] == b'\r' { end - 1 } else { end };
+    for b in &mut bytes[end..] {
+      *b = 0;
+    }
+    bytes.truncate(end);
+  }
+  SecretPin::from_bytes(bytes)
+}
+
+/// Whether the PIN of `user_type` is locked or was entered wrong before,
+/// according to the token flags.
+pub fn pin_state(flags: CK_FLAGS, user_type: CK_USER_TYPE) -> Result<Option<PinWarning>, Error> {
+  let (count_low, final_try, locked) = if user_type == CKU_SO {
+    (CKF_SO_PIN_COUNT_LOW, CKF_SO_PIN_FINAL_TRY, CKF_SO_PIN_LOCKED)
+  } else {
+    (CKF_USER_PIN_COUNT_LOW, CKF_USER_PIN_FINAL_TRY, CKF_USER_PIN_LOCKED)
+  };
+  if flags & locked != 0 {
+    Err(Error::Pkcs11(CKR_PIN_LOCKED))
+  } else if flags & final_try != 0 {
+    Ok(Some(PinWarning::FinalTry))
+  } else if flags & count_low != 0 {
+    Ok(Some(PinWarning::CountLow))
+  } else {
+    Ok(None)
+  }
+}
+
+/// Logs `session` in as `user_type`, on the protected authentication path if
+/// the token has one and else with the PIN from `source`. Fails with
+/// `CKR_PIN_LOCKED` without trying if the PIN is locked, and returns the
+/// [`PinWarning`] if it was entered wrong before.
+pub fn login(ctx: &Ctx, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, source: &dyn PinSource) -> Result<Option<PinWarning>, Error> {
+  let (pin, warning) = resolve(ctx, session, user_type, source)?;
+  ctx.login_secret(session, user_type, pin.as_ref())?;
+  Ok(warning)
+}
+
+/// Everything [`login`] does before `C_Login`: the PIN to log in with, `None`
+/// on the protected authentication path, and the warning.
+pub(crate) fn resolve(ctx: &Ctx, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, source: &dyn PinSource) -> Result<(Option<SecretPin>, Option<PinWarning>), Error> {
+  let slot_id = ctx.get_session_info(session)?.slotID;
+  let token = ctx.get_token_info(slot_id)?;
+  let warning = pin_state(token.flags, user_type)?;
+  if token.flags & CKF_PROTECTED_AUTHENTICATION_PATH != 0 {
+    return Ok((None, warning));
+  }
+  let request = PinRequest {
+    slot_id,
+    user_type,
+    token: &token,
+    warning,
+  };
+  match source.pin(&request)? {
+    Some(pin) => Ok((Some(pin), warning)),
+    None => Err(Error::InvalidInput("the token has no protected authentication path")),
+  }
+}
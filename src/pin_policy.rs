@@ -0,0 +1,369 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checking new PINs before they reach the module.
+//!
+//! A module that does not like a new PIN fails with `CKR_PIN_LEN_RANGE`,
+//! `CKR_PIN_INVALID` or something of its own. A [`PinPolicy`] checks the PIN
+//! against `ulMinPinLen` and `ulMaxPinLen` of the token and the rules it is
+//! given first, and says what is wrong with it:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::pin::{self, CallbackPin, EnvPin, PinRequest};
+//! # use pkcs11::pin_policy::PinPolicy;
+//! # use pkcs11::secret::SecretPin;
+//! # use pkcs11::types::*;
+//! # let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! # let session = 1;
+//! # fn prompt(rules: &str) -> SecretPin {
+//! #   eprint!("new PIN, {}: ", rules);
+//! #   let mut line = String::new();
+//! #   std::io::stdin().read_line(&mut line).unwrap();
+//! #   SecretPin::from(line.trim_end())
+//! # }
+//! let policy = PinPolicy::new().min_length(8).character_classes(2).max_repeated(2);
+//! let new_pin = CallbackPin::new(|request: &PinRequest| Ok(prompt(&policy.describe(request.token))));
+//! if policy.login_and_renew(&ctx, session, CKU_USER, &EnvPin::new("PIN"), &new_pin).unwrap() {
+//!   println!("the PIN was changed");
+//! }
+//! ```
+//!
+//! [`PinPolicy::login_and_renew`] changes the PIN with `C_SetPIN` when the
+//! token flags it as `CKF_USER_PIN_TO_BE_CHANGED` or `CKF_SO_PIN_TO_BE_CHANGED`,
+//! or the login fails with `CKR_PIN_EXPIRED`.
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+use std::error;
+use std::fmt;
+
+use super::Ctx;
+use errors::Error;
+use pin::{self, PinRequest, PinSource};
+use secret::SecretPin;
+use types::*;
+
+/// What is wrong with a PIN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+  /// Fewer bytes than the token or the policy want.
+  TooShort { min: usize },
+  /// More bytes than the token takes.
+  TooLong { max: usize },
+  /// Not all digits, for a policy of digits only.
+  NotDigits,
+  /// Fewer kinds of characters, of lowercase, uppercase, digits and others.
+  TooFewClasses { min: usize },
+  /// The same character more than `max` times in a row.
+  Repeated { max: usize },
+  /// More than `max` characters in a row counting up or down, like `1234`.
+  Sequence { max: usize },
+  /// On the list of PINs not to use.
+  Denied,
+  /// The same as the old PIN.
+  Unchanged,
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Violation::TooShort { min } => write!(f, "the PIN is shorter than {} characters", min),
+      Violation::TooLong { max } => write!(f, "the PIN is longer than {} characters", max),
+      Violation::NotDigits => f.write_str("the PIN may only have digits"),
+      Violation::TooFewClasses { min } => write!(f, "the PIN needs {} of lowercase letters, uppercase letters, digits and other characters", min),
+      Violation::Repeated { max } => write!(f, "the PIN has a character more than {} times in a row", max),
+      Violation::Sequence { max } => write!(f, "the PIN counts up or down for more than {} characters", max),
+      Violation::Denied => f.write_str("the PIN is too common"),
+      Violation::Unchanged => f.write_str("the new PIN is the old one"),
+    }
+  }
+}
+
+/// Why a PIN was not set.
+#[derive(Debug)]
+pub enum PinError {
+  /// The PIN breaks the policy; the module was not asked.
+  Rejected(Vec<Violation>),
+  /// The module or a PIN source failed.
+  Pkcs11(Error),
+}
+
+impl From<Error> for PinError {
+  fn from(err: Error) -> PinError {
+    PinError::Pkcs11(err)
+  }
+}
+
+impl fmt::Display for PinError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      PinError::Rejected(ref violations) => {
+        for (i, violation) in violations.iter().enumerate() {
+          if i > 0 {
+            f.write_str(", ")?;
+          }
+          violation.fmt(f)?;
+        }
+        Ok(())
+      }
+      PinError::Pkcs11(ref err) => err.fmt(f),
+    }
+  }
+}
+
+impl error::Error for PinError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match *self {
+      PinError::Pkcs11(ref err) => Some(err),
+      PinError::Rejected(_) => None,
+    }
+  }
+}
+
+/// Rules for new PINs, on top of the length limits of the token.
+#[derive(Debug, Clone, Default)]
+pub struct PinPolicy {
+  min_length: usize,
+  digits_only: bool,
+  character_classes: usize,
+  max_repeated: Option<usize>,
+  max_sequence: Option<usize>,
+  denied: Vec<String>,
+}
+
+impl PinPolicy {
+  /// Only the length limits of the token.
+  pub fn new() -> PinPolicy {
+    PinPolicy::default()
+  }
+
+  /// At least `min` bytes, if the token allows shorter PINs.
+  pub fn min_length(mut self, min: usize) -> PinPolicy {
+    self.min_length = min;
+    self
+  }
+
+  /// Only the digits 0 to 9, for PIN pads.
+  pub fn digits_only(mut self) -> PinPolicy {
+    self.digits_only = true;
+    self
+  }
+
+  /// At least `min` of lowercase letters, uppercase letters, digits and
+  /// other characters.
+  pub fn character_classes(mut self, min: usize) -> PinPolicy {
+    self.character_classes = min;
+    self
+  }
+
+  /// No character more than `max` times in a row.
+  pub fn max_repeated(mut self, max: usize) -> PinPolicy {
+    self.max_repeated = Some(max);
+    self
+  }
+
+  /// No more than `max` characters in a row counting up or down.
+  pub fn max_sequence(mut self, max: usize) -> PinPolicy {
+    self.max_sequence = Some(max);
+    self
+  }
+
+  /// Refuses `pin`, e.g. a default PIN of the vendor.
+  pub fn deny(mut self, pin: &str) -> PinPolicy {
+    self.denied.push(pin.to_string());
+    self
+  }
+
+  /// The shortest and longest PIN for the token.
+  fn lengths(&self, token: &CK_TOKEN_INFO) -> (usize, Option<usize>) {
+    let max = match token.ulMaxPinLen {
+      CK_UNAVAILABLE_INFORMATION | CK_EFFECTIVELY_INFINITE => None,
+      max => Some(max as usize),
+    };
+    let min = match token.ulMinPinLen {
+      CK_UNAVAILABLE_INFORMATION => 0,
+      min => min as usize,
+    };
+    (self.min_length.max(min), max)
+  }
+
+  /// The rules in words, to show when asking for a new PIN.
+  pub fn describe(&self, token: &CK_TOKEN_INFO) -> String {
+    let mut rules = vec![match self.lengths(token) {
+      (min, Some(max)) => format!("{} to {} characters", min, max),
+      (min, None) => format!("at least {} characters", min),
+    }];
+    if self.digits_only {
+      rules.push("digits only".to_string());
+    }
+    if self.character_classes > 1 {
+      rules.push(format!("{} of lowercase letters, uppercase letters, digits and other characters", self.character_classes));
+    }
+    if let Some(max) = self.max_repeated {
+      rules.push(format!("no character more than {} times in a row", max));
+    }
+    if let Some(max) = self.max_sequence {
+      rules.push(format!("no more than {} characters counting up or down", max));
+    }
+    rules.join(", ")
+  }
+
+  /// Everything that is wrong with `pin` as a new PIN for the token.
+  pub fn violations(&self, pin: &SecretPin, token: &CK_TOKEN_INFO) -> Vec<Violation> {
+    let bytes = pin.as_bytes();
+    let mut violations = Vec::new();
+    let (min, max) = self.lengths(token);
+    if bytes.len() < min {
+      violations.push(Violation::TooShort { min });
+    }
+    if let Some(max) = max {
+      if bytes.len() > max {
+        violations.push(Violation::TooLong { max });
+      }
+    }
+    if self.digits_only && !bytes.iter().all(u8::is_ascii_digit) {
+      violations.push(Violation::NotDigits);
+    }
+    let classes = [
+      bytes.iter().any(u8::is_ascii_lowercase),
+      bytes.iter().any(u8::is_ascii_uppercase),
+      bytes.iter().any(u8::is_ascii_digit),
+      bytes.iter().any(|b| !b.is_ascii_alphanumeric()),
+    ];
+    if classes.iter().filter(|&&c| c).count() < self.character_classes {
+      violations.push(Violation::TooFewClasses { min: self.character_classes });
+    }
+    if let Some(max) = self.max_repeated {
+      if longest_run(bytes, |a, b| a == b) > max {
+        violations.push(Violation::Repeated { max });
+      }
+    }
+    if let Some(max) = self.max_sequence {
+      let up = longest_run(bytes, |a, b| a.checked_add(1) == Some(b));
+      let down = longest_run(bytes, |a, b| b.checked_add(1) == Some(a));
+      if up.max(down) > max {
+        violations.push(Violation::Sequence { max });
+      }
+    }
+    if self.denied.iter().any(|denied| denied.as_bytes() == bytes) {
+      violations.push(Violation::Denied);
+    }
+    violations
+  }
+
+  /// `Ok` if `pin` may be the new PIN for the token.
+  pub fn check(&self, pin: &SecretPin, token: &CK_TOKEN_INFO) -> Result<(), PinError> {
+    match self.violations(pin, token) {
+      ref violations if violations.is_empty() => Ok(()),
+      violations => Err(PinError::Rejected(violations)),
+    }
+  }
+
+  /// `Ctx::init_token_secret` once the SO PIN passes the policy. No PIN
+  /// means the protected authentication path and is not checked.
+  pub fn init_token(&self, ctx: &Ctx, slot_id: CK_SLOT_ID, so_pin: Option<&SecretPin>, label: &str) -> Result<(), PinError> {
+    if let Some(pin) = so_pin {
+      self.check(pin, &ctx.get_token_info(slot_id)?)?;
+    }
+    Ok(ctx.init_token_secret(slot_id, so_pin, label)?)
+  }
+
+  /// `Ctx::init_pin_secret` once the user PIN passes the policy.
+  pub fn init_pin(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, pin: Option<&SecretPin>) -> Result<(), PinError> {
+    if let Some(pin) = pin {
+      self.check(pin, &token_of(ctx, session)?)?;
+    }
+    Ok(ctx.init_pin_secret(session, pin)?)
+  }
+
+  /// `Ctx::set_pin_secret` once the new PIN passes the policy and differs
+  /// from the old one.
+  pub fn set_pin(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, old_pin: Option<&SecretPin>, new_pin: Option<&SecretPin>) -> Result<(), PinError> {
+    if let Some(new) = new_pin {
+      let mut violations = self.violations(new, &token_of(ctx, session)?);
+      if old_pin.map(SecretPin::as_bytes) == Some(new.as_bytes()) {
+        violations.push(Violation::Unchanged);
+      }
+      if !violations.is_empty() {
+        return Err(PinError::Rejected(violations));
+      }
+    }
+    Ok(ctx.set_pin_secret(session, old_pin, new_pin)?)
+  }
+
+  /// Logs in as `user_type` with the PIN from `current`, and changes it to
+  /// one from `new_pin` if the token wants it changed. Returns whether it
+  /// was. On tokens with a protected authentication path, both PINs are
+  /// entered there.
+  pub fn login_and_renew(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, user_type: CK_USER_TYPE, current: &dyn PinSource, new_pin: &dyn PinSource) -> Result<bool, PinError> {
+    let slot_id = ctx.get_session_info(session)?.slotID;
+    let token = ctx.get_token_info(slot_id)?;
+    let warning = pin::pin_state(token.flags, user_type)?;
+    let protected = token.flags & CKF_PROTECTED_AUTHENTICATION_PATH != 0;
+    let old = if protected {
+      None
+    } else {
+      current.pin(&PinRequest {
+        slot_id,
+        user_type,
+        token: &token,
+        warning,
+      })?
+    };
+    if old.is_none() && !protected {
+      return Err(Error::InvalidInput("the token has no protected authentication path").into());
+    }
+    let expired = match ctx.login_secret(session, user_type, old.as_ref()) {
+      Ok(()) => false,
+      Err(Error::Pkcs11(CKR_PIN_EXPIRED)) => true,
+      Err(err) => return Err(err.into()),
+    };
+    let to_be_changed = if user_type == CKU_SO { CKF_SO_PIN_TO_BE_CHANGED } else { CKF_USER_PIN_TO_BE_CHANGED };
+    if !expired && token.flags & to_be_changed == 0 {
+      return Ok(false);
+    }
+    if protected {
+      ctx.set_pin_secret(session, None, None)?;
+      return Ok(true);
+    }
+    let new = new_pin.pin(&PinRequest {
+      slot_id,
+      user_type,
+      token: &token,
+      warning: None,
+    })?;
+    match new {
+      Some(new) => self.set_pin(ctx, session, old.as_ref(), Some(&new))?,
+      None => return Err(Error::InvalidInput("the token has no protected authentication path").into()),
+    }
+    Ok(true)
+  }
+}
+
+fn token_of(ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<CK_TOKEN_INFO, Error> {
+  ctx.get_token_info(ctx.get_session_info(session)?.slotID)
+}
+
+/// The longest run of bytes where each follows the one before by `follows`.
+fn longest_run<F: Fn(u8, u8) -> bool>(bytes: &[u8], follows: F) -> usize {
+  let mut longest = bytes.len().min(1);
+  let mut run = longest;
+  for pair in bytes.windows(2) {
+    run = if follows(pair[0], pair[1]) { run + 1 } else { 1 };
+    longest = longest.max(run);
+  }
+  longest
+}
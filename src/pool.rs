@@ -0,0 +1,315 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pool of logged-in sessions on one token, for many threads at once.
+//!
+//! A PKCS#11 session must not be used by two threads at the same time, and
+//! opening one and logging in for every operation is slow. The pool keeps
+//! between `min` and `max` sessions open and hands each one to a single
+//! caller until the [`PooledSession`] is dropped:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! # use pkcs11::Ctx;
+//! # use pkcs11::pool::{PoolConfig, SessionPool};
+//! let ctx = Arc::new(Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap());
+//! let pool = SessionPool::new(ctx, PoolConfig::new(0).pin("1234").min(2).max(8).acquire_timeout(Duration::from_secs(5))).unwrap();
+//! let session = pool.acquire().unwrap();
+//! let random = session.ctx().generate_random(session.handle(), 16).unwrap();
+//! ```
+//!
+//! The login state is shared by all sessions of the application on a token,
+//! so the pool only logs in when a session is not logged in yet. Once the
+//! token rejects the PIN as incorrect or locked, the pool fails every
+//! login with that error instead of sending the PIN again. Sessions
+//! are checked with `C_GetSessionInfo` before they are handed out again, and
+//! the ones the module closed in the meantime are replaced. For concurrent
+//! use, the module should be initialized with `CKF_OS_LOCKING_OK`.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::Ctx;
+use errors::Error;
+use secret::SecretPin;
+use types::*;
+
+/// The settings of a [`SessionPool`].
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+  slot_id: CK_SLOT_ID,
+  pin: Option<SecretPin>,
+  min: usize,
+  max: usize,
+  acquire_timeout: Duration,
+  read_write: bool,
+}
+
+impl PoolConfig {
+  /// Up to 4 read-write sessions on the token in `slot_id`, without login,
+  /// waiting up to 30 seconds for one.
+  pub fn new(slot_id: CK_SLOT_ID) -> PoolConfig {
+    PoolConfig {
+      slot_id,
+      pin: None,
+      min: 0,
+      max: 4,
+      acquire_timeout: Duration::from_secs(30),
+      read_write: true,
+    }
+  }
+
+  /// The user PIN to log in with.
+  pub fn pin(mut self, pin: &str) -> PoolConfig {
+    self.pin = Some(SecretPin::from(pin));
+    self
+  }
+
+  /// How many sessions are opened up front.
+  pub fn min(mut self, min: usize) -> PoolConfig {
+    self.min = min;
+    self
+  }
+
+  /// How many sessions may be open at once.
+  pub fn max(mut self, max: usize) -> PoolConfig {
+    self.max = max;
+    self
+  }
+
+  /// How long [`SessionPool::acquire`] waits for a session to come back.
+  pub fn acquire_timeout(mut self, timeout: Duration) -> PoolConfig {
+    self.acquire_timeout = timeout;
+    self
+  }
+
+  /// Opens read-only sessions instead.
+  pub fn read_only(mut self) -> PoolConfig {
+    self.read_write = false;
+    self
+  }
+}
+
+#[derive(Debug)]
+struct State {
+  idle: Vec<CK_SESSION_HANDLE>,
+  // the idle sessions, the ones handed out and the ones being opened
+  open: usize,
+  // CKR_PIN_INCORRECT or CKR_PIN_LOCKED once the token rejected the PIN
+  pin_failed: Option<CK_RV>,
+}
+
+/// Sessions on one token, each handed to one caller at a time.
+#[derive(Debug)]
+pub struct SessionPool {
+  ctx: Arc<Ctx>,
+  config: PoolConfig,
+  state: Mutex<State>,
+  returned: Condvar,
+}
+
+/// Whether `err` means the module no longer knows the session.
+fn is_gone(err: &Error) -> bool {
+  matches!(*err, Error::Pkcs11(CKR_SESSION_HANDLE_INVALID) | Error::Pkcs11(CKR_SESSION_CLOSED))
+}
+
+impl SessionPool {
+  /// Opens the first `min` sessions, logging in if there is a PIN.
+  pub fn new(ctx: Arc<Ctx>, config: PoolConfig) -> Result<SessionPool, Error> {
+    if config.max == 0 || config.min > config.max {
+      return Err(Error::InvalidInput("session pool needs 0 < max and min <= max"));
+    }
+    let pool = SessionPool {
+      ctx,
+      config,
+      state: Mutex::new(State { idle: Vec::new(), open: 0, pin_failed: None }),
+      returned: Condvar::new(),
+    };
+    for _ in 0..pool.config.min {
+      let session = pool.open()?;
+      let mut state = pool.lock();
+      state.idle.push(session);
+      state.open += 1;
+    }
+    Ok(pool)
+  }
+
+  pub fn ctx(&self) -> &Ctx {
+    &self.ctx
+  }
+
+  pub fn slot_id(&self) -> CK_SLOT_ID {
+    self.config.slot_id
+  }
+
+  /// How many sessions are open, handed out or not.
+  pub fn open_sessions(&self) -> usize {
+    self.lock().open
+  }
+
+  /// How many sessions are waiting to be handed out.
+  pub fn idle_sessions(&self) -> usize {
+    self.lock().idle.len()
+  }
+
+  // a panic while the lock is held leaves the counts intact
+  fn lock(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// A session for the caller alone, waiting for one to come back when all
+  /// `max` are in use. Waiting longer than the acquire timeout fails with an
+  /// `Error::Io` of kind `TimedOut`.
+  pub fn acquire(&self) -> Result<PooledSession<'_>, Error> {
+    let deadline = Instant::now() + self.config.acquire_timeout;
+    let mut state = self.lock();
+    loop {
+      let session = match state.idle.pop() {
+        Some(session) => Some(session),
+        None if state.open < self.config.max => None,
+        None => {
+          let now = Instant::now();
+          if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a pooled session").into());
+          }
+          state = self.returned.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+          continue;
+        }
+      };
+      if session.is_none() {
+        state.open += 1;
+      }
+      drop(state);
+      let ready = match session {
+        Some(session) => self.check(session),
+        None => self.open(),
+      };
+      return match ready {
+        Ok(handle) => Ok(PooledSession { pool: self, handle: Some(handle) }),
+        Err(err) => {
+          self.forget();
+          Err(err)
+        }
+      };
+    }
+  }
+
+  /// The session itself if it is still usable, else a new one in its place.
+  fn check(&self, session: CK_SESSION_HANDLE) -> Result<CK_SESSION_HANDLE, Error> {
+    match self.ctx.get_session_info(session) {
+      Ok(info) => match self.login(session, info.state) {
+        Ok(()) => Ok(session),
+        Err(err) => {
+          let _ = self.ctx.close_session(session);
+          Err(err)
+        }
+      },
+      Err(ref err) if is_gone(err) => self.open(),
+      Err(err) => {
+        let _ = self.ctx.close_session(session);
+        Err(err)
+      }
+    }
+  }
+
+  fn open(&self) -> Result<CK_SESSION_HANDLE, Error> {
+    let flags = if self.config.read_write { CKF_SERIAL_SESSION | CKF_RW_SESSION } else { CKF_SERIAL_SESSION };
+    let session = self.ctx.open_session(self.config.slot_id, flags, None, None)?;
+    let logged_in = self.ctx.get_session_info(session).and_then(|info| self.login(session, info.state));
+    if let Err(err) = logged_in {
+      let _ = self.ctx.close_session(session);
+      return Err(err);
+    }
+    Ok(session)
+  }
+
+  /// Logs in unless the token is logged in already, or fails the way the
+  /// last login did if the token rejected the PIN.
+  fn login(&self, session: CK_SESSION_HANDLE, state: CK_ULONG) -> Result<(), Error> {
+    match self.config.pin {
+      Some(ref pin) if state == CKS_RO_PUBLIC_SESSION || state == CKS_RW_PUBLIC_SESSION => {
+        if let Some(rv) = self.lock().pin_failed {
+          return Err(Error::Pkcs11(rv));
+        }
+        match self.ctx.login_secret(session, CKU_USER, Some(pin)) {
+          Ok(()) | Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => Ok(()),
+          Err(Error::Pkcs11(rv)) if rv == CKR_PIN_INCORRECT || rv == CKR_PIN_LOCKED => {
+            self.lock().pin_failed = Some(rv);
+            Err(Error::Pkcs11(rv))
+          }
+          Err(err) => Err(err),
+        }
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Drops a session from the count and lets a waiting caller open another.
+  fn forget(&self) {
+    self.lock().open -= 1;
+    self.returned.notify_one();
+  }
+
+  fn give_back(&self, session: CK_SESSION_HANDLE) {
+    self.lock().idle.push(session);
+    self.returned.notify_one();
+  }
+}
+
+impl Drop for SessionPool {
+  fn drop(&mut self) {
+    for session in self.lock().idle.drain(..) {
+      let _ = self.ctx.close_session(session);
+    }
+  }
+}
+
+/// A session handed out by a [`SessionPool`], given back when dropped.
+///
+/// Operations such as a sign must be finished before it is dropped, or the
+/// next caller finds them still active.
+#[derive(Debug)]
+pub struct PooledSession<'a> {
+  pool: &'a SessionPool,
+  handle: Option<CK_SESSION_HANDLE>,
+}
+
+impl<'a> PooledSession<'a> {
+  pub fn handle(&self) -> CK_SESSION_HANDLE {
+    self.handle.unwrap_or_default()
+  }
+
+  pub fn ctx(&self) -> &'a Ctx {
+    &self.pool.ctx
+  }
+
+  /// Closes the session instead of giving it back, e.g. after an error that
+  /// left an operation active.
+  pub fn discard(mut self) {
+    if let Some(session) = self.handle.take() {
+      let _ = self.pool.ctx.close_session(session);
+      self.pool.forget();
+    }
+  }
+}
+
+impl<'a> Drop for PooledSession<'a> {
+  fn drop(&mut self) {
+    if let Some(session) = self.handle.take() {
+      self.pool.give_back(session);
+    }
+  }
+}
@@ -0,0 +1,1256 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The other side of the API: writing a PKCS#11 module in Rust.
+//!
+//! A token backend implements [`Pkcs11Provider`] with plain Rust types and
+//! exports it from a `cdylib` crate with [`pkcs11_provider!`]. The macro
+//! generates `C_GetFunctionList` and every `extern "C"` entry point. Those take
+//! care of the C side of the contract:
+//!
+//! * null and malformed pointers are rejected with `CKR_ARGUMENTS_BAD`,
+//! * calls before `C_Initialize` fail with `CKR_CRYPTOKI_NOT_INITIALIZED`,
+//! * session handles are kept in a table and checked on every call,
+//! * `C_FindObjects*` pages through the handles returned by the provider,
+//! * outputs follow the two-call convention: a size query or a call that gets
+//!   `CKR_BUFFER_TOO_SMALL` keeps the computed output until the caller
+//!   fetches it, so the provider computes every signature or ciphertext once,
+//! * a panic in the provider is returned as `CKR_GENERAL_ERROR`.
+//!
+//! Calls are serialized through one lock, so the provider gets `&mut self`.
+//! Every method but the ones describing slots, tokens and sessions has a
+//! default that returns `CKR_FUNCTION_NOT_SUPPORTED`. Object handles belong to
+//! the provider, [`HandleTable`] is there to keep them.
+//!
+//! ```no_run
+//! #[macro_use]
+//! extern crate pkcs11;
+//!
+//! use pkcs11::provider::{self, Pkcs11Provider};
+//! use pkcs11::types::*;
+//!
+//! struct Token;
+//!
+//! impl Pkcs11Provider for Token {
+//!   type Session = ();
+//!
+//!   fn initialize() -> provider::Result<Self> {
+//!     Ok(Token)
+//!   }
+//!   fn slots(&mut self, _token_present: bool) -> Vec<CK_SLOT_ID> {
+//!     vec![0]
+//!   }
+//!   fn slot_info(&mut self, _slot: CK_SLOT_ID) -> provider::Result<CK_SLOT_INFO> {
+//!     Ok(CK_SLOT_INFO { flags: CKF_TOKEN_PRESENT, ..Default::default() })
+//!   }
+//!   fn token_info(&mut self, _slot: CK_SLOT_ID) -> provider::Result<CK_TOKEN_INFO> {
+//!     Ok(CK_TOKEN_INFO { label: provider::blank_padded("example"), ..Default::default() })
+//!   }
+//!   fn open_session(&mut self, _slot: CK_SLOT_ID, _flags: CK_FLAGS) -> provider::Result<()> {
+//!     Ok(())
+//!   }
+//!   fn session_state(&mut self, _session: &()) -> CK_ULONG {
+//!     CKS_RO_PUBLIC_SESSION
+//!   }
+//! }
+//!
+//! pkcs11_provider!(Token);
+//! # fn main() {}
+//! ```
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::result;
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+
+use types::*;
+
+/// Provider methods fail with the return value the caller should see.
+pub type Result<T> = result::Result<T, CK_RV>;
+
+/// An attribute of a template, with its value copied out of the caller's memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attribute {
+  pub attr_type: CK_ATTRIBUTE_TYPE,
+  pub value: Vec<u8>,
+}
+
+impl Attribute {
+  pub fn new(attr_type: CK_ATTRIBUTE_TYPE, value: Vec<u8>) -> Attribute {
+    Attribute { attr_type, value }
+  }
+
+  pub fn from_ulong(attr_type: CK_ATTRIBUTE_TYPE, value: CK_ULONG) -> Attribute {
+    Attribute::new(attr_type, value.to_ne_bytes().to_vec())
+  }
+
+  pub fn from_bool(attr_type: CK_ATTRIBUTE_TYPE, value: bool) -> Attribute {
+    Attribute::new(attr_type, vec![if value { CK_TRUE } else { CK_FALSE }])
+  }
+
+  /// The value as a `CK_ULONG`, or `CKR_ATTRIBUTE_VALUE_INVALID` if it has the wrong size.
+  pub fn ulong(&self) -> Result<CK_ULONG> {
+    let mut bytes = [0; mem::size_of::<CK_ULONG>()];
+    if self.value.len() != bytes.len() {
+      return Err(CKR_ATTRIBUTE_VALUE_INVALID);
+    }
+    bytes.copy_from_slice(&self.value);
+    Ok(CK_ULONG::from_ne_bytes(bytes))
+  }
+
+  /// The value as a `CK_BBOOL`, or `CKR_ATTRIBUTE_VALUE_INVALID` if it is not one byte.
+  pub fn bool(&self) -> Result<bool> {
+    match self.value[..] {
+      [b] => Ok(b != CK_FALSE),
+      _ => Err(CKR_ATTRIBUTE_VALUE_INVALID),
+    }
+  }
+}
+
+/// Returns the first attribute of `attr_type` in `template`.
+pub fn find_attribute(template: &[Attribute], attr_type: CK_ATTRIBUTE_TYPE) -> Option<&Attribute> {
+  template.iter().find(|a| a.attr_type == attr_type)
+}
+
+/// A mechanism with its parameter copied out of the caller's memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mechanism {
+  pub mechanism: CK_MECHANISM_TYPE,
+  pub parameter: Vec<u8>,
+}
+
+/// Fills a blank padded field of the info structs, cutting `s` at `N` bytes.
+pub fn blank_padded<const N: usize>(s: &str) -> [CK_UTF8CHAR; N] {
+  let mut field = [b' '; N];
+  let len = s.len().min(N);
+  field[..len].copy_from_slice(&s.as_bytes()[..len]);
+  field
+}
+
+/// Hands out handles for values, starting at 1 and not reusing a handle that is still taken.
+#[derive(Debug)]
+pub struct HandleTable<T> {
+  items: BTreeMap<CK_ULONG, T>,
+  next: CK_ULONG,
+}
+
+impl<T> Default for HandleTable<T> {
+  fn default() -> HandleTable<T> {
+    HandleTable::new()
+  }
+}
+
+impl<T> HandleTable<T> {
+  pub const fn new() -> HandleTable<T> {
+    HandleTable { items: BTreeMap::new(), next: 1 }
+  }
+
+  pub fn insert(&mut self, value: T) -> CK_ULONG {
+    loop {
+      let handle = self.next;
+      self.next = self.next.wrapping_add(1).max(1);
+      if let Entry::Vacant(entry) = self.items.entry(handle) {
+        entry.insert(value);
+        return handle;
+      }
+    }
+  }
+
+  pub fn get(&self, handle: CK_ULONG) -> Option<&T> {
+    self.items.get(&handle)
+  }
+
+  pub fn get_mut(&mut self, handle: CK_ULONG) -> Option<&mut T> {
+    self.items.get_mut(&handle)
+  }
+
+  pub fn remove(&mut self, handle: CK_ULONG) -> Option<T> {
+    self.items.remove(&handle)
+  }
+
+  pub fn contains(&self, handle: CK_ULONG) -> bool {
+    self.items.contains_key(&handle)
+  }
+
+  pub fn handles(&self) -> Vec<CK_ULONG> {
+    self.items.keys().cloned().collect()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (CK_ULONG, &T)> {
+    self.items.iter().map(|(h, v)| (*h, v))
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (CK_ULONG, &mut T)> {
+    self.items.iter_mut().map(|(h, v)| (*h, v))
+  }
+
+  pub fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.items.is_empty()
+  }
+}
+
+/// A PKCS#11 token backend. `session` arguments are the provider's own state for
+/// the session the call came in on; the framework has already checked the handle.
+///
+/// Single-part operations and `*_final` end the operation when they return, be it
+/// with a result or an error. PINs are `None` when the caller passed a null
+/// pointer to use the protected authentication path.
+#[allow(unused_variables)]
+pub trait Pkcs11Provider: Send + Sized + 'static {
+  /// Per-session state, created by `open_session`.
+  type Session: Send;
+
+  /// Called by `C_Initialize`.
+  fn initialize() -> Result<Self>;
+  /// Called by `C_Finalize`, after all sessions were closed.
+  fn finalize(self) {}
+
+  fn info(&mut self) -> CK_INFO {
+    CK_INFO {
+      cryptokiVersion: CK_VERSION { major: 2, minor: 40 },
+      manufacturerID: blank_padded("rust-pkcs11"),
+      flags: 0,
+      libraryDescription: blank_padded("Rust PKCS#11 provider"),
+      libraryVersion: CK_VERSION { major: 0, minor: 0 },
+    }
+  }
+
+  fn slots(&mut self, token_present: bool) -> Vec<CK_SLOT_ID>;
+  fn slot_info(&mut self, slot: CK_SLOT_ID) -> Result<CK_SLOT_INFO>;
+  fn token_info(&mut self, slot: CK_SLOT_ID) -> Result<CK_TOKEN_INFO>;
+  fn mechanisms(&mut self, slot: CK_SLOT_ID) -> Result<Vec<CK_MECHANISM_TYPE>> {
+    Ok(Vec::new())
+  }
+  fn mechanism_info(&mut self, slot: CK_SLOT_ID, mechanism: CK_MECHANISM_TYPE) -> Result<CK_MECHANISM_INFO> {
+    Err(CKR_MECHANISM_INVALID)
+  }
+  fn init_token(&mut self, slot: CK_SLOT_ID, so_pin: Option<&[u8]>, label: &[CK_UTF8CHAR; 32]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  /// `flags` always contain `CKF_SERIAL_SESSION`.
+  fn open_session(&mut self, slot: CK_SLOT_ID, flags: CK_FLAGS) -> Result<Self::Session>;
+  fn close_session(&mut self, session: Self::Session) {}
+  /// One of the `CKS_*` session states.
+  fn session_state(&mut self, session: &Self::Session) -> CK_ULONG;
+  fn operation_state(&mut self, session: &mut Self::Session) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn set_operation_state(&mut self, session: &mut Self::Session, state: &[u8], encryption_key: CK_OBJECT_HANDLE, authentication_key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn init_pin(&mut self, session: &mut Self::Session, pin: Option<&[u8]>) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn set_pin(&mut self, session: &mut Self::Session, old_pin: Option<&[u8]>, new_pin: Option<&[u8]>) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn login(&mut self, session: &mut Self::Session, user_type: CK_USER_TYPE, pin: Option<&[u8]>) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn logout(&mut self, session: &mut Self::Session) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn create_object(&mut self, session: &mut Self::Session, template: &[Attribute]) -> Result<CK_OBJECT_HANDLE> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn copy_object(&mut self, session: &mut Self::Session, object: CK_OBJECT_HANDLE, template: &[Attribute]) -> Result<CK_OBJECT_HANDLE> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn destroy_object(&mut self, session: &mut Self::Session, object: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn object_size(&mut self, session: &mut Self::Session, object: CK_OBJECT_HANDLE) -> Result<CK_ULONG> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  /// The value of one attribute. `CKR_ATTRIBUTE_SENSITIVE` and
+  /// `CKR_ATTRIBUTE_TYPE_INVALID` only mark that attribute as unavailable,
+  /// other errors fail the whole `C_GetAttributeValue`.
+  fn attribute(&mut self, session: &mut Self::Session, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn set_attributes(&mut self, session: &mut Self::Session, object: CK_OBJECT_HANDLE, template: &[Attribute]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  /// All objects matching `template`; the framework hands them out in pages.
+  fn find_objects(&mut self, session: &mut Self::Session, template: &[Attribute]) -> Result<Vec<CK_OBJECT_HANDLE>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn encrypt_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn encrypt(&mut self, session: &mut Self::Session, data: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn encrypt_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn encrypt_final(&mut self, session: &mut Self::Session) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt(&mut self, session: &mut Self::Session, data: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt_final(&mut self, session: &mut Self::Session) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn digest_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn digest(&mut self, session: &mut Self::Session, data: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn digest_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn digest_key(&mut self, session: &mut Self::Session, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn digest_final(&mut self, session: &mut Self::Session) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn sign_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign(&mut self, session: &mut Self::Session, data: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign_final(&mut self, session: &mut Self::Session) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign_recover_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign_recover(&mut self, session: &mut Self::Session, data: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn verify_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn verify(&mut self, session: &mut Self::Session, data: &[u8], signature: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn verify_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn verify_final(&mut self, session: &mut Self::Session, signature: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn verify_recover_init(&mut self, session: &mut Self::Session, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn verify_recover(&mut self, session: &mut Self::Session, signature: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn digest_encrypt_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt_digest_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn sign_encrypt_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn decrypt_verify_update(&mut self, session: &mut Self::Session, part: &[u8]) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn generate_key(&mut self, session: &mut Self::Session, mechanism: &Mechanism, template: &[Attribute]) -> Result<CK_OBJECT_HANDLE> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  /// Returns the public and the private key handle.
+  fn generate_key_pair(&mut self, session: &mut Self::Session, mechanism: &Mechanism, public_template: &[Attribute], private_template: &[Attribute]) -> Result<(CK_OBJECT_HANDLE, CK_OBJECT_HANDLE)> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn wrap_key(&mut self, session: &mut Self::Session, mechanism: &Mechanism, wrapping_key: CK_OBJECT_HANDLE, key: CK_OBJECT_HANDLE) -> Result<Vec<u8>> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn unwrap_key(&mut self, session: &mut Self::Session, mechanism: &Mechanism, unwrapping_key: CK_OBJECT_HANDLE, wrapped_key: &[u8], template: &[Attribute]) -> Result<CK_OBJECT_HANDLE> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn derive_key(&mut self, session: &mut Self::Session, mechanism: &Mechanism, base_key: CK_OBJECT_HANDLE, template: &[Attribute]) -> Result<CK_OBJECT_HANDLE> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+
+  fn seed_random(&mut self, session: &mut Self::Session, seed: &[u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+  fn generate_random(&mut self, session: &mut Self::Session, out: &mut [u8]) -> Result<()> {
+    Err(CKR_FUNCTION_NOT_SUPPORTED)
+  }
+}
+
+/// An output computed by the provider that the caller has not received yet.
+struct Pending {
+  function: &'static str,
+  input: Vec<u8>,
+  output: Vec<u8>,
+}
+
+struct Session<S> {
+  slot: CK_SLOT_ID,
+  flags: CK_FLAGS,
+  state: S,
+  find: Option<VecDeque<CK_OBJECT_HANDLE>>,
+  pending: Option<Pending>,
+}
+
+impl<S> Session<S> {
+  /// Writes the output of `compute` with the two-call convention. When the
+  /// caller only asks for the size or the buffer is too small, the output is
+  /// kept and handed out by the next call of the same function with the same input.
+  unsafe fn output<F>(&mut self, function: &'static str, input: &[u8], out: CK_BYTE_PTR, out_len: CK_ULONG_PTR, compute: F) -> Result<()>
+  where
+    F: FnOnce(&mut S) -> Result<Vec<u8>>,
+  {
+    if out_len.is_null() {
+      return Err(CKR_ARGUMENTS_BAD);
+    }
+    let output = match self.pending.take() {
+      Some(p) if p.function == function && p.input == input => p.output,
+      _ => compute(&mut self.state)?,
+    };
+    let available = *out_len as usize;
+    *out_len = output.len() as CK_ULONG;
+    if out.is_null() || available < output.len() {
+      let rv = if out.is_null() { Ok(()) } else { Err(CKR_BUFFER_TOO_SMALL) };
+      self.pending = Some(Pending { function, input: input.to_vec(), output });
+      return rv;
+    }
+    slice::from_raw_parts_mut(out, output.len()).copy_from_slice(&output);
+    Ok(())
+  }
+}
+
+struct Inner<P: Pkcs11Provider> {
+  provider: P,
+  sessions: HandleTable<Session<P::Session>>,
+}
+
+/// The module state behind the generated entry points, see [`pkcs11_provider!`].
+pub struct State<P: Pkcs11Provider> {
+  inner: Mutex<Option<Inner<P>>>,
+}
+
+impl<P: Pkcs11Provider> Default for State<P> {
+  fn default() -> State<P> {
+    State::new()
+  }
+}
+
+impl<P: Pkcs11Provider> State<P> {
+  pub const fn new() -> State<P> {
+    State { inner: Mutex::new(None) }
+  }
+
+  // a panicking provider poisons the lock, the state is still usable
+  fn lock(&self) -> MutexGuard<'_, Option<Inner<P>>> {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn run<F>(&self, f: F) -> CK_RV
+  where
+    F: FnOnce(&mut Inner<P>) -> Result<()>,
+  {
+    guard(|| match self.lock().as_mut() {
+      Some(inner) => f(inner),
+      None => Err(CKR_CRYPTOKI_NOT_INITIALIZED),
+    })
+  }
+
+  fn session<F>(&self, handle: CK_SESSION_HANDLE, function: &'static str, f: F) -> CK_RV
+  where
+    F: FnOnce(&mut P, &mut Session<P::Session>) -> Result<()>,
+  {
+    self.run(|inner| {
+      let session = inner.sessions.get_mut(handle).ok_or(CKR_SESSION_HANDLE_INVALID)?;
+      // any other call drops an output the caller did not pick up
+      if session.pending.as_ref().is_some_and(|p| p.function != function) {
+        session.pending = None;
+      }
+      f(&mut inner.provider, session)
+    })
+  }
+}
+
+fn guard<F>(f: F) -> CK_RV
+where
+  F: FnOnce() -> Result<()>,
+{
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(())) => CKR_OK,
+    Ok(Err(rv)) => rv,
+    Err(_) => CKR_GENERAL_ERROR,
+  }
+}
+
+unsafe fn bytes<'a>(data: *const CK_BYTE, len: CK_ULONG) -> Result<&'a [u8]> {
+  if data.is_null() {
+    return if len == 0 { Ok(&[]) } else { Err(CKR_ARGUMENTS_BAD) };
+  }
+  Ok(slice::from_raw_parts(data, len as usize))
+}
+
+unsafe fn bytes_mut<'a>(data: *mut CK_BYTE, len: CK_ULONG) -> Result<&'a mut [u8]> {
+  if data.is_null() {
+    return if len == 0 { Ok(&mut []) } else { Err(CKR_ARGUMENTS_BAD) };
+  }
+  Ok(slice::from_raw_parts_mut(data, len as usize))
+}
+
+unsafe fn pin<'a>(pin: *const CK_UTF8CHAR, len: CK_ULONG) -> Result<Option<&'a [u8]>> {
+  if pin.is_null() {
+    return if len == 0 { Ok(None) } else { Err(CKR_ARGUMENTS_BAD) };
+  }
+  bytes(pin, len).map(Some)
+}
+
+unsafe fn template(attrs: CK_ATTRIBUTE_PTR, count: CK_ULONG) -> Result<Vec<Attribute>> {
+  if count == 0 {
+    return Ok(Vec::new());
+  }
+  if attrs.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  slice::from_raw_parts(attrs, count as usize)
+    .iter()
+    .map(|a| Ok(Attribute::new(a.attrType, bytes(a.pValue as *const CK_BYTE, a.ulValueLen)?.to_vec())))
+    .collect()
+}
+
+unsafe fn mechanism(mechanism: CK_MECHANISM_PTR) -> Result<Mechanism> {
+  if mechanism.is_null() {
+    return Err(CKR_ARGUMENTS_BAD);
+  }
+  let m = &*mechanism;
+  Ok(Mechanism {
+    mechanism: m.mechanism,
+    parameter: bytes(m.pParameter as *const CK_BYTE, m.ulParameterLen)?.to_vec(),
+  })
+}
+
+unsafe fn out<'a, T>(ptr: *mut T) -> Result<&'a mut T> {
+  ptr.as_mut().ok_or(CKR_ARGUMENTS_BAD)
+}
+
+/// Writes a list with the two-call convention.
+unsafe fn list<T: Copy>(items: &[T], out: *mut T, count: CK_ULONG_PTR) -> Result<()> {
+  let count = self::out(count)?;
+  let available = *count as usize;
+  *count = items.len() as CK_ULONG;
+  if out.is_null() {
+    return Ok(());
+  }
+  if available < items.len() {
+    return Err(CKR_BUFFER_TOO_SMALL);
+  }
+  slice::from_raw_parts_mut(out, items.len()).copy_from_slice(items);
+  Ok(())
+}
+
+/// Identifies the input of a key wrap for [`Session::output`].
+fn wrap_input(mechanism: &Mechanism, wrapping_key: CK_OBJECT_HANDLE, key: CK_OBJECT_HANDLE) -> Vec<u8> {
+  let mut input = Vec::new();
+  for n in &[mechanism.mechanism, wrapping_key, key] {
+    input.extend_from_slice(&n.to_ne_bytes());
+  }
+  input.extend_from_slice(&mechanism.parameter);
+  input
+}
+
+/// The functions behind the entry points generated by [`pkcs11_provider!`],
+/// named after the PKCS#11 function they implement.
+#[doc(hidden)]
+pub mod entry {
+  // these take the raw arguments of the C functions; the PKCS#11 caller vouches for the pointers
+  #![allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+
+  use super::*;
+
+  pub unsafe fn C_GetFunctionList(list: &'static CK_FUNCTION_LIST, ppFunctionList: CK_FUNCTION_LIST_PTR_PTR) -> CK_RV {
+    match out(ppFunctionList) {
+      Ok(pp) => {
+        *pp = list as *const CK_FUNCTION_LIST as CK_FUNCTION_LIST_PTR;
+        CKR_OK
+      }
+      Err(rv) => rv,
+    }
+  }
+
+  pub unsafe fn C_Initialize<P: Pkcs11Provider>(state: &State<P>, pInitArgs: CK_C_INITIALIZE_ARGS_PTR) -> CK_RV {
+    guard(|| {
+      // the lock is a std mutex, so the locking callbacks are not needed
+      if !pInitArgs.is_null() && !(*pInitArgs).pReserved.is_null() {
+        return Err(CKR_ARGUMENTS_BAD);
+      }
+      let mut inner = state.lock();
+      if inner.is_some() {
+        return Err(CKR_CRYPTOKI_ALREADY_INITIALIZED);
+      }
+      *inner = Some(Inner { provider: P::initialize()?, sessions: HandleTable::new() });
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_Finalize<P: Pkcs11Provider>(state: &State<P>, pReserved: CK_VOID_PTR) -> CK_RV {
+    guard(|| {
+      if !pReserved.is_null() {
+        return Err(CKR_ARGUMENTS_BAD);
+      }
+      let Inner { mut provider, mut sessions } = state.lock().take().ok_or(CKR_CRYPTOKI_NOT_INITIALIZED)?;
+      for handle in sessions.handles() {
+        provider.close_session(sessions.remove(handle).unwrap().state);
+      }
+      provider.finalize();
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetInfo<P: Pkcs11Provider>(state: &State<P>, pInfo: CK_INFO_PTR) -> CK_RV {
+    state.run(|inner| {
+      *out(pInfo)? = inner.provider.info();
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetSlotList<P: Pkcs11Provider>(state: &State<P>, tokenPresent: CK_BBOOL, pSlotList: CK_SLOT_ID_PTR, pulCount: CK_ULONG_PTR) -> CK_RV {
+    state.run(|inner| list(&inner.provider.slots(tokenPresent != CK_FALSE), pSlotList, pulCount))
+  }
+
+  pub unsafe fn C_GetSlotInfo<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR) -> CK_RV {
+    state.run(|inner| {
+      let info = out(pInfo)?;
+      *info = inner.provider.slot_info(slotID)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetTokenInfo<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR) -> CK_RV {
+    state.run(|inner| {
+      let info = out(pInfo)?;
+      *info = inner.provider.token_info(slotID)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetMechanismList<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, pMechanismList: CK_MECHANISM_TYPE_PTR, pulCount: CK_ULONG_PTR) -> CK_RV {
+    state.run(|inner| list(&inner.provider.mechanisms(slotID)?, pMechanismList, pulCount))
+  }
+
+  pub unsafe fn C_GetMechanismInfo<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, mechType: CK_MECHANISM_TYPE, pInfo: CK_MECHANISM_INFO_PTR) -> CK_RV {
+    state.run(|inner| {
+      let info = out(pInfo)?;
+      *info = inner.provider.mechanism_info(slotID, mechType)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_InitToken<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG, pLabel: CK_UTF8CHAR_PTR) -> CK_RV {
+    state.run(|inner| {
+      let label = out(pLabel as *mut [CK_UTF8CHAR; 32])?;
+      if inner.sessions.iter().any(|(_, s)| s.slot == slotID) {
+        return Err(CKR_SESSION_EXISTS);
+      }
+      inner.provider.init_token(slotID, pin(pPin, ulPinLen)?, label)
+    })
+  }
+
+  pub unsafe fn C_InitPIN<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_InitPIN", |p, s| p.init_pin(&mut s.state, pin(pPin, ulPinLen)?))
+  }
+
+  pub unsafe fn C_SetPIN<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pOldPin: CK_UTF8CHAR_PTR, ulOldLen: CK_ULONG, pNewPin: CK_UTF8CHAR_PTR, ulNewLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_SetPIN", |p, s| p.set_pin(&mut s.state, pin(pOldPin, ulOldLen)?, pin(pNewPin, ulNewLen)?))
+  }
+
+  pub unsafe fn C_OpenSession<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID, flags: CK_FLAGS, _pApplication: CK_VOID_PTR, _Notify: CK_NOTIFY, phSession: CK_SESSION_HANDLE_PTR) -> CK_RV {
+    state.run(|inner| {
+      let handle = out(phSession)?;
+      if flags & CKF_SERIAL_SESSION == 0 {
+        return Err(CKR_SESSION_PARALLEL_NOT_SUPPORTED);
+      }
+      let session = inner.provider.open_session(slotID, flags)?;
+      *handle = inner.sessions.insert(Session {
+        slot: slotID,
+        flags: flags & (CKF_RW_SESSION | CKF_SERIAL_SESSION),
+        state: session,
+        find: None,
+        pending: None,
+      });
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_CloseSession<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE) -> CK_RV {
+    state.run(|inner| {
+      let session = inner.sessions.remove(hSession).ok_or(CKR_SESSION_HANDLE_INVALID)?;
+      inner.provider.close_session(session.state);
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_CloseAllSessions<P: Pkcs11Provider>(state: &State<P>, slotID: CK_SLOT_ID) -> CK_RV {
+    state.run(|inner| {
+      if !inner.provider.slots(false).contains(&slotID) {
+        return Err(CKR_SLOT_ID_INVALID);
+      }
+      for handle in inner.sessions.handles() {
+        if inner.sessions.get(handle).unwrap().slot == slotID {
+          inner.provider.close_session(inner.sessions.remove(handle).unwrap().state);
+        }
+      }
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetSessionInfo<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR) -> CK_RV {
+    state.session(hSession, "C_GetSessionInfo", |p, s| {
+      *out(pInfo)? = CK_SESSION_INFO {
+        slotID: s.slot,
+        state: p.session_state(&s.state),
+        flags: s.flags,
+        ulDeviceError: 0,
+      };
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetOperationState<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, pulOperationStateLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_GetOperationState", |p, s| s.output("C_GetOperationState", &[], pOperationState, pulOperationStateLen, |s| p.operation_state(s)))
+  }
+
+  pub unsafe fn C_SetOperationState<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    pOperationState: CK_BYTE_PTR,
+    ulOperationStateLen: CK_ULONG,
+    hEncryptionKey: CK_OBJECT_HANDLE,
+    hAuthenticationKey: CK_OBJECT_HANDLE,
+  ) -> CK_RV {
+    state.session(hSession, "C_SetOperationState", |p, s| {
+      p.set_operation_state(&mut s.state, bytes(pOperationState, ulOperationStateLen)?, hEncryptionKey, hAuthenticationKey)
+    })
+  }
+
+  pub unsafe fn C_Login<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, userType: CK_USER_TYPE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_Login", |p, s| p.login(&mut s.state, userType, pin(pPin, ulPinLen)?))
+  }
+
+  pub unsafe fn C_Logout<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE) -> CK_RV {
+    state.session(hSession, "C_Logout", |p, s| p.logout(&mut s.state))
+  }
+
+  pub unsafe fn C_CreateObject<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phObject: CK_OBJECT_HANDLE_PTR) -> CK_RV {
+    state.session(hSession, "C_CreateObject", |p, s| {
+      let handle = out(phObject)?;
+      *handle = p.create_object(&mut s.state, &template(pTemplate, ulCount)?)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_CopyObject<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    hObject: CK_OBJECT_HANDLE,
+    pTemplate: CK_ATTRIBUTE_PTR,
+    ulCount: CK_ULONG,
+    phNewObject: CK_OBJECT_HANDLE_PTR,
+  ) -> CK_RV {
+    state.session(hSession, "C_CopyObject", |p, s| {
+      let handle = out(phNewObject)?;
+      *handle = p.copy_object(&mut s.state, hObject, &template(pTemplate, ulCount)?)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_DestroyObject<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_DestroyObject", |p, s| p.destroy_object(&mut s.state, hObject))
+  }
+
+  pub unsafe fn C_GetObjectSize<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pulSize: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_GetObjectSize", |p, s| {
+      let size = out(pulSize)?;
+      *size = p.object_size(&mut s.state, hObject)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GetAttributeValue<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_GetAttributeValue", |p, s| {
+      if pTemplate.is_null() && ulCount != 0 {
+        return Err(CKR_ARGUMENTS_BAD);
+      }
+      let attrs = if ulCount == 0 { &mut [] } else { slice::from_raw_parts_mut(pTemplate, ulCount as usize) };
+      let mut result = Ok(());
+      for attr in attrs.iter_mut() {
+        let unavailable = match p.attribute(&mut s.state, hObject, attr.attrType) {
+          Ok(ref value) if attr.pValue.is_null() => {
+            attr.ulValueLen = value.len() as CK_ULONG;
+            continue;
+          }
+          Ok(ref value) if (attr.ulValueLen as usize) >= value.len() => {
+            slice::from_raw_parts_mut(attr.pValue as *mut CK_BYTE, value.len()).copy_from_slice(value);
+            attr.ulValueLen = value.len() as CK_ULONG;
+            continue;
+          }
+          Ok(_) => CKR_BUFFER_TOO_SMALL,
+          Err(rv @ CKR_ATTRIBUTE_SENSITIVE) | Err(rv @ CKR_ATTRIBUTE_TYPE_INVALID) => rv,
+          Err(rv) => return Err(rv),
+        };
+        attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+        result = result.and(Err(unavailable));
+      }
+      result
+    })
+  }
+
+  pub unsafe fn C_SetAttributeValue<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_SetAttributeValue", |p, s| p.set_attributes(&mut s.state, hObject, &template(pTemplate, ulCount)?))
+  }
+
+  pub unsafe fn C_FindObjectsInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_FindObjectsInit", |p, s| {
+      if s.find.is_some() {
+        return Err(CKR_OPERATION_ACTIVE);
+      }
+      let found = p.find_objects(&mut s.state, &template(pTemplate, ulCount)?)?;
+      s.find = Some(found.into());
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_FindObjects<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, phObject: CK_OBJECT_HANDLE_PTR, ulMaxObjectCount: CK_ULONG, pulObjectCount: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_FindObjects", |_, s| {
+      let count = out(pulObjectCount)?;
+      if phObject.is_null() && ulMaxObjectCount != 0 {
+        return Err(CKR_ARGUMENTS_BAD);
+      }
+      let found = s.find.as_mut().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+      let n = found.len().min(ulMaxObjectCount as usize);
+      for (i, handle) in found.drain(..n).enumerate() {
+        *phObject.add(i) = handle;
+      }
+      *count = n as CK_ULONG;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_FindObjectsFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE) -> CK_RV {
+    state.session(hSession, "C_FindObjectsFinal", |_, s| s.find.take().map(|_| ()).ok_or(CKR_OPERATION_NOT_INITIALIZED))
+  }
+
+  pub unsafe fn C_EncryptInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_EncryptInit", |p, s| p.encrypt_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_Encrypt<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pEncryptedData: CK_BYTE_PTR, pulEncryptedDataLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_Encrypt", |p, s| {
+      let data = bytes(pData, ulDataLen)?;
+      s.output("C_Encrypt", data, pEncryptedData, pulEncryptedDataLen, |s| p.encrypt(s, data))
+    })
+  }
+
+  pub unsafe fn C_EncryptUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_EncryptUpdate", |p, s| {
+      let part = bytes(pPart, ulPartLen)?;
+      s.output("C_EncryptUpdate", part, pEncryptedPart, pulEncryptedPartLen, |s| p.encrypt_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_EncryptFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pLastEncryptedPart: CK_BYTE_PTR, pulLastEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_EncryptFinal", |p, s| s.output("C_EncryptFinal", &[], pLastEncryptedPart, pulLastEncryptedPartLen, |s| p.encrypt_final(s)))
+  }
+
+  pub unsafe fn C_DecryptInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_DecryptInit", |p, s| p.decrypt_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_Decrypt<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pEncryptedData: CK_BYTE_PTR, ulEncryptedDataLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_Decrypt", |p, s| {
+      let data = bytes(pEncryptedData, ulEncryptedDataLen)?;
+      s.output("C_Decrypt", data, pData, pulDataLen, |s| p.decrypt(s, data))
+    })
+  }
+
+  pub unsafe fn C_DecryptUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DecryptUpdate", |p, s| {
+      let part = bytes(pEncryptedPart, ulEncryptedPartLen)?;
+      s.output("C_DecryptUpdate", part, pPart, pulPartLen, |s| p.decrypt_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_DecryptFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pLastPart: CK_BYTE_PTR, pulLastPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DecryptFinal", |p, s| s.output("C_DecryptFinal", &[], pLastPart, pulLastPartLen, |s| p.decrypt_final(s)))
+  }
+
+  pub unsafe fn C_DigestInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR) -> CK_RV {
+    state.session(hSession, "C_DigestInit", |p, s| p.digest_init(&mut s.state, &mechanism(pMechanism)?))
+  }
+
+  pub unsafe fn C_Digest<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_Digest", |p, s| {
+      let data = bytes(pData, ulDataLen)?;
+      s.output("C_Digest", data, pDigest, pulDigestLen, |s| p.digest(s, data))
+    })
+  }
+
+  pub unsafe fn C_DigestUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_DigestUpdate", |p, s| p.digest_update(&mut s.state, bytes(pPart, ulPartLen)?))
+  }
+
+  pub unsafe fn C_DigestKey<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_DigestKey", |p, s| p.digest_key(&mut s.state, hKey))
+  }
+
+  pub unsafe fn C_DigestFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DigestFinal", |p, s| s.output("C_DigestFinal", &[], pDigest, pulDigestLen, |s| p.digest_final(s)))
+  }
+
+  pub unsafe fn C_SignInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_SignInit", |p, s| p.sign_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_Sign<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_Sign", |p, s| {
+      let data = bytes(pData, ulDataLen)?;
+      s.output("C_Sign", data, pSignature, pulSignatureLen, |s| p.sign(s, data))
+    })
+  }
+
+  pub unsafe fn C_SignUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_SignUpdate", |p, s| p.sign_update(&mut s.state, bytes(pPart, ulPartLen)?))
+  }
+
+  pub unsafe fn C_SignFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_SignFinal", |p, s| s.output("C_SignFinal", &[], pSignature, pulSignatureLen, |s| p.sign_final(s)))
+  }
+
+  pub unsafe fn C_SignRecoverInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_SignRecoverInit", |p, s| p.sign_recover_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_SignRecover<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_SignRecover", |p, s| {
+      let data = bytes(pData, ulDataLen)?;
+      s.output("C_SignRecover", data, pSignature, pulSignatureLen, |s| p.sign_recover(s, data))
+    })
+  }
+
+  pub unsafe fn C_VerifyInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_VerifyInit", |p, s| p.verify_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_Verify<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_Verify", |p, s| p.verify(&mut s.state, bytes(pData, ulDataLen)?, bytes(pSignature, ulSignatureLen)?))
+  }
+
+  pub unsafe fn C_VerifyUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_VerifyUpdate", |p, s| p.verify_update(&mut s.state, bytes(pPart, ulPartLen)?))
+  }
+
+  pub unsafe fn C_VerifyFinal<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_VerifyFinal", |p, s| p.verify_final(&mut s.state, bytes(pSignature, ulSignatureLen)?))
+  }
+
+  pub unsafe fn C_VerifyRecoverInit<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE) -> CK_RV {
+    state.session(hSession, "C_VerifyRecoverInit", |p, s| p.verify_recover_init(&mut s.state, &mechanism(pMechanism)?, hKey))
+  }
+
+  pub unsafe fn C_VerifyRecover<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_VerifyRecover", |p, s| {
+      let signature = bytes(pSignature, ulSignatureLen)?;
+      s.output("C_VerifyRecover", signature, pData, pulDataLen, |s| p.verify_recover(s, signature))
+    })
+  }
+
+  pub unsafe fn C_DigestEncryptUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DigestEncryptUpdate", |p, s| {
+      let part = bytes(pPart, ulPartLen)?;
+      s.output("C_DigestEncryptUpdate", part, pEncryptedPart, pulEncryptedPartLen, |s| p.digest_encrypt_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_DecryptDigestUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DecryptDigestUpdate", |p, s| {
+      let part = bytes(pEncryptedPart, ulEncryptedPartLen)?;
+      s.output("C_DecryptDigestUpdate", part, pPart, pulPartLen, |s| p.decrypt_digest_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_SignEncryptUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_SignEncryptUpdate", |p, s| {
+      let part = bytes(pPart, ulPartLen)?;
+      s.output("C_SignEncryptUpdate", part, pEncryptedPart, pulEncryptedPartLen, |s| p.sign_encrypt_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_DecryptVerifyUpdate<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR) -> CK_RV {
+    state.session(hSession, "C_DecryptVerifyUpdate", |p, s| {
+      let part = bytes(pEncryptedPart, ulEncryptedPartLen)?;
+      s.output("C_DecryptVerifyUpdate", part, pPart, pulPartLen, |s| p.decrypt_verify_update(s, part))
+    })
+  }
+
+  pub unsafe fn C_GenerateKey<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR) -> CK_RV {
+    state.session(hSession, "C_GenerateKey", |p, s| {
+      let handle = out(phKey)?;
+      *handle = p.generate_key(&mut s.state, &mechanism(pMechanism)?, &template(pTemplate, ulCount)?)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_GenerateKeyPair<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    pPublicKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPublicKeyAttributeCount: CK_ULONG,
+    pPrivateKeyTemplate: CK_ATTRIBUTE_PTR,
+    ulPrivateKeyAttributeCount: CK_ULONG,
+    phPublicKey: CK_OBJECT_HANDLE_PTR,
+    phPrivateKey: CK_OBJECT_HANDLE_PTR,
+  ) -> CK_RV {
+    state.session(hSession, "C_GenerateKeyPair", |p, s| {
+      let public_handle = out(phPublicKey)?;
+      let private_handle = out(phPrivateKey)?;
+      let public_template = template(pPublicKeyTemplate, ulPublicKeyAttributeCount)?;
+      let private_template = template(pPrivateKeyTemplate, ulPrivateKeyAttributeCount)?;
+      let (public_key, private_key) = p.generate_key_pair(&mut s.state, &mechanism(pMechanism)?, &public_template, &private_template)?;
+      *public_handle = public_key;
+      *private_handle = private_key;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_WrapKey<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hWrappingKey: CK_OBJECT_HANDLE,
+    hKey: CK_OBJECT_HANDLE,
+    pWrappedKey: CK_BYTE_PTR,
+    pulWrappedKeyLen: CK_ULONG_PTR,
+  ) -> CK_RV {
+    state.session(hSession, "C_WrapKey", |p, s| {
+      let mechanism = mechanism(pMechanism)?;
+      let input = wrap_input(&mechanism, hWrappingKey, hKey);
+      s.output("C_WrapKey", &input, pWrappedKey, pulWrappedKeyLen, |s| p.wrap_key(s, &mechanism, hWrappingKey, hKey))
+    })
+  }
+
+  pub unsafe fn C_UnwrapKey<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hUnwrappingKey: CK_OBJECT_HANDLE,
+    pWrappedKey: CK_BYTE_PTR,
+    ulWrappedKeyLen: CK_ULONG,
+    pTemplate: CK_ATTRIBUTE_PTR,
+    ulAttributeCount: CK_ULONG,
+    phKey: CK_OBJECT_HANDLE_PTR,
+  ) -> CK_RV {
+    state.session(hSession, "C_UnwrapKey", |p, s| {
+      let handle = out(phKey)?;
+      *handle = p.unwrap_key(&mut s.state, &mechanism(pMechanism)?, hUnwrappingKey, bytes(pWrappedKey, ulWrappedKeyLen)?, &template(pTemplate, ulAttributeCount)?)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_DeriveKey<P: Pkcs11Provider>(
+    state: &State<P>,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hBaseKey: CK_OBJECT_HANDLE,
+    pTemplate: CK_ATTRIBUTE_PTR,
+    ulAttributeCount: CK_ULONG,
+    phKey: CK_OBJECT_HANDLE_PTR,
+  ) -> CK_RV {
+    state.session(hSession, "C_DeriveKey", |p, s| {
+      let handle = out(phKey)?;
+      *handle = p.derive_key(&mut s.state, &mechanism(pMechanism)?, hBaseKey, &template(pTemplate, ulAttributeCount)?)?;
+      Ok(())
+    })
+  }
+
+  pub unsafe fn C_SeedRandom<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, pSeed: CK_BYTE_PTR, ulSeedLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_SeedRandom", |p, s| p.seed_random(&mut s.state, bytes(pSeed, ulSeedLen)?))
+  }
+
+  pub unsafe fn C_GenerateRandom<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE, RandomData: CK_BYTE_PTR, ulRandomLen: CK_ULONG) -> CK_RV {
+    state.session(hSession, "C_GenerateRandom", |p, s| p.generate_random(&mut s.state, bytes_mut(RandomData, ulRandomLen)?))
+  }
+
+  pub unsafe fn C_GetFunctionStatus<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE) -> CK_RV {
+    state.session(hSession, "C_GetFunctionStatus", |_, _| Err(CKR_FUNCTION_NOT_PARALLEL))
+  }
+
+  pub unsafe fn C_CancelFunction<P: Pkcs11Provider>(state: &State<P>, hSession: CK_SESSION_HANDLE) -> CK_RV {
+    state.session(hSession, "C_CancelFunction", |_, _| Err(CKR_FUNCTION_NOT_PARALLEL))
+  }
+
+  pub unsafe fn C_WaitForSlotEvent<P: Pkcs11Provider>(state: &State<P>, _flags: CK_FLAGS, _pSlot: CK_SLOT_ID_PTR, _pReserved: CK_VOID_PTR) -> CK_RV {
+    state.run(|_| Err(CKR_FUNCTION_NOT_SUPPORTED))
+  }
+}
+
+/// Exports a [`Pkcs11Provider`] as a PKCS#11 module: generates
+/// `C_GetFunctionList` and all `extern "C"` entry points of the `cdylib`.
+/// Invoke it once, at the crate root of the module.
+#[macro_export]
+macro_rules! pkcs11_provider {
+  ($provider:ty) => {
+    static PKCS11_PROVIDER_STATE: $crate::provider::State<$provider> = $crate::provider::State::new();
+
+    $crate::__pkcs11_provider_functions! {
+      PKCS11_PROVIDER_STATE;
+      C_Initialize(pInitArgs: CK_C_INITIALIZE_ARGS_PTR);
+      C_Finalize(pReserved: CK_VOID_PTR);
+      C_GetInfo(pInfo: CK_INFO_PTR);
+      C_GetSlotList(tokenPresent: CK_BBOOL, pSlotList: CK_SLOT_ID_PTR, pulCount: CK_ULONG_PTR);
+      C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR);
+      C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR);
+      C_GetMechanismList(slotID: CK_SLOT_ID, pMechanismList: CK_MECHANISM_TYPE_PTR, pulCount: CK_ULONG_PTR);
+      C_GetMechanismInfo(slotID: CK_SLOT_ID, mechType: CK_MECHANISM_TYPE, pInfo: CK_MECHANISM_INFO_PTR);
+      C_InitToken(slotID: CK_SLOT_ID, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG, pLabel: CK_UTF8CHAR_PTR);
+      C_InitPIN(hSession: CK_SESSION_HANDLE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG);
+      C_SetPIN(hSession: CK_SESSION_HANDLE, pOldPin: CK_UTF8CHAR_PTR, ulOldLen: CK_ULONG, pNewPin: CK_UTF8CHAR_PTR, ulNewLen: CK_ULONG);
+      C_OpenSession(slotID: CK_SLOT_ID, flags: CK_FLAGS, pApplication: CK_VOID_PTR, Notify: CK_NOTIFY, phSession: CK_SESSION_HANDLE_PTR);
+      C_CloseSession(hSession: CK_SESSION_HANDLE);
+      C_CloseAllSessions(slotID: CK_SLOT_ID);
+      C_GetSessionInfo(hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR);
+      C_GetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, pulOperationStateLen: CK_ULONG_PTR);
+      C_SetOperationState(hSession: CK_SESSION_HANDLE, pOperationState: CK_BYTE_PTR, ulOperationStateLen: CK_ULONG, hEncryptionKey: CK_OBJECT_HANDLE, hAuthenticationKey: CK_OBJECT_HANDLE);
+      C_Login(hSession: CK_SESSION_HANDLE, userType: CK_USER_TYPE, pPin: CK_UTF8CHAR_PTR, ulPinLen: CK_ULONG);
+      C_Logout(hSession: CK_SESSION_HANDLE);
+      C_CreateObject(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phObject: CK_OBJECT_HANDLE_PTR);
+      C_CopyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phNewObject: CK_OBJECT_HANDLE_PTR);
+      C_DestroyObject(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE);
+      C_GetObjectSize(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pulSize: CK_ULONG_PTR);
+      C_GetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+      C_SetAttributeValue(hSession: CK_SESSION_HANDLE, hObject: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+      C_FindObjectsInit(hSession: CK_SESSION_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG);
+      C_FindObjects(hSession: CK_SESSION_HANDLE, phObject: CK_OBJECT_HANDLE_PTR, ulMaxObjectCount: CK_ULONG, pulObjectCount: CK_ULONG_PTR);
+      C_FindObjectsFinal(hSession: CK_SESSION_HANDLE);
+      C_EncryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_Encrypt(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pEncryptedData: CK_BYTE_PTR, pulEncryptedDataLen: CK_ULONG_PTR);
+      C_EncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+      C_EncryptFinal(hSession: CK_SESSION_HANDLE, pLastEncryptedPart: CK_BYTE_PTR, pulLastEncryptedPartLen: CK_ULONG_PTR);
+      C_DecryptInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_Decrypt(hSession: CK_SESSION_HANDLE, pEncryptedData: CK_BYTE_PTR, ulEncryptedDataLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR);
+      C_DecryptUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+      C_DecryptFinal(hSession: CK_SESSION_HANDLE, pLastPart: CK_BYTE_PTR, pulLastPartLen: CK_ULONG_PTR);
+      C_DigestInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR);
+      C_Digest(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR);
+      C_DigestUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+      C_DigestKey(hSession: CK_SESSION_HANDLE, hKey: CK_OBJECT_HANDLE);
+      C_DigestFinal(hSession: CK_SESSION_HANDLE, pDigest: CK_BYTE_PTR, pulDigestLen: CK_ULONG_PTR);
+      C_SignInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_Sign(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+      C_SignUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+      C_SignFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+      C_SignRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_SignRecover(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, pulSignatureLen: CK_ULONG_PTR);
+      C_VerifyInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_Verify(hSession: CK_SESSION_HANDLE, pData: CK_BYTE_PTR, ulDataLen: CK_ULONG, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG);
+      C_VerifyUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG);
+      C_VerifyFinal(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG);
+      C_VerifyRecoverInit(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hKey: CK_OBJECT_HANDLE);
+      C_VerifyRecover(hSession: CK_SESSION_HANDLE, pSignature: CK_BYTE_PTR, ulSignatureLen: CK_ULONG, pData: CK_BYTE_PTR, pulDataLen: CK_ULONG_PTR);
+      C_DigestEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+      C_DecryptDigestUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+      C_SignEncryptUpdate(hSession: CK_SESSION_HANDLE, pPart: CK_BYTE_PTR, ulPartLen: CK_ULONG, pEncryptedPart: CK_BYTE_PTR, pulEncryptedPartLen: CK_ULONG_PTR);
+      C_DecryptVerifyUpdate(hSession: CK_SESSION_HANDLE, pEncryptedPart: CK_BYTE_PTR, ulEncryptedPartLen: CK_ULONG, pPart: CK_BYTE_PTR, pulPartLen: CK_ULONG_PTR);
+      C_GenerateKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, pTemplate: CK_ATTRIBUTE_PTR, ulCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR);
+      C_GenerateKeyPair(
+        hSession: CK_SESSION_HANDLE,
+        pMechanism: CK_MECHANISM_PTR,
+        pPublicKeyTemplate: CK_ATTRIBUTE_PTR,
+        ulPublicKeyAttributeCount: CK_ULONG,
+        pPrivateKeyTemplate: CK_ATTRIBUTE_PTR,
+        ulPrivateKeyAttributeCount: CK_ULONG,
+        phPublicKey: CK_OBJECT_HANDLE_PTR,
+        phPrivateKey: CK_OBJECT_HANDLE_PTR
+      );
+      C_WrapKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hWrappingKey: CK_OBJECT_HANDLE, hKey: CK_OBJECT_HANDLE, pWrappedKey: CK_BYTE_PTR, pulWrappedKeyLen: CK_ULONG_PTR);
+      C_UnwrapKey(
+        hSession: CK_SESSION_HANDLE,
+        pMechanism: CK_MECHANISM_PTR,
+        hUnwrappingKey: CK_OBJECT_HANDLE,
+        pWrappedKey: CK_BYTE_PTR,
+        ulWrappedKeyLen: CK_ULONG,
+        pTemplate: CK_ATTRIBUTE_PTR,
+        ulAttributeCount: CK_ULONG,
+        phKey: CK_OBJECT_HANDLE_PTR
+      );
+      C_DeriveKey(hSession: CK_SESSION_HANDLE, pMechanism: CK_MECHANISM_PTR, hBaseKey: CK_OBJECT_HANDLE, pTemplate: CK_ATTRIBUTE_PTR, ulAttributeCount: CK_ULONG, phKey: CK_OBJECT_HANDLE_PTR);
+      C_SeedRandom(hSession: CK_SESSION_HANDLE, pSeed: CK_BYTE_PTR, ulSeedLen: CK_ULONG);
+      C_GenerateRandom(hSession: CK_SESSION_HANDLE, RandomData: CK_BYTE_PTR, ulRandomLen: CK_ULONG);
+      C_GetFunctionStatus(hSession: CK_SESSION_HANDLE);
+      C_CancelFunction(hSession: CK_SESSION_HANDLE);
+      C_WaitForSlotEvent(flags: CK_FLAGS, pSlot: CK_SLOT_ID_PTR, pRserved: CK_VOID_PTR);
+    }
+  };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __pkcs11_provider_functions {
+  ($state:ident; $($name:ident($($arg:ident: $ty:ident),*);)*) => {
+    $(
+      #[no_mangle]
+      #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref, clippy::too_many_arguments)]
+      pub extern "C" fn $name($($arg: $crate::types::$ty),*) -> $crate::types::CK_RV {
+        unsafe { $crate::provider::entry::$name(&$state, $($arg),*) }
+      }
+    )*
+
+    static PKCS11_PROVIDER_FUNCTIONS: $crate::types::CK_FUNCTION_LIST = $crate::types::CK_FUNCTION_LIST {
+      version: $crate::types::CK_VERSION { major: 2, minor: 40 },
+      C_GetFunctionList: Some(C_GetFunctionList),
+      $($name: Some($name),)*
+    };
+
+    #[no_mangle]
+    #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+    pub extern "C" fn C_GetFunctionList(ppFunctionList: $crate::types::CK_FUNCTION_LIST_PTR_PTR) -> $crate::types::CK_RV {
+      unsafe { $crate::provider::entry::C_GetFunctionList(&PKCS11_PROVIDER_FUNCTIONS, ppFunctionList) }
+    }
+  };
+}
@@ -0,0 +1,346 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bringing a token into a described state: initialized, with a user PIN
+//! and the keys, certificates and data objects it should have.
+//!
+//! A [`TokenPlan`] describes the token. [`TokenPlan::apply`] only does what
+//! is missing, so it can run again and again, and reports what it changed:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::pin::EnvPin;
+//! # use pkcs11::provision::{Curve, ObjectPlan, TokenPlan};
+//! let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! let plan = TokenPlan::new("signing")
+//!   .object(ObjectPlan::EcKeyPair { label: "signer".into(), id: vec![1], curve: Curve::P256 })
+//!   .object(ObjectPlan::AesKey { label: "backup".into(), id: vec![2], bits: 256 });
+//! let report = plan.apply(&ctx, 0, &EnvPin::new("SO_PIN"), &EnvPin::new("USER_PIN")).unwrap();
+//! for change in &report.changes {
+//!   println!("{}", change);
+//! }
+//! ```
+//!
+//! An uninitialized token is initialized with the SO PIN and the label, a
+//! token without a user PIN gets one, and objects are created unless one of
+//! the same class, label and, if given, `CKA_ID` is there. Keys are token
+//! objects that are private and sensitive; objects that exist already are
+//! left as they are. A token with another label is refused rather than
+//! initialized again.
+//!
+//! With the `serde` feature, plans implement `Serialize` and `Deserialize`,
+//! so they can be kept in a JSON, TOML or YAML file. Objects are tagged with
+//! `type`, and IDs, data values and certificates are hex strings:
+//!
+//! ```text
+//! { "label": "signing", "objects": [
+//!   { "type": "ec_key_pair", "label": "signer", "id": "01", "curve": "p256" },
+//!   { "type": "data", "label": "config", "application": "app", "value": "cafe" } ] }
+//! ```
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{label_from_str, Ctx};
+use errors::Error;
+use info::trimmed;
+use keygen::{AesKey, EcKeyPair, RsaKeyPair};
+use pin::{self, PinRequest, PinSource};
+use secret::SecretPin;
+use types::*;
+use x509::Certificate;
+
+pub use keygen::Curve;
+
+/// An object the token should have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(tag = "type", rename_all = "snake_case"))]
+pub enum ObjectPlan {
+  /// An RSA key pair for signing and decryption, exponent 65537.
+  RsaKeyPair {
+    label: String,
+    #[cfg_attr(feature = "serde", serde(default, with = "hex_bytes"))]
+    id: Vec<u8>,
+    bits: CK_ULONG,
+  },
+  /// An EC key pair for signing.
+  EcKeyPair {
+    label: String,
+    #[cfg_attr(feature = "serde", serde(default, with = "hex_bytes"))]
+    id: Vec<u8>,
+    curve: Curve,
+  },
+  /// An AES key for encryption.
+  AesKey {
+    label: String,
+    #[cfg_attr(feature = "serde", serde(default, with = "hex_bytes"))]
+    id: Vec<u8>,
+    bits: CK_ULONG,
+  },
+  /// An X.509 certificate, in DER. Without an ID, it gets its subject key
+  /// identifier.
+  Certificate {
+    label: String,
+    #[cfg_attr(feature = "serde", serde(default, with = "hex_bytes"))]
+    id: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+    der: Vec<u8>,
+  },
+  /// A public data object.
+  Data {
+    label: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    application: String,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+    value: Vec<u8>,
+  },
+}
+
+impl ObjectPlan {
+  pub fn label(&self) -> &str {
+    match *self {
+      ObjectPlan::RsaKeyPair { ref label, .. }
+      | ObjectPlan::EcKeyPair { ref label, .. }
+      | ObjectPlan::AesKey { ref label, .. }
+      | ObjectPlan::Certificate { ref label, .. }
+      | ObjectPlan::Data { ref label, .. } => label,
+    }
+  }
+
+  /// The class the object is looked up by, the private key for key pairs.
+  fn class(&self) -> CK_OBJECT_CLASS {
+    match *self {
+      ObjectPlan::RsaKeyPair { .. } | ObjectPlan::EcKeyPair { .. } => CKO_PRIVATE_KEY,
+      ObjectPlan::AesKey { .. } => CKO_SECRET_KEY,
+      ObjectPlan::Certificate { .. } => CKO_CERTIFICATE,
+      ObjectPlan::Data { .. } => CKO_DATA,
+    }
+  }
+
+  fn id(&self) -> &[u8] {
+    match *self {
+      ObjectPlan::RsaKeyPair { ref id, .. } | ObjectPlan::EcKeyPair { ref id, .. } | ObjectPlan::AesKey { ref id, .. } | ObjectPlan::Certificate { ref id, .. } => id,
+      ObjectPlan::Data { .. } => &[],
+    }
+  }
+
+  fn kind(&self) -> &'static str {
+    match *self {
+      ObjectPlan::RsaKeyPair { .. } => "RSA key pair",
+      ObjectPlan::EcKeyPair { .. } => "EC key pair",
+      ObjectPlan::AesKey { .. } => "AES key",
+      ObjectPlan::Certificate { .. } => "certificate",
+      ObjectPlan::Data { .. } => "data object",
+    }
+  }
+}
+
+/// What a token should look like.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TokenPlan {
+  pub label: String,
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub objects: Vec<ObjectPlan>,
+}
+
+/// Something [`TokenPlan::apply`] did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+  TokenInitialized,
+  UserPinInitialized,
+  Created { kind: &'static str, label: String },
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Change::TokenInitialized => f.write_str("initialized the token"),
+      Change::UserPinInitialized => f.write_str("set the user PIN"),
+      Change::Created { kind, ref label } => write!(f, "created {} {:?}", kind, label),
+    }
+  }
+}
+
+/// What [`TokenPlan::apply`] changed, and which objects were there already.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+  pub changes: Vec<Change>,
+  pub unchanged: Vec<String>,
+}
+
+impl Report {
+  pub fn is_changed(&self) -> bool {
+    !self.changes.is_empty()
+  }
+}
+
+impl TokenPlan {
+  pub fn new(label: &str) -> TokenPlan {
+    TokenPlan {
+      label: label.to_string(),
+      objects: Vec::new(),
+    }
+  }
+
+  pub fn object(mut self, object: ObjectPlan) -> TokenPlan {
+    self.objects.push(object);
+    self
+  }
+
+  /// Brings the token in `slot_id` into the planned state, asking `so_pin`
+  /// for the SO PIN only if the token or its user PIN is not initialized
+  /// yet, and `user_pin` for the user PIN.
+  pub fn apply(&self, ctx: &Ctx, slot_id: CK_SLOT_ID, so_pin: &dyn PinSource, user_pin: &dyn PinSource) -> Result<Report, Error> {
+    let mut report = Report::default();
+    let mut token = ctx.get_token_info(slot_id)?;
+    let mut so = None;
+    if token.flags & CKF_TOKEN_INITIALIZED == 0 {
+      let pin = ask(so_pin, slot_id, CKU_SO, &token)?;
+      ctx.init_token_secret(slot_id, pin.as_ref(), &self.label)?;
+      report.changes.push(Change::TokenInitialized);
+      token = ctx.get_token_info(slot_id)?;
+      so = pin;
+    } else if trimmed(&token.label) != trimmed(&label_from_str(&self.label)) {
+      // the token keeps the first 32 bytes of the label only
+      return Err(Error::InvalidInput("the token is initialized with another label"));
+    }
+    let session = ctx.open_session(slot_id, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)?;
+    // the SO PIN the token was initialized with just now is not asked again
+    let so_pin = match so {
+      Some(ref pin) => pin as &dyn PinSource,
+      None => so_pin,
+    };
+    let res = self.apply_in(ctx, session, &token, so_pin, user_pin, &mut report);
+    let _ = ctx.close_session(session);
+    res.map(|()| report)
+  }
+
+  fn apply_in(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, token: &CK_TOKEN_INFO, so_pin: &dyn PinSource, user_pin: &dyn PinSource, report: &mut Report) -> Result<(), Error> {
+    if token.flags & CKF_USER_PIN_INITIALIZED == 0 {
+      pin::login(ctx, session, CKU_SO, so_pin)?;
+      let pin = ask(user_pin, ctx.get_session_info(session)?.slotID, CKU_USER, token)?;
+      ctx.init_pin_secret(session, pin.as_ref())?;
+      ctx.logout(session)?;
+      report.changes.push(Change::UserPinInitialized);
+    }
+    match pin::login(ctx, session, CKU_USER, user_pin) {
+      Ok(_) | Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => {}
+      Err(err) => return Err(err),
+    }
+    for object in &self.objects {
+      if exists(ctx, session, object)? {
+        report.unchanged.push(object.label().to_string());
+      } else {
+        create(ctx, session, object)?;
+        report.changes.push(Change::Created {
+          kind: object.kind(),
+          label: object.label().to_string(),
+        });
+      }
+    }
+    Ok(())
+  }
+}
+
+fn ask(source: &dyn PinSource, slot_id: CK_SLOT_ID, user_type: CK_USER_TYPE, token: &CK_TOKEN_INFO) -> Result<Option<SecretPin>, Error> {
+  source.pin(&PinRequest {
+    slot_id,
+    user_type,
+    token,
+    warning: None,
+  })
+}
+
+fn exists(ctx: &Ctx, session: CK_SESSION_HANDLE, object: &ObjectPlan) -> Result<bool, Error> {
+  let class = object.class();
+  let mut template = vec![
+    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+    CK_ATTRIBUTE::new(CKA_LABEL).with_string(object.label()),
+  ];
+  if !object.id().is_empty() {
+    template.push(CK_ATTRIBUTE::new(CKA_ID).with_bytes(object.id()));
+  }
+  ctx.find_objects_init(session, &template)?;
+  let found = ctx.find_objects(session, 1);
+  ctx.find_objects_final(session)?;
+  Ok(!found?.is_empty())
+}
+
+fn create(ctx: &Ctx, session: CK_SESSION_HANDLE, object: &ObjectPlan) -> Result<(), Error> {
+  let yes = CK_TRUE;
+  let id = match *object {
+    ObjectPlan::Certificate { ref der, .. } if object.id().is_empty() => Certificate::from_der(der)?.subject_key_identifier().unwrap_or_default(),
+    ObjectPlan::Data { .. } => Vec::new(),
+    _ if object.id().is_empty() => ctx.generate_random(session, 8)?,
+    _ => object.id().to_vec(),
+  };
+  match *object {
+    ObjectPlan::RsaKeyPair { ref label, bits, .. } => {
+      RsaKeyPair::new(bits).label(label).id(&id).decrypt(true).generate(ctx, session)?;
+    }
+    ObjectPlan::EcKeyPair { ref label, curve, .. } => {
+      EcKeyPair::new(curve).label(label).id(&id).generate(ctx, session)?;
+    }
+    ObjectPlan::AesKey { ref label, bits, .. } => {
+      AesKey::new(bits).label(label).id(&id).generate(ctx, session)?;
+    }
+    ObjectPlan::Certificate { ref label, ref der, .. } => {
+      Certificate::from_der(der)?.store(ctx, session, label, &id)?;
+    }
+    ObjectPlan::Data { ref label, ref application, ref value } => {
+      let class = CKO_DATA;
+      let no = CK_FALSE;
+      let template = vec![
+        CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+        CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+        CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&no),
+        CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+        CK_ATTRIBUTE::new(CKA_APPLICATION).with_string(application),
+        CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(value),
+      ];
+      ctx.create_object(session, &template)?;
+    }
+  }
+  Ok(())
+}
+
+/// Byte strings as hex in plan files.
+#[cfg(feature = "serde")]
+mod hex_bytes {
+  use serde::de::Error;
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let hex: String = hex.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    // only ASCII can be sliced by byte index
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+      return Err(D::Error::custom("invalid hex string"));
+    }
+    if hex.len() & 1 == 1 {
+      return Err(D::Error::custom("hex string of odd length"));
+    }
+    (0..hex.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| D::Error::custom("invalid hex string")))
+      .collect()
+  }
+}
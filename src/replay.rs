@@ -0,0 +1,478 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Record and replay of PKCS#11 call traces, through the spy module of the
+//! `spy` workspace member.
+//!
+//! Recording forwards every call to a module and writes it to a trace file
+//! with its inputs, outputs, handles and return value. Replaying needs no
+//! module at all: the spy answers every call with the recorded outputs, so a
+//! session recorded next to a customer's HSM runs again through the same `Ctx`
+//! API anywhere:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::replay::Spy;
+//! let spy = Spy::new("target/debug/libpkcs11_spy.so").unwrap();
+//! spy.replay("customer.trace").unwrap();
+//! let ctx = Ctx::new_and_initialize("target/debug/libpkcs11_spy.so").unwrap();
+//! // ... the calls of the application ...
+//! if let Some(divergence) = spy.divergence().unwrap() {
+//!   panic!("{}", divergence);
+//! }
+//! ```
+//!
+//! Calls have to come in the recorded order and with the recorded arguments.
+//! The first one that does not fails with `CKR_GENERAL_ERROR`, as does every
+//! call after it, and [`Spy::divergence`] tells which call and argument it
+//! was. PINs are neither recorded nor compared. Secrets only end up in the
+//! trace by their length: the values of `CKA_VALUE` and of the private parts
+//! of RSA keys, decrypted data, random data and operation states. Replaying
+//! hands out as many zero bytes in their place. Mechanism parameters are
+//! only compared by length, as many of them hold pointers, and the info
+//! structures are recorded in their memory layout, so a trace replays on the
+//! platform it was recorded on.
+//!
+//! Without the Rust API, the spy records to `PKCS11_SPY_RECORD` and replays
+//! `PKCS11_SPY_REPLAY`.
+
+use std::ffi::CString;
+use std::fmt::{self, Write};
+use std::fs;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::ptr;
+use std::str::FromStr;
+
+use libloading;
+
+use errors::Error;
+use types::*;
+
+/// The first line of every trace file.
+pub const HEADER: &str = "pkcs11-trace 1";
+
+/// An argument of a call, read from or written to what its pointer points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+  Null,
+  /// A pointer that is not followed, e.g. `pInitArgs` or `pReserved`.
+  Pointer,
+  /// A PIN, which never gets recorded.
+  Redacted,
+  Ulong(CK_ULONG),
+  Bytes(Vec<u8>),
+  /// Secret output bytes, of which only the length gets recorded.
+  Secret(CK_ULONG),
+  List(Vec<CK_ULONG>),
+  Mechanism(CK_MECHANISM_TYPE, Vec<u8>),
+  Template(Vec<Attribute>),
+}
+
+/// An entry of a template. The `C_GetAttributeValue` input only records
+/// whether a buffer was given, with an empty value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+  pub attr_type: CK_ATTRIBUTE_TYPE,
+  pub len: CK_ULONG,
+  /// `None` for a null `pValue`.
+  pub value: Option<Vec<u8>>,
+  /// The value is secret and recorded empty, only `len` counts.
+  pub secret: bool,
+}
+
+fn hex(data: &[u8]) -> String {
+  if data.is_empty() {
+    return "-".to_string();
+  }
+  let mut s = String::with_capacity(2 * data.len());
+  for b in data {
+    let _ = write!(s, "{:02x}", b);
+  }
+  s
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, Error> {
+  if s == "-" {
+    return Ok(Vec::new());
+  }
+  if s.len() & 1 == 1 || !s.is_ascii() {
+    return Err(Error::InvalidInput("invalid hex string in trace"));
+  }
+  (0..s.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| Error::InvalidInput("invalid hex string in trace")))
+    .collect()
+}
+
+fn parse_ulong(s: &str) -> Result<CK_ULONG, Error> {
+  let res = match s.strip_prefix("0x") {
+    Some(hex) => CK_ULONG::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  res.map_err(|_| Error::InvalidInput("invalid number in trace"))
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Value::Null => write!(f, "null"),
+      Value::Pointer => write!(f, "pointer"),
+      Value::Redacted => write!(f, "redacted"),
+      Value::Ulong(v) => write!(f, "ulong {:#x}", v),
+      Value::Bytes(ref data) => write!(f, "bytes {}", hex(data)),
+      Value::Secret(len) => write!(f, "secret {:#x}", len),
+      Value::List(ref items) if items.is_empty() => write!(f, "list -"),
+      Value::List(ref items) => {
+        let items: Vec<String> = items.iter().map(|i| format!("{:#x}", i)).collect();
+        write!(f, "list {}", items.join(","))
+      }
+      Value::Mechanism(mechanism, ref parameter) => write!(f, "mechanism {:#x} {}", mechanism, hex(parameter)),
+      Value::Template(ref attrs) if attrs.is_empty() => write!(f, "template -"),
+      Value::Template(ref attrs) => {
+        let attrs: Vec<String> = attrs
+          .iter()
+          .map(|a| match a.value {
+            Some(_) if a.secret => format!("{:#x}:{:#x}:secret", a.attr_type, a.len),
+            Some(ref value) => format!("{:#x}:{:#x}:{}", a.attr_type, a.len, hex(value)),
+            None => format!("{:#x}:{:#x}:null", a.attr_type, a.len),
+          })
+          .collect();
+        write!(f, "template {}", attrs.join(","))
+      }
+    }
+  }
+}
+
+impl FromStr for Value {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Value, Error> {
+    let mut words = s.split_whitespace();
+    let value = match (words.next(), words.next(), words.next()) {
+      (Some("null"), None, None) => Value::Null,
+      (Some("pointer"), None, None) => Value::Pointer,
+      (Some("redacted"), None, None) => Value::Redacted,
+      (Some("ulong"), Some(v), None) => Value::Ulong(parse_ulong(v)?),
+      (Some("bytes"), Some(data), None) => Value::Bytes(unhex(data)?),
+      (Some("secret"), Some(len), None) => Value::Secret(parse_ulong(len)?),
+      (Some("list"), Some("-"), None) => Value::List(Vec::new()),
+      (Some("list"), Some(items), None) => Value::List(items.split(',').map(parse_ulong).collect::<Result<_, _>>()?),
+      (Some("mechanism"), Some(mechanism), Some(parameter)) => Value::Mechanism(parse_ulong(mechanism)?, unhex(parameter)?),
+      (Some("template"), Some("-"), None) => Value::Template(Vec::new()),
+      (Some("template"), Some(attrs), None) => Value::Template(attrs.split(',').map(parse_attribute).collect::<Result<_, _>>()?),
+      _ => return Err(Error::InvalidInput("invalid value in trace")),
+    };
+    match words.next() {
+      None => Ok(value),
+      Some(_) => Err(Error::InvalidInput("invalid value in trace")),
+    }
+  }
+}
+
+fn parse_attribute(s: &str) -> Result<Attribute, Error> {
+  let mut parts = s.split(':');
+  match (parts.next(), parts.next(), parts.next(), parts.next()) {
+    (Some(attr_type), Some(len), Some(value), None) => Ok(Attribute {
+      attr_type: parse_ulong(attr_type)?,
+      len: parse_ulong(len)?,
+      value: match value {
+        "null" => None,
+        "secret" => Some(Vec::new()),
+        _ => Some(unhex(value)?),
+      },
+      secret: value == "secret",
+    }),
+    _ => Err(Error::InvalidInput("invalid template attribute in trace")),
+  }
+}
+
+/// Whether an argument matches the recording. Mechanism parameters only
+/// need the same length.
+fn matches(recorded: &Value, actual: &Value) -> bool {
+  match (recorded, actual) {
+    (&Value::Mechanism(m1, ref p1), &Value::Mechanism(m2, ref p2)) => m1 == m2 && p1.len() == p2.len(),
+    _ => recorded == actual,
+  }
+}
+
+/// One call with its arguments in the order of the C signature.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Call {
+  pub function: String,
+  pub inputs: Vec<(String, Value)>,
+  pub outputs: Vec<(String, Value)>,
+  pub rv: CK_RV,
+}
+
+impl Call {
+  pub fn new(function: &str) -> Call {
+    Call {
+      function: function.to_string(),
+      ..Call::default()
+    }
+  }
+
+  pub fn input(&self, name: &str) -> Option<&Value> {
+    self.inputs.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+  }
+
+  pub fn output(&self, name: &str) -> Option<&Value> {
+    self.outputs.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+  }
+}
+
+impl fmt::Display for Call {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{} {:#x}", self.function, self.rv)?;
+    for (name, value) in &self.inputs {
+      writeln!(f, "  > {} {}", name, value)?;
+    }
+    for (name, value) in &self.outputs {
+      writeln!(f, "  < {} {}", name, value)?;
+    }
+    Ok(())
+  }
+}
+
+/// A recorded session: the calls in the order they returned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+  pub calls: Vec<Call>,
+}
+
+impl Trace {
+  pub fn load<P>(path: P) -> Result<Trace, Error>
+  where
+    P: AsRef<Path>,
+  {
+    fs::read_to_string(path)?.parse()
+  }
+}
+
+impl fmt::Display for Trace {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{}", HEADER)?;
+    for call in &self.calls {
+      write!(f, "{}", call)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Trace {
+  type Err = Error;
+
+  /// Parses the form `Display` writes. The header is optional, empty lines
+  /// and lines starting with `#` are skipped.
+  fn from_str(s: &str) -> Result<Trace, Error> {
+    let mut calls: Vec<Call> = Vec::new();
+    for line in s.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
+      if line == HEADER {
+        continue;
+      }
+      if let Some(arg) = line.strip_prefix("  ") {
+        let call = calls.last_mut().ok_or(Error::InvalidInput("argument before the first call in trace"))?;
+        let (list, arg) = match (arg.strip_prefix("> "), arg.strip_prefix("< ")) {
+          (Some(arg), _) => (&mut call.inputs, arg),
+          (None, Some(arg)) => (&mut call.outputs, arg),
+          _ => return Err(Error::InvalidInput("arguments in trace need a `>` or `<` direction")),
+        };
+        let (name, value) = arg.split_once(' ').ok_or(Error::InvalidInput("argument without value in trace"))?;
+        list.push((name.to_string(), value.parse()?));
+        continue;
+      }
+      match line.split_once(' ') {
+        Some((function, rv)) if function.starts_with("C_") => calls.push(Call {
+          rv: parse_ulong(rv)? as CK_RV,
+          ..Call::new(function)
+        }),
+        _ => return Err(Error::InvalidInput("invalid call in trace")),
+      }
+    }
+    Ok(Trace { calls })
+  }
+}
+
+/// Where a replayed session left the recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+  /// The number of the call, counted from 1.
+  pub call: usize,
+  /// The recorded call, `None` after the end of the recording.
+  pub expected: Option<Call>,
+  pub actual: Call,
+  /// The first input that differs, `None` for a different function.
+  pub argument: Option<String>,
+}
+
+impl Divergence {
+  /// Compares the inputs of the `call`-th call with the recording.
+  pub fn check(call: usize, expected: Option<&Call>, actual: &Call) -> Option<Divergence> {
+    let divergence = |argument: Option<&str>| Divergence {
+      call,
+      expected: expected.cloned(),
+      actual: actual.clone(),
+      argument: argument.map(str::to_string),
+    };
+    let expected = match expected {
+      Some(expected) => expected,
+      None => return Some(divergence(None)),
+    };
+    if expected.function != actual.function {
+      return Some(divergence(None));
+    }
+    let names = expected.inputs.iter().chain(actual.inputs.iter()).map(|(name, _)| name.as_str());
+    for name in names {
+      match (expected.input(name), actual.input(name)) {
+        (Some(e), Some(a)) if matches(e, a) => {}
+        _ => return Some(divergence(Some(name))),
+      }
+    }
+    None
+  }
+
+  /// The form the spy hands the divergence over in, a `divergence` line
+  /// followed by the actual and the expected call.
+  #[doc(hidden)]
+  pub fn encode(&self) -> String {
+    let mut s = format!("divergence {} {}\n{}", self.call, self.argument.as_deref().unwrap_or("-"), self.actual);
+    if let Some(ref expected) = self.expected {
+      let _ = write!(s, "{}", expected);
+    }
+    s
+  }
+
+  #[doc(hidden)]
+  pub fn decode(s: &str) -> Result<Divergence, Error> {
+    let (first, calls) = s.split_once('\n').ok_or(Error::InvalidInput("invalid divergence"))?;
+    let mut words = first.split_whitespace();
+    let (call, argument) = match (words.next(), words.next(), words.next(), words.next()) {
+      (Some("divergence"), Some(call), Some(argument), None) => (call, argument),
+      _ => return Err(Error::InvalidInput("invalid divergence")),
+    };
+    let mut calls = calls.parse::<Trace>()?.calls.into_iter();
+    Ok(Divergence {
+      call: call.parse().map_err(|_| Error::InvalidInput("invalid divergence"))?,
+      actual: calls.next().ok_or(Error::InvalidInput("invalid divergence"))?,
+      expected: calls.next(),
+      argument: if argument == "-" { None } else { Some(argument.to_string()) },
+    })
+  }
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let actual = &self.actual.function;
+    match (&self.expected, &self.argument) {
+      (None, _) => write!(f, "call {}: the application called {} after the end of the recording", self.call, actual),
+      (Some(expected), None) => write!(f, "call {}: the application called {} instead of {}", self.call, actual, expected.function),
+      (Some(expected), Some(argument)) => {
+        let show = |v: Option<&Value>| v.map_or("nothing".to_string(), Value::to_string);
+        write!(
+          f,
+          "call {} ({}): the application passed {} = {} instead of {}",
+          self.call,
+          actual,
+          argument,
+          show(self.actual.input(argument)),
+          show(expected.input(argument))
+        )
+      }
+    }
+  }
+}
+
+/// Controls recording and replaying of a loaded spy module.
+///
+/// Like the `fault::FaultInjector`, the controller and a `Ctx` created on
+/// the same path share one instance of the library. The spy is configured
+/// once per instance, so load a private copy of it for every recording or
+/// replay in the same process.
+pub struct Spy {
+  lib: libloading::Library,
+}
+
+impl Spy {
+  pub fn new<P>(spy_module: P) -> Result<Spy, Error>
+  where
+    P: AsRef<Path>,
+  {
+    let lib = libloading::Library::new(spy_module.as_ref())?;
+    unsafe {
+      lib.get::<unsafe extern "C" fn(*const c_char, *const c_char) -> CK_RV>(b"pkcs11_spy_replay")
+        .map_err(|_| Error::Module("not the spy module: pkcs11_spy_replay function not found"))?;
+    }
+    Ok(Spy { lib })
+  }
+
+  /// Forwards all calls to `module` and records them to `trace`, which gets
+  /// overwritten. This has to happen before a `Ctx` gets created on the spy.
+  pub fn record<P, Q>(&self, module: P, trace: Q) -> Result<(), Error>
+  where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+  {
+    let module = c_path(module.as_ref())?;
+    let trace = c_path(trace.as_ref())?;
+    unsafe {
+      let func = self.lib.get::<unsafe extern "C" fn(*const c_char, *const c_char, *const c_char) -> CK_RV>(b"pkcs11_spy_record")?;
+      check(func(module.as_ptr(), trace.as_ptr(), ptr::null()))
+    }
+  }
+
+  /// Answers all calls from `trace`. This has to happen before a `Ctx` gets
+  /// created on the spy.
+  pub fn replay<P>(&self, trace: P) -> Result<(), Error>
+  where
+    P: AsRef<Path>,
+  {
+    // reports syntax errors here rather than as a failing C_GetFunctionList
+    Trace::load(trace.as_ref())?;
+    let trace = c_path(trace.as_ref())?;
+    unsafe {
+      let func = self.lib.get::<unsafe extern "C" fn(*const c_char, *const c_char) -> CK_RV>(b"pkcs11_spy_replay")?;
+      check(func(trace.as_ptr(), ptr::null()))
+    }
+  }
+
+  /// The first call of a replay that differs from the recording, if any.
+  pub fn divergence(&self) -> Result<Option<Divergence>, Error> {
+    unsafe {
+      let func = self.lib.get::<unsafe extern "C" fn(*mut u8, *mut CK_ULONG) -> CK_RV>(b"pkcs11_spy_divergence")?;
+      let mut len: CK_ULONG = 0;
+      check(func(ptr::null_mut(), &mut len))?;
+      if len == 0 {
+        return Ok(None);
+      }
+      let mut text = vec![0; len as usize];
+      check(func(text.as_mut_ptr(), &mut len))?;
+      text.truncate(len as usize);
+      let text = String::from_utf8(text).map_err(|_| Error::Module("divergence is not valid UTF-8"))?;
+      Divergence::decode(&text).map(Some)
+    }
+  }
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+  let path = path.to_str().ok_or(Error::InvalidInput("path is not valid UTF-8"))?;
+  CString::new(path).map_err(|_| Error::InvalidInput("path contains a NUL byte"))
+}
+
+fn check(rv: CK_RV) -> Result<(), Error> {
+  match rv {
+    CKR_OK => Ok(()),
+    err => Err(Error::Pkcs11(err)),
+  }
+}
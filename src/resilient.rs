@@ -0,0 +1,438 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A session that survives the module forgetting it.
+//!
+//! When an HSM reboots or a USB token is plugged out and in again, every
+//! session handle turns into `CKR_SESSION_HANDLE_INVALID` or
+//! `CKR_DEVICE_REMOVED`, and object handles may change as well. A
+//! [`ResilientSession`] remembers where its session came from: on those
+//! errors it opens a new one, logs in again, finds its objects again by
+//! `CKA_ID` and `CKA_LABEL` and retries the operation after a backoff.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use pkcs11::Ctx;
+//! # use pkcs11::resilient::{ObjectRef, ResilientSession};
+//! # use pkcs11::select::TokenSelector;
+//! # use pkcs11::types::*;
+//! let ctx = Arc::new(Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap());
+//! let mut session = ResilientSession::with_selector(ctx, TokenSelector::new().label("signing")).pin("1234");
+//! let key = ObjectRef::label("signer").class(CKO_PRIVATE_KEY);
+//! let mechanism = CK_MECHANISM { mechanism: CKM_ECDSA, pParameter: std::ptr::null_mut(), ulParameterLen: 0 };
+//! let signature = session.sign(&key, &mechanism, &[0; 32]).unwrap();
+//! ```
+//!
+//! Only operations that can run again from their start are retried, so
+//! [`ResilientSession::run`] is for single-part operations and the like.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::Ctx;
+use errors::Error;
+use secret::SecretPin;
+use select::{SelectError, TokenSelector};
+use types::*;
+
+/// How often and after how long a failed operation is tried again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+  pub max_retries: u32,
+  /// The wait before the first retry.
+  pub initial_backoff: Duration,
+  /// How much longer each following wait is.
+  pub multiplier: f64,
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  /// 3 retries after 100ms, 200ms and 400ms.
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      max_retries: 3,
+      initial_backoff: Duration::from_millis(100),
+      multiplier: 2.0,
+      max_backoff: Duration::from_secs(5),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// No retries at all.
+  pub fn none() -> RetryPolicy {
+    RetryPolicy {
+      max_retries: 0,
+      ..RetryPolicy::default()
+    }
+  }
+
+  /// The wait before retry number `retry`, counting from 0.
+  pub fn backoff(&self, retry: u32) -> Duration {
+    let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry.min(i32::MAX as u32) as i32);
+    Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()))
+  }
+}
+
+/// Whether an operation that failed with `err` is worth another try on a
+/// new session.
+pub fn is_recoverable(err: &Error) -> bool {
+  match *err {
+    Error::Pkcs11(rv) => matches!(
+      rv,
+      CKR_SESSION_HANDLE_INVALID | CKR_SESSION_CLOSED | CKR_DEVICE_REMOVED | CKR_DEVICE_ERROR | CKR_TOKEN_NOT_PRESENT | CKR_TOKEN_NOT_RECOGNIZED | CKR_USER_NOT_LOGGED_IN
+    ),
+    _ => false,
+  }
+}
+
+/// Whether `err` means an object handle is no longer valid.
+fn is_stale_handle(err: &Error) -> bool {
+  matches!(*err, Error::Pkcs11(CKR_OBJECT_HANDLE_INVALID) | Error::Pkcs11(CKR_KEY_HANDLE_INVALID))
+}
+
+/// An object by its attributes rather than its handle, which may change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ObjectRef {
+  pub class: Option<CK_OBJECT_CLASS>,
+  pub id: Option<Vec<u8>>,
+  pub label: Option<String>,
+}
+
+impl ObjectRef {
+  /// The object with `CKA_ID` `id`.
+  pub fn id(id: &[u8]) -> ObjectRef {
+    ObjectRef {
+      id: Some(id.to_vec()),
+      ..ObjectRef::default()
+    }
+  }
+
+  /// The object with `CKA_LABEL` `label`.
+  pub fn label(label: &str) -> ObjectRef {
+    ObjectRef {
+      label: Some(label.to_string()),
+      ..ObjectRef::default()
+    }
+  }
+
+  pub fn with_label(mut self, label: &str) -> ObjectRef {
+    self.label = Some(label.to_string());
+    self
+  }
+
+  pub fn class(mut self, class: CK_OBJECT_CLASS) -> ObjectRef {
+    self.class = Some(class);
+    self
+  }
+
+  pub fn template(&self) -> Vec<CK_ATTRIBUTE> {
+    let mut template = Vec::new();
+    if let Some(ref class) = self.class {
+      template.push(CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(class));
+    }
+    if let Some(ref id) = self.id {
+      template.push(CK_ATTRIBUTE::new(CKA_ID).with_bytes(id));
+    }
+    if let Some(ref label) = self.label {
+      template.push(CK_ATTRIBUTE::new(CKA_LABEL).with_string(label));
+    }
+    template
+  }
+}
+
+/// Where the session goes.
+#[derive(Debug, Clone)]
+enum Target {
+  Slot(CK_SLOT_ID),
+  // selected again on every reconnect, a re-plugged token may be in another slot
+  Token(TokenSelector),
+}
+
+type PinFn = dyn Fn() -> Result<SecretPin, Error> + Send + Sync;
+
+/// A session that is opened again, logged in again and retried on session
+/// and device loss, see the [module documentation](self).
+///
+/// The session is opened on first use.
+pub struct ResilientSession {
+  ctx: Arc<Ctx>,
+  target: Target,
+  user_type: CK_USER_TYPE,
+  pin: Option<Arc<PinFn>>,
+  read_write: bool,
+  retry: RetryPolicy,
+  session: Option<CK_SESSION_HANDLE>,
+  objects: HashMap<ObjectRef, CK_OBJECT_HANDLE>,
+  recoveries: u64,
+}
+
+impl fmt::Debug for ResilientSession {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ResilientSession")
+      .field("target", &self.target)
+      .field("user_type", &self.user_type)
+      .field("read_write", &self.read_write)
+      .field("retry", &self.retry)
+      .field("session", &self.session)
+      .field("recoveries", &self.recoveries)
+      .finish_non_exhaustive()
+  }
+}
+
+impl ResilientSession {
+  /// A read-write session on the token in `slot_id`, without login.
+  pub fn new(ctx: Arc<Ctx>, slot_id: CK_SLOT_ID) -> ResilientSession {
+    ResilientSession::with_target(ctx, Target::Slot(slot_id))
+  }
+
+  /// A read-write session on the one token `selector` matches, without
+  /// login.
+  pub fn with_selector(ctx: Arc<Ctx>, selector: TokenSelector) -> ResilientSession {
+    ResilientSession::with_target(ctx, Target::Token(selector))
+  }
+
+  fn with_target(ctx: Arc<Ctx>, target: Target) -> ResilientSession {
+    ResilientSession {
+      ctx,
+      target,
+      user_type: CKU_USER,
+      pin: None,
+      read_write: true,
+      retry: RetryPolicy::default(),
+      session: None,
+      objects: HashMap::new(),
+      recoveries: 0,
+    }
+  }
+
+  /// Logs in with `pin`.
+  pub fn pin(self, pin: &str) -> ResilientSession {
+    let pin = SecretPin::from(pin);
+    self.pin_with(move || Ok(pin.clone()))
+  }
+
+  /// Logs in with the PIN `source` returns, a `String` or a `SecretPin`,
+  /// asked again for every login.
+  pub fn pin_with<F, P>(mut self, source: F) -> ResilientSession
+  where
+    F: Fn() -> Result<P, Error> + Send + Sync + 'static,
+    P: Into<SecretPin>,
+  {
+    self.pin = Some(Arc::new(move || source().map(Into::into)));
+    self
+  }
+
+  /// Logs in as `user_type` instead of `CKU_USER`.
+  pub fn user_type(mut self, user_type: CK_USER_TYPE) -> ResilientSession {
+    self.user_type = user_type;
+    self
+  }
+
+  pub fn read_only(mut self) -> ResilientSession {
+    self.read_write = false;
+    self
+  }
+
+  pub fn retry(mut self, retry: RetryPolicy) -> ResilientSession {
+    self.retry = retry;
+    self
+  }
+
+  pub fn ctx(&self) -> &Ctx {
+    &self.ctx
+  }
+
+  /// How often the session was opened again after a loss.
+  pub fn recoveries(&self) -> u64 {
+    self.recoveries
+  }
+
+  /// The current session, opened and logged in if there is none.
+  pub fn session(&mut self) -> Result<CK_SESSION_HANDLE, Error> {
+    match self.session {
+      Some(session) => Ok(session),
+      None => {
+        let session = self.open()?;
+        self.session = Some(session);
+        Ok(session)
+      }
+    }
+  }
+
+  fn slot_id(&self) -> Result<CK_SLOT_ID, Error> {
+    match self.target {
+      Target::Slot(slot_id) => Ok(slot_id),
+      Target::Token(ref selector) => match selector.select(&self.ctx) {
+        Ok(slot_id) => Ok(slot_id),
+        Err(SelectError::NoMatch(..)) => Err(Error::Pkcs11(CKR_TOKEN_NOT_PRESENT)),
+        Err(SelectError::Ambiguous(..)) => Err(Error::InvalidInput("more than one token matches the selector")),
+        Err(SelectError::Pkcs11(err)) => Err(err),
+      },
+    }
+  }
+
+  fn open(&self) -> Result<CK_SESSION_HANDLE, Error> {
+    let flags = if self.read_write { CKF_SERIAL_SESSION | CKF_RW_SESSION } else { CKF_SERIAL_SESSION };
+    let session = self.ctx.open_session(self.slot_id()?, flags, None, None)?;
+    if let Err(err) = self.login(session) {
+      let _ = self.ctx.close_session(session);
+      return Err(err);
+    }
+    Ok(session)
+  }
+
+  /// Logs in unless the token is logged in already.
+  fn login(&self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+    let pin = match self.pin {
+      Some(ref pin) => pin,
+      None => return Ok(()),
+    };
+    let state = self.ctx.get_session_info(session)?.state;
+    if state != CKS_RO_PUBLIC_SESSION && state != CKS_RW_PUBLIC_SESSION {
+      return Ok(());
+    }
+    match self.ctx.login_secret(session, self.user_type, Some(&pin()?)) {
+      Ok(()) | Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => Ok(()),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Forgets the session and the object handles found with it.
+  fn reset(&mut self) {
+    if let Some(session) = self.session.take() {
+      let _ = self.ctx.close_session(session);
+    }
+    self.objects.clear();
+  }
+
+  /// Runs `operation` on the session, starting over on a new session after
+  /// a recoverable error until the retry policy gives up. `operation` must
+  /// be safe to run more than once.
+  pub fn run<T, F>(&mut self, mut operation: F) -> Result<T, Error>
+  where
+    F: FnMut(&Ctx, CK_SESSION_HANDLE) -> Result<T, Error>,
+  {
+    self.retrying(None, |ctx, session, _| operation(ctx, session))
+  }
+
+  /// Runs `operation` like [`run`](Self::run), with the handle of `object`
+  /// on the current session. A handle the module no longer knows is looked
+  /// up once more.
+  pub fn run_with<T, F>(&mut self, object: &ObjectRef, operation: F) -> Result<T, Error>
+  where
+    F: FnMut(&Ctx, CK_SESSION_HANDLE, CK_OBJECT_HANDLE) -> Result<T, Error>,
+  {
+    self.retrying(Some(object), operation)
+  }
+
+  /// The handle of the one object `object` refers to.
+  pub fn find(&mut self, object: &ObjectRef) -> Result<CK_OBJECT_HANDLE, Error> {
+    self.retrying(Some(object), |_, _, handle| Ok(handle))
+  }
+
+  fn retrying<T, F>(&mut self, object: Option<&ObjectRef>, mut operation: F) -> Result<T, Error>
+  where
+    F: FnMut(&Ctx, CK_SESSION_HANDLE, CK_OBJECT_HANDLE) -> Result<T, Error>,
+  {
+    let (mut retry, mut found_again) = (0, false);
+    loop {
+      match self.attempt(object, &mut operation) {
+        Err(ref err) if is_stale_handle(err) && !found_again => match object {
+          Some(object) => {
+            self.objects.remove(object);
+            found_again = true;
+          }
+          None => return Err(Error::Pkcs11(CKR_OBJECT_HANDLE_INVALID)),
+        },
+        Err(err) if is_recoverable(&err) => {
+          // the session is gone either way
+          self.reset();
+          if retry >= self.retry.max_retries {
+            return Err(err);
+          }
+          thread::sleep(self.retry.backoff(retry));
+          retry += 1;
+          self.recoveries += 1;
+        }
+        res => return res,
+      }
+    }
+  }
+
+  fn attempt<T, F>(&mut self, object: Option<&ObjectRef>, operation: &mut F) -> Result<T, Error>
+  where
+    F: FnMut(&Ctx, CK_SESSION_HANDLE, CK_OBJECT_HANDLE) -> Result<T, Error>,
+  {
+    let session = self.session()?;
+    let handle = match object {
+      Some(object) => self.lookup(session, object)?,
+      None => CK_INVALID_HANDLE,
+    };
+    operation(&self.ctx, session, handle)
+  }
+
+  fn lookup(&mut self, session: CK_SESSION_HANDLE, object: &ObjectRef) -> Result<CK_OBJECT_HANDLE, Error> {
+    if let Some(&handle) = self.objects.get(object) {
+      return Ok(handle);
+    }
+    self.ctx.find_objects_init(session, &object.template())?;
+    let found = self.ctx.find_objects(session, 2);
+    self.ctx.find_objects_final(session)?;
+    let handle = match found?.as_slice() {
+      [handle] => *handle,
+      [] => return Err(Error::InvalidInput("no object matches the reference")),
+      _ => return Err(Error::InvalidInput("more than one object matches the reference")),
+    };
+    self.objects.insert(object.clone(), handle);
+    Ok(handle)
+  }
+
+  pub fn sign(&mut self, key: &ObjectRef, mechanism: &CK_MECHANISM, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.run_with(key, |ctx, session, key| {
+      ctx.sign_init(session, mechanism, key)?;
+      ctx.sign(session, data)
+    })
+  }
+
+  pub fn verify(&mut self, key: &ObjectRef, mechanism: &CK_MECHANISM, data: &[CK_BYTE], signature: &[CK_BYTE]) -> Result<(), Error> {
+    self.run_with(key, |ctx, session, key| {
+      ctx.verify_init(session, mechanism, key)?;
+      ctx.verify(session, data, signature)
+    })
+  }
+
+  pub fn encrypt(&mut self, key: &ObjectRef, mechanism: &CK_MECHANISM, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.run_with(key, |ctx, session, key| {
+      ctx.encrypt_init(session, mechanism, key)?;
+      ctx.encrypt(session, data)
+    })
+  }
+
+  pub fn decrypt(&mut self, key: &ObjectRef, mechanism: &CK_MECHANISM, data: &[CK_BYTE]) -> Result<Vec<CK_BYTE>, Error> {
+    self.run_with(key, |ctx, session, key| {
+      ctx.decrypt_init(session, mechanism, key)?;
+      ctx.decrypt(session, data)
+    })
+  }
+}
+
+impl Drop for ResilientSession {
+  fn drop(&mut self) {
+    self.reset();
+  }
+}
@@ -0,0 +1,139 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PINs and plaintext that are wiped from memory when dropped.
+//!
+//! [`SecretPin`] is what the `*_secret` methods of `Ctx`, such as
+//! `Ctx::login_secret`, take, and [`SecretBytes`] what `Ctx::decrypt_secret`
+//! and the other methods returning plaintext or attribute values give back.
+//! Both overwrite their memory with zeros when dropped and print as
+//! `[REDACTED]` with `{:?}`:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::secret::SecretPin;
+//! # use pkcs11::types::*;
+//! # let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! # let session = 1;
+//! let pin = SecretPin::from(std::env::var("PKCS11_PIN").unwrap());
+//! ctx.login_secret(session, CKU_USER, Some(&pin)).unwrap();
+//! let plaintext = ctx.decrypt_secret(session, &[0; 16]).unwrap();
+//! ```
+//!
+//! The methods taking a `&str` PIN or returning a `Vec<u8>` are kept and go
+//! through these types, but the copies they hand back are the caller's to
+//! wipe.
+
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+
+use zeroize::Zeroize;
+
+/// A PIN, wiped when dropped.
+///
+/// PKCS#11 passes PINs with their length, so unlike the `&str` methods of
+/// `Ctx`, the bytes are passed as they are, nul bytes included.
+#[derive(Clone, Default)]
+pub struct SecretPin(Vec<u8>);
+
+impl SecretPin {
+  /// A PIN of raw bytes, for tokens whose PINs are not UTF-8.
+  pub fn from_bytes(pin: Vec<u8>) -> SecretPin {
+    SecretPin(pin)
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+impl<'a> From<&'a str> for SecretPin {
+  fn from(pin: &'a str) -> SecretPin {
+    SecretPin(pin.as_bytes().to_vec())
+  }
+}
+
+impl From<String> for SecretPin {
+  fn from(pin: String) -> SecretPin {
+    SecretPin(pin.into_bytes())
+  }
+}
+
+impl fmt::Debug for SecretPin {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("SecretPin([REDACTED])")
+  }
+}
+
+impl Drop for SecretPin {
+  fn drop(&mut self) {
+    self.0.zeroize();
+  }
+}
+
+/// Plaintext or a secret attribute value, wiped when dropped.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretBytes(pub(crate) Vec<u8>);
+
+impl SecretBytes {
+  pub fn as_slice(&self) -> &[u8] {
+    &self.0
+  }
+
+  /// The bytes without the wiping; they are the caller's to take care of.
+  pub fn into_vec(mut self) -> Vec<u8> {
+    mem::take(&mut self.0)
+  }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+  fn from(bytes: Vec<u8>) -> SecretBytes {
+    SecretBytes(bytes)
+  }
+}
+
+impl Deref for SecretBytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+  fn as_ref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl fmt::Debug for SecretBytes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "SecretBytes([REDACTED; {}])", self.0.len())
+  }
+}
+
+impl Drop for SecretBytes {
+  fn drop(&mut self) {
+    self.0.zeroize();
+  }
+}
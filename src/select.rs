@@ -0,0 +1,266 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding the slot of a token by its label, serial number, manufacturer or
+//! model.
+//!
+//! The fields are compared without their blank padding, exactly or as globs
+//! with `*` and `?`:
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::select::TokenSelector;
+//! let ctx = Ctx::new_and_initialize("/usr/local/lib/softhsm/libsofthsm2.so").unwrap();
+//! let slot = TokenSelector::new().label_glob("signing-*").manufacturer("SoftHSM project").select(&ctx).unwrap();
+//! ```
+//!
+//! Some modules show the same token in more than one slot. Matches that all
+//! have the same serial number, manufacturer and model count as one token,
+//! in its first slot.
+
+use std::error;
+use std::fmt;
+
+use super::Ctx;
+use errors::Error;
+use info::TokenInfo;
+use types::*;
+
+/// How a field is matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+  Exact(String),
+  /// `*` matches any text, `?` any one character.
+  Glob(String),
+}
+
+/// Whether `text` matches the glob `pattern`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+  let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+  let (mut p, mut t) = (0, 0);
+  // where the last `*` was and how much of the text it takes so far
+  let mut star: Option<(usize, usize)> = None;
+  while t < text.len() {
+    if p < pattern.len() && pattern[p] == '*' {
+      star = Some((p, t));
+      p += 1;
+    } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    } else if let Some((star_p, star_t)) = star {
+      p = star_p + 1;
+      t = star_t + 1;
+      star = Some((star_p, star_t + 1));
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Pattern {
+  pub fn matches(&self, text: &str) -> bool {
+    match *self {
+      Pattern::Exact(ref exact) => exact == text,
+      Pattern::Glob(ref glob) => glob_match(glob, text),
+    }
+  }
+}
+
+impl fmt::Display for Pattern {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Pattern::Exact(ref exact) => write!(f, "{:?}", exact),
+      Pattern::Glob(ref glob) => write!(f, "glob {:?}", glob),
+    }
+  }
+}
+
+/// The token fields to match; fields without a pattern match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenSelector {
+  label: Option<Pattern>,
+  serial: Option<Pattern>,
+  manufacturer: Option<Pattern>,
+  model: Option<Pattern>,
+}
+
+/// A token the selector looked at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+  pub slot_id: CK_SLOT_ID,
+  pub info: TokenInfo,
+}
+
+impl Candidate {
+  fn same_token(&self, other: &Candidate) -> bool {
+    !self.info.serial_number.is_empty() && self.info.serial_number == other.info.serial_number && self.info.manufacturer == other.info.manufacturer && self.info.model == other.info.model
+  }
+}
+
+impl fmt::Display for Candidate {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "slot {}: label {:?}, serial {:?}, manufacturer {:?}, model {:?}",
+      self.slot_id, self.info.label, self.info.serial_number, self.info.manufacturer, self.info.model
+    )
+  }
+}
+
+/// Why [`TokenSelector::select`] found no single token.
+#[derive(Debug)]
+pub enum SelectError {
+  /// No token matches the selector, as text; these are the tokens there are.
+  NoMatch(String, Vec<Candidate>),
+  /// More than one token matches the selector.
+  Ambiguous(String, Vec<Candidate>),
+  /// Listing the tokens failed.
+  Pkcs11(Error),
+}
+
+impl From<Error> for SelectError {
+  fn from(err: Error) -> SelectError {
+    SelectError::Pkcs11(err)
+  }
+}
+
+fn list(f: &mut fmt::Formatter, candidates: &[Candidate]) -> fmt::Result {
+  for candidate in candidates {
+    write!(f, "\n  {}", candidate)?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for SelectError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      SelectError::NoMatch(ref selector, ref candidates) if candidates.is_empty() => write!(f, "no token matches {}, there are no tokens", selector),
+      SelectError::NoMatch(ref selector, ref candidates) => {
+        write!(f, "no token matches {}, the tokens are:", selector)?;
+        list(f, candidates)
+      }
+      SelectError::Ambiguous(ref selector, ref matches) => {
+        write!(f, "{} tokens match {}:", matches.len(), selector)?;
+        list(f, matches)
+      }
+      SelectError::Pkcs11(ref err) => err.fmt(f),
+    }
+  }
+}
+
+impl error::Error for SelectError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match *self {
+      SelectError::Pkcs11(ref err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl TokenSelector {
+  /// A selector matching any token.
+  pub fn new() -> TokenSelector {
+    TokenSelector::default()
+  }
+
+  pub fn label(mut self, label: &str) -> TokenSelector {
+    self.label = Some(Pattern::Exact(label.to_string()));
+    self
+  }
+
+  pub fn label_glob(mut self, glob: &str) -> TokenSelector {
+    self.label = Some(Pattern::Glob(glob.to_string()));
+    self
+  }
+
+  pub fn serial(mut self, serial: &str) -> TokenSelector {
+    self.serial = Some(Pattern::Exact(serial.to_string()));
+    self
+  }
+
+  pub fn serial_glob(mut self, glob: &str) -> TokenSelector {
+    self.serial = Some(Pattern::Glob(glob.to_string()));
+    self
+  }
+
+  pub fn manufacturer(mut self, manufacturer: &str) -> TokenSelector {
+    self.manufacturer = Some(Pattern::Exact(manufacturer.to_string()));
+    self
+  }
+
+  pub fn manufacturer_glob(mut self, glob: &str) -> TokenSelector {
+    self.manufacturer = Some(Pattern::Glob(glob.to_string()));
+    self
+  }
+
+  pub fn model(mut self, model: &str) -> TokenSelector {
+    self.model = Some(Pattern::Exact(model.to_string()));
+    self
+  }
+
+  pub fn model_glob(mut self, glob: &str) -> TokenSelector {
+    self.model = Some(Pattern::Glob(glob.to_string()));
+    self
+  }
+
+  pub fn matches(&self, info: &TokenInfo) -> bool {
+    let fields = [(&self.label, &info.label), (&self.serial, &info.serial_number), (&self.manufacturer, &info.manufacturer), (&self.model, &info.model)];
+    fields.iter().all(|&(pattern, field)| pattern.as_ref().is_none_or(|p| p.matches(field)))
+  }
+
+  /// The tokens present and whether they match.
+  fn candidates(&self, ctx: &Ctx) -> Result<Vec<(Candidate, bool)>, Error> {
+    let mut candidates = Vec::new();
+    for slot_id in ctx.get_slot_list(true)? {
+      let info = TokenInfo::from(ctx.get_token_info(slot_id)?);
+      let matches = self.matches(&info);
+      candidates.push((Candidate { slot_id, info }, matches));
+    }
+    Ok(candidates)
+  }
+
+  /// Every slot with a matching token, a token in several slots included.
+  pub fn select_all(&self, ctx: &Ctx) -> Result<Vec<CK_SLOT_ID>, Error> {
+    Ok(self.candidates(ctx)?.into_iter().filter(|&(_, matches)| matches).map(|(c, _)| c.slot_id).collect())
+  }
+
+  /// The slot of the one matching token.
+  pub fn select(&self, ctx: &Ctx) -> Result<CK_SLOT_ID, SelectError> {
+    let (matches, others): (Vec<_>, Vec<_>) = self.candidates(ctx)?.into_iter().partition(|&(_, matches)| matches);
+    let matches: Vec<Candidate> = matches.into_iter().map(|(c, _)| c).collect();
+    match matches.first() {
+      None => Err(SelectError::NoMatch(self.to_string(), others.into_iter().map(|(c, _)| c).collect())),
+      Some(first) if matches[1..].iter().all(|c| c.same_token(first)) => Ok(first.slot_id),
+      Some(_) => Err(SelectError::Ambiguous(self.to_string(), matches)),
+    }
+  }
+}
+
+impl fmt::Display for TokenSelector {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let fields = [("label", &self.label), ("serial", &self.serial), ("manufacturer", &self.manufacturer), ("model", &self.model)];
+    let mut first = true;
+    for &(name, pattern) in &fields {
+      if let Some(ref pattern) = *pattern {
+        write!(f, "{}{} {}", if first { "" } else { ", " }, name, pattern)?;
+        first = false;
+      }
+    }
+    if first {
+      f.write_str("any token")?;
+    }
+    Ok(())
+  }
+}
@@ -14,11 +14,18 @@
 
 
 use std::env;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::fs;
 use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 /// Tests need to be run with `RUST_TEST_THREADS=1` currently to pass.
 extern crate num_traits;
 extern crate hex;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
 use self::num_traits::Num;
 use self::hex::FromHex;
@@ -45,6 +52,68 @@ fn pkcs11_module_name() -> PathBuf {
   path_buf
 }
 
+/// Runs `cargo build` with the arguments for `target` once per test run and
+/// returns the output directory.
+fn workspace_build(target: &'static str, args: &[&str]) -> PathBuf {
+  static BUILT: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+  // the test binary lives in target/<profile>/deps
+  let dir = env::current_exe().unwrap().parent().unwrap().parent().unwrap().to_path_buf();
+  let mut built = BUILT.lock().unwrap_or_else(|e| e.into_inner());
+  if !built.contains(&target) {
+    let profile = match dir.file_name().unwrap().to_str().unwrap() {
+      "debug" => "dev".to_string(),
+      profile => profile.to_string(),
+    };
+    let status = Command::new(env!("CARGO"))
+      .args(["build", "--quiet", "--profile", &profile])
+      .args(args)
+      .env("CARGO_TARGET_DIR", dir.parent().unwrap())
+      .status()
+      .unwrap();
+    assert!(status.success(), "failed to build {}", target);
+    built.push(target);
+  }
+  dir
+}
+
+/// Builds the `cdylib` of a workspace member once per test run and returns its path.
+fn workspace_module(package: &'static str, lib: &str) -> PathBuf {
+  workspace_build(package, &["-p", package]).join(format!("{}{}{}", DLL_PREFIX, lib, DLL_SUFFIX))
+}
+
+/// A private copy of a module. Every copy is a separate library with a state of its own.
+fn module_copy(module: &PathBuf, lib: &str) -> PathBuf {
+  static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+  let copy = env::temp_dir().join(format!("{}{}-{}-{}{}", DLL_PREFIX, lib, process::id(), COPIES.fetch_add(1, Ordering::SeqCst), DLL_SUFFIX));
+  fs::copy(module, &copy).unwrap();
+  copy
+}
+
+/// Returns the path of a private copy of the in-memory module of the `mock`
+/// workspace member, built once per test run unless `PKCS11_MOCK_MODULE`
+/// points to a build of it. Every copy has a token of its own, so tests on
+/// the mock do not need to be serial.
+fn mock_module_copy() -> PathBuf {
+  let module = match env::var_os("PKCS11_MOCK_MODULE") {
+    Some(path) => PathBuf::from(path),
+    None => workspace_module("pkcs11-mock", "pkcs11_mock"),
+  };
+  module_copy(&module, "pkcs11_mock")
+}
+
+fn mock_ctx_at(copy: &PathBuf) -> Ctx {
+  let ctx = Ctx::new_and_initialize(copy).unwrap();
+  // fails on Windows while the library is loaded, which only leaves the copy behind
+  let _ = fs::remove_file(copy);
+  ctx
+}
+
+fn mock_ctx() -> Ctx {
+  mock_ctx_at(&mock_module_copy())
+}
+
 #[test]
 #[serial]
 fn test_label_from_str() {
@@ -581,7 +650,10 @@ fn attr_biginteger() {
 /// If you look at the tests here in a "serial" manner, if all the tests are working up until
 /// here, this will always succeed.
 fn fixture_token() -> Result<(Ctx, CK_SESSION_HANDLE), Error> {
-  let ctx = Ctx::new_and_initialize(pkcs11_module_name()).unwrap();
+  fixture_token_in(Ctx::new_and_initialize(pkcs11_module_name()).unwrap())
+}
+
+fn fixture_token_in(ctx: Ctx) -> Result<(Ctx, CK_SESSION_HANDLE), Error> {
   let slots = ctx.get_slot_list(false).unwrap();
   let pin = Some("1234");
   const LABEL: &str = "rust-unit-test";
@@ -1556,3 +1628,2020 @@ fn ctx_wait_for_slot_event() {
     );
   }
 }
+
+#[test]
+fn der_encoding() {
+  assert_eq!(der::oid(x509::OID_SHA256_WITH_RSA), vec![0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b]);
+  assert_eq!(der::unsigned_integer(&[0x80]), vec![0x02, 0x02, 0x00, 0x80]);
+  assert_eq!(der::unsigned_integer(&[0x00, 0x00, 0x01]), vec![0x02, 0x01, 0x01]);
+  assert_eq!(der::unsigned_integer(&[]), vec![0x02, 0x01, 0x00]);
+  assert_eq!(der::named_bits(x509::KU_DIGITAL_SIGNATURE | x509::KU_KEY_CERT_SIGN | x509::KU_CRL_SIGN), vec![0x03, 0x02, 0x01, 0x86]);
+  assert_eq!(der::tlv(der::TAG_OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+
+  let leap = std::time::UNIX_EPOCH + std::time::Duration::from_secs(951_782_400);
+  assert_eq!(der::civil_time(leap), (2000, 2, 29, 0, 0, 0));
+  assert_eq!(der::time(leap), der::tlv(der::TAG_UTC_TIME, b"000229000000Z"));
+  // UTCTime covers 1950 to 2049 only
+  let utc_from = std::time::UNIX_EPOCH - std::time::Duration::from_secs(631_152_000);
+  assert_eq!(der::time(utc_from - std::time::Duration::from_secs(1)), der::tlv(der::TAG_GENERALIZED_TIME, b"19491231235959Z"));
+  assert_eq!(der::time(utc_from), der::tlv(der::TAG_UTC_TIME, b"500101000000Z"));
+  let utc_until = std::time::UNIX_EPOCH + std::time::Duration::from_secs(2_524_608_000);
+  assert_eq!(der::time(utc_until - std::time::Duration::from_secs(1)), der::tlv(der::TAG_UTC_TIME, b"491231235959Z"));
+  assert_eq!(der::time(utc_until), der::tlv(der::TAG_GENERALIZED_TIME, b"20500101000000Z"));
+
+  let mut reader = der::Reader::new(&[0x30, 0x03, 0x02, 0x01, 0x05]);
+  let mut seq = reader.read_sequence().unwrap();
+  assert_eq!(seq.read_unsigned_integer().unwrap(), &[0x05]);
+  assert!(seq.is_empty() && reader.is_empty());
+  assert_eq!(der::Reader::new(&der::oid(x509::OID_SECP384R1)).read_oid().unwrap(), x509::OID_SECP384R1);
+  assert!(der::Reader::new(&[0x30, 0x05, 0x02]).read_sequence().is_err());
+}
+
+#[test]
+fn der_base64_and_ecdsa_signatures() {
+  assert_eq!(der::base64_encode(b"foobar", false), "Zm9vYmFy");
+  assert_eq!(der::base64_encode(b"fo", false), "Zm8=");
+  assert_eq!(der::base64_encode(&[0xfb, 0xff], true), "-_8");
+  assert_eq!(der::base64_decode("Zm8=").unwrap(), b"fo");
+  assert_eq!(der::base64_decode("-_8").unwrap(), vec![0xfb, 0xff]);
+
+  let mut raw = vec![0u8; 64];
+  raw[1] = 0x80;
+  raw[63] = 0x01;
+  let sig = der::ecdsa_signature_from_raw(&raw).unwrap();
+  assert_eq!(der::ecdsa_signature_to_raw(&sig, 32).unwrap(), raw);
+  assert!(der::ecdsa_signature_from_raw(&raw[1..]).is_err());
+}
+
+#[test]
+fn x509_name_and_extensions() {
+  let name = x509::Name::new().country("DE").common_name("Test CA");
+  let encoded = name.to_der();
+  assert_eq!(x509::Name::from_der(&encoded).unwrap(), name);
+
+  let ext = x509::Extension::basic_constraints(true, Some(0));
+  assert_eq!(x509::Extension::from_der(&ext.to_der()).unwrap(), ext);
+  assert_eq!(ext.value, vec![0x30, 0x06, 0x01, 0x01, 0xff, 0x02, 0x01, 0x00]);
+}
+
+#[test]
+#[serial]
+fn x509_self_signed_and_issued_certificates() {
+  let (ctx, sh) = fixture_token().unwrap();
+  let (pubOh, privOh) = fixture_key_pair(&ctx, sh, "rust-unit-test-ca-pub".into(), "rust-unit-test-ca-priv".into()).unwrap();
+
+  let ca = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().country("DE").common_name("rust-unit-test CA"))
+    .extension(x509::Extension::basic_constraints(true, None))
+    .extension(x509::Extension::key_usage(x509::KU_KEY_CERT_SIGN | x509::KU_CRL_SIGN))
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  assert_eq!(ca.issuer(), ca.subject());
+  assert!(ca.subject_key_identifier().is_some());
+  assert_eq!(x509::Certificate::from_pem(&ca.to_pem()).unwrap(), ca);
+
+  let oh = ca.store(&ctx, sh, "rust-unit-test-ca", &[1]).unwrap();
+  assert_eq!(x509::Certificate::from_token(&ctx, sh, oh).unwrap(), ca);
+
+  let leaf = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().common_name("leaf"))
+    .issuer(&ca)
+    .public_key(x509::SubjectPublicKeyInfo::from_token(&ctx, sh, pubOh).unwrap())
+    .serial_number(&[0x01, 0x02])
+    .extension(x509::Extension::subject_alt_name(&["leaf.example.com"], &[]))
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  assert_eq!(leaf.issuer(), ca.subject());
+  assert_eq!(leaf.serial_number(), &[0x01, 0x02]);
+
+  // an issuer name that Name does not parse is still copied as it is; the
+  // subject comes after the identical issuer of the self-signed CA
+  let mut der = ca.as_der().to_vec();
+  let at = der.windows(ca.subject().len()).rposition(|w| w == ca.subject()).unwrap();
+  der[at + 2] = 0x30;
+  let odd_ca = x509::Certificate::from_der(&der).unwrap();
+  assert!(x509::Name::from_der(odd_ca.subject()).is_err());
+  let leaf = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().common_name("leaf"))
+    .issuer(&odd_ca)
+    .public_key(x509::SubjectPublicKeyInfo::from_token(&ctx, sh, pubOh).unwrap())
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  assert_eq!(leaf.issuer(), odd_ca.subject());
+}
+
+#[test]
+#[serial]
+fn x509_certificate_from_request_copies_only_the_subject_alt_name() {
+  let (ctx, sh) = fixture_token().unwrap();
+  let (pubOh, privOh) = fixture_key_pair(&ctx, sh, "rust-unit-test-csr-pub".into(), "rust-unit-test-csr-priv".into()).unwrap();
+  let spki = x509::SubjectPublicKeyInfo::from_token(&ctx, sh, pubOh).unwrap();
+
+  // the request asks for a CA certificate that may sign certificates
+  let requested = [
+    x509::Extension::basic_constraints(true, None),
+    x509::Extension::key_usage(x509::KU_KEY_CERT_SIGN),
+    x509::Extension::subject_alt_name(&["leaf.example.com"], &[]),
+  ];
+  let exts: Vec<Vec<u8>> = requested.iter().map(x509::Extension::to_der).collect();
+  let attribute = der::sequence(&[der::oid(x509::OID_EXTENSION_REQUEST), der::set_of(&[der::sequence(&exts)])]);
+  let info = der::sequence(&[
+    der::small_integer(0),
+    x509::Name::new().common_name("leaf").to_der(),
+    spki.as_der().to_vec(),
+    der::tlv(der::tag_explicit(0), &attribute),
+  ]);
+  let alg = der::sequence(&[der::oid(x509::OID_SHA256_WITH_RSA), der::null()]);
+  let csr = x509::CertificationRequest::from_der(&der::sequence(&[info, alg, der::bit_string(&[0; 256])])).unwrap();
+  assert_eq!(csr.extensions().len(), 3);
+
+  let leaf = x509::CertificateBuilder::from_request(&csr)
+    .issuer_name(x509::Name::new().common_name("rust-unit-test CA"))
+    .extension(x509::Extension::key_usage(x509::KU_DIGITAL_SIGNATURE))
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  let ext = |oid: &[u64]| leaf.extensions().iter().find(|ext| ext.oid == oid).cloned();
+  assert!(ext(x509::OID_BASIC_CONSTRAINTS).is_none());
+  assert_eq!(ext(x509::OID_KEY_USAGE), Some(x509::Extension::key_usage(x509::KU_DIGITAL_SIGNATURE)));
+  assert_eq!(ext(x509::OID_SUBJECT_ALT_NAME), Some(requested[2].clone()));
+}
+
+#[test]
+fn cms_digest_algorithms() {
+  use cms::DigestAlgorithm;
+
+  let abc = Vec::from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap();
+  assert_eq!(DigestAlgorithm::Sha256.hash(b"abc"), abc);
+  assert_eq!(DigestAlgorithm::Sha384.hash(b"abc").len(), 48);
+  assert_eq!(DigestAlgorithm::Sha512.hash(b"abc").len(), 64);
+
+  let data = vec![0x5au8; 200 * 1024];
+  assert_eq!(DigestAlgorithm::Sha512.hash_reader(None, &mut &data[..]).unwrap(), DigestAlgorithm::Sha512.hash(&data));
+
+  assert_eq!(DigestAlgorithm::from_oid(cms::OID_SHA384).unwrap(), DigestAlgorithm::Sha384);
+  assert_eq!(DigestAlgorithm::for_signature(x509::SignatureAlgorithm::EcdsaWithSha512), DigestAlgorithm::Sha512);
+  assert!(DigestAlgorithm::from_oid(x509::OID_SHA256_WITH_RSA).is_err());
+}
+
+#[test]
+#[serial]
+fn cms_signed_data_detached_and_encapsulated() {
+  let (ctx, sh) = fixture_token().unwrap();
+  let (pubOh, privOh) = fixture_key_pair(&ctx, sh, "rust-unit-test-cms-pub".into(), "rust-unit-test-cms-priv".into()).unwrap();
+
+  let cert = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().common_name("rust-unit-test signer"))
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  cert.store_next_to(&ctx, sh, privOh).unwrap();
+  let content = b"firmware image";
+
+  let encapsulated = cms::SignedDataBuilder::for_key(&ctx, sh, privOh).unwrap().sign(&ctx, sh, privOh, content).unwrap();
+  let parsed = cms::SignedData::from_der(&encapsulated).unwrap();
+  assert_eq!(parsed.content(), Some(&content[..]));
+  assert_eq!(parsed.signer_certificate(), Some(&cert));
+  parsed.verify(&ctx, sh, pubOh, None).unwrap();
+  parsed.verify_with_embedded_certificate(&ctx, sh, None).unwrap();
+  // content given next to the encapsulated one is not silently ignored
+  assert!(matches!(parsed.verify(&ctx, sh, pubOh, Some(content)), Err(Error::InvalidInput(_))));
+
+  // the signed content type attribute names id-signedData instead of id-data
+  let mut mismatched = encapsulated.clone();
+  let data_oid = der::oid(cms::OID_DATA);
+  let at = mismatched.windows(data_oid.len()).rposition(|w| w == &data_oid[..]).unwrap();
+  mismatched[at + data_oid.len() - 1] = 0x02;
+  assert!(matches!(cms::SignedData::from_der(&mismatched), Err(Error::InvalidInput(_))));
+
+  let detached = cms::SignedDataBuilder::new(cert.clone())
+    .detached(true)
+    .digest_on_token(true)
+    .sign_reader(&ctx, sh, privOh, &mut &content[..])
+    .unwrap();
+  let parsed = cms::SignedData::from_der(&detached).unwrap();
+  assert_eq!(parsed.content(), None);
+  parsed.verify(&ctx, sh, pubOh, Some(content)).unwrap();
+  assert!(parsed.verify(&ctx, sh, pubOh, None).is_err());
+  match parsed.verify(&ctx, sh, pubOh, Some(b"tampered")) {
+    Err(Error::Pkcs11(CKR_SIGNATURE_INVALID)) => {}
+    other => panic!("expected an invalid signature, got {:?}", other),
+  }
+}
+
+#[test]
+fn jose_algorithms_headers_and_signatures() {
+  use jose::{Algorithm, Header};
+
+  for name in &["RS256", "PS384", "ES512", "EdDSA"] {
+    assert_eq!(Algorithm::from_name(name).unwrap().name(), *name);
+  }
+  assert!(Algorithm::from_name("none").is_err());
+  assert!(Algorithm::from_name("HS256").is_err());
+
+  let header = Header::jwt(Algorithm::ES256).with_kid("key \"1\"");
+  assert_eq!(header.to_json(), r#"{"alg":"ES256","typ":"JWT","kid":"key \"1\""}"#);
+  assert_eq!(Header::from_json(&header.to_json()).unwrap(), header);
+  let foreign = r#" { "jwk": {"kty": "EC", "x": "a"}, "b64": false, "alg" : "PS256" } "#;
+  assert_eq!(Header::from_json(foreign).unwrap(), Header::new(Algorithm::PS256));
+  // no extension is understood, so none may be critical
+  for crit in &[r#"{"alg":"PS256","crit":["exp"],"exp":1}"#, r#"{"crit":[],"alg":"PS256"}"#] {
+    assert!(Header::from_json(crit).is_err(), "{}", crit);
+  }
+  assert!(Header::from_json(r#"{"typ":"JWT"}"#).is_err());
+  assert!(Header::from_json(r#"{"alg":"RS256""#).is_err());
+  assert_eq!(jose::json_members(" { } ").unwrap(), vec![]);
+  assert_eq!(jose::json_members(r#"{"x":"y","z":[1]}"#).unwrap(), vec![("x".to_string(), Some("y".to_string())), ("z".to_string(), None)]);
+  for trailing in &[r#"{"x":"y",}"#, r#"{"alg":"RS256" , }"#, r#"{,}"#] {
+    assert!(jose::json_members(trailing).is_err(), "{}", trailing);
+  }
+
+  // r = 0x01, s = 0x80 in DER and as short raw halves both end up 32 + 32 bytes wide
+  let mut expected = vec![0u8; 64];
+  expected[31] = 0x01;
+  expected[63] = 0x80;
+  assert_eq!(jose::ecdsa_to_jose(&[0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00, 0x80], 32).unwrap(), expected);
+  assert_eq!(jose::ecdsa_to_jose(&[0x01, 0x80], 32).unwrap(), expected);
+  assert_eq!(jose::ecdsa_to_jose(&expected, 32).unwrap(), expected);
+  assert!(jose::ecdsa_to_jose(&[0u8; 66], 32).is_err());
+  assert_eq!(jose::ecdsa_from_jose(&expected).unwrap(), vec![0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00, 0x80]);
+}
+
+#[test]
+#[serial]
+fn jose_jws_and_jwk_with_token_keys() {
+  use jose::{Algorithm, CompactJws, Jwk, KeyId};
+
+  let (ctx, sh) = fixture_token().unwrap();
+  let (pubOh, privOh) = fixture_key_pair(&ctx, sh, "rust-unit-test-jose-pub".into(), "rust-unit-test-jose-priv".into()).unwrap();
+
+  let claims = r#"{"sub":"rust-unit-test","exp":4102444800}"#;
+  for alg in &[Algorithm::RS256, Algorithm::RS512, Algorithm::PS256] {
+    let token = jose::sign_jwt(&ctx, sh, privOh, *alg, Some("k1"), claims).unwrap();
+    assert_eq!(jose::verify_compact(&ctx, sh, pubOh, *alg, &token).unwrap(), claims.as_bytes());
+    let jws = CompactJws::parse(&token).unwrap();
+    assert_eq!(jws.header().kid, Some("k1".to_string()));
+    assert!(jws.verify(&ctx, sh, pubOh, Algorithm::RS384).is_err());
+  }
+  assert!(Algorithm::ES256.sign(&ctx, sh, privOh, b"data").is_err());
+
+  let jwk = Jwk::from_token(&ctx, sh, pubOh, KeyId::Thumbprint).unwrap();
+  assert_eq!(jwk.member("kty"), Some("RSA"));
+  assert_eq!(jwk.kid(), jwk.thumbprint());
+  assert_eq!(Jwk::from_token(&ctx, sh, privOh, KeyId::Thumbprint).unwrap(), jwk);
+  assert!(jwk.to_json(Some(Algorithm::RS256)).contains(r#""alg":"RS256","use":"sig""#));
+
+  // leading zero bytes of the modulus and exponent are left out
+  let mut modulus = vec![0];
+  modulus.extend(ctx.get_attribute_bytes(sh, pubOh, CKA_MODULUS).unwrap());
+  let template = vec![
+    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PUBLIC_KEY),
+    CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&CKK_RSA),
+    CK_ATTRIBUTE::new(CKA_MODULUS).with_bytes(&modulus),
+    CK_ATTRIBUTE::new(CKA_PUBLIC_EXPONENT).with_bytes(&[0, 0, 1, 0, 1]),
+  ];
+  let padded = ctx.create_object(sh, &template).unwrap();
+  let padded = Jwk::from_token(&ctx, sh, padded, KeyId::Thumbprint).unwrap();
+  assert_eq!(padded.member("e"), Some("AQAB"));
+  assert_eq!(padded.member("n"), jwk.member("n"));
+}
+
+#[test]
+fn mock_token_and_pin_lifecycle() {
+  let ctx = mock_ctx();
+  let slot = ctx.get_slot_list(true).unwrap()[0];
+  assert_eq!(ctx.get_token_info(slot).unwrap().flags & CKF_TOKEN_INITIALIZED, 0);
+  assert!(matches!(ctx.open_session(slot, CKF_SERIAL_SESSION, None, None), Err(Error::Pkcs11(CKR_TOKEN_NOT_RECOGNIZED))));
+
+  let (ctx, sh) = fixture_token_in(ctx).unwrap();
+  let info = ctx.get_token_info(slot).unwrap();
+  assert_eq!(info.flags & (CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED), CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED);
+  assert!(info.label.starts_with(b"rust-unit-test  "));
+  assert_eq!(ctx.get_session_info(sh).unwrap().state, CKS_RW_USER_FUNCTIONS);
+  assert!(matches!(ctx.init_token(slot, Some("1234"), "again"), Err(Error::Pkcs11(CKR_SESSION_EXISTS))));
+
+  ctx.set_pin(sh, Some("1234"), Some("5678")).unwrap();
+  ctx.logout(sh).unwrap();
+  assert_eq!(ctx.get_session_info(sh).unwrap().state, CKS_RW_PUBLIC_SESSION);
+  for _ in 0..2 {
+    assert!(matches!(ctx.login(sh, CKU_USER, Some("1234")), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  }
+  assert_ne!(ctx.get_token_info(slot).unwrap().flags & CKF_USER_PIN_FINAL_TRY, 0);
+  assert!(matches!(ctx.login(sh, CKU_USER, Some("1234")), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  assert!(matches!(ctx.login(sh, CKU_USER, Some("5678")), Err(Error::Pkcs11(CKR_PIN_LOCKED))));
+  assert_ne!(ctx.get_token_info(slot).unwrap().flags & CKF_USER_PIN_LOCKED, 0);
+
+  // the SO can still log in and reset the user PIN
+  ctx.login(sh, CKU_SO, Some("1234")).unwrap();
+  ctx.init_pin(sh, Some("4321")).unwrap();
+  ctx.logout(sh).unwrap();
+  ctx.login(sh, CKU_USER, Some("4321")).unwrap();
+}
+
+#[test]
+fn names_lookup_and_parse() {
+  use names::*;
+  assert_eq!(MechanismType(CKM_ECDSA).to_string(), "CKM_ECDSA");
+  assert_eq!(MechanismType(CKM_VENDOR_DEFINED | 1).to_string(), format!("{:#x}", CKM_VENDOR_DEFINED | 1));
+  // the deprecated alias of the same value is not shown, but parsed
+  assert_eq!(MechanismType(CKM_EC_KEY_PAIR_GEN).name(), Some("CKM_EC_KEY_PAIR_GEN"));
+  assert_eq!("CKM_ECDSA_KEY_PAIR_GEN".parse::<MechanismType>().unwrap().0, CKM_EC_KEY_PAIR_GEN);
+  for s in &["CKM_SHA256_RSA_PKCS", "sha256-rsa-pkcs", "SHA256_RSA_PKCS", "0x40"] {
+    assert_eq!(s.parse::<MechanismType>().unwrap(), MechanismType(CKM_SHA256_RSA_PKCS), "{}", s);
+  }
+  assert!(matches!("sha257-rsa-pkcs".parse::<MechanismType>(), Err(Error::InvalidInput("unknown mechanism name"))));
+  assert!(matches!("CKA_LABEL".parse::<MechanismType>(), Err(Error::InvalidInput(_))));
+
+  assert_eq!(AttributeType(CKA_LABEL).to_string(), "CKA_LABEL");
+  assert_eq!("value-len".parse::<AttributeType>().unwrap().0, CKA_VALUE_LEN);
+  assert_eq!(ObjectClass(CKO_PRIVATE_KEY).to_string(), "CKO_PRIVATE_KEY");
+  assert_eq!("secret-key".parse::<ObjectClass>().unwrap().0, CKO_SECRET_KEY);
+  assert_eq!(KeyType::from(CKK_EC).to_string(), "CKK_EC");
+  assert_eq!("aes".parse::<KeyType>().unwrap().0, CKK_AES);
+  assert_eq!(CertificateType(CKC_X_509).to_string(), "CKC_X_509");
+  assert_eq!("x-509".parse::<CertificateType>().unwrap().0, CKC_X_509);
+  assert_eq!(UserType(CKU_CONTEXT_SPECIFIC).to_string(), "CKU_CONTEXT_SPECIFIC");
+  assert_eq!("so".parse::<UserType>().unwrap().0, CKU_SO);
+
+  assert_eq!(name(KEY_TYPES, CKK_RSA), "CKK_RSA");
+  assert_eq!(lookup(ATTRIBUTES, CKA_VENDOR_DEFINED | 7), None);
+  assert_eq!(parse(OBJECT_CLASSES, "CKO_", "Certificate"), Some(CKO_CERTIFICATE));
+}
+
+#[test]
+fn uri_parse_and_format() {
+  use uri::Pkcs11Uri;
+  // examples of RFC 7512
+  let uri: Pkcs11Uri = "pkcs11:token=The%20Software%20PKCS%2311%20Softtoken;manufacturer=Snake%20Oil,%20Inc.;model=1.0;object=my-certificate;type=cert;id=%69%95%3E%5C%F4%BD%EC%91;serial=?pin-source=file:/etc/token_pin".parse().unwrap();
+  assert_eq!(uri.token.as_deref(), Some("The Software PKCS#11 Softtoken"));
+  assert_eq!(uri.manufacturer.as_deref(), Some("Snake Oil, Inc."));
+  assert_eq!(uri.model.as_deref(), Some("1.0"));
+  assert_eq!(uri.serial.as_deref(), Some(""));
+  assert_eq!(uri.object_type, Some(CKO_CERTIFICATE));
+  assert_eq!(uri.id, Some(vec![0x69, 0x95, 0x3e, 0x5c, 0xf4, 0xbd, 0xec, 0x91]));
+  assert_eq!(uri.pin_source.as_deref(), Some("file:/etc/token_pin"));
+  assert_eq!(
+    uri.to_string(),
+    "pkcs11:token=The%20Software%20PKCS%2311%20Softtoken;manufacturer=Snake%20Oil,%20Inc.;model=1.0;serial=;object=my-certificate;type=cert;id=%69%95%3E%5C%F4%BD%EC%91?pin-source=file:/etc/token_pin"
+  );
+  assert_eq!(uri.to_string().parse::<Pkcs11Uri>().unwrap(), uri);
+
+  let uri: Pkcs11Uri = "pkcs11:library-manufacturer=Snake%20Oil,%20Inc.;library-description=Soft%20Token%20Library;library-version=1.23".parse().unwrap();
+  assert_eq!(uri.library_version, Some(info::Version { major: 1, minor: 23 }));
+  let uri: Pkcs11Uri = "pkcs11:token=Name%20with%20a%20small%20A%20with%20acute:%20%C3%A1;object=my-certificate;type=cert".parse().unwrap();
+  assert_eq!(uri.token.as_deref(), Some("Name with a small A with acute: \u{e1}"));
+  assert_eq!(uri.to_string(), "pkcs11:token=Name%20with%20a%20small%20A%20with%20acute:%20%C3%A1;object=my-certificate;type=cert");
+  let uri: Pkcs11Uri = "pkcs11:token=my-token;object=my-certificate;type=cert;vendor-aaa=value-a?pin-source=file:/etc/token_pin&vendor-bbb=value-b".parse().unwrap();
+  assert_eq!(uri.vendor_path, vec![("vendor-aaa".to_string(), "value-a".to_string())]);
+  assert_eq!(uri.vendor_query, vec![("vendor-bbb".to_string(), "value-b".to_string())]);
+  let uri: Pkcs11Uri = "PKCS11:slot-id=3;object=my-sign-key;type=private?module-path=/mnt/libmypkcs11.so.1&pin-value=the-pin".parse().unwrap();
+  assert_eq!((uri.slot_id, uri.module_path.as_deref(), uri.pin().unwrap()), (Some(3), Some("/mnt/libmypkcs11.so.1"), Some("the-pin".to_string())));
+  let debug = format!("{:?}", uri);
+  assert!(debug.contains("pin_value: Some([REDACTED])") && !debug.contains("the-pin"), "{}", debug);
+  assert_eq!("pkcs11:".parse::<Pkcs11Uri>().unwrap(), Pkcs11Uri::default());
+
+  for bad in &["pkcs12:token=x", "pkcs11:token", "pkcs11:id=%1", "pkcs11:id=%+1", "pkcs11:id=%-1", "pkcs11:id=%G0", "pkcs11:type=key", "pkcs11:token=a;token=b", "pkcs11:slot-id=x", "pkcs11:token=%FF"] {
+    assert!(matches!(bad.parse::<Pkcs11Uri>(), Err(Error::InvalidInput(_))), "{}", bad);
+  }
+}
+
+#[test]
+fn uri_resolves_objects() {
+  use uri::Pkcs11Uri;
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  for &(label, class, private) in &[("uri-key", CKO_DATA, CK_TRUE), ("uri-key", CKO_CERTIFICATE, CK_FALSE)] {
+    let template = vec![
+      CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+      CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+      CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&private),
+      CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+      CK_ATTRIBUTE::new(CKA_ID).with_bytes(&[0xab]),
+    ];
+    ctx.create_object(sh, &template).unwrap();
+  }
+  ctx.logout(sh).unwrap();
+  ctx.close_session(sh).unwrap();
+
+  let resolve = |uri: &str| uri.parse::<Pkcs11Uri>().unwrap().resolve(&ctx);
+  let (session, object) = resolve("pkcs11:token=rust-unit-test;model=Mock;object=uri-key;type=cert").unwrap();
+  assert_eq!(ctx.get_attribute_ulong(session, object, CKA_CLASS).unwrap(), CKO_CERTIFICATE);
+  ctx.close_session(session).unwrap();
+  // the private object needs the PIN
+  let (session, object) = resolve("pkcs11:token=rust-unit-test;id=%AB;type=data?pin-value=1234").unwrap();
+  assert_eq!(ctx.get_attribute_ulong(session, object, CKA_CLASS).unwrap(), CKO_DATA);
+  assert_eq!(ctx.get_session_info(session).unwrap().state, CKS_RO_USER_FUNCTIONS);
+  ctx.close_session(session).unwrap();
+  let (session, _) = resolve("pkcs11:library-manufacturer=rust-pkcs11;slot-description=Mock%20slot;id=%AB;type=data?pin-value=1234").unwrap();
+  ctx.close_session(session).unwrap();
+
+  let pin = env::temp_dir().join(format!("pkcs11-uri-pin-{}", process::id()));
+  fs::write(&pin, "1234\n").unwrap();
+  let uri = format!("pkcs11:object=uri-key?pin-source=file:{}", pin.display());
+  assert!(matches!(resolve(&uri), Err(Error::InvalidInput("the URI matches more than one object"))));
+  let _ = fs::remove_file(&pin);
+  assert!(matches!(resolve("pkcs11:object=uri-key;type=data"), Err(Error::InvalidInput("no object matches the URI"))));
+  assert!(matches!(resolve("pkcs11:token=other;object=uri-key"), Err(Error::InvalidInput("no token matches the URI"))));
+  assert!(matches!(resolve("pkcs11:library-version=9.9;object=uri-key"), Err(Error::InvalidInput("URI does not match the module"))));
+  assert!(matches!(resolve("pkcs11:object=uri-key;type=data?pin-value=0000"), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  // no session is left open
+  assert_eq!(ctx.get_token_info(0).unwrap().ulSessionCount, 0);
+}
+
+#[test]
+fn pin_sources_and_login_warnings() {
+  use pin::*;
+  use secret::SecretPin;
+  use std::cell::Cell;
+  use std::io;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  let relogin = |source: &dyn PinSource| {
+    let _ = ctx.logout(sh);
+    pin::login(&ctx, sh, CKU_USER, source)
+  };
+
+  // other tests read the environment at the same time, so it is not written
+  let token = ctx.get_token_info(0).unwrap();
+  let request = PinRequest { slot_id: 0, user_type: CKU_USER, token: &token, warning: None };
+  let path = EnvPin::new("PATH").pin(&request).unwrap().unwrap();
+  assert_eq!(path.as_bytes(), env::var("PATH").unwrap().as_bytes());
+  let var = format!("PKCS11_TEST_PIN_{}", process::id());
+  assert!(matches!(relogin(&EnvPin::new(&var)), Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound));
+
+  let file = env::temp_dir().join(format!("pkcs11-pin-source-{}", process::id()));
+  fs::write(&file, "1234\r\nsecond line\n").unwrap();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(matches!(relogin(&FilePin::new(&file)), Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::PermissionDenied));
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+  }
+  assert_eq!(relogin(&FilePin::new(&file)).unwrap(), None);
+  let _ = fs::remove_file(&file);
+
+  #[cfg(unix)]
+  {
+    assert_eq!(relogin(&CommandPin::new("sh").arg("-c").arg("echo 1234")).unwrap(), None);
+    assert!(matches!(relogin(&CommandPin::new("sh").arg("-c").arg("echo 1234; exit 1")), Err(Error::Io(_))));
+  }
+  assert!(matches!(relogin(&ProtectedPath), Err(Error::InvalidInput(_))));
+
+  // wrong PINs show up in the flags, and a locked PIN is not tried again
+  let warnings = Cell::new(Vec::new());
+  let prompt = CallbackPin::new(|request: &PinRequest| {
+    let mut seen = warnings.take();
+    seen.push(request.warning);
+    warnings.set(seen);
+    Ok(SecretPin::from("0000"))
+  });
+  for _ in 0..3 {
+    assert!(matches!(relogin(&prompt), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  }
+  assert_eq!(warnings.take(), vec![None, Some(PinWarning::CountLow), Some(PinWarning::FinalTry)]);
+  let tries = faults.call_count("C_Login").unwrap();
+  assert!(matches!(relogin(&SecretPin::from("1234")), Err(Error::Pkcs11(CKR_PIN_LOCKED))));
+  assert_eq!(faults.call_count("C_Login").unwrap(), tries);
+  assert_eq!(pin_state(CKF_SO_PIN_FINAL_TRY | CKF_USER_PIN_COUNT_LOW, CKU_SO).unwrap(), Some(PinWarning::FinalTry));
+}
+
+#[test]
+fn always_authenticate_keys_log_in_per_operation() {
+  use secret::SecretPin;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  let mut mechanism = CK_MECHANISM {
+    mechanism: CKM_RSA_PKCS_KEY_PAIR_GEN,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let bits: CK_ULONG = 1024;
+  let public = [
+    CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&bits),
+    CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&CK_TRUE),
+  ];
+  let always = [
+    CK_ATTRIBUTE::new(CKA_ALWAYS_AUTHENTICATE).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&CK_TRUE),
+  ];
+  let (public_key, key) = ctx.generate_key_pair(sh, &mechanism, &public, &always).unwrap();
+  let (_, plain_key) = ctx.generate_key_pair(sh, &mechanism, &public, &[]).unwrap();
+  assert!(auth::always_authenticate(&ctx, sh, key).unwrap());
+  assert!(!auth::always_authenticate(&ctx, sh, plain_key).unwrap());
+
+  mechanism.mechanism = CKM_SHA256_RSA_PKCS;
+  ctx.sign_init(sh, &mechanism, key).unwrap();
+  assert!(matches!(ctx.sign(sh, b"data"), Err(Error::Pkcs11(CKR_USER_NOT_LOGGED_IN))));
+  let pin = SecretPin::from_bytes(b"1234".to_vec());
+  let logins = faults.call_count("C_Login").unwrap();
+  let signature = auth::sign(&ctx, sh, &mechanism, key, b"data", &pin).unwrap();
+  assert_eq!(faults.call_count("C_Login").unwrap(), logins + 1);
+  ctx.verify_init(sh, &mechanism, public_key).unwrap();
+  ctx.verify(sh, b"data", &signature).unwrap();
+  auth::sign(&ctx, sh, &mechanism, plain_key, b"data", &pin).unwrap();
+  assert_eq!(faults.call_count("C_Login").unwrap(), logins + 1);
+
+  // the token is looked at and the PIN asked for before the operation starts
+  let inits = faults.call_count("C_SignInit").unwrap();
+  let asked_after = std::cell::Cell::new(None);
+  let source = pin::CallbackPin::new(|_: &pin::PinRequest| {
+    asked_after.set(Some(faults.call_count("C_SignInit").unwrap()));
+    Ok(pin.clone())
+  });
+  auth::sign(&ctx, sh, &mechanism, key, b"data", &source).unwrap();
+  assert_eq!(asked_after.get(), Some(inits));
+
+  mechanism.mechanism = CKM_RSA_PKCS;
+  ctx.encrypt_init(sh, &mechanism, public_key).unwrap();
+  let ciphertext = ctx.encrypt(sh, b"secret").unwrap();
+  assert_eq!(auth::decrypt(&ctx, sh, &mechanism, key, &ciphertext, &pin).unwrap().as_slice(), b"secret");
+  assert!(matches!(auth::decrypt(&ctx, sh, &mechanism, key, &ciphertext, &SecretPin::from("0000")), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+}
+
+#[test]
+fn provisioning_is_repeatable() {
+  use provision::*;
+  use secret::SecretPin;
+  let ctx = mock_ctx();
+  let slot = ctx.get_slot_list(false).unwrap()[0];
+  let (so_pin, user_pin) = (SecretPin::from("87654321"), SecretPin::from("1234"));
+  let plan = TokenPlan::new("provisioned")
+    .object(ObjectPlan::EcKeyPair { label: "signer".into(), id: vec![1], curve: Curve::P256 })
+    .object(ObjectPlan::AesKey { label: "backup".into(), id: vec![], bits: 128 })
+    .object(ObjectPlan::Data { label: "config".into(), application: "app".into(), value: b"v1".to_vec() });
+
+  // the SO PIN is asked for once, for the initialization and the user PIN
+  let asked = std::cell::Cell::new(0);
+  let counted_so_pin = pin::CallbackPin::new(|_: &pin::PinRequest| {
+    asked.set(asked.get() + 1);
+    Ok(so_pin.clone())
+  });
+  let report = plan.apply(&ctx, slot, &counted_so_pin, &user_pin).unwrap();
+  assert_eq!(asked.get(), 1);
+  assert_eq!(report.changes[..2], [Change::TokenInitialized, Change::UserPinInitialized]);
+  assert_eq!(report.changes.len(), 5);
+  assert_eq!(report.changes[2].to_string(), "created EC key pair \"signer\"");
+  let flags = ctx.get_token_info(slot).unwrap().flags;
+  assert_eq!(flags & (CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED), CKF_TOKEN_INITIALIZED | CKF_USER_PIN_INITIALIZED);
+
+  // the second time around nothing is left to do, and the SO PIN is not needed
+  let no_so_pin = pin::CallbackPin::new(|_: &pin::PinRequest| -> Result<SecretPin, Error> { panic!("asked for the SO PIN") });
+  let report = plan.apply(&ctx, slot, &no_so_pin, &user_pin).unwrap();
+  assert!(!report.is_changed());
+  assert_eq!(report.unchanged, vec!["signer", "backup", "config"]);
+
+  let sh = ctx.open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None).unwrap();
+  ctx.login(sh, CKU_USER, Some("1234")).unwrap();
+  let class = CKO_PRIVATE_KEY;
+  ctx.find_objects_init(sh, &[CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class), CK_ATTRIBUTE::new(CKA_LABEL).with_string("signer")]).unwrap();
+  let key = ctx.find_objects(sh, 2).unwrap();
+  ctx.find_objects_final(sh).unwrap();
+  assert_eq!(key.len(), 1);
+  assert_eq!(ctx.get_attribute_ulong(sh, key[0], CKA_SENSITIVE).unwrap(), CK_TRUE as CK_ULONG);
+  let class = CKO_PUBLIC_KEY;
+  ctx.find_objects_init(sh, &[CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class), CK_ATTRIBUTE::new(CKA_ID).with_bytes(&[1])]).unwrap();
+  let public_key = ctx.find_objects(sh, 1).unwrap();
+  ctx.find_objects_final(sh).unwrap();
+  let cert = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().common_name("signer"))
+    .public_key(x509::SubjectPublicKeyInfo::from_token(&ctx, sh, public_key[0]).unwrap())
+    .sign(&ctx, sh, key[0])
+    .unwrap();
+  ctx.close_session(sh).unwrap();
+
+  let plan = plan.object(ObjectPlan::Certificate { label: "signer".into(), id: vec![], der: cert.as_der().to_vec() });
+  let report = plan.apply(&ctx, slot, &so_pin, &user_pin).unwrap();
+  assert_eq!(report.changes, vec![Change::Created { kind: "certificate", label: "signer".into() }]);
+  assert!(!plan.apply(&ctx, slot, &so_pin, &user_pin).unwrap().is_changed());
+  assert!(matches!(TokenPlan::new("other").apply(&ctx, slot, &so_pin, &user_pin), Err(Error::InvalidInput(_))));
+  assert!(matches!(TokenPlan::new("provisioned").apply(&ctx, slot, &so_pin, &SecretPin::from("0000")), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+}
+
+#[test]
+fn provisioning_with_long_label_is_repeatable() {
+  use provision::*;
+  use secret::SecretPin;
+  let ctx = mock_ctx();
+  let slot = ctx.get_slot_list(false).unwrap()[0];
+  let (so_pin, user_pin) = (SecretPin::from("87654321"), SecretPin::from("1234"));
+  // the token keeps 32 bytes of it
+  let plan = TokenPlan::new("a label that is longer than the token label field");
+  assert!(plan.apply(&ctx, slot, &so_pin, &user_pin).unwrap().is_changed());
+  assert_eq!(info::trimmed(&ctx.get_token_info(slot).unwrap().label), "a label that is longer than the");
+  assert!(!plan.apply(&ctx, slot, &so_pin, &user_pin).unwrap().is_changed());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn provisioning_plan_serde() {
+  use provision::*;
+  let json = r#"{ "label": "signing", "objects": [
+    { "type": "ec_key_pair", "label": "signer", "id": "01", "curve": "p256" },
+    { "type": "rsa_key_pair", "label": "legacy", "bits": 2048 },
+    { "type": "data", "label": "config", "application": "app", "value": "ca:fe" } ] }"#;
+  let plan: TokenPlan = serde_json::from_str(json).unwrap();
+  let expected = TokenPlan::new("signing")
+    .object(ObjectPlan::EcKeyPair { label: "signer".into(), id: vec![1], curve: Curve::P256 })
+    .object(ObjectPlan::RsaKeyPair { label: "legacy".into(), id: vec![], bits: 2048 })
+    .object(ObjectPlan::Data { label: "config".into(), application: "app".into(), value: vec![0xca, 0xfe] });
+  assert_eq!(plan, expected);
+  assert_eq!(serde_json::from_str::<TokenPlan>(&serde_json::to_string(&plan).unwrap()).unwrap(), plan);
+  assert!(serde_json::from_str::<TokenPlan>(r#"{ "label": "x", "objects": [{ "type": "aes_key", "label": "k", "id": "0", "bits": 128 }] }"#).is_err());
+  assert!(serde_json::from_str::<TokenPlan>(r#"{ "label": "x", "objects": [{ "type": "aes_key", "label": "k", "id": "aé", "bits": 128 }] }"#).is_err());
+}
+
+#[test]
+fn pin_policy_checks_and_renews_pins() {
+  use pin_policy::*;
+  use secret::SecretPin;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  let token = ctx.get_token_info(0).unwrap();
+  let policy = PinPolicy::new().min_length(6).character_classes(2).max_repeated(2).max_sequence(3).deny("abc-123");
+  let violations = |pin: &str| policy.violations(&SecretPin::from(pin), &token);
+  assert_eq!(violations("1234"), vec![Violation::TooShort { min: 6 }, Violation::TooFewClasses { min: 2 }, Violation::Sequence { max: 3 }]);
+  assert_eq!(violations("aaa-xy7"), vec![Violation::Repeated { max: 2 }]);
+  assert_eq!(violations("abc-123"), vec![Violation::Denied]);
+  assert_eq!(violations(&"x1".repeat(128)), vec![Violation::TooLong { max: 255 }]);
+  assert!(violations("river-42").is_empty());
+  assert_eq!(PinPolicy::new().digits_only().violations(&SecretPin::from("12a4"), &token), vec![Violation::NotDigits]);
+  assert_eq!(policy.describe(&token), "6 to 255 characters, 2 of lowercase letters, uppercase letters, digits and other characters, no character more than 2 times in a row, no more than 3 characters counting up or down");
+
+  // a PIN that breaks the policy never reaches the module
+  let old = SecretPin::from("1234");
+  let err = policy.set_pin(&ctx, sh, Some(&old), Some(&SecretPin::from("1111"))).unwrap_err();
+  assert_eq!(err.to_string(), "the PIN is shorter than 6 characters, the PIN needs 2 of lowercase letters, uppercase letters, digits and other characters, the PIN has a character more than 2 times in a row");
+  assert!(matches!(PinPolicy::new().set_pin(&ctx, sh, Some(&old), Some(&old)), Err(PinError::Rejected(ref v)) if v == &[Violation::Unchanged]));
+  assert_eq!(faults.call_count("C_SetPIN").unwrap(), 0);
+
+  // the SO set the user PIN, so the token wants it changed at the next login
+  assert_ne!(token.flags & CKF_USER_PIN_TO_BE_CHANGED, 0);
+  ctx.logout(sh).unwrap();
+  let new_pin = pin::CallbackPin::new(|_: &pin::PinRequest| Ok(SecretPin::from("river-42")));
+  assert!(policy.login_and_renew(&ctx, sh, CKU_USER, &old, &new_pin).unwrap());
+  assert_eq!(ctx.get_token_info(0).unwrap().flags & CKF_USER_PIN_TO_BE_CHANGED, 0);
+  ctx.logout(sh).unwrap();
+  assert!(!policy.login_and_renew(&ctx, sh, CKU_USER, &SecretPin::from("river-42"), &new_pin).unwrap());
+  assert_eq!(faults.call_count("C_SetPIN").unwrap(), 1);
+}
+
+#[test]
+fn key_builders_generate_with_safe_defaults() {
+  use keygen::*;
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  let attr = |key: CK_OBJECT_HANDLE, attribute: CK_ATTRIBUTE_TYPE| ctx.get_attribute_bytes(sh, key, attribute).unwrap();
+  let ulong = |key: CK_OBJECT_HANDLE, attribute: CK_ATTRIBUTE_TYPE| {
+    let value = attr(key, attribute);
+    let mut bytes = [0; std::mem::size_of::<CK_ULONG>()];
+    bytes.copy_from_slice(&value);
+    CK_ULONG::from_ne_bytes(bytes)
+  };
+
+  let rsa = RsaKeyPair::new(1024).label("signer").id(&[1]).sign(true).generate(&ctx, sh).unwrap();
+  let private = rsa.private.handle();
+  assert_eq!(attr(private, CKA_LABEL), b"signer");
+  assert_eq!(attr(private, CKA_ID), [1]);
+  for &(attribute, value) in &[(CKA_TOKEN, CK_TRUE), (CKA_PRIVATE, CK_TRUE), (CKA_SENSITIVE, CK_TRUE), (CKA_EXTRACTABLE, CK_FALSE), (CKA_SIGN, CK_TRUE), (CKA_DECRYPT, CK_FALSE)] {
+    assert_eq!(attr(private, attribute), [value], "attribute {:#x}", attribute);
+  }
+  assert_eq!(attr(rsa.public.into(), CKA_PRIVATE), [CK_FALSE]);
+  assert_eq!(attr(rsa.public.into(), CKA_VERIFY), [CK_TRUE]);
+  let mechanism = CK_MECHANISM { mechanism: CKM_SHA256_RSA_PKCS, pParameter: ptr::null_mut(), ulParameterLen: 0 };
+  ctx.sign_init(sh, &mechanism, private).unwrap();
+  let signature = ctx.sign(sh, b"data").unwrap();
+  ctx.verify_init(sh, &mechanism, rsa.public.handle()).unwrap();
+  ctx.verify(sh, b"data", &signature).unwrap();
+
+  let ec = EcKeyPair::p256().token(false).derive(true).generate(&ctx, sh).unwrap();
+  assert_eq!(attr(ec.public.handle(), CKA_EC_PARAMS), der::oid(Curve::P256.oid()));
+  assert_eq!(attr(ec.private.handle(), CKA_TOKEN), [CK_FALSE]);
+  assert_eq!(attr(ec.private.handle(), CKA_DERIVE), [CK_TRUE]);
+
+  let aes = AesKey::new(256).wrap(true).generate(&ctx, sh).unwrap();
+  assert_eq!(ulong(aes.handle(), CKA_VALUE_LEN), 32);
+  assert_eq!(attr(aes.handle(), CKA_UNWRAP), [CK_TRUE]);
+  assert!(matches!(AesKey::new(100).generate(&ctx, sh), Err(Error::InvalidInput(_))));
+
+  let hmac = HmacKey::sha256().generate(&ctx, sh).unwrap();
+  assert_eq!(ulong(hmac.handle(), CKA_KEY_TYPE), CKK_GENERIC_SECRET);
+  assert_eq!(ulong(hmac.handle(), CKA_VALUE_LEN), 32);
+  assert_eq!(attr(hmac.handle(), CKA_SIGN), [CK_TRUE]);
+  let hmac = HmacKey::sha256().sign(false).derive(true).generate(&ctx, sh).unwrap();
+  assert_eq!(attr(hmac.handle(), CKA_VERIFY), [CK_FALSE]);
+  assert_eq!(attr(hmac.handle(), CKA_DERIVE), [CK_TRUE]);
+
+  let des3 = Des3Key::new().extractable(true).sensitive(false).generate(&ctx, sh).unwrap();
+  assert_eq!(ulong(des3.handle(), CKA_KEY_TYPE), CKK_DES3);
+  assert_eq!(attr(des3.handle(), CKA_VALUE).len(), 24);
+  assert_ne!(aes, des3);
+}
+
+#[test]
+fn p11kit_config_files() {
+  use p11kit::*;
+  let config = ModuleConfig::parse("softhsm2", "# SoftHSM\nmodule: libsofthsm2.so\npriority: 5\nenable-in: app, other\n  disable-in:other\ncritical: yes\ntrust-policy: no\n").unwrap();
+  assert_eq!(config.module, Some(PathBuf::from("libsofthsm2.so")));
+  assert_eq!(config.module_path(std::path::Path::new("/usr/lib/pkcs11")), Some(PathBuf::from("/usr/lib/pkcs11/libsofthsm2.so")));
+  assert_eq!((config.priority, config.critical), (5, true));
+  assert!(config.is_enabled_for("app"));
+  assert!(!config.is_enabled_for("other"));
+  assert!(!config.is_enabled_for("third"));
+  assert_eq!(config.options.last(), Some(&("trust-policy".to_string(), "no".to_string())));
+  assert!(!ModuleConfig::parse("none", "priority: 1\n").unwrap().is_enabled_for("app"));
+  assert!(matches!(ModuleConfig::parse("bad", "module /lib/x.so\n"), Err(Error::InvalidInput(_))));
+  assert!(matches!(ModuleConfig::parse("bad", "critical: maybe\n"), Err(Error::InvalidInput(_))));
+}
+
+#[test]
+fn p11kit_loads_configured_modules() {
+  use p11kit::*;
+  let root = env::temp_dir().join(format!("pkcs11-p11kit-{}", process::id()));
+  let (system, user) = (root.join("system"), root.join("user"));
+  fs::create_dir_all(&system).unwrap();
+  fs::create_dir_all(&user).unwrap();
+  let (first, second) = (mock_module_copy(), mock_module_copy());
+  fs::write(system.join("first.module"), format!("module: {}\npriority: 1\n", first.display())).unwrap();
+  fs::write(system.join("second.module"), format!("module: {}\npriority: 2\n", second.file_name().unwrap().to_str().unwrap())).unwrap();
+  fs::write(system.join("broken.module"), "module: /nonexistent/libbroken.so\n").unwrap();
+  fs::write(system.join("elsewhere.module"), format!("module: {}\nenable-in: other-program\n", first.display())).unwrap();
+  fs::write(system.join("README"), "not a module").unwrap();
+  // the user configuration raises the priority of first over second
+  fs::write(user.join("first.module"), "priority: 3\n").unwrap();
+
+  let config = Config::with_dirs(&[&system, &user, &root.join("missing")]).module_dir(second.parent().unwrap()).program("test-program");
+  let names: Vec<String> = config.modules().unwrap().into_iter().map(|m| m.name).collect();
+  assert_eq!(names, vec!["first", "second", "broken", "elsewhere"]);
+  let modules = config.load().unwrap();
+  assert_eq!(modules.modules.iter().map(|m| m.config.name.as_str()).collect::<Vec<_>>(), vec!["first", "second"]);
+  assert_eq!(modules.modules[1].path, second);
+  assert_eq!(modules.failed.len(), 1);
+  assert_eq!(modules.failed[0].0.name, "broken");
+  assert!(matches!(modules.failed[0].1, Error::Io(_)));
+
+  let tokens = modules.tokens();
+  assert_eq!(tokens.tokens.len(), 2);
+  assert_eq!(tokens.tokens[0].module.config.name, "first");
+  assert_eq!(tokens.tokens[0].info.model, "Mock");
+  assert!(tokens.failed.is_empty());
+
+  // a module failing to list its tokens leaves the others
+  let faults = fault::FaultInjector::new(&first).unwrap();
+  faults.set_rules(&fault::Rules::new().fail_always("C_GetSlotList", CKR_DEVICE_ERROR)).unwrap();
+  let tokens = modules.tokens();
+  assert_eq!(tokens.tokens.len(), 1);
+  assert_eq!(tokens.tokens[0].module.config.name, "second");
+  assert_eq!(tokens.failed.len(), 1);
+  assert_eq!(tokens.failed[0].0.config.name, "first");
+  assert!(matches!(tokens.failed[0].1, Error::Pkcs11(CKR_DEVICE_ERROR)));
+  faults.clear().unwrap();
+  assert!(modules.get("second").is_some());
+  assert!(modules.get("broken").is_none());
+
+  fs::write(user.join("broken.module"), "critical: yes\n").unwrap();
+  assert!(config.load().is_err());
+  drop(modules);
+  let _ = fs::remove_dir_all(&root);
+  let _ = fs::remove_file(&first);
+  let _ = fs::remove_file(&second);
+}
+
+#[test]
+fn select_token_by_fields() {
+  use select::*;
+  assert!(glob_match("rust-*", "rust-unit-test"));
+  assert!(glob_match("*-test", "rust-unit-test"));
+  assert!(glob_match("r?st*t", "rust-unit-test"));
+  assert!(glob_match("*", ""));
+  assert!(!glob_match("rust-?", "rust-unit-test"));
+  assert!(!glob_match("*x*", "rust-unit-test"));
+
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  ctx.close_session(sh).unwrap();
+  assert_eq!(TokenSelector::new().select(&ctx).unwrap(), 0);
+  assert_eq!(TokenSelector::new().label("rust-unit-test").model("Mock").select(&ctx).unwrap(), 0);
+  assert_eq!(TokenSelector::new().serial_glob("0*1").manufacturer_glob("rust-*").select_all(&ctx).unwrap(), vec![0]);
+  assert!(TokenSelector::new().label_glob("other-*").select_all(&ctx).unwrap().is_empty());
+
+  let selector = TokenSelector::new().label("other").serial_glob("1*");
+  assert_eq!(selector.to_string(), "label \"other\", serial glob \"1*\"");
+  assert_eq!(TokenSelector::new().to_string(), "any token");
+  let err = selector.select(&ctx).unwrap_err();
+  assert!(matches!(err, SelectError::NoMatch(_, ref candidates) if candidates.len() == 1));
+  assert_eq!(
+    err.to_string(),
+    "no token matches label \"other\", serial glob \"1*\", the tokens are:\n  slot 0: label \"rust-unit-test\", serial \"0000000000000001\", manufacturer \"rust-pkcs11\", model \"Mock\""
+  );
+}
+
+#[test]
+fn pool_hands_out_and_replaces_sessions() {
+  use pool::*;
+  use std::sync::Arc;
+  use std::time::Duration;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  // closing the last session logs the token out
+  ctx.close_session(sh).unwrap();
+  faults.clear().unwrap();
+  let ctx = Arc::new(ctx);
+  assert!(matches!(SessionPool::new(ctx.clone(), PoolConfig::new(0).min(3).max(2)), Err(Error::InvalidInput(_))));
+
+  let pool = SessionPool::new(ctx.clone(), PoolConfig::new(0).pin("1234").min(2).max(3).acquire_timeout(Duration::from_millis(200))).unwrap();
+  assert_eq!((pool.open_sessions(), pool.idle_sessions()), (2, 2));
+  assert_eq!(faults.call_count("C_Login").unwrap(), 1);
+  {
+    let sessions: Vec<_> = (0..3).map(|_| pool.acquire().unwrap()).collect();
+    assert_eq!(ctx.get_session_info(sessions[2].handle()).unwrap().state, CKS_RW_USER_FUNCTIONS);
+    assert_eq!((pool.open_sessions(), pool.idle_sessions()), (3, 0));
+    assert_eq!(faults.call_count("C_Login").unwrap(), 1);
+    let start = std::time::Instant::now();
+    assert!(matches!(pool.acquire(), Err(Error::Io(ref err)) if err.kind() == std::io::ErrorKind::TimedOut));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+  }
+  assert_eq!(pool.idle_sessions(), 3);
+
+  // a session closed behind the pool's back and one the module reports closed get replaced
+  let closed = pool.acquire().unwrap().handle();
+  ctx.close_session(closed).unwrap();
+  let session = pool.acquire().unwrap();
+  assert_ne!(session.handle(), closed);
+  ctx.generate_random(session.handle(), 8).unwrap();
+  drop(session);
+  faults.set_rules(&fault::Rules::new().fail_nth("C_GetSessionInfo", 1, CKR_SESSION_CLOSED)).unwrap();
+  let session = pool.acquire().unwrap();
+  ctx.get_session_info(session.handle()).unwrap();
+  session.discard();
+  assert_eq!(pool.open_sessions(), 2);
+  faults.set_rules(&fault::Rules::new().fail_always("C_GetSessionInfo", CKR_DEVICE_ERROR)).unwrap();
+  assert!(matches!(pool.acquire(), Err(Error::Pkcs11(CKR_DEVICE_ERROR))));
+  assert_eq!(pool.open_sessions(), 1);
+  faults.clear().unwrap();
+
+  // threads never share a session, nor open more than max
+  let in_use = Mutex::new(Vec::new());
+  std::thread::scope(|scope| {
+    for _ in 0..8 {
+      scope.spawn(|| {
+        for _ in 0..10 {
+          let session = pool.acquire().unwrap();
+          {
+            let mut in_use = in_use.lock().unwrap();
+            assert!(!in_use.contains(&session.handle()));
+            in_use.push(session.handle());
+          }
+          session.ctx().generate_random(session.handle(), 8).unwrap();
+          in_use.lock().unwrap().retain(|&h| h != session.handle());
+        }
+      });
+    }
+  });
+  let open = pool.open_sessions();
+  assert!(open <= 3);
+  faults.clear().unwrap();
+  drop(pool);
+  assert_eq!(faults.call_count("C_CloseSession").unwrap(), open as u64);
+  drop(faults);
+  let _ = fs::remove_file(&copy);
+}
+
+#[test]
+fn pool_closes_sessions_it_cannot_log_in_and_sends_a_wrong_pin_once() {
+  use pool::*;
+  use std::sync::Arc;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  ctx.close_session(sh).unwrap();
+  let ctx = Arc::new(ctx);
+
+  // an idle session of a token that was logged out is closed when the login fails
+  let pool = SessionPool::new(ctx.clone(), PoolConfig::new(0).pin("1234").min(1)).unwrap();
+  let idle = pool.acquire().unwrap().handle();
+  ctx.logout(idle).unwrap();
+  faults.set_rules(&fault::Rules::new().fail_nth("C_Login", 1, CKR_DEVICE_ERROR)).unwrap();
+  assert!(matches!(pool.acquire(), Err(Error::Pkcs11(CKR_DEVICE_ERROR))));
+  assert_eq!(pool.open_sessions(), 0);
+  assert!(matches!(ctx.get_session_info(idle), Err(Error::Pkcs11(CKR_SESSION_HANDLE_INVALID))));
+  drop(pool);
+
+  // the token gets to reject a wrong PIN only once
+  faults.clear().unwrap();
+  let pool = SessionPool::new(ctx.clone(), PoolConfig::new(0).pin("87654321")).unwrap();
+  for _ in 0..3 {
+    assert!(matches!(pool.acquire(), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  }
+  assert_eq!(faults.call_count("C_Login").unwrap(), 1);
+  assert_eq!(pool.open_sessions(), 0);
+}
+
+#[test]
+fn resilient_session_recovers() {
+  use resilient::*;
+  use select::TokenSelector;
+  use std::sync::Arc;
+  use std::time::Duration;
+  let policy = RetryPolicy::default();
+  assert_eq!((policy.backoff(0), policy.backoff(2)), (Duration::from_millis(100), Duration::from_millis(400)));
+  assert_eq!(policy.backoff(100), policy.max_backoff);
+
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  let mut mechanism = CK_MECHANISM {
+    mechanism: CKM_RSA_PKCS_KEY_PAIR_GEN,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let bits: CK_ULONG = 1024;
+  let private = vec![
+    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&[0x42]),
+    CK_ATTRIBUTE::new(CKA_LABEL).with_string("resilient"),
+  ];
+  ctx.generate_key_pair(sh, &mechanism, &[CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&bits)], &private).unwrap();
+  ctx.close_session(sh).unwrap();
+  mechanism.mechanism = CKM_SHA256_RSA_PKCS;
+  let ctx = Arc::new(ctx);
+  let fast = RetryPolicy {
+    initial_backoff: Duration::from_millis(1),
+    ..RetryPolicy::default()
+  };
+  let key = ObjectRef::id(&[0x42]).with_label("resilient").class(CKO_PRIVATE_KEY);
+
+  // the device goes away during the first signature
+  faults.set_rules(&fault::Rules::new().fail_nth("C_Sign", 1, CKR_DEVICE_REMOVED)).unwrap();
+  let mut session = ResilientSession::new(ctx.clone(), 0).pin("1234").retry(fast);
+  assert!(!session.sign(&key, &mechanism, b"data").unwrap().is_empty());
+  assert_eq!(session.recoveries(), 1);
+  assert_eq!(faults.call_count("C_OpenSession").unwrap(), 2);
+  assert_eq!(faults.call_count("C_Login").unwrap(), 2);
+
+  // handles are found again once the module no longer knows them
+  faults.set_rules(&fault::Rules::new().fail_nth("C_SignInit", 1, CKR_KEY_HANDLE_INVALID)).unwrap();
+  session.sign(&key, &mechanism, b"data").unwrap();
+  assert_eq!(faults.call_count("C_FindObjectsInit").unwrap(), 1);
+  assert_eq!(session.recoveries(), 1);
+
+  // recoverable errors are retried until the policy gives up, the others not at all
+  faults.set_rules(&fault::Rules::new().fail_always("C_Sign", CKR_DEVICE_REMOVED)).unwrap();
+  assert!(matches!(session.sign(&key, &mechanism, b"data"), Err(Error::Pkcs11(CKR_DEVICE_REMOVED))));
+  assert_eq!(faults.call_count("C_Sign").unwrap(), 4);
+  faults.set_rules(&fault::Rules::new().fail_nth("C_Sign", 1, CKR_DATA_LEN_RANGE)).unwrap();
+  assert!(matches!(session.sign(&key, &mechanism, b"data"), Err(Error::Pkcs11(CKR_DATA_LEN_RANGE))));
+  assert_eq!(faults.call_count("C_Sign").unwrap(), 1);
+  faults.clear().unwrap();
+  assert!(matches!(session.find(&ObjectRef::label("missing")), Err(Error::InvalidInput(_))));
+  drop(session);
+
+  let mut session = ResilientSession::with_selector(ctx.clone(), TokenSelector::new().label("rust-unit-test")).pin("1234").read_only();
+  let random = session.run(|ctx, session| ctx.generate_random(session, 8)).unwrap();
+  assert_eq!(random.len(), 8);
+  let mut session = ResilientSession::with_selector(ctx.clone(), TokenSelector::new().label("other")).retry(RetryPolicy::none());
+  assert!(matches!(session.session(), Err(Error::Pkcs11(CKR_TOKEN_NOT_PRESENT))));
+  drop(faults);
+  let _ = fs::remove_file(&copy);
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn async_ctx_runs_sessions_apart() {
+  use async_ctx::AsyncCtx;
+  use provider::{Attribute, Mechanism};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+  let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  ctx.close_session(sh).unwrap();
+  assert!(matches!(AsyncCtx::new(mock_ctx(), 0), Err(Error::InvalidInput(_))));
+  let ctx = AsyncCtx::new(ctx, 2).unwrap();
+  assert_eq!(rt.block_on(ctx.get_slot_list(true)).unwrap(), vec![0]);
+
+  let session = rt.block_on(ctx.open_session(0, CKF_SERIAL_SESSION | CKF_RW_SESSION)).unwrap();
+  rt.block_on(session.login(CKU_USER, "1234")).unwrap();
+  let template = [Attribute::from_bool(CKA_TOKEN, false), Attribute::from_ulong(CKA_MODULUS_BITS, 1024)];
+  let generate = Mechanism {
+    mechanism: CKM_RSA_PKCS_KEY_PAIR_GEN,
+    parameter: Vec::new(),
+  };
+  let (public, private) = rt.block_on(session.generate_key_pair(&generate, &template, &[])).unwrap();
+  let sign = Mechanism {
+    mechanism: CKM_SHA256_RSA_PKCS,
+    parameter: Vec::new(),
+  };
+  let signature = rt.block_on(session.sign(&sign, private, b"data")).unwrap();
+  rt.block_on(session.verify(&sign, public, b"data", &signature)).unwrap();
+  assert!(matches!(rt.block_on(session.verify(&sign, public, b"other", &signature)), Err(Error::Pkcs11(CKR_SIGNATURE_INVALID))));
+  let found = rt.block_on(session.find_objects(&[Attribute::from_ulong(CKA_CLASS, CKO_PRIVATE_KEY)])).unwrap();
+  assert_eq!(found, vec![private]);
+
+  // calls are queued right away, those of one session never overlap
+  let busy = Arc::new(AtomicBool::new(false));
+  let pause = |busy: Arc<AtomicBool>| {
+    move |_: &Ctx, _| {
+      assert!(!busy.swap(true, Ordering::SeqCst));
+      std::thread::sleep(Duration::from_millis(100));
+      busy.store(false, Ordering::SeqCst);
+      Ok(())
+    }
+  };
+  let start = Instant::now();
+  let calls: Vec<_> = (0..2).map(|_| session.call(pause(busy.clone()))).collect();
+  for call in calls {
+    rt.block_on(call).unwrap();
+  }
+  assert!(start.elapsed() >= Duration::from_millis(200));
+  // while two sessions share the two threads
+  let other = rt.block_on(ctx.open_session(0, CKF_SERIAL_SESSION)).unwrap();
+  let start = Instant::now();
+  let calls = vec![session.call(pause(busy.clone())), other.call(pause(Arc::new(AtomicBool::new(false))))];
+  for call in calls {
+    rt.block_on(call).unwrap();
+  }
+  assert!(start.elapsed() < Duration::from_millis(200));
+
+  let panicked = session.call(|_, _| -> Result<(), Error> { panic!("in a pool thread") });
+  assert!(matches!(rt.block_on(panicked), Err(Error::Module(_))));
+  assert_eq!(rt.block_on(session.generate_random(8)).unwrap().len(), 8);
+  let handle = other.handle();
+  rt.block_on(other.close()).unwrap();
+  assert!(matches!(ctx.ctx().get_session_info(handle), Err(Error::Pkcs11(CKR_SESSION_HANDLE_INVALID))));
+}
+
+/// The name and fields of each span.
+#[cfg(feature = "tracing")]
+type Spans = Vec<(String, Vec<(String, String)>)>;
+
+/// Collects the spans of `tracing` with their fields as text.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct SpanRecorder {
+  spans: std::sync::Arc<Mutex<Spans>>,
+}
+
+#[cfg(feature = "tracing")]
+struct FieldText<'a>(&'a mut Vec<(String, String)>);
+
+#[cfg(feature = "tracing")]
+impl<'a> tracing::field::Visit for FieldText<'a> {
+  fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+    self.0.push((field.name().to_string(), value.to_string()));
+  }
+
+  fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+    self.0.push((field.name().to_string(), format!("{:?}", value)));
+  }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+  fn enabled(&self, _: &tracing::Metadata) -> bool {
+    true
+  }
+
+  fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+    let mut spans = self.spans.lock().unwrap();
+    let mut fields = Vec::new();
+    span.record(&mut FieldText(&mut fields));
+    spans.push((span.metadata().name().to_string(), fields));
+    tracing::span::Id::from_u64(spans.len() as u64)
+  }
+
+  fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record) {
+    values.record(&mut FieldText(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1].1));
+  }
+
+  fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+  fn event(&self, _: &tracing::Event) {}
+
+  fn enter(&self, _: &tracing::span::Id) {}
+
+  fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[test]
+#[cfg(feature = "tracing")]
+fn tracing_spans_leave_out_secrets() {
+  let recorder = SpanRecorder::default();
+  let ctx = mock_ctx();
+  tracing::subscriber::with_default(recorder.clone(), || {
+    let (ctx, sh) = fixture_token_in(ctx).unwrap();
+    ctx.logout(sh).unwrap();
+    assert!(matches!(ctx.login(sh, CKU_USER, Some("87654321")), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+    ctx.login(sh, CKU_USER, Some("1234")).unwrap();
+    let template = vec![
+      CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+      CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&CKK_AES),
+      CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(b"0123456789abcdef"),
+      CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&CK_TRUE),
+    ];
+    let key = ctx.create_object(sh, &template).unwrap();
+    let mechanism = CK_MECHANISM {
+      mechanism: CKM_AES_ECB,
+      pParameter: ptr::null_mut(),
+      ulParameterLen: 0,
+    };
+    ctx.encrypt_init(sh, &mechanism, key).unwrap();
+    ctx.encrypt(sh, &[0x5a; 32]).unwrap();
+  });
+
+  let spans = recorder.spans.lock().unwrap().clone();
+  let span = |name: &str| spans.iter().find(|s| s.0 == name).unwrap_or_else(|| panic!("no {} span", name)).1.clone();
+  let field = |fields: &[(String, String)], name: &str| fields.iter().find(|f| f.0 == name).map(|f| f.1.clone());
+  let init_token = span("C_InitToken");
+  assert_eq!(field(&init_token, "slot_id").as_deref(), Some("0"));
+  assert_eq!(field(&init_token, "rv").as_deref(), Some("CKR_OK"));
+  assert!(field(&init_token, "duration_us").is_some());
+  let failed = spans.iter().filter(|s| s.0 == "C_Login").map(|s| &s.1).find(|f| field(f, "rv").as_deref() != Some("CKR_OK")).unwrap();
+  assert_eq!(field(failed, "user_type").as_deref(), Some("CKU_USER"));
+  assert_eq!(field(failed, "rv").as_deref(), Some("CKR_PIN_INCORRECT"));
+  assert!(field(failed, "error").unwrap().contains("CKR_PIN_INCORRECT"));
+  assert_eq!(field(&span("C_CreateObject"), "template_len").as_deref(), Some("4"));
+  assert_eq!(field(&span("C_EncryptInit"), "mechanism").as_deref(), Some("CKM_AES_ECB"));
+  assert_eq!(field(&span("C_Encrypt"), "data_len").as_deref(), Some("32"));
+  // neither PINs nor the label nor the key get recorded
+  let values: Vec<&String> = spans.iter().flat_map(|s| &s.1).filter(|f| f.0 != "duration_us").map(|f| &f.1).collect();
+  let text = format!("{:?}", values);
+  for secret in &["1234", "87654321", "rust-unit-test", "0123456789abcdef"] {
+    assert!(!text.contains(secret), "{} is in the spans", secret);
+  }
+}
+
+#[test]
+fn metrics_count_calls_per_function_and_slot() {
+  use metrics::*;
+  use std::sync::Arc;
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let collector = Arc::new(MetricsCollector::with_buckets(&[0.5, 0.001]));
+  // the session is opened before the observer is set and still counts for its slot
+  let (mut ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  ctx.set_observer(collector.clone());
+  let slot = ctx.get_session_info(sh).unwrap().slotID;
+  collector.reset();
+
+  ctx.generate_random(sh, 8).unwrap();
+  faults.set_rules(&fault::Rules::new().fail_nth("C_GenerateRandom", 1, CKR_DEVICE_ERROR)).unwrap();
+  assert!(ctx.generate_random(sh, 8).is_err());
+  faults.clear().unwrap();
+  ctx.get_slot_list(false).unwrap();
+  assert!(ctx.get_token_info(slot + 1000).is_err());
+
+  let snapshot = collector.snapshot();
+  let random = snapshot.get("C_GenerateRandom", Some(slot)).unwrap();
+  assert_eq!(random.calls, 2);
+  assert_eq!(random.errors.get(&CKR_DEVICE_ERROR), Some(&1));
+  assert_eq!(random.buckets.iter().map(|b| b.0).collect::<Vec<_>>(), vec![0.001, 0.5]);
+  assert!(random.buckets[0].1 <= random.buckets[1].1 && random.buckets[1].1 <= 2);
+  // the length, then the list
+  assert_eq!(snapshot.get("C_GetSlotList", None).unwrap().calls, 2);
+  assert_eq!(snapshot.get("C_GetTokenInfo", Some(slot + 1000)).unwrap().errors.get(&CKR_SLOT_ID_INVALID), Some(&1));
+  assert_eq!(snapshot.calls.len(), 3);
+
+  let text = snapshot.to_prometheus();
+  let labels = format!("function=\"C_GenerateRandom\",slot=\"{}\"", slot);
+  assert!(text.contains("# TYPE pkcs11_calls_total counter\n"));
+  assert!(text.contains(&format!("pkcs11_calls_total{{{}}} 2\n", labels)));
+  assert!(text.contains(&format!("pkcs11_errors_total{{{},rv=\"CKR_DEVICE_ERROR\"}} 1\n", labels)));
+  assert!(text.contains(&format!("pkcs11_call_duration_seconds_bucket{{{},le=\"+Inf\"}} 2\n", labels)));
+  assert!(text.contains(&format!("pkcs11_call_duration_seconds_count{{{}}} 2\n", labels)));
+  assert!(text.contains("pkcs11_calls_total{function=\"C_GetSlotList\",slot=\"\"} 2\n"));
+
+  // a failed close forgets the session all the same, and so does finalize
+  let other = ctx.open_session(slot, CKF_SERIAL_SESSION, None, None).unwrap();
+  faults.set_rules(&fault::Rules::new().fail_nth("C_CloseSession", 1, CKR_SESSION_HANDLE_INVALID)).unwrap();
+  assert!(ctx.close_session(other).is_err());
+  faults.clear().unwrap();
+  assert_eq!(ctx.lock_sessions().len(), 1);
+  ctx.finalize().unwrap();
+  assert!(ctx.lock_sessions().is_empty());
+  // calls that do not reach the module are not counted
+  collector.reset();
+  assert!(ctx.get_slot_list(false).is_err());
+  assert!(collector.snapshot().calls.is_empty());
+}
+
+#[test]
+fn secret_pins_and_plaintext() {
+  use secret::*;
+  let pin = SecretPin::from("1234");
+  assert_eq!(format!("{:?}", pin), "SecretPin([REDACTED])");
+  let plaintext = SecretBytes::from(b"attack at dawn".to_vec());
+  assert_eq!(format!("{:?}", plaintext), "SecretBytes([REDACTED; 14])");
+  assert_eq!(&*plaintext, b"attack at dawn");
+
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  ctx.logout(sh).unwrap();
+  assert!(matches!(ctx.login(sh, CKU_USER, Some("12\u{0}34")), Err(Error::InvalidInput(_))));
+  assert!(matches!(ctx.login_secret(sh, CKU_USER, Some(&SecretPin::from("4321"))), Err(Error::Pkcs11(CKR_PIN_INCORRECT))));
+  ctx.login_secret(sh, CKU_USER, Some(&pin)).unwrap();
+  assert!(matches!(ctx.set_pin_secret(sh, Some(&pin), None), Err(Error::InvalidInput(_))));
+  ctx.set_pin_secret(sh, Some(&pin), Some(&SecretPin::from_bytes(b"5678".to_vec()))).unwrap();
+  ctx.set_pin(sh, Some("5678"), Some("1234")).unwrap();
+
+  let key = ctx
+    .create_object(
+      sh,
+      &[
+        CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+        CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&CKK_AES),
+        CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(b"0123456789abcdef"),
+        CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&CK_TRUE),
+        CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&CK_TRUE),
+        CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_FALSE),
+        CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_TRUE),
+      ],
+    )
+    .unwrap();
+  assert_eq!(ctx.get_attribute_secret(sh, key, CKA_VALUE).unwrap().as_slice(), b"0123456789abcdef");
+  let mechanism = CK_MECHANISM {
+    mechanism: CKM_AES_ECB,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  ctx.encrypt_init(sh, &mechanism, key).unwrap();
+  let ciphertext = ctx.encrypt(sh, &[0x5a; 32]).unwrap();
+  ctx.decrypt_init(sh, &mechanism, key).unwrap();
+  assert_eq!(ctx.decrypt_secret(sh, &ciphertext).unwrap().as_slice(), &[0x5a; 32][..]);
+  ctx.decrypt_init(sh, &mechanism, key).unwrap();
+  assert_eq!(ctx.decrypt(sh, &ciphertext).unwrap(), vec![0x5a; 32]);
+}
+
+#[test]
+fn info_structs_and_inventory() {
+  let ctx = mock_ctx();
+  let slot = ctx.get_slot_list(true).unwrap()[0];
+  let token = info::TokenInfo::from(ctx.get_token_info(slot).unwrap());
+  assert_eq!((token.label.as_str(), token.model.as_str(), token.serial_number.as_str()), ("", "Mock", "0000000000000001"));
+  assert_eq!(token.flags, vec!["CKF_RNG", "CKF_LOGIN_REQUIRED"]);
+  assert_eq!(token.hardware_version.to_string(), "0.5");
+  assert_eq!(info::flag_names(info::MECHANISM_FLAGS, CKF_SIGN | CKF_VERIFY | 0x4000_0000), vec!["CKF_SIGN", "CKF_VERIFY", "0x40000000"]);
+  assert_eq!(info::trimmed(b"label\0\0  "), "label");
+  // an uninitialized token has no objects to walk
+  assert!(info::inventory(&ctx).unwrap().slots[0].token.as_ref().unwrap().objects.is_empty());
+
+  let (ctx, sh) = fixture_token_in(ctx).unwrap();
+  let session = info::SessionInfo::from(ctx.get_session_info(sh).unwrap());
+  assert_eq!(session.state, "CKS_RW_USER_FUNCTIONS");
+  assert_eq!(session.flags, vec!["CKF_RW_SESSION", "CKF_SERIAL_SESSION"]);
+  for &(label, private) in &[("public-data", CK_FALSE), ("private-data", CK_TRUE)] {
+    let template = vec![
+      CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_DATA),
+      CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+      CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&private),
+      CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+      CK_ATTRIBUTE::new(CKA_ID).with_bytes(&[1, 2]),
+    ];
+    ctx.create_object(sh, &template).unwrap();
+  }
+  ctx.logout(sh).unwrap();
+
+  let inventory = info::inventory(&ctx).unwrap();
+  assert_eq!(inventory.library.cryptoki_version, info::Version { major: 2, minor: 40 });
+  assert_eq!(inventory.slots.len(), 1);
+  assert_eq!(inventory.slots[0].info.as_ref().unwrap().description, "Mock slot");
+  assert_eq!(inventory.slots[0].error, None);
+  let token = inventory.slots[0].token.as_ref().unwrap();
+  assert_eq!(token.info.label, "rust-unit-test");
+  assert!(token.info.flags.contains(&"CKF_USER_PIN_INITIALIZED".to_string()));
+  let ecdsa = token.mechanisms.iter().find(|m| m.mechanism_type == CKM_ECDSA).unwrap();
+  assert!(ecdsa.info.flags.contains(&"CKF_SIGN".to_string()));
+  assert_eq!(token.objects.len(), 1);
+  assert_eq!(token.objects[0].class, CKO_DATA);
+  assert_eq!(token.objects[0].key_type, None);
+  assert_eq!(token.objects[0].label.as_deref(), Some("public-data"));
+  assert_eq!(token.objects[0].id.as_deref(), Some(&[1u8, 2][..]));
+  // the session of the inventory is gone again
+  assert_eq!(ctx.get_token_info(slot).unwrap().ulSessionCount, 1);
+}
+
+#[test]
+fn inventory_keeps_going_after_a_failing_slot() {
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let ctx = mock_ctx_at(&copy);
+  faults.set_rules(&fault::Rules::new().fail_nth("C_GetTokenInfo", 1, CKR_DEVICE_ERROR)).unwrap();
+  let inventory = info::inventory(&ctx).unwrap();
+  assert_eq!(inventory.slots[0].info.as_ref().unwrap().description, "Mock slot");
+  assert_eq!(inventory.slots[0].token, None);
+  assert!(inventory.slots[0].error.as_ref().unwrap().contains("CKR_DEVICE_ERROR"));
+
+  faults.set_rules(&fault::Rules::new().fail_nth("C_GetSlotInfo", 1, CKR_DEVICE_ERROR)).unwrap();
+  let inventory = info::inventory(&ctx).unwrap();
+  assert_eq!((&inventory.slots[0].info, &inventory.slots[0].token), (&None, &None));
+  assert!(inventory.slots[0].error.is_some());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn info_serde() {
+  let ctx = mock_ctx();
+  let inventory = info::inventory(&ctx).unwrap();
+  let json = serde_json::to_string(&inventory).unwrap();
+  assert!(json.starts_with(r#"{"library":{"cryptoki_version":{"major":2,"minor":40},"manufacturer":"rust-pkcs11""#), "{}", json);
+  assert!(json.contains(r#""flags":["CKF_RNG","CKF_LOGIN_REQUIRED"]"#), "{}", json);
+  assert_eq!(serde_json::from_str::<info::Inventory>(&json).unwrap(), inventory);
+}
+
+#[test]
+fn mock_objects_and_attributes() {
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  let slot = ctx.get_slot_list(true).unwrap()[0];
+
+  let class = CKO_DATA;
+  let label = String::from("rust-unit-test");
+  let template = vec![
+    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label),
+    CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(b"Hello World!"),
+  ];
+  let oh = ctx.create_object(sh, &template).unwrap();
+  ctx.find_objects_init(sh, &[CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label)]).unwrap();
+  assert_eq!(ctx.find_objects(sh, 10).unwrap(), vec![oh]);
+  ctx.find_objects_final(sh).unwrap();
+  assert_eq!(ctx.get_attribute_bytes(sh, oh, CKA_VALUE).unwrap(), b"Hello World!".to_vec());
+  assert!(matches!(ctx.get_attribute_bytes(sh, oh, CKA_MODULUS), Err(Error::Pkcs11(CKR_ATTRIBUTE_TYPE_INVALID))));
+
+  let label2 = String::from("rust-unit-test2");
+  let copy = ctx.copy_object(sh, oh, &[CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label2)]).unwrap();
+  ctx.set_attribute_value(sh, oh, &[CK_ATTRIBUTE::new(CKA_MODIFIABLE).with_bool(&CK_FALSE)]).unwrap();
+  assert!(matches!(ctx.set_attribute_value(sh, oh, &[CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label2)]), Err(Error::Pkcs11(CKR_ACTION_PROHIBITED))));
+  ctx.destroy_object(sh, copy).unwrap();
+  assert!(ctx.get_object_size(sh, copy).is_err());
+
+  // private objects are hidden without login, session objects go with their session
+  let sh2 = ctx.open_session(slot, CKF_SERIAL_SESSION, None, None).unwrap();
+  let session_object = ctx.create_object(sh, &template[..1]).unwrap();
+  ctx.logout(sh).unwrap();
+  assert!(matches!(ctx.get_attribute_bytes(sh2, oh, CKA_VALUE), Err(Error::Pkcs11(CKR_OBJECT_HANDLE_INVALID))));
+  ctx.close_session(sh).unwrap();
+  assert!(ctx.get_object_size(sh2, session_object).is_err());
+
+  // secret key values stay on the token
+  ctx.login(sh2, CKU_USER, Some("1234")).unwrap();
+  let mechanism = CK_MECHANISM {
+    mechanism: CKM_AES_KEY_GEN,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let key = ctx.generate_key(sh2, &mechanism, &[CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&32)]).unwrap();
+  assert_eq!(ctx.get_attribute_ulong(sh2, key, CKA_VALUE_LEN).unwrap(), 32);
+  assert!(matches!(ctx.get_attribute_bytes(sh2, key, CKA_VALUE), Err(Error::Pkcs11(CKR_ATTRIBUTE_SENSITIVE))));
+}
+
+#[test]
+fn mock_crypto_operations() {
+  let (ctx, sh) = fixture_token_in(mock_ctx()).unwrap();
+  let data: Vec<u8> = (0..40).collect();
+
+  let mut mechanism = CK_MECHANISM {
+    mechanism: CKM_SHA256,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  ctx.digest_init(sh, &mechanism).unwrap();
+  assert_eq!(ctx.digest(sh, &data).unwrap(), cms::DigestAlgorithm::Sha256.hash(&data));
+
+  mechanism.mechanism = CKM_AES_KEY_GEN;
+  let key = ctx.generate_key(sh, &mechanism, &[CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&16)]).unwrap();
+  let mut iv = [7u8; 16];
+  let aes = CK_MECHANISM {
+    mechanism: CKM_AES_CBC_PAD,
+    pParameter: iv.as_mut_ptr() as CK_VOID_PTR,
+    ulParameterLen: iv.len() as CK_ULONG,
+  };
+  ctx.encrypt_init(sh, &aes, key).unwrap();
+  let mut encrypted = ctx.encrypt_update(sh, &data[..20]).unwrap();
+  encrypted.extend(ctx.encrypt_update(sh, &data[20..]).unwrap());
+  encrypted.extend(ctx.encrypt_final(sh).unwrap().unwrap());
+  assert_eq!(encrypted.len(), 48);
+  ctx.decrypt_init(sh, &aes, key).unwrap();
+  assert_eq!(ctx.decrypt(sh, &encrypted).unwrap(), data);
+
+  mechanism.mechanism = CKM_RSA_PKCS_KEY_PAIR_GEN;
+  let bits: CK_ULONG = 1024;
+  let (pubOh, privOh) = ctx.generate_key_pair(sh, &mechanism, &[CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&bits)], &[]).unwrap();
+  mechanism.mechanism = CKM_SHA256_RSA_PKCS;
+  ctx.sign_init(sh, &mechanism, privOh).unwrap();
+  let mut signature = ctx.sign(sh, &data).unwrap();
+  assert_eq!(signature.len(), 128);
+  ctx.verify_init(sh, &mechanism, pubOh).unwrap();
+  ctx.verify(sh, &data, &signature).unwrap();
+  signature[0] ^= 1;
+  ctx.verify_init(sh, &mechanism, pubOh).unwrap();
+  assert!(matches!(ctx.verify(sh, &data, &signature), Err(Error::Pkcs11(CKR_SIGNATURE_INVALID))));
+
+  mechanism.mechanism = CKM_EC_KEY_PAIR_GEN;
+  let params = der::oid(x509::OID_SECP256R1);
+  let (pubOh, privOh) = ctx.generate_key_pair(sh, &mechanism, &[CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(&params)], &[]).unwrap();
+  let cert = x509::CertificateBuilder::new()
+    .subject(x509::Name::new().common_name("rust-unit-test EC"))
+    .public_key(x509::SubjectPublicKeyInfo::from_token(&ctx, sh, pubOh).unwrap())
+    .sign(&ctx, sh, privOh)
+    .unwrap();
+  let signed = cms::SignedDataBuilder::new(cert).sign(&ctx, sh, privOh, &data).unwrap();
+  cms::SignedData::from_der(&signed).unwrap().verify_with_embedded_certificate(&ctx, sh, None).unwrap();
+}
+
+#[test]
+fn fault_rules() {
+  use fault::*;
+  use std::time::Duration;
+
+  let rules: Rules = "C_Sign#3 fail 0x32; C_Sign#5.. fail 0xe0\nC_Login delay 2s;C_Login#2 delay 500ms".parse().unwrap();
+  assert_eq!(rules, Rules::new()
+    .fail_nth("C_Sign", 3, CKR_DEVICE_REMOVED)
+    .fail_from("C_Sign", 5, CKR_TOKEN_NOT_PRESENT)
+    .delay("C_Login", Duration::from_secs(2))
+    .delay_nth("C_Login", 2, Duration::from_millis(500)));
+  assert_eq!(rules.to_string().parse::<Rules>().unwrap(), rules);
+
+  assert_eq!(rules.check("C_Sign", 2), (Duration::from_secs(0), None));
+  assert_eq!(rules.check("C_Sign", 3), (Duration::from_secs(0), Some(CKR_DEVICE_REMOVED)));
+  assert_eq!(rules.check("C_Sign", 4).1, None);
+  assert_eq!(rules.check("C_Sign", 9).1, Some(CKR_TOKEN_NOT_PRESENT));
+  assert_eq!(rules.check("C_Login", 1), (Duration::from_secs(2), None));
+  assert_eq!(rules.check("C_Login", 2), (Duration::from_millis(2500), None));
+
+  for bad in &["C_Sgn fail 0x32", "C_Sign#0 fail 0x32", "C_Sign fail", "C_Sign delay 5", "C_Sign explode 1"] {
+    assert!(bad.parse::<Rules>().is_err(), "{} should not parse", bad);
+  }
+}
+
+#[test]
+fn fault_injection_on_mock() {
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  assert_eq!(faults.call_count("C_Login").unwrap(), 2);
+
+  let mut mechanism = CK_MECHANISM {
+    mechanism: CKM_RSA_PKCS_KEY_PAIR_GEN,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let bits: CK_ULONG = 1024;
+  let (_, privOh) = ctx.generate_key_pair(sh, &mechanism, &[CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&bits)], &[]).unwrap();
+  mechanism.mechanism = CKM_SHA256_RSA_PKCS;
+
+  // each Ctx::sign queries the length first, so the 3rd C_Sign is the first call of the second signature
+  faults.set_rules(&fault::Rules::new().fail_nth("C_Sign", 3, CKR_DEVICE_REMOVED)).unwrap();
+  ctx.sign_init(sh, &mechanism, privOh).unwrap();
+  ctx.sign(sh, b"first").unwrap();
+  assert_eq!(faults.call_count("C_Sign").unwrap(), 2);
+  ctx.sign_init(sh, &mechanism, privOh).unwrap();
+  assert!(matches!(ctx.sign(sh, b"second"), Err(Error::Pkcs11(CKR_DEVICE_REMOVED))));
+
+  faults
+    .set_rules(&fault::Rules::new()
+      .fail_from("C_GetSessionInfo", 2, CKR_SESSION_HANDLE_INVALID)
+      .delay("C_GenerateRandom", std::time::Duration::from_millis(100)))
+    .unwrap();
+  ctx.get_session_info(sh).unwrap();
+  for _ in 0..3 {
+    assert!(matches!(ctx.get_session_info(sh), Err(Error::Pkcs11(CKR_SESSION_HANDLE_INVALID))));
+  }
+  let start = std::time::Instant::now();
+  ctx.generate_random(sh, 8).unwrap();
+  assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+
+  faults.clear().unwrap();
+  ctx.get_session_info(sh).unwrap();
+  assert!(faults.set_rules(&fault::Rules::new().fail_always("C_Nope", CKR_GENERAL_ERROR)).is_err());
+}
+
+#[test]
+fn fault_injection_wrapping_a_module() {
+  // any module can be wrapped, here a second copy of the mock with a token of its own
+  let wrapped = mock_module_copy();
+  let copy = mock_module_copy();
+  let faults = fault::FaultInjector::new(&copy).unwrap();
+  faults.wrap(&wrapped).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&copy)).unwrap();
+  let _ = fs::remove_file(&wrapped);
+  assert_eq!(ctx.get_token_info(0).unwrap().label[..14], b"rust-unit-test"[..]);
+
+  faults.set_rules(&fault::Rules::new().fail_always("C_Login", CKR_PIN_LOCKED)).unwrap();
+  ctx.logout(sh).unwrap();
+  assert!(matches!(ctx.login(sh, CKU_USER, Some("1234")), Err(Error::Pkcs11(CKR_PIN_LOCKED))));
+  faults.clear().unwrap();
+  ctx.login(sh, CKU_USER, Some("1234")).unwrap();
+}
+
+#[test]
+fn spy_logs_calls() {
+  let spy = module_copy(&workspace_module("pkcs11-spy", "pkcs11_spy"), "pkcs11_spy");
+  let mock = mock_module_copy();
+  let log = env::temp_dir().join(format!("pkcs11_spy-{}.log", process::id()));
+  let _ = fs::remove_file(&log);
+  let ctx = {
+    // configured through the same library instance that Ctx loads afterwards
+    let lib = libloading::Library::new(&spy).unwrap();
+    let module = std::ffi::CString::new(mock.to_str().unwrap()).unwrap();
+    let output = std::ffi::CString::new(log.to_str().unwrap()).unwrap();
+    unsafe {
+      let configure: libloading::Symbol<unsafe extern "C" fn(*const std::os::raw::c_char, *const std::os::raw::c_char) -> CK_RV> = lib.get(b"pkcs11_spy_configure").unwrap();
+      assert_eq!(configure(module.as_ptr(), output.as_ptr()), CKR_OK);
+      assert_eq!(configure(module.as_ptr(), output.as_ptr()), CKR_CRYPTOKI_ALREADY_INITIALIZED);
+    }
+    mock_ctx_at(&spy)
+  };
+  let _ = fs::remove_file(&mock);
+  let (ctx, sh) = fixture_token_in(ctx).unwrap();
+  assert!(matches!(ctx.login(sh, CKU_USER, Some("1234")), Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN))));
+
+  let mechanism = CK_MECHANISM {
+    mechanism: CKM_AES_KEY_GEN,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let len: CK_ULONG = 16;
+  let template = vec![
+    CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&len),
+    CK_ATTRIBUTE::new(CKA_LABEL).with_string("spied-key"),
+    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_FALSE),
+    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_TRUE),
+  ];
+  let key = ctx.generate_key(sh, &mechanism, &template).unwrap();
+  let mut value = vec![0; 16];
+  let mut query = vec![CK_ATTRIBUTE::new(CKA_CLASS), CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(&value)];
+  let class: CK_OBJECT_CLASS = 0;
+  query[0] = query[0].with_ck_ulong(&class);
+  ctx.get_attribute_value(sh, key, &mut query).unwrap();
+  value = query[1].get_bytes();
+  assert_eq!(ctx.generate_random(sh, 8).unwrap().len(), 8);
+  drop(ctx);
+
+  let text = fs::read_to_string(&log).unwrap();
+  let _ = fs::remove_file(&log);
+  assert!(text.contains("C_Initialize"));
+  assert!(text.contains("  [in] userType = CKU_USER\n  [in] pPin = <redacted>\n"));
+  assert!(text.contains("returned CKR_USER_ALREADY_LOGGED_IN (0x100)"));
+  assert!(text.contains("  [in] pMechanism = CKM_AES_KEY_GEN\n"));
+  assert!(text.contains("      CKA_LABEL = \"spied-key\"\n"));
+  assert!(text.contains("      CKA_VALUE_LEN = 16\n"));
+  assert!(text.contains("      CKA_CLASS = CKO_SECRET_KEY\n"));
+  assert!(text.contains("      CKA_VALUE = <redacted, 16 bytes>\n"));
+  assert!(text.contains("  [out] RandomData = [8] <redacted>\n"));
+  assert!(text.contains("C_Finalize"));
+  // neither the PIN nor the key shows up
+  assert!(!text.contains("31323334"));
+  assert!(!text.contains(&hex::encode(&value)));
+}
+
+#[test]
+fn replay_trace_format() {
+  let mut call = replay::Call::new("C_GetAttributeValue");
+  call.inputs.push(("hSession".to_string(), replay::Value::Ulong(1)));
+  call.inputs.push(("pPin".to_string(), replay::Value::Redacted));
+  call.inputs.push(("pMechanism".to_string(), replay::Value::Mechanism(CKM_AES_CBC, vec![0; 16])));
+  call.outputs.push((
+    "pTemplate".to_string(),
+    replay::Value::Template(vec![
+      replay::Attribute {
+        attr_type: CKA_LABEL,
+        len: 3,
+        value: Some(b"abc".to_vec()),
+        secret: false,
+      },
+      replay::Attribute {
+        attr_type: CKA_VALUE,
+        len: CK_UNAVAILABLE_INFORMATION,
+        value: None,
+        secret: false,
+      },
+      replay::Attribute {
+        attr_type: CKA_PRIVATE_EXPONENT,
+        len: 256,
+        value: Some(Vec::new()),
+        secret: true,
+      },
+    ]),
+  ));
+  call.outputs.push(("phObject".to_string(), replay::Value::List(vec![2, 3])));
+  call.outputs.push(("pData".to_string(), replay::Value::Bytes(Vec::new())));
+  call.outputs.push(("pPart".to_string(), replay::Value::Secret(32)));
+  call.rv = CKR_ATTRIBUTE_SENSITIVE;
+  let trace = replay::Trace { calls: vec![call.clone(), replay::Call::new("C_Finalize")] };
+  let text = trace.to_string();
+  assert!(text.starts_with("pkcs11-trace 1\nC_GetAttributeValue 0x11\n  > hSession ulong 0x1\n  > pPin redacted\n"));
+  assert!(text.contains(&format!("  < pTemplate template 0x3:0x3:616263,0x11:{:#x}:null,0x123:0x100:secret\n", CK_UNAVAILABLE_INFORMATION)));
+  assert!(text.contains("  < pPart secret 0x20\n"));
+  assert_eq!(text.parse::<replay::Trace>().unwrap(), trace);
+  assert!("C_Login 0x0\n  > pPin secret".parse::<replay::Trace>().is_err());
+  assert!("  > hSession ulong 0x1".parse::<replay::Trace>().is_err());
+
+  // mechanism parameters only need the same length
+  let mut actual = call.clone();
+  actual.inputs[2].1 = replay::Value::Mechanism(CKM_AES_CBC, vec![1; 16]);
+  assert!(replay::Divergence::check(1, Some(&call), &actual).is_none());
+  actual.inputs[0].1 = replay::Value::Ulong(2);
+  let divergence = replay::Divergence::check(7, Some(&call), &actual).unwrap();
+  assert_eq!(divergence.argument.as_deref(), Some("hSession"));
+  assert_eq!(divergence.to_string(), "call 7 (C_GetAttributeValue): the application passed hSession = ulong 0x2 instead of ulong 0x1");
+  assert_eq!(replay::Divergence::decode(&divergence.encode()).unwrap(), divergence);
+  let other = replay::Divergence::check(2, Some(&call), &replay::Call::new("C_Logout")).unwrap();
+  assert_eq!(other.to_string(), "call 2: the application called C_Logout instead of C_GetAttributeValue");
+  let end = replay::Divergence::check(3, None, &call).unwrap();
+  assert_eq!(end.to_string(), "call 3: the application called C_GetAttributeValue after the end of the recording");
+  assert_eq!(replay::Divergence::decode(&end.encode()).unwrap(), end);
+}
+
+/// What the session of the replay test got from the module.
+#[derive(Debug, PartialEq)]
+struct ReplayedSession {
+  key: CK_OBJECT_HANDLE,
+  encrypted: Vec<u8>,
+  decrypted: Vec<u8>,
+  key_value: Vec<u8>,
+  random: Vec<u8>,
+  label: String,
+}
+
+/// The session of the replay test, which encrypts and decrypts `data`.
+fn replay_session(ctx: &Ctx, sh: CK_SESSION_HANDLE, data: &[u8]) -> Result<ReplayedSession, Error> {
+  let len: CK_ULONG = 16;
+  let template = vec![
+    CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&len),
+    CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&CK_TRUE),
+    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_FALSE),
+    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_TRUE),
+  ];
+  let mechanism = |mechanism| CK_MECHANISM {
+    mechanism,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  };
+  let key = ctx.generate_key(sh, &mechanism(CKM_AES_KEY_GEN), &template)?;
+  ctx.encrypt_init(sh, &mechanism(CKM_AES_ECB), key)?;
+  let encrypted = ctx.encrypt(sh, data)?;
+  ctx.decrypt_init(sh, &mechanism(CKM_AES_ECB), key)?;
+  let decrypted = ctx.decrypt(sh, &encrypted)?;
+  let key_value = ctx.get_attribute_bytes(sh, key, CKA_VALUE)?;
+  let random = ctx.generate_random(sh, 16)?;
+  let slot = ctx.get_session_info(sh)?.slotID;
+  let label = String::from_utf8_lossy(&ctx.get_token_info(slot)?.label).trim_end().to_string();
+  Ok(ReplayedSession { key, encrypted, decrypted, key_value, random, label })
+}
+
+#[test]
+fn replay_serves_recorded_session() {
+  let spy = workspace_module("pkcs11-spy", "pkcs11_spy");
+  let trace = env::temp_dir().join(format!("pkcs11_replay-{}.trace", process::id()));
+  let data = [7; 32];
+
+  let recorder = module_copy(&spy, "pkcs11_spy");
+  let mock = mock_module_copy();
+  let recorded = {
+    let control = replay::Spy::new(&recorder).unwrap();
+    control.record(&mock, &trace).unwrap();
+    assert!(matches!(control.record(&mock, &trace), Err(Error::Pkcs11(CKR_CRYPTOKI_ALREADY_INITIALIZED))));
+    assert!(matches!(control.divergence(), Err(Error::Pkcs11(CKR_FUNCTION_NOT_SUPPORTED))));
+    let (ctx, sh) = fixture_token_in(mock_ctx_at(&recorder)).unwrap();
+    replay_session(&ctx, sh, &data).unwrap()
+  };
+  let _ = fs::remove_file(&recorder);
+  let _ = fs::remove_file(&mock);
+  let text = fs::read_to_string(&trace).unwrap();
+  assert!(text.contains("C_Login 0x0\n  > hSession ulong 0x1\n  > userType ulong 0x1\n  > pPin redacted\n"));
+  assert!(text.contains(&format!("  > pData bytes {}\n", hex::encode(data))));
+  assert_eq!(recorded.decrypted, data);
+  // secrets are recorded by their length only
+  assert!(text.contains("  < pData secret 0x20\n"));
+  assert!(text.contains("  < pTemplate template 0x11:0x10:secret\n"));
+  assert!(text.contains("  < RandomData secret 0x10\n"));
+  for secret in &[&recorded.key_value, &recorded.random] {
+    assert!(!text.contains(&hex::encode(secret)));
+  }
+
+  // without any module, the same calls get the same answers, zero bytes for the secrets
+  let replayer = module_copy(&spy, "pkcs11_spy");
+  let control = replay::Spy::new(&replayer).unwrap();
+  control.replay(&trace).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&replayer)).unwrap();
+  let replayed = replay_session(&ctx, sh, &data).unwrap();
+  assert_eq!(
+    replayed,
+    ReplayedSession {
+      decrypted: vec![0; 32],
+      key_value: vec![0; 16],
+      random: vec![0; 16],
+      ..recorded
+    }
+  );
+  assert!(control.divergence().unwrap().is_none());
+  drop(ctx);
+  let _ = fs::remove_file(&replayer);
+
+  // the first call with other arguments fails, and every call after it
+  let replayer = module_copy(&spy, "pkcs11_spy");
+  let control = replay::Spy::new(&replayer).unwrap();
+  control.replay(&trace).unwrap();
+  let (ctx, sh) = fixture_token_in(mock_ctx_at(&replayer)).unwrap();
+  assert!(matches!(replay_session(&ctx, sh, &[8; 32]), Err(Error::Pkcs11(CKR_GENERAL_ERROR))));
+  assert!(matches!(ctx.generate_random(sh, 16), Err(Error::Pkcs11(CKR_GENERAL_ERROR))));
+  let divergence = control.divergence().unwrap().unwrap();
+  assert_eq!(divergence.actual.function, "C_Encrypt");
+  assert_eq!(divergence.argument.as_deref(), Some("pData"));
+  assert_eq!(divergence.expected.as_ref().map(|c| c.rv), Some(CKR_OK));
+  assert!(divergence.to_string().contains(&format!("(C_Encrypt): the application passed pData = bytes {} instead of", hex::encode([8; 32]))));
+  drop(ctx);
+  let _ = fs::remove_file(&replayer);
+  let _ = fs::remove_file(&trace);
+}
+
+/// Runs the `pkcs11` tool on a private copy of the mock module and returns
+/// its exit code, standard output and standard error.
+fn pkcs11_tool(args: &[&str]) -> (i32, String, String) {
+  let tool = workspace_build("pkcs11", &["--bin", "pkcs11"]).join(format!("pkcs11{}", env::consts::EXE_SUFFIX));
+  let mock = mock_module_copy();
+  let output = Command::new(tool).env("PKCS11_MODULE", &mock).env_remove("PKCS11_PIN").args(args).output().unwrap();
+  let _ = fs::remove_file(&mock);
+  (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn tool_lists_slots_tokens_and_mechanisms() {
+  let (code, out, _) = pkcs11_tool(&["--json", "slots"]);
+  assert_eq!(code, 0);
+  assert!(out.starts_with(r#"[{"slot":0,"description":"Mock slot","manufacturer":"rust-pkcs11","flags":["CKF_TOKEN_PRESENT"]"#), "{}", out);
+
+  let (code, out, _) = pkcs11_tool(&["tokens"]);
+  assert_eq!(code, 0);
+  assert!(out.starts_with("Slot 0\n  label: \n  manufacturer: rust-pkcs11\n  model: Mock\n"), "{}", out);
+  assert!(out.contains("  flags: CKF_RNG, CKF_LOGIN_REQUIRED\n"), "{}", out);
+
+  let (code, out, _) = pkcs11_tool(&["--slot", "0", "mechanisms"]);
+  assert_eq!(code, 0);
+  assert!(out.contains("Mechanism CKM_EC_KEY_PAIR_GEN\n"), "{}", out);
+  assert!(out.contains("flags: CKF_SIGN, CKF_VERIFY"), "{}", out);
+
+  let (code, out, _) = pkcs11_tool(&["--json", "init-token", "--label", "tool-test", "--so-pin", "1234"]);
+  assert_eq!(code, 0);
+  assert!(out.contains(r#""label":"tool-test""#), "{}", out);
+  assert!(out.contains(r#""CKF_TOKEN_INITIALIZED""#), "{}", out);
+}
+
+#[test]
+fn tool_reports_usage_errors_and_failures() {
+  let (code, _, err) = pkcs11_tool(&["frobnicate"]);
+  assert_eq!(code, 2);
+  assert!(err.starts_with("pkcs11: unknown command frobnicate\n\nUsage: "), "{}", err);
+
+  let (code, _, err) = pkcs11_tool(&["slots", "--label", "x"]);
+  assert_eq!(code, 2);
+  assert!(err.starts_with("pkcs11: --label is not an option of slots\n"), "{}", err);
+
+  let (code, _, err) = pkcs11_tool(&["generate", "dsa:1024", "--label", "x"]);
+  assert_eq!(code, 2);
+  assert!(err.starts_with("pkcs11: unknown key spec dsa:1024"), "{}", err);
+
+  let (code, _, err) = pkcs11_tool(&["--slot", "0", "--token", "x", "mechanisms"]);
+  assert_eq!(code, 2);
+  assert!(err.starts_with("pkcs11: --slot and --token cannot be used together\n"), "{}", err);
+
+  for id in &["0x0x12", "0x+1", "+1"] {
+    let (code, _, err) = pkcs11_tool(&["generate", "aes:128", "--label", "x", "--id", id]);
+    assert_eq!(code, 2);
+    assert!(err.starts_with(&format!("pkcs11: {} is not hex\n", id)), "{}", err);
+  }
+
+  // the mock token starts out uninitialized
+  let (code, _, err) = pkcs11_tool(&["--pin", "1234", "objects"]);
+  assert_eq!(code, 1);
+  assert_eq!(err, "pkcs11: PKCS#11: CKR_TOKEN_NOT_RECOGNIZED (0xe1)\n");
+}
+
+#[test]
+#[serial]
+fn tool_generates_keys_and_signs_on_softhsm() {
+  let module = pkcs11_module_name();
+  let (ctx, sh) = fixture_token().unwrap();
+  ctx.close_session(sh).unwrap();
+  let dir = env::temp_dir().join(format!("pkcs11-tool-{}", process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(dir.join("data"), b"signed with the tool").unwrap();
+  let tool = workspace_build("pkcs11", &["--bin", "pkcs11"]).join(format!("pkcs11{}", env::consts::EXE_SUFFIX));
+  let run = |args: &[&str]| {
+    let output = Command::new(&tool).current_dir(&dir).arg("--module").arg(&module).args(["--pin", "1234", "--json"]).args(args).output().unwrap();
+    assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+  };
+
+  for spec in &["rsa:2048", "ec:p256"] {
+    assert!(run(&["generate", spec, "--label", "tool-key", "--id", "0102"]).contains(r#""id":"0102""#));
+    run(&["sign", "--label", "tool-key", "--in", "data", "--out", "signature"]);
+    assert_eq!(run(&["verify", "--id", "0102", "--in", "data", "--signature", "signature"]), "{\"valid\":true}\n");
+    run(&["export-pubkey", "--label", "tool-key", "--out", "key.pem"]);
+    assert!(run(&["import-pubkey", "--in", "key.pem", "--label", "tool-copy"]).contains(r#""label":"tool-copy""#));
+    assert!(run(&["delete", "--label", "tool-copy"]).starts_with(r#"{"deleted":["#));
+    assert!(run(&["delete", "--id", "0102", "--all"]).starts_with(r#"{"deleted":["#));
+  }
+  assert_eq!(run(&["objects"]), "[]\n");
+  let _ = fs::remove_dir_all(&dir);
+}
+
+static PROVIDER_SIGNATURES: AtomicUsize = AtomicUsize::new(0);
+
+/// A token with one slot whose "signatures" are the SHA-256 of key and data.
+struct TestProvider {
+  objects: provider::HandleTable<Vec<provider::Attribute>>,
+}
+
+struct TestSession {
+  signing_key: Option<Vec<u8>>,
+}
+
+impl provider::Pkcs11Provider for TestProvider {
+  type Session = TestSession;
+
+  fn initialize() -> provider::Result<Self> {
+    Ok(TestProvider { objects: provider::HandleTable::new() })
+  }
+  fn slots(&mut self, _token_present: bool) -> Vec<CK_SLOT_ID> {
+    vec![1]
+  }
+  fn slot_info(&mut self, slot: CK_SLOT_ID) -> provider::Result<CK_SLOT_INFO> {
+    match slot {
+      1 => Ok(CK_SLOT_INFO { flags: CKF_TOKEN_PRESENT, ..Default::default() }),
+      _ => Err(CKR_SLOT_ID_INVALID),
+    }
+  }
+  fn token_info(&mut self, slot: CK_SLOT_ID) -> provider::Result<CK_TOKEN_INFO> {
+    self.slot_info(slot)?;
+    Ok(CK_TOKEN_INFO { label: provider::blank_padded("provider"), ..Default::default() })
+  }
+  fn open_session(&mut self, slot: CK_SLOT_ID, _flags: CK_FLAGS) -> provider::Result<TestSession> {
+    self.slot_info(slot)?;
+    Ok(TestSession { signing_key: None })
+  }
+  fn session_state(&mut self, _session: &TestSession) -> CK_ULONG {
+    CKS_RO_PUBLIC_SESSION
+  }
+  fn create_object(&mut self, _session: &mut TestSession, template: &[provider::Attribute]) -> provider::Result<CK_OBJECT_HANDLE> {
+    Ok(self.objects.insert(template.to_vec()))
+  }
+  fn attribute(&mut self, _session: &mut TestSession, object: CK_OBJECT_HANDLE, attr_type: CK_ATTRIBUTE_TYPE) -> provider::Result<Vec<u8>> {
+    let object = self.objects.get(object).ok_or(CKR_OBJECT_HANDLE_INVALID)?;
+    if attr_type == CKA_VALUE {
+      return Err(CKR_ATTRIBUTE_SENSITIVE);
+    }
+    provider::find_attribute(object, attr_type).map(|a| a.value.clone()).ok_or(CKR_ATTRIBUTE_TYPE_INVALID)
+  }
+  fn find_objects(&mut self, _session: &mut TestSession, template: &[provider::Attribute]) -> provider::Result<Vec<CK_OBJECT_HANDLE>> {
+    Ok(self.objects.iter().filter(|(_, object)| template.iter().all(|a| object.contains(a))).map(|(h, _)| h).collect())
+  }
+  fn sign_init(&mut self, session: &mut TestSession, _mechanism: &provider::Mechanism, key: CK_OBJECT_HANDLE) -> provider::Result<()> {
+    let object = self.objects.get(key).ok_or(CKR_KEY_HANDLE_INVALID)?;
+    session.signing_key = Some(provider::find_attribute(object, CKA_VALUE).ok_or(CKR_KEY_TYPE_INCONSISTENT)?.value.clone());
+    Ok(())
+  }
+  fn sign(&mut self, session: &mut TestSession, data: &[u8]) -> provider::Result<Vec<u8>> {
+    let mut input = session.signing_key.take().ok_or(CKR_OPERATION_NOT_INITIALIZED)?;
+    PROVIDER_SIGNATURES.fetch_add(1, Ordering::SeqCst);
+    input.extend_from_slice(data);
+    Ok(cms::DigestAlgorithm::Sha256.hash(&input))
+  }
+  fn generate_random(&mut self, _session: &mut TestSession, out: &mut [u8]) -> provider::Result<()> {
+    assert!(out.len() != 13, "unlucky number");
+    out.iter_mut().for_each(|b| *b = 0x42);
+    Ok(())
+  }
+}
+
+pkcs11_provider!(TestProvider);
+
+#[test]
+fn provider_handle_table() {
+  let mut table = provider::HandleTable::new();
+  let a = table.insert("a");
+  let b = table.insert("b");
+  assert_eq!((a, b), (1, 2));
+  assert_eq!(table.remove(a), Some("a"));
+  assert_eq!(table.insert("c"), 3);
+  assert_eq!(table.get(b), Some(&"b"));
+  assert!(!table.contains(a));
+  assert_eq!(table.handles(), vec![2, 3]);
+
+  let attr = provider::Attribute::from_ulong(CKA_CLASS, CKO_SECRET_KEY);
+  assert_eq!(attr.ulong(), Ok(CKO_SECRET_KEY));
+  assert_eq!(attr.bool(), Err(CKR_ATTRIBUTE_VALUE_INVALID));
+  assert_eq!(provider::Attribute::from_bool(CKA_TOKEN, true).bool(), Ok(true));
+  assert_eq!(provider::blank_padded::<8>("label"), *b"label   ");
+}
+
+#[test]
+fn provider_entry_points() {
+  // the only test using the module generated for TestProvider, so it has the state to itself
+  assert_eq!(C_GetFunctionList(ptr::null_mut()), CKR_ARGUMENTS_BAD);
+  let mut list: CK_FUNCTION_LIST_PTR = ptr::null_mut();
+  assert_eq!(C_GetFunctionList(&mut list), CKR_OK);
+  let f = unsafe { &*list };
+  assert_eq!(f.version.major, 2);
+
+  let mut count: CK_ULONG = 0;
+  assert_eq!((f.C_GetSlotList.unwrap())(CK_FALSE, ptr::null_mut(), &mut count), CKR_CRYPTOKI_NOT_INITIALIZED);
+  assert_eq!((f.C_Initialize.unwrap())(ptr::null_mut()), CKR_OK);
+  assert_eq!((f.C_Initialize.unwrap())(ptr::null_mut()), CKR_CRYPTOKI_ALREADY_INITIALIZED);
+
+  // lists use the two-call convention
+  assert_eq!((f.C_GetSlotList.unwrap())(CK_FALSE, ptr::null_mut(), &mut count), CKR_OK);
+  assert_eq!(count, 1);
+  let mut slots: Vec<CK_SLOT_ID> = vec![0; 2];
+  count = 0;
+  assert_eq!((f.C_GetSlotList.unwrap())(CK_FALSE, slots.as_mut_ptr(), &mut count), CKR_BUFFER_TOO_SMALL);
+  assert_eq!(count, 1);
+  assert_eq!((f.C_GetSlotList.unwrap())(CK_FALSE, slots.as_mut_ptr(), &mut count), CKR_OK);
+  assert_eq!(slots[0], 1);
+  assert_eq!((f.C_GetSlotList.unwrap())(CK_FALSE, slots.as_mut_ptr(), ptr::null_mut()), CKR_ARGUMENTS_BAD);
+  let mut token = CK_TOKEN_INFO::default();
+  assert_eq!((f.C_GetTokenInfo.unwrap())(1, &mut token), CKR_OK);
+  assert_eq!(&token.label[..9], b"provider ");
+
+  let mut sh: CK_SESSION_HANDLE = 0;
+  assert_eq!((f.C_OpenSession.unwrap())(1, 0, ptr::null_mut(), None, &mut sh), CKR_SESSION_PARALLEL_NOT_SUPPORTED);
+  assert_eq!((f.C_OpenSession.unwrap())(2, CKF_SERIAL_SESSION, ptr::null_mut(), None, &mut sh), CKR_SLOT_ID_INVALID);
+  assert_eq!((f.C_OpenSession.unwrap())(1, CKF_SERIAL_SESSION, ptr::null_mut(), None, &mut sh), CKR_OK);
+  assert_eq!((f.C_Logout.unwrap())(sh + 1), CKR_SESSION_HANDLE_INVALID);
+  assert_eq!((f.C_GetSessionInfo.unwrap())(sh, ptr::null_mut()), CKR_ARGUMENTS_BAD);
+  let mut info = CK_SESSION_INFO { slotID: 0, state: 0, flags: 0, ulDeviceError: 0 };
+  assert_eq!((f.C_GetSessionInfo.unwrap())(sh, &mut info), CKR_OK);
+  assert_eq!((info.slotID, info.state, info.flags), (1, CKS_RO_PUBLIC_SESSION, CKF_SERIAL_SESSION));
+  assert_eq!((f.C_Logout.unwrap())(sh), CKR_FUNCTION_NOT_SUPPORTED);
+
+  let mut key: CK_OBJECT_HANDLE = 0;
+  let mut template = vec![CK_ATTRIBUTE::new(CKA_LABEL).with_string("signing-key"), CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(b"secret")];
+  assert_eq!((f.C_CreateObject.unwrap())(sh, template.as_mut_ptr(), 2, ptr::null_mut()), CKR_ARGUMENTS_BAD);
+  assert_eq!((f.C_CreateObject.unwrap())(sh, template.as_mut_ptr(), 2, &mut key), CKR_OK);
+
+  let mut label = vec![0; 4];
+  let mut query = vec![CK_ATTRIBUTE::new(CKA_LABEL), CK_ATTRIBUTE::new(CKA_VALUE), CK_ATTRIBUTE::new(CKA_ID)];
+  assert_eq!((f.C_GetAttributeValue.unwrap())(sh, key, query.as_mut_ptr(), 3), CKR_ATTRIBUTE_SENSITIVE);
+  assert_eq!(query[0].ulValueLen, 11);
+  assert_eq!((query[1].ulValueLen, query[2].ulValueLen), (CK_UNAVAILABLE_INFORMATION, CK_UNAVAILABLE_INFORMATION));
+  query.truncate(1);
+  query[0] = CK_ATTRIBUTE::new(CKA_LABEL).with_bytes(&label);
+  assert_eq!((f.C_GetAttributeValue.unwrap())(sh, key, query.as_mut_ptr(), 1), CKR_BUFFER_TOO_SMALL);
+  label.resize(11, 0);
+  query[0] = CK_ATTRIBUTE::new(CKA_LABEL).with_bytes(&label);
+  assert_eq!((f.C_GetAttributeValue.unwrap())(sh, key, query.as_mut_ptr(), 1), CKR_OK);
+  assert_eq!(&label[..], b"signing-key");
+  assert_eq!((f.C_GetAttributeValue.unwrap())(sh, key + 1, query.as_mut_ptr(), 1), CKR_OBJECT_HANDLE_INVALID);
+
+  // find results are handed out in pages
+  let mut other: CK_OBJECT_HANDLE = 0;
+  assert_eq!((f.C_CreateObject.unwrap())(sh, template.as_mut_ptr(), 1, &mut other), CKR_OK);
+  let mut found = vec![0; 2];
+  assert_eq!((f.C_FindObjects.unwrap())(sh, found.as_mut_ptr(), 1, &mut count), CKR_OPERATION_NOT_INITIALIZED);
+  assert_eq!((f.C_FindObjectsInit.unwrap())(sh, template.as_mut_ptr(), 1), CKR_OK);
+  assert_eq!((f.C_FindObjectsInit.unwrap())(sh, template.as_mut_ptr(), 1), CKR_OPERATION_ACTIVE);
+  assert_eq!((f.C_FindObjects.unwrap())(sh, found.as_mut_ptr(), 1, &mut count), CKR_OK);
+  assert_eq!((count, found[0]), (1, key));
+  assert_eq!((f.C_FindObjects.unwrap())(sh, found.as_mut_ptr(), 2, &mut count), CKR_OK);
+  assert_eq!((count, found[0]), (1, other));
+  assert_eq!((f.C_FindObjects.unwrap())(sh, found.as_mut_ptr(), 2, &mut count), CKR_OK);
+  assert_eq!(count, 0);
+  assert_eq!((f.C_FindObjectsFinal.unwrap())(sh), CKR_OK);
+
+  // the size query and the short buffer get the signature computed by the first call
+  let mut mechanism = CK_MECHANISM { mechanism: CKM_SHA256_HMAC, pParameter: ptr::null_mut(), ulParameterLen: 0 };
+  let mut data = b"data".to_vec();
+  assert_eq!((f.C_SignInit.unwrap())(sh, ptr::null_mut(), key), CKR_ARGUMENTS_BAD);
+  assert_eq!((f.C_SignInit.unwrap())(sh, &mut mechanism, key), CKR_OK);
+  let mut len: CK_ULONG = 0;
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, ptr::null_mut(), &mut len), CKR_OK);
+  assert_eq!(len, 32);
+  let mut signature = vec![0; 32];
+  len = 8;
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, signature.as_mut_ptr(), &mut len), CKR_BUFFER_TOO_SMALL);
+  assert_eq!(len, 32);
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, signature.as_mut_ptr(), &mut len), CKR_OK);
+  assert_eq!(signature, cms::DigestAlgorithm::Sha256.hash(b"secretdata"));
+  assert_eq!(PROVIDER_SIGNATURES.load(Ordering::SeqCst), 1);
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, signature.as_mut_ptr(), &mut len), CKR_OPERATION_NOT_INITIALIZED);
+  // an output the caller did not pick up is dropped by the next other call
+  assert_eq!((f.C_SignInit.unwrap())(sh, &mut mechanism, key), CKR_OK);
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, ptr::null_mut(), &mut len), CKR_OK);
+  assert_eq!((f.C_SignInit.unwrap())(sh, &mut mechanism, other), CKR_KEY_TYPE_INCONSISTENT);
+  assert_eq!((f.C_Sign.unwrap())(sh, data.as_mut_ptr(), 4, signature.as_mut_ptr(), &mut len), CKR_OPERATION_NOT_INITIALIZED);
+  assert_eq!((f.C_EncryptInit.unwrap())(sh, &mut mechanism, key), CKR_FUNCTION_NOT_SUPPORTED);
+
+  // a panic in the provider is an error for the caller, not the end of the module
+  let mut random = vec![0; 13];
+  assert_eq!((f.C_GenerateRandom.unwrap())(sh, random.as_mut_ptr(), 13), CKR_GENERAL_ERROR);
+  assert_eq!((f.C_GenerateRandom.unwrap())(sh, random.as_mut_ptr(), 4), CKR_OK);
+  assert_eq!(random[..4], [0x42; 4]);
+  assert_eq!((f.C_GenerateRandom.unwrap())(sh, ptr::null_mut(), 4), CKR_ARGUMENTS_BAD);
+
+  assert_eq!((f.C_CloseAllSessions.unwrap())(2), CKR_SLOT_ID_INVALID);
+  assert_eq!((f.C_CloseAllSessions.unwrap())(1), CKR_OK);
+  assert_eq!((f.C_CloseSession.unwrap())(sh), CKR_SESSION_HANDLE_INVALID);
+  assert_eq!((f.C_Finalize.unwrap())(ptr::null_mut()), CKR_OK);
+  assert_eq!((f.C_Finalize.unwrap())(ptr::null_mut()), CKR_CRYPTOKI_NOT_INITIALIZED);
+}
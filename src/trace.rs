@@ -0,0 +1,90 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Spans around the `Ctx` methods (feature `tracing`).
+//!
+//! Every `Ctx` method that calls into the module runs in a `DEBUG` span with
+//! the target `pkcs11`, named after the PKCS#11 function, e.g. `C_Sign`.
+//! The span has the fields:
+//!
+//! - `slot_id`, `session`, `object`, `key`, `wrapping_key`, `unwrapping_key`
+//!   and `base_key`: the handles the method was given.
+//! - `mechanism` and `user_type`: their names.
+//! - `data_len`, `signature_len`, `template_len` and the like: the lengths
+//!   of the data and templates the method was given.
+//! - `rv`: the name of the return value of the last call into the module.
+//! - `duration_us`: how long the method took, in microseconds.
+//! - `error`: the error of that call, if it failed.
+//!
+//! Methods that fail before calling into the module, e.g. on an
+//! uninitialized `Ctx`, leave `rv` and `error` empty.
+//!
+//! PINs, labels, data and the values of template attributes are never
+//! recorded, so key material cannot end up in the logs. With
+//! `tracing-subscriber`, for example:
+//!
+//! ```text
+//! RUST_LOG=pkcs11=debug
+//! ```
+//!
+//! together with `FmtSpan::CLOSE` prints one line per call.
+
+use std::cell::RefCell;
+use std::time::Instant;
+
+use tracing::field;
+use tracing::span::EnteredSpan;
+use tracing::Span;
+
+use errors::{strerror, Error};
+use types::*;
+
+thread_local! {
+  // the spans of the `Ctx` methods running on this thread, innermost last
+  static CALLS: RefCell<Vec<Span>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The span of a `Ctx` method, entered until dropped, which records the
+/// duration of the method.
+pub(crate) struct Call {
+  span: EnteredSpan,
+  start: Instant,
+}
+
+impl Call {
+  pub(crate) fn enter(span: Span) -> Call {
+    CALLS.with(|calls| calls.borrow_mut().push(span.clone()));
+    Call { span: span.entered(), start: Instant::now() }
+  }
+}
+
+impl Drop for Call {
+  fn drop(&mut self) {
+    self.span.record("duration_us", self.start.elapsed().as_micros() as u64);
+    CALLS.with(|calls| calls.borrow_mut().pop());
+  }
+}
+
+/// Records the return value of a call into the module in the span of the
+/// method making it.
+pub(crate) fn record(rv: CK_RV) {
+  CALLS.with(|calls| {
+    if let Some(span) = calls.borrow().last() {
+      span.record("rv", strerror(rv));
+      if rv != CKR_OK {
+        span.record("error", field::display(Error::Pkcs11(rv)));
+      }
+    }
+  });
+}
@@ -461,6 +461,8 @@ pub const CKK_SEED: CK_KEY_TYPE = 0x0000002F;
 pub const CKK_GOSTR3410: CK_KEY_TYPE = 0x00000030;
 pub const CKK_GOSTR3411: CK_KEY_TYPE = 0x00000031;
 pub const CKK_GOST28147: CK_KEY_TYPE = 0x00000032;
+/// PKCS #11 v3.0
+pub const CKK_EC_EDWARDS: CK_KEY_TYPE = 0x00000040;
 pub const CKK_VENDOR_DEFINED: CK_KEY_TYPE = 0x80000000;
 
 /// CK_CERTIFICATE_TYPE is a value that identifies a certificate
@@ -1177,6 +1179,10 @@ pub const CKM_ECMQV_DERIVE: CK_MECHANISM_TYPE = 0x00001052;
 pub const CKM_ECDH_AES_KEY_WRAP: CK_MECHANISM_TYPE = 0x00001053;
 pub const CKM_RSA_AES_KEY_WRAP: CK_MECHANISM_TYPE = 0x00001054;
 
+/// PKCS #11 v3.0
+pub const CKM_EC_EDWARDS_KEY_PAIR_GEN: CK_MECHANISM_TYPE = 0x00001055;
+pub const CKM_EDDSA: CK_MECHANISM_TYPE = 0x00001057;
+
 pub const CKM_JUNIPER_KEY_GEN: CK_MECHANISM_TYPE = 0x00001060;
 pub const CKM_JUNIPER_ECB128: CK_MECHANISM_TYPE = 0x00001061;
 pub const CKM_JUNIPER_CBC128: CK_MECHANISM_TYPE = 0x00001062;
@@ -0,0 +1,373 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! RFC 7512 `pkcs11:` URIs, the way OpenSSL, GnuTLS and p11-kit refer to
+//! tokens and objects.
+//!
+//! ```no_run
+//! # use pkcs11::Ctx;
+//! # use pkcs11::uri::Pkcs11Uri;
+//! let uri: Pkcs11Uri = "pkcs11:token=signer;object=release-key;type=private?pin-value=1234".parse().unwrap();
+//! let ctx = Ctx::new_and_initialize(uri.module_path.as_ref().map_or("/usr/local/lib/softhsm/libsofthsm2.so", |p| p.as_str())).unwrap();
+//! let (session, key) = uri.resolve(&ctx).unwrap();
+//! ```
+
+// CK_ULONG is not usize everywhere
+#![allow(clippy::unnecessary_cast)]
+
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use super::Ctx;
+use errors::Error;
+use info::{trimmed, Version};
+use types::*;
+
+const SCHEME: &str = "pkcs11:";
+
+/// Characters besides the unreserved ones that path values keep unescaped.
+const PATH_CHARS: &str = ":[]@!$'()*+,=&";
+/// Characters besides the unreserved ones that query values keep unescaped.
+const QUERY_CHARS: &str = ":[]@!$'()*+,=/?|";
+
+/// The `type` values of RFC 7512.
+const TYPES: &[(CK_OBJECT_CLASS, &str)] = &[(CKO_CERTIFICATE, "cert"), (CKO_DATA, "data"), (CKO_PRIVATE_KEY, "private"), (CKO_PUBLIC_KEY, "public"), (CKO_SECRET_KEY, "secret-key")];
+
+/// A `pkcs11:` URI. Path attributes that are `None` match anything; the
+/// query attributes say where the module and the PIN come from. Attributes
+/// RFC 7512 does not define are kept as vendor attributes.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Pkcs11Uri {
+  pub library_manufacturer: Option<String>,
+  pub library_description: Option<String>,
+  pub library_version: Option<Version>,
+  pub slot_manufacturer: Option<String>,
+  pub slot_description: Option<String>,
+  pub slot_id: Option<CK_SLOT_ID>,
+  /// The token label.
+  pub token: Option<String>,
+  pub manufacturer: Option<String>,
+  pub model: Option<String>,
+  pub serial: Option<String>,
+  /// The object label.
+  pub object: Option<String>,
+  /// The object class of `type`.
+  pub object_type: Option<CK_OBJECT_CLASS>,
+  pub id: Option<Vec<u8>>,
+  pub vendor_path: Vec<(String, String)>,
+  pub pin_source: Option<String>,
+  pub pin_value: Option<String>,
+  pub module_name: Option<String>,
+  pub module_path: Option<String>,
+  pub vendor_query: Vec<(String, String)>,
+}
+
+impl fmt::Debug for Pkcs11Uri {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Pkcs11Uri")
+      .field("library_manufacturer", &self.library_manufacturer)
+      .field("library_description", &self.library_description)
+      .field("library_version", &self.library_version)
+      .field("slot_manufacturer", &self.slot_manufacturer)
+      .field("slot_description", &self.slot_description)
+      .field("slot_id", &self.slot_id)
+      .field("token", &self.token)
+      .field("manufacturer", &self.manufacturer)
+      .field("model", &self.model)
+      .field("serial", &self.serial)
+      .field("object", &self.object)
+      .field("object_type", &self.object_type)
+      .field("id", &self.id)
+      .field("vendor_path", &self.vendor_path)
+      .field("pin_source", &self.pin_source)
+      .field("pin_value", &self.pin_value.as_ref().map(|_| format_args!("[REDACTED]")))
+      .field("module_name", &self.module_name)
+      .field("module_path", &self.module_path)
+      .field("vendor_query", &self.vendor_query)
+      .finish()
+  }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Error> {
+  let bytes = value.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      // from_str_radix would take a sign as in %+1
+      let hex = value.get(i + 1..i + 3).filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit())).ok_or(Error::InvalidInput("invalid percent-encoding in URI"))?;
+      decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidInput("invalid percent-encoding in URI"))?);
+      i += 3;
+    } else {
+      decoded.push(bytes[i]);
+      i += 1;
+    }
+  }
+  Ok(decoded)
+}
+
+fn decode_string(value: &str) -> Result<String, Error> {
+  String::from_utf8(decode(value)?).map_err(|_| Error::InvalidInput("URI attribute is not valid UTF-8"))
+}
+
+fn encode(f: &mut fmt::Formatter, value: &[u8], allowed: &str) -> fmt::Result {
+  for &b in value {
+    if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || allowed.as_bytes().contains(&b) {
+      write!(f, "{}", b as char)?;
+    } else {
+      write!(f, "%{:02X}", b)?;
+    }
+  }
+  Ok(())
+}
+
+fn set<T>(field: &mut Option<T>, value: T) -> Result<(), Error> {
+  if field.is_some() {
+    return Err(Error::InvalidInput("URI attribute given twice"));
+  }
+  *field = Some(value);
+  Ok(())
+}
+
+fn parse_version(value: &str) -> Result<Version, Error> {
+  let (major, minor) = value.split_once('.').unwrap_or((value, "0"));
+  match (major.parse(), minor.parse()) {
+    (Ok(major), Ok(minor)) => Ok(Version { major, minor }),
+    _ => Err(Error::InvalidInput("invalid library-version in URI")),
+  }
+}
+
+impl FromStr for Pkcs11Uri {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Pkcs11Uri, Error> {
+    if s.len() < SCHEME.len() || !s[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+      return Err(Error::InvalidInput("URI does not start with pkcs11:"));
+    }
+    let (path, query) = s[SCHEME.len()..].split_once('?').unwrap_or((&s[SCHEME.len()..], ""));
+    let mut uri = Pkcs11Uri::default();
+    for attr in path.split(';').filter(|a| !a.is_empty()) {
+      let (name, value) = attr.split_once('=').ok_or(Error::InvalidInput("URI attribute without a value"))?;
+      match name {
+        "library-manufacturer" => set(&mut uri.library_manufacturer, decode_string(value)?)?,
+        "library-description" => set(&mut uri.library_description, decode_string(value)?)?,
+        "library-version" => set(&mut uri.library_version, parse_version(&decode_string(value)?)?)?,
+        "slot-manufacturer" => set(&mut uri.slot_manufacturer, decode_string(value)?)?,
+        "slot-description" => set(&mut uri.slot_description, decode_string(value)?)?,
+        "slot-id" => set(&mut uri.slot_id, decode_string(value)?.parse().map_err(|_| Error::InvalidInput("invalid slot-id in URI"))?)?,
+        "token" => set(&mut uri.token, decode_string(value)?)?,
+        "manufacturer" => set(&mut uri.manufacturer, decode_string(value)?)?,
+        "model" => set(&mut uri.model, decode_string(value)?)?,
+        "serial" => set(&mut uri.serial, decode_string(value)?)?,
+        "object" => set(&mut uri.object, decode_string(value)?)?,
+        "type" => {
+          let value = decode_string(value)?;
+          let class = TYPES.iter().find(|&&(_, t)| t == value).ok_or(Error::InvalidInput("invalid type in URI"))?.0;
+          set(&mut uri.object_type, class)?
+        }
+        "id" => set(&mut uri.id, decode(value)?)?,
+        _ => uri.vendor_path.push((name.to_string(), decode_string(value)?)),
+      }
+    }
+    for attr in query.split('&').filter(|a| !a.is_empty()) {
+      let (name, value) = attr.split_once('=').ok_or(Error::InvalidInput("URI attribute without a value"))?;
+      match name {
+        "pin-source" => set(&mut uri.pin_source, decode_string(value)?)?,
+        "pin-value" => set(&mut uri.pin_value, decode_string(value)?)?,
+        "module-name" => set(&mut uri.module_name, decode_string(value)?)?,
+        "module-path" => set(&mut uri.module_path, decode_string(value)?)?,
+        _ => uri.vendor_query.push((name.to_string(), decode_string(value)?)),
+      }
+    }
+    Ok(uri)
+  }
+}
+
+impl fmt::Display for Pkcs11Uri {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(SCHEME)?;
+    let version = self.library_version.map(|v| v.to_string());
+    let slot_id = self.slot_id.map(|s| s.to_string());
+    let object_type = self.object_type.and_then(|class| TYPES.iter().find(|&&(c, _)| c == class)).map(|&(_, t)| t);
+    let path = [
+      ("library-manufacturer", self.library_manufacturer.as_deref()),
+      ("library-description", self.library_description.as_deref()),
+      ("library-version", version.as_deref()),
+      ("slot-manufacturer", self.slot_manufacturer.as_deref()),
+      ("slot-description", self.slot_description.as_deref()),
+      ("slot-id", slot_id.as_deref()),
+      ("token", self.token.as_deref()),
+      ("manufacturer", self.manufacturer.as_deref()),
+      ("model", self.model.as_deref()),
+      ("serial", self.serial.as_deref()),
+      ("object", self.object.as_deref()),
+      ("type", object_type),
+    ];
+    let mut first = true;
+    let path = path.iter().filter_map(|&(name, value)| value.map(|v| (name, v))).chain(self.vendor_path.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+    for (name, value) in path {
+      if !first {
+        f.write_str(";")?;
+      }
+      first = false;
+      write!(f, "{}=", name)?;
+      encode(f, value.as_bytes(), PATH_CHARS)?;
+    }
+    if let Some(ref id) = self.id {
+      if !first {
+        f.write_str(";")?;
+      }
+      // every byte of the id is escaped, as RFC 7512 recommends
+      f.write_str("id=")?;
+      for b in id {
+        write!(f, "%{:02X}", b)?;
+      }
+    }
+    let query = [
+      ("pin-source", self.pin_source.as_deref()),
+      ("pin-value", self.pin_value.as_deref()),
+      ("module-name", self.module_name.as_deref()),
+      ("module-path", self.module_path.as_deref()),
+    ];
+    let query = query.iter().filter_map(|&(name, value)| value.map(|v| (name, v))).chain(self.vendor_query.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+    for (i, (name, value)) in query.enumerate() {
+      write!(f, "{}{}=", if i == 0 { "?" } else { "&" }, name)?;
+      encode(f, value.as_bytes(), QUERY_CHARS)?;
+    }
+    Ok(())
+  }
+}
+
+fn matches(wanted: &Option<String>, field: &[u8]) -> bool {
+  wanted.as_ref().is_none_or(|w| *w == trimmed(field))
+}
+
+impl Pkcs11Uri {
+  /// Whether the `library-*` attributes match the module.
+  pub fn matches_library(&self, info: &CK_INFO) -> bool {
+    matches(&self.library_manufacturer, &{ info.manufacturerID })
+      && matches(&self.library_description, &{ info.libraryDescription })
+      && self.library_version.is_none_or(|v| v == Version::from(info.libraryVersion))
+  }
+
+  /// Whether the `slot-*` attributes match the slot.
+  pub fn matches_slot(&self, slot_id: CK_SLOT_ID, info: &CK_SLOT_INFO) -> bool {
+    self.slot_id.is_none_or(|s| s == slot_id) && matches(&self.slot_manufacturer, &{ info.manufacturerID }) && matches(&self.slot_description, &{ info.slotDescription })
+  }
+
+  /// Whether the token attributes match the token.
+  pub fn matches_token(&self, info: &CK_TOKEN_INFO) -> bool {
+    matches(&self.token, &{ info.label }) && matches(&self.manufacturer, &{ info.manufacturerID }) && matches(&self.model, &{ info.model }) && matches(&self.serial, &{ info.serialNumber })
+  }
+
+  /// The `C_FindObjectsInit` template of the object attributes. It points
+  /// into the URI.
+  pub fn template(&self) -> Vec<CK_ATTRIBUTE> {
+    let mut template = Vec::new();
+    if let Some(ref class) = self.object_type {
+      template.push(CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(class));
+    }
+    if let Some(ref label) = self.object {
+      template.push(CK_ATTRIBUTE::new(CKA_LABEL).with_string(label));
+    }
+    if let Some(ref id) = self.id {
+      template.push(CK_ATTRIBUTE::new(CKA_ID).with_bytes(id));
+    }
+    template
+  }
+
+  /// The PIN of `pin-value`, or read from the file of `pin-source`, which
+  /// may be given as a path or a `file:` URI. Only the first line counts.
+  pub fn pin(&self) -> Result<Option<String>, Error> {
+    if let Some(ref pin) = self.pin_value {
+      return Ok(Some(pin.clone()));
+    }
+    match self.pin_source {
+      Some(ref source) => {
+        let path = source.strip_prefix("file://").or_else(|| source.strip_prefix("file:")).unwrap_or(source);
+        let contents = fs::read_to_string(path)?;
+        Ok(Some(contents.lines().next().unwrap_or("").to_string()))
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// The first slot whose module, slot and token match.
+  pub fn find_slot(&self, ctx: &Ctx) -> Result<CK_SLOT_ID, Error> {
+    if !self.vendor_path.is_empty() {
+      return Err(Error::InvalidInput("URI has path attributes that are not supported"));
+    }
+    if !self.matches_library(&ctx.get_info()?) {
+      return Err(Error::InvalidInput("URI does not match the module"));
+    }
+    for slot_id in ctx.get_slot_list(true)? {
+      if self.matches_slot(slot_id, &ctx.get_slot_info(slot_id)?) && self.matches_token(&ctx.get_token_info(slot_id)?) {
+        return Ok(slot_id);
+      }
+    }
+    Err(Error::InvalidInput("no token matches the URI"))
+  }
+
+  /// Opens a read-only session on the token of the URI. If the token wants
+  /// a login and the URI is not about public objects, it logs in as user
+  /// with `pin`, or with the protected authentication path of the token.
+  pub fn open(&self, ctx: &Ctx, pin: Option<&str>) -> Result<CK_SESSION_HANDLE, Error> {
+    let slot_id = self.find_slot(ctx)?;
+    let flags = ctx.get_token_info(slot_id)?.flags;
+    let session = ctx.open_session(slot_id, CKF_SERIAL_SESSION, None, None)?;
+    let public = self.object_type == Some(CKO_PUBLIC_KEY) || self.object_type == Some(CKO_CERTIFICATE);
+    if flags & CKF_LOGIN_REQUIRED != 0 && !public && (pin.is_some() || flags & CKF_PROTECTED_AUTHENTICATION_PATH != 0) {
+      match ctx.login(session, CKU_USER, pin) {
+        Ok(()) | Err(Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => {}
+        Err(err) => {
+          let _ = ctx.close_session(session);
+          return Err(err);
+        }
+      }
+    }
+    Ok(session)
+  }
+
+  /// All objects of `session` that match the object attributes.
+  pub fn find_objects(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
+    ctx.find_objects_init(session, &self.template())?;
+    let mut objects = Vec::new();
+    let res = loop {
+      match ctx.find_objects(session, 64) {
+        Ok(ref found) if found.is_empty() => break Ok(objects),
+        Ok(found) => objects.extend(found),
+        Err(err) => break Err(err),
+      }
+    };
+    ctx.find_objects_final(session)?;
+    res
+  }
+
+  /// Opens a session on the token with the PIN of the URI and finds the one
+  /// object the URI refers to.
+  pub fn resolve(&self, ctx: &Ctx) -> Result<(CK_SESSION_HANDLE, CK_OBJECT_HANDLE), Error> {
+    let pin = self.pin()?;
+    let session = self.open(ctx, pin.as_deref())?;
+    let object = self.find_objects(ctx, session).and_then(|objects| match objects.as_slice() {
+      [object] => Ok(*object),
+      [] => Err(Error::InvalidInput("no object matches the URI")),
+      _ => Err(Error::InvalidInput("the URI matches more than one object")),
+    });
+    if object.is_err() {
+      let _ = ctx.close_session(session);
+    }
+    Ok((session, object?))
+  }
+}
@@ -0,0 +1,887 @@
+// Copyright 2017 Marcus Heese
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ptr;
+use std::time::{Duration, SystemTime};
+
+use super::Ctx;
+use der;
+use der::Reader;
+use errors::Error;
+use types::*;
+
+pub const OID_RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+pub const OID_SHA256_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+pub const OID_SHA384_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 12];
+pub const OID_SHA512_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 13];
+pub const OID_EC_PUBLIC_KEY: &[u64] = &[1, 2, 840, 10045, 2, 1];
+pub const OID_ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+pub const OID_ECDSA_WITH_SHA384: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+pub const OID_ECDSA_WITH_SHA512: &[u64] = &[1, 2, 840, 10045, 4, 3, 4];
+pub const OID_SECP256R1: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+pub const OID_SECP384R1: &[u64] = &[1, 3, 132, 0, 34];
+pub const OID_SECP521R1: &[u64] = &[1, 3, 132, 0, 35];
+
+pub const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+pub const OID_SERIAL_NUMBER: &[u64] = &[2, 5, 4, 5];
+pub const OID_COUNTRY: &[u64] = &[2, 5, 4, 6];
+pub const OID_LOCALITY: &[u64] = &[2, 5, 4, 7];
+pub const OID_STATE: &[u64] = &[2, 5, 4, 8];
+pub const OID_ORGANIZATION: &[u64] = &[2, 5, 4, 10];
+pub const OID_ORGANIZATIONAL_UNIT: &[u64] = &[2, 5, 4, 11];
+pub const OID_EMAIL_ADDRESS: &[u64] = &[1, 2, 840, 113549, 1, 9, 1];
+pub const OID_EXTENSION_REQUEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 14];
+
+pub const OID_SUBJECT_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 14];
+pub const OID_KEY_USAGE: &[u64] = &[2, 5, 29, 15];
+pub const OID_SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+pub const OID_BASIC_CONSTRAINTS: &[u64] = &[2, 5, 29, 19];
+pub const OID_AUTHORITY_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 35];
+pub const OID_EXTENDED_KEY_USAGE: &[u64] = &[2, 5, 29, 37];
+
+// the extensions of a certification request that make it into the certificate
+const REQUESTED_EXTENSIONS: &[&[u64]] = &[OID_SUBJECT_ALT_NAME];
+
+pub const OID_KP_SERVER_AUTH: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 1];
+pub const OID_KP_CLIENT_AUTH: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 2];
+pub const OID_KP_CODE_SIGNING: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 3];
+pub const OID_KP_EMAIL_PROTECTION: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 4];
+pub const OID_KP_TIME_STAMPING: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 8];
+pub const OID_KP_OCSP_SIGNING: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 3, 9];
+
+/// KeyUsage bits, to be combined and passed to `Extension::key_usage`
+pub const KU_DIGITAL_SIGNATURE: u16 = 0x8000;
+pub const KU_NON_REPUDIATION: u16 = 0x4000;
+pub const KU_KEY_ENCIPHERMENT: u16 = 0x2000;
+pub const KU_DATA_ENCIPHERMENT: u16 = 0x1000;
+pub const KU_KEY_AGREEMENT: u16 = 0x0800;
+pub const KU_KEY_CERT_SIGN: u16 = 0x0400;
+pub const KU_CRL_SIGN: u16 = 0x0200;
+pub const KU_ENCIPHER_ONLY: u16 = 0x0100;
+pub const KU_DECIPHER_ONLY: u16 = 0x0080;
+
+pub(crate) fn mechanism(mechanism: CK_MECHANISM_TYPE) -> CK_MECHANISM {
+  CK_MECHANISM {
+    mechanism,
+    pParameter: ptr::null_mut(),
+    ulParameterLen: 0,
+  }
+}
+
+/// The signature algorithms that can be used to sign certificates with a token key.
+///
+/// RSA signatures use the combined `CKM_SHA*_RSA_PKCS` mechanisms. For ECDSA
+/// the data is hashed with `C_Digest` first and then signed with `CKM_ECDSA`,
+/// which is more widely supported than the combined ECDSA mechanisms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+  Sha256WithRsa,
+  Sha384WithRsa,
+  Sha512WithRsa,
+  EcdsaWithSha256,
+  EcdsaWithSha384,
+  EcdsaWithSha512,
+}
+
+impl SignatureAlgorithm {
+  pub fn oid(self) -> &'static [u64] {
+    match self {
+      SignatureAlgorithm::Sha256WithRsa => OID_SHA256_WITH_RSA,
+      SignatureAlgorithm::Sha384WithRsa => OID_SHA384_WITH_RSA,
+      SignatureAlgorithm::Sha512WithRsa => OID_SHA512_WITH_RSA,
+      SignatureAlgorithm::EcdsaWithSha256 => OID_ECDSA_WITH_SHA256,
+      SignatureAlgorithm::EcdsaWithSha384 => OID_ECDSA_WITH_SHA384,
+      SignatureAlgorithm::EcdsaWithSha512 => OID_ECDSA_WITH_SHA512,
+    }
+  }
+
+  pub fn from_oid(oid: &[u64]) -> Result<SignatureAlgorithm, Error> {
+    [
+      SignatureAlgorithm::Sha256WithRsa,
+      SignatureAlgorithm::Sha384WithRsa,
+      SignatureAlgorithm::Sha512WithRsa,
+      SignatureAlgorithm::EcdsaWithSha256,
+      SignatureAlgorithm::EcdsaWithSha384,
+      SignatureAlgorithm::EcdsaWithSha512,
+    ]
+      .iter()
+      .find(|alg| alg.oid() == oid)
+      .cloned()
+      .ok_or(Error::InvalidInput("unsupported signature algorithm"))
+  }
+
+  pub fn is_ecdsa(self) -> bool {
+    matches!(self, SignatureAlgorithm::EcdsaWithSha256 | SignatureAlgorithm::EcdsaWithSha384 | SignatureAlgorithm::EcdsaWithSha512)
+  }
+
+  /// The digest mechanism matching the hash of this algorithm.
+  pub fn digest_mechanism(self) -> CK_MECHANISM_TYPE {
+    match self {
+      SignatureAlgorithm::Sha256WithRsa | SignatureAlgorithm::EcdsaWithSha256 => CKM_SHA256,
+      SignatureAlgorithm::Sha384WithRsa | SignatureAlgorithm::EcdsaWithSha384 => CKM_SHA384,
+      SignatureAlgorithm::Sha512WithRsa | SignatureAlgorithm::EcdsaWithSha512 => CKM_SHA512,
+    }
+  }
+
+  /// The DER `AlgorithmIdentifier`; RSA carries explicit NULL parameters, ECDSA none.
+  pub fn algorithm_identifier(self) -> Vec<u8> {
+    if self.is_ecdsa() {
+      der::sequence(&[der::oid(self.oid())])
+    } else {
+      der::sequence(&[der::oid(self.oid()), der::null()])
+    }
+  }
+
+  pub fn from_algorithm_identifier(alg_id: &[u8]) -> Result<SignatureAlgorithm, Error> {
+    let mut seq = Reader::new(alg_id).read_sequence()?;
+    SignatureAlgorithm::from_oid(&seq.read_oid()?)
+  }
+
+  /// Picks a default algorithm for the given token key: SHA-256 with the key's family.
+  pub fn for_key(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<SignatureAlgorithm, Error> {
+    match ctx.get_attribute_ulong(session, key, CKA_KEY_TYPE)? {
+      CKK_RSA => Ok(SignatureAlgorithm::Sha256WithRsa),
+      CKK_EC => Ok(SignatureAlgorithm::EcdsaWithSha256),
+      _ => Err(Error::InvalidInput("key type is not supported for certificate signatures")),
+    }
+  }
+
+  /// Signs `data` with the private key and returns the signature in the
+  /// encoding used by X.509 and CMS (a DER `Ecdsa-Sig-Value` for ECDSA).
+  pub fn sign(self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if self.is_ecdsa() {
+      let hash = digest(ctx, session, self.digest_mechanism(), data)?;
+      ctx.sign_init(session, &mechanism(CKM_ECDSA), key)?;
+      der::ecdsa_signature_from_raw(&ctx.sign(session, &hash)?)
+    } else {
+      ctx.sign_init(session, &mechanism(self.rsa_mechanism()), key)?;
+      ctx.sign(session, data)
+    }
+  }
+
+  /// Verifies a signature created by `sign` with a public key object on the token.
+  pub fn verify(self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    if self.is_ecdsa() {
+      let params = ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?;
+      let raw = der::ecdsa_signature_to_raw(signature, curve_width(&params)?)?;
+      let hash = digest(ctx, session, self.digest_mechanism(), data)?;
+      ctx.verify_init(session, &mechanism(CKM_ECDSA), key)?;
+      ctx.verify(session, &hash, &raw)
+    } else {
+      ctx.verify_init(session, &mechanism(self.rsa_mechanism()), key)?;
+      ctx.verify(session, data, signature)
+    }
+  }
+
+  fn rsa_mechanism(self) -> CK_MECHANISM_TYPE {
+    match self.digest_mechanism() {
+      CKM_SHA384 => CKM_SHA384_RSA_PKCS,
+      CKM_SHA512 => CKM_SHA512_RSA_PKCS,
+      _ => CKM_SHA256_RSA_PKCS,
+    }
+  }
+}
+
+/// Hashes `data` on the token with a single `C_Digest` call.
+pub fn digest(ctx: &Ctx, session: CK_SESSION_HANDLE, mechanism_type: CK_MECHANISM_TYPE, data: &[u8]) -> Result<Vec<u8>, Error> {
+  ctx.digest_init(session, &mechanism(mechanism_type))?;
+  ctx.digest(session, data)
+}
+
+/// Returns the size in bytes of a field element for the named curve in `CKA_EC_PARAMS`.
+pub fn curve_width(ec_params: &[u8]) -> Result<usize, Error> {
+  let oid = Reader::new(ec_params).read_oid()?;
+  if oid == OID_SECP256R1 {
+    Ok(32)
+  } else if oid == OID_SECP384R1 {
+    Ok(48)
+  } else if oid == OID_SECP521R1 {
+    Ok(66)
+  } else {
+    Err(Error::InvalidInput("unsupported elliptic curve"))
+  }
+}
+
+/// A distinguished name, built from relative distinguished names in order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Name {
+  rdns: Vec<Vec<u8>>,
+}
+
+impl Name {
+  pub fn new() -> Name {
+    Name { rdns: Vec::new() }
+  }
+
+  /// Wraps an already encoded `Name`.
+  pub fn from_der(der: &[u8]) -> Result<Name, Error> {
+    let mut reader = Reader::new(der);
+    let mut seq = reader.read_sequence()?;
+    if !reader.is_empty() {
+      return Err(Error::InvalidInput("trailing data after name"));
+    }
+    let mut rdns = Vec::new();
+    while !seq.is_empty() {
+      rdns.push(seq.read_element(der::TAG_SET)?.to_vec());
+    }
+    Ok(Name { rdns })
+  }
+
+  /// Appends an attribute with a UTF8String value.
+  pub fn add(mut self, oid: &[u64], value: &str) -> Name {
+    let atv = der::sequence(&[der::oid(oid), der::utf8_string(value)]);
+    self.rdns.push(der::set_of(&[atv]));
+    self
+  }
+
+  pub fn country(mut self, value: &str) -> Name {
+    let atv = der::sequence(&[der::oid(OID_COUNTRY), der::printable_string(value)]);
+    self.rdns.push(der::set_of(&[atv]));
+    self
+  }
+
+  pub fn state(self, value: &str) -> Name {
+    self.add(OID_STATE, value)
+  }
+
+  pub fn locality(self, value: &str) -> Name {
+    self.add(OID_LOCALITY, value)
+  }
+
+  pub fn organization(self, value: &str) -> Name {
+    self.add(OID_ORGANIZATION, value)
+  }
+
+  pub fn organizational_unit(self, value: &str) -> Name {
+    self.add(OID_ORGANIZATIONAL_UNIT, value)
+  }
+
+  pub fn common_name(self, value: &str) -> Name {
+    self.add(OID_COMMON_NAME, value)
+  }
+
+  pub fn serial_number(mut self, value: &str) -> Name {
+    let atv = der::sequence(&[der::oid(OID_SERIAL_NUMBER), der::printable_string(value)]);
+    self.rdns.push(der::set_of(&[atv]));
+    self
+  }
+
+  pub fn email_address(mut self, value: &str) -> Name {
+    let atv = der::sequence(&[der::oid(OID_EMAIL_ADDRESS), der::ia5_string(value)]);
+    self.rdns.push(der::set_of(&[atv]));
+    self
+  }
+
+  pub fn to_der(&self) -> Vec<u8> {
+    der::sequence(&self.rdns)
+  }
+}
+
+/// A certificate extension with its DER encoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+  pub oid: Vec<u64>,
+  pub critical: bool,
+  pub value: Vec<u8>,
+}
+
+impl Extension {
+  pub fn new(oid: &[u64], critical: bool, value: Vec<u8>) -> Extension {
+    Extension {
+      oid: oid.to_vec(),
+      critical,
+      value,
+    }
+  }
+
+  pub fn basic_constraints(ca: bool, path_len: Option<u32>) -> Extension {
+    let mut fields = Vec::new();
+    if ca {
+      fields.push(der::boolean(true));
+    }
+    if let Some(len) = path_len {
+      fields.push(der::small_integer(u64::from(len)));
+    }
+    Extension::new(OID_BASIC_CONSTRAINTS, true, der::sequence(&fields))
+  }
+
+  /// `bits` is a combination of the `KU_*` constants.
+  pub fn key_usage(bits: u16) -> Extension {
+    Extension::new(OID_KEY_USAGE, true, der::named_bits(bits))
+  }
+
+  pub fn extended_key_usage(purposes: &[&[u64]]) -> Extension {
+    let oids: Vec<Vec<u8>> = purposes.iter().map(|p| der::oid(p)).collect();
+    Extension::new(OID_EXTENDED_KEY_USAGE, false, der::sequence(&oids))
+  }
+
+  pub fn subject_key_identifier(id: &[u8]) -> Extension {
+    Extension::new(OID_SUBJECT_KEY_IDENTIFIER, false, der::octet_string(id))
+  }
+
+  pub fn authority_key_identifier(id: &[u8]) -> Extension {
+    Extension::new(OID_AUTHORITY_KEY_IDENTIFIER, false, der::sequence(&[der::tlv(der::tag_implicit(0), id)]))
+  }
+
+  /// A subjectAltName with DNS names and e-mail addresses.
+  pub fn subject_alt_name(dns_names: &[&str], emails: &[&str]) -> Extension {
+    let mut names: Vec<Vec<u8>> = emails.iter().map(|e| der::tlv(der::tag_implicit(1), e.as_bytes())).collect();
+    names.extend(dns_names.iter().map(|d| der::tlv(der::tag_implicit(2), d.as_bytes())));
+    Extension::new(OID_SUBJECT_ALT_NAME, false, der::sequence(&names))
+  }
+
+  pub fn to_der(&self) -> Vec<u8> {
+    let mut fields = vec![der::oid(&self.oid)];
+    if self.critical {
+      fields.push(der::boolean(true));
+    }
+    fields.push(der::octet_string(&self.value));
+    der::sequence(&fields)
+  }
+
+  pub fn from_der(der: &[u8]) -> Result<Extension, Error> {
+    let mut seq = Reader::new(der).read_sequence()?;
+    let oid = seq.read_oid()?;
+    let critical = match seq.read_optional(der::TAG_BOOLEAN)? {
+      Some(b) => b.first().is_some_and(|b| *b != 0),
+      None => false,
+    };
+    let value = seq.read(der::TAG_OCTET_STRING)?.to_vec();
+    Ok(Extension { oid, critical, value })
+  }
+}
+
+fn parse_extensions(der: &[u8]) -> Result<Vec<Extension>, Error> {
+  let mut seq = Reader::new(der).read_sequence()?;
+  let mut extensions = Vec::new();
+  while !seq.is_empty() {
+    extensions.push(Extension::from_der(seq.read_element(der::TAG_SEQUENCE)?)?);
+  }
+  Ok(extensions)
+}
+
+/// A DER encoded `SubjectPublicKeyInfo` for an RSA or EC key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubjectPublicKeyInfo {
+  der: Vec<u8>,
+}
+
+impl SubjectPublicKeyInfo {
+  pub fn from_der(der: &[u8]) -> Result<SubjectPublicKeyInfo, Error> {
+    let spki = SubjectPublicKeyInfo { der: der.to_vec() };
+    spki.parts()?;
+    Ok(spki)
+  }
+
+  /// Builds the public key info from a public key object, or from an RSA
+  /// private key object which carries the modulus and public exponent too.
+  pub fn from_token(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<SubjectPublicKeyInfo, Error> {
+    match ctx.get_attribute_ulong(session, key, CKA_KEY_TYPE)? {
+      CKK_RSA => {
+        let modulus = ctx.get_attribute_bytes(session, key, CKA_MODULUS)?;
+        let exponent = ctx.get_attribute_bytes(session, key, CKA_PUBLIC_EXPONENT)?;
+        Ok(SubjectPublicKeyInfo::rsa(&modulus, &exponent))
+      }
+      CKK_EC => {
+        let params = ctx.get_attribute_bytes(session, key, CKA_EC_PARAMS)?;
+        let point = ctx.get_attribute_bytes(session, key, CKA_EC_POINT)?;
+        Ok(SubjectPublicKeyInfo::ec(&params, &unwrap_ec_point(&point)))
+      }
+      _ => Err(Error::InvalidInput("key type is not supported for public key infos")),
+    }
+  }
+
+  /// An RSA key from its big-endian modulus and public exponent.
+  pub fn rsa(modulus: &[u8], public_exponent: &[u8]) -> SubjectPublicKeyInfo {
+    let key = der::sequence(&[der::unsigned_integer(modulus), der::unsigned_integer(public_exponent)]);
+    let alg = der::sequence(&[der::oid(OID_RSA_ENCRYPTION), der::null()]);
+    SubjectPublicKeyInfo {
+      der: der::sequence(&[alg, der::bit_string(&key)]),
+    }
+  }
+
+  /// An EC key from the `CKA_EC_PARAMS` encoding and the uncompressed point.
+  pub fn ec(ec_params: &[u8], point: &[u8]) -> SubjectPublicKeyInfo {
+    let mut alg = der::oid(OID_EC_PUBLIC_KEY);
+    alg.extend_from_slice(ec_params);
+    SubjectPublicKeyInfo {
+      der: der::sequence(&[der::tlv(der::TAG_SEQUENCE, &alg), der::bit_string(point)]),
+    }
+  }
+
+  // returns (algorithm OID, algorithm parameters, subjectPublicKey)
+  #[allow(clippy::type_complexity)]
+  fn parts(&self) -> Result<(Vec<u64>, &[u8], &[u8]), Error> {
+    let mut spki = Reader::new(&self.der).read_sequence()?;
+    let mut alg = spki.read_sequence()?;
+    let oid = alg.read_oid()?;
+    let params = if alg.is_empty() { &[][..] } else { alg.read_any()?.2 };
+    let key = spki.read_bit_string()?;
+    Ok((oid, params, key))
+  }
+
+  pub fn as_der(&self) -> &[u8] {
+    &self.der
+  }
+
+  /// The contents of the subjectPublicKey BIT STRING.
+  pub fn subject_public_key(&self) -> &[u8] {
+    self.parts().map(|(_, _, key)| key).unwrap_or(&[])
+  }
+
+  pub fn key_type(&self) -> Result<CK_KEY_TYPE, Error> {
+    let (oid, _, _) = self.parts()?;
+    if oid == OID_RSA_ENCRYPTION {
+      Ok(CKK_RSA)
+    } else if oid == OID_EC_PUBLIC_KEY {
+      Ok(CKK_EC)
+    } else {
+      Err(Error::InvalidInput("unsupported public key algorithm"))
+    }
+  }
+
+  /// Creates a session public key object for this key that can be used with
+  /// `C_Verify`. The caller is responsible for destroying it.
+  pub fn import(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<CK_OBJECT_HANDLE, Error> {
+    let token = CK_FALSE;
+    let verify = CK_TRUE;
+    self.create(ctx, session, &[CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token), CK_ATTRIBUTE::new(CKA_VERIFY).with_bool(&verify)])
+  }
+
+  /// Stores the key as a `CKO_PUBLIC_KEY` token object for verification and
+  /// encryption, like `Certificate::store`.
+  pub fn store(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, label: &str, id: &[u8]) -> Result<CK_OBJECT_HANDLE, Error> {
+    let token = CK_TRUE;
+    let usage = CK_TRUE;
+    let mut template = vec![
+      CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+      CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+      CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+      CK_ATTRIBUTE::new(CKA_VERIFY).with_bool(&usage),
+    ];
+    if self.key_type()? == CKK_RSA {
+      template.push(CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&usage));
+    }
+    self.create(ctx, session, &template)
+  }
+
+  fn create(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, extra: &[CK_ATTRIBUTE]) -> Result<CK_OBJECT_HANDLE, Error> {
+    let (oid, params, key) = self.parts()?;
+    let class = CKO_PUBLIC_KEY;
+    if oid == OID_RSA_ENCRYPTION {
+      let mut seq = Reader::new(key).read_sequence()?;
+      let modulus = seq.read_unsigned_integer()?;
+      let exponent = seq.read_unsigned_integer()?;
+      let key_type = CKK_RSA;
+      let mut template = vec![
+        CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+        CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+        CK_ATTRIBUTE::new(CKA_MODULUS).with_bytes(modulus),
+        CK_ATTRIBUTE::new(CKA_PUBLIC_EXPONENT).with_bytes(exponent),
+      ];
+      template.extend_from_slice(extra);
+      ctx.create_object(session, &template)
+    } else if oid == OID_EC_PUBLIC_KEY {
+      let key_type = CKK_EC;
+      let point = der::octet_string(key);
+      let mut template = vec![
+        CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+        CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+        CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(params),
+        CK_ATTRIBUTE::new(CKA_EC_POINT).with_bytes(&point),
+      ];
+      template.extend_from_slice(extra);
+      ctx.create_object(session, &template)
+    } else {
+      Err(Error::InvalidInput("unsupported public key algorithm"))
+    }
+  }
+}
+
+/// `CKA_EC_POINT` should be a DER OCTET STRING, but some modules return the raw point.
+pub fn unwrap_ec_point(point: &[u8]) -> Vec<u8> {
+  let mut reader = Reader::new(point);
+  match reader.read(der::TAG_OCTET_STRING) {
+    Ok(inner) if reader.is_empty() && inner.first() == Some(&0x04) => inner.to_vec(),
+    _ => point.to_vec(),
+  }
+}
+
+/// A parsed PKCS#10 certification request.
+#[derive(Debug, Clone)]
+pub struct CertificationRequest {
+  der: Vec<u8>,
+  info: Vec<u8>,
+  subject: Name,
+  public_key: SubjectPublicKeyInfo,
+  extensions: Vec<Extension>,
+  signature_algorithm: SignatureAlgorithm,
+  signature: Vec<u8>,
+}
+
+impl CertificationRequest {
+  pub fn from_der(der: &[u8]) -> Result<CertificationRequest, Error> {
+    let mut csr = Reader::new(der).read_sequence()?;
+    let info = csr.read_element(der::TAG_SEQUENCE)?;
+    let signature_algorithm = SignatureAlgorithm::from_algorithm_identifier(csr.read_element(der::TAG_SEQUENCE)?)?;
+    let signature = csr.read_bit_string()?.to_vec();
+
+    let mut cri = Reader::new(info).read_sequence()?;
+    cri.read_unsigned_integer()?;
+    let subject = Name::from_der(cri.read_element(der::TAG_SEQUENCE)?)?;
+    let public_key = SubjectPublicKeyInfo::from_der(cri.read_element(der::TAG_SEQUENCE)?)?;
+    let mut extensions = Vec::new();
+    if let Some(attributes) = cri.read_optional(der::tag_explicit(0))? {
+      let mut attributes = Reader::new(attributes);
+      while !attributes.is_empty() {
+        let mut attribute = attributes.read_sequence()?;
+        if attribute.read_oid()? == OID_EXTENSION_REQUEST {
+          let mut values = Reader::new(attribute.read(der::TAG_SET)?);
+          extensions = parse_extensions(values.read_element(der::TAG_SEQUENCE)?)?;
+        }
+      }
+    }
+    Ok(CertificationRequest {
+      der: der.to_vec(),
+      info: info.to_vec(),
+      subject,
+      public_key,
+      extensions,
+      signature_algorithm,
+      signature,
+    })
+  }
+
+  pub fn from_pem(pem: &str) -> Result<CertificationRequest, Error> {
+    CertificationRequest::from_der(&der::pem_decode("CERTIFICATE REQUEST", pem)?)
+  }
+
+  pub fn as_der(&self) -> &[u8] {
+    &self.der
+  }
+
+  pub fn subject(&self) -> &Name {
+    &self.subject
+  }
+
+  pub fn public_key(&self) -> &SubjectPublicKeyInfo {
+    &self.public_key
+  }
+
+  /// The extensions requested through the extensionRequest attribute.
+  pub fn extensions(&self) -> &[Extension] {
+    &self.extensions
+  }
+
+  /// Checks the proof of possession by importing the requested public key as
+  /// a session object and verifying the request signature with it.
+  pub fn verify(&self, ctx: &Ctx, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+    let key = self.public_key.import(ctx, session)?;
+    let res = self.signature_algorithm.verify(ctx, session, key, &self.info, &self.signature);
+    ctx.destroy_object(session, key)?;
+    res
+  }
+}
+
+/// A DER encoded X.509 certificate together with the fields needed to store
+/// it on a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+  der: Vec<u8>,
+  serial_number: Vec<u8>,
+  issuer: Vec<u8>,
+  subject: Vec<u8>,
+  public_key: SubjectPublicKeyInfo,
+  extensions: Vec<Extension>,
+}
+
+impl Certificate {
+  pub fn from_der(der: &[u8]) -> Result<Certificate, Error> {
+    let mut cert = Reader::new(der).read_sequence()?;
+    let mut tbs = cert.read_sequence()?;
+    tbs.read_optional(der::tag_explicit(0))?;
+    let serial_number = tbs.read_unsigned_integer()?.to_vec();
+    tbs.read_sequence()?;
+    let issuer = tbs.read_element(der::TAG_SEQUENCE)?.to_vec();
+    tbs.read_sequence()?;
+    let subject = tbs.read_element(der::TAG_SEQUENCE)?.to_vec();
+    let public_key = SubjectPublicKeyInfo::from_der(tbs.read_element(der::TAG_SEQUENCE)?)?;
+    tbs.read_optional(der::tag_implicit(1))?;
+    tbs.read_optional(der::tag_implicit(2))?;
+    let extensions = match tbs.read_optional(der::tag_explicit(3))? {
+      Some(exts) => parse_extensions(exts)?,
+      None => Vec::new(),
+    };
+    Ok(Certificate {
+      der: der.to_vec(),
+      serial_number,
+      issuer,
+      subject,
+      public_key,
+      extensions,
+    })
+  }
+
+  pub fn from_pem(pem: &str) -> Result<Certificate, Error> {
+    Certificate::from_der(&der::pem_decode("CERTIFICATE", pem)?)
+  }
+
+  /// Reads the `CKA_VALUE` of a `CKO_CERTIFICATE` object.
+  pub fn from_token(ctx: &Ctx, session: CK_SESSION_HANDLE, object: CK_OBJECT_HANDLE) -> Result<Certificate, Error> {
+    Certificate::from_der(&ctx.get_attribute_bytes(session, object, CKA_VALUE)?)
+  }
+
+  pub fn as_der(&self) -> &[u8] {
+    &self.der
+  }
+
+  pub fn to_pem(&self) -> String {
+    der::pem_encode("CERTIFICATE", &self.der)
+  }
+
+  /// The serial number as big-endian magnitude.
+  pub fn serial_number(&self) -> &[u8] {
+    &self.serial_number
+  }
+
+  /// The DER encoded issuer name.
+  pub fn issuer(&self) -> &[u8] {
+    &self.issuer
+  }
+
+  /// The DER encoded subject name.
+  pub fn subject(&self) -> &[u8] {
+    &self.subject
+  }
+
+  pub fn public_key(&self) -> &SubjectPublicKeyInfo {
+    &self.public_key
+  }
+
+  pub fn extensions(&self) -> &[Extension] {
+    &self.extensions
+  }
+
+  pub fn subject_key_identifier(&self) -> Option<Vec<u8>> {
+    self
+      .extensions
+      .iter()
+      .find(|ext| ext.oid == OID_SUBJECT_KEY_IDENTIFIER)
+      .and_then(|ext| Reader::new(&ext.value).read(der::TAG_OCTET_STRING).ok())
+      .map(|id| id.to_vec())
+  }
+
+  /// Stores the certificate as a `CKO_CERTIFICATE` token object. Pass the
+  /// `CKA_ID` of the matching key so that applications find them as a pair.
+  pub fn store(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, label: &str, id: &[u8]) -> Result<CK_OBJECT_HANDLE, Error> {
+    let class = CKO_CERTIFICATE;
+    let cert_type = CKC_X_509;
+    let token = CK_TRUE;
+    let serial_number = der::unsigned_integer(&self.serial_number);
+    let template = vec![
+      CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+      CK_ATTRIBUTE::new(CKA_CERTIFICATE_TYPE).with_ck_ulong(&cert_type),
+      CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+      CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+      CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+      CK_ATTRIBUTE::new(CKA_SUBJECT).with_bytes(&self.subject),
+      CK_ATTRIBUTE::new(CKA_ISSUER).with_bytes(&self.issuer),
+      CK_ATTRIBUTE::new(CKA_SERIAL_NUMBER).with_bytes(&serial_number),
+      CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(&self.der),
+    ];
+    ctx.create_object(session, &template)
+  }
+
+  /// Like `store`, but takes `CKA_ID` and `CKA_LABEL` from the given key object.
+  pub fn store_next_to(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<CK_OBJECT_HANDLE, Error> {
+    let id = ctx.get_attribute_bytes(session, key, CKA_ID)?;
+    let label = ctx.get_attribute_bytes(session, key, CKA_LABEL)?;
+    self.store(ctx, session, &String::from_utf8_lossy(&label), &id)
+  }
+
+  /// Finds the `CKO_CERTIFICATE` object that shares its `CKA_ID` with the given key.
+  pub fn find_next_to(ctx: &Ctx, session: CK_SESSION_HANDLE, key: CK_OBJECT_HANDLE) -> Result<Certificate, Error> {
+    let id = ctx.get_attribute_bytes(session, key, CKA_ID)?;
+    let class = CKO_CERTIFICATE;
+    let template = vec![CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class), CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id)];
+    ctx.find_objects_init(session, &template)?;
+    let found = ctx.find_objects(session, 1);
+    ctx.find_objects_final(session)?;
+    match found?.first() {
+      Some(&object) => Certificate::from_token(ctx, session, object),
+      None => Err(Error::InvalidInput("no certificate with the CKA_ID of the key found on the token")),
+    }
+  }
+}
+
+/// Builds and signs X.509 v3 certificates with a token private key.
+///
+/// Without an issuer the certificate is self-signed. A subject key identifier
+/// (SHA-1 over the public key, computed on the token) is always added unless
+/// one is given explicitly, and an authority key identifier is derived from
+/// the issuer certificate or, for self-signed certificates, the subject key.
+#[derive(Debug, Clone)]
+pub struct CertificateBuilder {
+  serial_number: Option<Vec<u8>>,
+  subject: Name,
+  /// The DER encoded issuer name and the authority key identifier.
+  issuer: Option<(Vec<u8>, Option<Vec<u8>>)>,
+  not_before: SystemTime,
+  not_after: SystemTime,
+  public_key: Option<SubjectPublicKeyInfo>,
+  extensions: Vec<Extension>,
+  signature_algorithm: Option<SignatureAlgorithm>,
+}
+
+impl Default for CertificateBuilder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl CertificateBuilder {
+  /// A builder for a certificate valid for one year from now.
+  pub fn new() -> CertificateBuilder {
+    let now = SystemTime::now();
+    CertificateBuilder {
+      serial_number: None,
+      subject: Name::new(),
+      issuer: None,
+      not_before: now,
+      not_after: now + Duration::from_secs(365 * 86400),
+      public_key: None,
+      extensions: Vec::new(),
+      signature_algorithm: None,
+    }
+  }
+
+  /// Starts a certificate for the subject and public key of a certification
+  /// request. Of the requested extensions only the subject alternative name
+  /// is copied, whether the certificate is a CA and what the key may be used
+  /// for is up to the issuer to set. Call `verify` on the request first if
+  /// the proof of possession matters.
+  pub fn from_request(csr: &CertificationRequest) -> CertificateBuilder {
+    let mut builder = CertificateBuilder::new().subject(csr.subject().clone()).public_key(csr.public_key().clone());
+    builder.extensions = csr.extensions().iter().filter(|ext| REQUESTED_EXTENSIONS.contains(&&ext.oid[..])).cloned().collect();
+    builder
+  }
+
+  /// Sets the serial number as big-endian magnitude. Defaults to 16 random
+  /// bytes from the token.
+  pub fn serial_number(mut self, serial: &[u8]) -> CertificateBuilder {
+    self.serial_number = Some(serial.to_vec());
+    self
+  }
+
+  pub fn subject(mut self, subject: Name) -> CertificateBuilder {
+    self.subject = subject;
+    self
+  }
+
+  /// Issues the certificate below the given CA certificate. Its subject is
+  /// copied as it is encoded, so the issuer name matches it byte for byte.
+  pub fn issuer(mut self, issuer: &Certificate) -> CertificateBuilder {
+    self.issuer = Some((issuer.subject().to_vec(), issuer.subject_key_identifier()));
+    self
+  }
+
+  /// Sets the issuer name without an issuer certificate at hand.
+  pub fn issuer_name(mut self, issuer: Name) -> CertificateBuilder {
+    self.issuer = Some((issuer.to_der(), None));
+    self
+  }
+
+  pub fn validity(mut self, not_before: SystemTime, not_after: SystemTime) -> CertificateBuilder {
+    self.not_before = not_before;
+    self.not_after = not_after;
+    self
+  }
+
+  pub fn public_key(mut self, public_key: SubjectPublicKeyInfo) -> CertificateBuilder {
+    self.public_key = Some(public_key);
+    self
+  }
+
+  pub fn extension(mut self, extension: Extension) -> CertificateBuilder {
+    self.extensions.retain(|ext| ext.oid != extension.oid);
+    self.extensions.push(extension);
+    self
+  }
+
+  /// Defaults to SHA-256 with the family of the signing key.
+  pub fn signature_algorithm(mut self, alg: SignatureAlgorithm) -> CertificateBuilder {
+    self.signature_algorithm = Some(alg);
+    self
+  }
+
+  /// Encodes the TBSCertificate and signs it with `signing_key`. For
+  /// self-signed certificates without an explicit public key, the public key
+  /// is read from the signing key, which then has to be an RSA private key.
+  pub fn sign(&self, ctx: &Ctx, session: CK_SESSION_HANDLE, signing_key: CK_OBJECT_HANDLE) -> Result<Certificate, Error> {
+    let alg = match self.signature_algorithm {
+      Some(alg) => alg,
+      None => SignatureAlgorithm::for_key(ctx, session, signing_key)?,
+    };
+    let public_key = match self.public_key {
+      Some(ref pk) => pk.clone(),
+      None if self.issuer.is_none() => SubjectPublicKeyInfo::from_token(ctx, session, signing_key)?,
+      None => return Err(Error::InvalidInput("a public key is required for issued certificates")),
+    };
+    let serial_number = match self.serial_number {
+      Some(ref serial) => serial.clone(),
+      None => {
+        let mut serial = ctx.generate_random(session, 16)?;
+        serial[0] &= 0x7f;
+        serial[0] |= 0x01;
+        serial
+      }
+    };
+
+    let mut extensions = self.extensions.clone();
+    let ski = match extensions.iter().find(|ext| ext.oid == OID_SUBJECT_KEY_IDENTIFIER) {
+      Some(ext) => Reader::new(&ext.value).read(der::TAG_OCTET_STRING)?.to_vec(),
+      None => {
+        let ski = digest(ctx, session, CKM_SHA_1, public_key.subject_public_key())?;
+        extensions.push(Extension::subject_key_identifier(&ski));
+        ski
+      }
+    };
+    let (issuer, aki) = match self.issuer {
+      Some((ref name, ref aki)) => (name.clone(), aki.clone()),
+      None => (self.subject.to_der(), Some(ski)),
+    };
+    if let Some(aki) = aki {
+      if !extensions.iter().any(|ext| ext.oid == OID_AUTHORITY_KEY_IDENTIFIER) {
+        extensions.push(Extension::authority_key_identifier(&aki));
+      }
+    }
+
+    let mut tbs = vec![
+      der::explicit(0, &der::small_integer(2)),
+      der::unsigned_integer(&serial_number),
+      alg.algorithm_identifier(),
+      issuer,
+      der::sequence(&[der::time(self.not_before), der::time(self.not_after)]),
+      self.subject.to_der(),
+      public_key.as_der().to_vec(),
+    ];
+    if !extensions.is_empty() {
+      let exts: Vec<Vec<u8>> = extensions.iter().map(Extension::to_der).collect();
+      tbs.push(der::explicit(3, &der::sequence(&exts)));
+    }
+    let tbs = der::sequence(&tbs);
+    let signature = alg.sign(ctx, session, signing_key, &tbs)?;
+    Certificate::from_der(&der::sequence(&[tbs, alg.algorithm_identifier(), der::bit_string(&signature)]))
+  }
+}